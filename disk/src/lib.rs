@@ -16,6 +16,10 @@ pub struct Storage {
     max_file_size: usize,
     /// maximum number of files before deleting old file
     max_file_count: usize,
+    /// maximum total size of files on disk before deleting old files
+    max_disk_size: Option<usize>,
+    /// total size of files on disk
+    disk_size: usize,
     /// current open file
     current_write_file: BytesMut,
     /// current_read_file
@@ -30,12 +34,19 @@ impl Storage {
     ) -> io::Result<Storage> {
         let backup_path = backlog_dir.into();
         let backlog_file_ids = get_file_ids(&backup_path)?;
+        let mut disk_size = 0;
+        for id in backlog_file_ids.iter() {
+            let path = backup_path.join(format!("backup@{}", id));
+            disk_size += fs::metadata(path)?.len() as usize;
+        }
 
         Ok(Storage {
             backlog_file_ids,
             backup_path,
             max_file_size,
             max_file_count,
+            max_disk_size: None,
+            disk_size,
             current_write_file: BytesMut::with_capacity(max_file_size * 2),
             current_read_file: BytesMut::with_capacity(max_file_size * 2),
         })
@@ -49,10 +60,103 @@ impl Storage {
         &mut self.current_read_file
     }
 
+    /// Limits total size of files on disk, oldest files are deleted to stay within the limit
+    pub fn set_max_disk_size(&mut self, max_disk_size: usize) {
+        self.max_disk_size = Some(max_disk_size);
+    }
+
+    /// Total size of files on disk, excluding in memory buffers
+    pub fn disk_size(&self) -> usize {
+        self.disk_size
+    }
+
+    /// Number of files on disk, yet to be read
+    pub fn segment_count(&self) -> usize {
+        self.backlog_file_ids.len()
+    }
+
+    /// Size of data in the in memory write buffer, yet to be flushed onto disk
+    pub fn write_buffer_size(&self) -> usize {
+        self.current_write_file.len()
+    }
+
+    /// Checks if the write buffer has overflown and flushing it would delete older files to stay
+    /// within the limits of file count or disk size
+    pub fn is_full(&self) -> bool {
+        let size = self.current_write_file.len();
+        if size < self.max_file_size || self.backlog_file_ids.is_empty() {
+            return false;
+        }
+
+        match self.max_disk_size {
+            Some(max_disk_size) if self.disk_size + size > max_disk_size => true,
+            _ => self.backlog_file_ids.len() >= self.max_file_count,
+        }
+    }
+
+    /// Writes a probe file into the persistence directory and reads it back, to verify that
+    /// the directory is both writable and readable before relying on it to buffer data
+    pub fn warmup(&self) -> io::Result<()> {
+        const PROBE: &[u8] = b"uplink storage warmup";
+        let probe_path = self.backup_path.join("warmup");
+
+        let mut file =
+            OpenOptions::new().write(true).create(true).truncate(true).open(&probe_path)?;
+        file.write_all(PROBE)?;
+        file.sync_all()?;
+
+        let read = fs::read(&probe_path)?;
+        fs::remove_file(&probe_path)?;
+        if read != PROBE {
+            return Err(io::Error::new(ErrorKind::InvalidData, "Warmup probe mismatch"));
+        }
+
+        Ok(())
+    }
+
+    /// Reclaims space held by storage, once data on disk is caught up. Removes empty files left
+    /// behind by flushes that were interrupted before writing any data, along with the probe of an
+    /// interrupted warmup, and releases memory of empty buffers grown beyond their usual capacity.
+    /// Files with data are never removed, so that no data is lost if uplink crashes midway.
+    pub fn compact(&mut self) -> io::Result<Compaction> {
+        let mut compaction = Compaction::default();
+
+        let mut empty = vec![];
+        for id in self.backlog_file_ids.iter() {
+            let path = self.backup_path.join(format!("backup@{}", id));
+            if fs::metadata(&path)?.len() == 0 {
+                empty.push(*id);
+            }
+        }
+        for id in empty {
+            self.remove(id)?;
+            self.backlog_file_ids.retain(|backlog_id| *backlog_id != id);
+            compaction.files += 1;
+        }
+
+        match fs::remove_file(self.backup_path.join("warmup")) {
+            Ok(_) => compaction.files += 1,
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        let capacity = self.max_file_size * 2;
+        for buffer in [&mut self.current_read_file, &mut self.current_write_file] {
+            if buffer.is_empty() && buffer.capacity() > capacity {
+                compaction.memory += buffer.capacity() - capacity;
+                *buffer = BytesMut::with_capacity(capacity);
+            }
+        }
+
+        Ok(compaction)
+    }
+
     /// Removes a file with provided id
-    fn remove(&self, id: u64) -> io::Result<()> {
+    fn remove(&mut self, id: u64) -> io::Result<()> {
         let path = self.backup_path.join(&format!("backup@{}", id));
+        let size = fs::metadata(&path)?.len() as usize;
         fs::remove_file(path)?;
+        self.disk_size = self.disk_size.saturating_sub(size);
         Ok(())
     }
 
@@ -63,47 +167,60 @@ impl Storage {
         self.current_read_file.put_slice(&init);
     }
 
-    /// Opens file to flush current inmemory write buffer to disk.
+    /// Opens file to flush current inmemory write buffer, of given size, to disk.
     /// Also handles retention of previous files on disk
-    fn open_next_write_file(&mut self) -> io::Result<NextFile> {
+    fn open_next_write_file(&mut self, size: usize) -> io::Result<NextFile> {
         let next_file_id = self.backlog_file_ids.last().map_or(0, |id| id + 1);
         let next_file_path = self.backup_path.join(&format!("backup@{}", next_file_id));
         let next_file = OpenOptions::new().write(true).create(true).open(&next_file_path)?;
         self.backlog_file_ids.push(next_file_id);
 
-        let mut next = NextFile { path: next_file_path, file: next_file, deleted: None };
+        let mut next = NextFile { path: next_file_path, file: next_file, deleted: 0 };
 
         let backlog_files_count = self.backlog_file_ids.len();
         if backlog_files_count > self.max_file_count {
             // Backlog should always be > 0 given the earliest push. doesn't panic
             let id = self.backlog_file_ids.remove(0);
             warn!("file limit reached. deleting backup@{}", id);
-            next.deleted = Some(id);
+            next.deleted += 1;
             self.remove(id)?;
         }
 
+        // Delete old files till the next file fits within disk limit, never deleting the next file itself
+        if let Some(max_disk_size) = self.max_disk_size {
+            while self.disk_size + size > max_disk_size && self.backlog_file_ids.len() > 1 {
+                let id = self.backlog_file_ids.remove(0);
+                warn!("disk limit reached. deleting backup@{}", id);
+                next.deleted += 1;
+                self.remove(id)?;
+            }
+        }
+
         Ok(next)
     }
 
-    /// Flushes what ever is in current write buffer into a new file on the disk
+    /// Flushes what ever is in current write buffer into a new file on the disk.
+    /// Returns the number of old files deleted to make space
     #[inline]
-    fn flush(&mut self) -> io::Result<Option<u64>> {
-        let mut next_file = self.open_next_write_file()?;
+    fn flush(&mut self) -> io::Result<usize> {
+        let size = self.current_write_file.len();
+        let mut next_file = self.open_next_write_file(size)?;
         info!("Flushing data to disk!! {:?}", next_file.path);
         next_file.file.write_all(&self.current_write_file[..])?;
         next_file.file.flush()?;
         self.current_write_file.clear();
+        self.disk_size += size;
         Ok(next_file.deleted)
     }
 
     /// Checks current write buffer size and flushes it to disk when the size
-    /// exceeds configured size
-    pub fn flush_on_overflow(&mut self) -> io::Result<Option<u64>> {
+    /// exceeds configured size. Returns the number of old files deleted to make space
+    pub fn flush_on_overflow(&mut self) -> io::Result<usize> {
         if self.current_write_file.len() >= self.max_file_size {
             return self.flush();
         }
 
-        Ok(None)
+        Ok(0)
     }
 
     /// Reloads next buffer even if there is pending data in current buffer
@@ -183,10 +300,19 @@ fn get_file_ids(path: &Path) -> io::Result<Vec<u64>> {
     Ok(file_ids)
 }
 
+/// Space reclaimed by [`Storage::compact`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Compaction {
+    /// Number of files removed
+    pub files: usize,
+    /// Bytes of memory released
+    pub memory: usize,
+}
+
 struct NextFile {
     path: PathBuf,
     file: File,
-    deleted: Option<u64>,
+    deleted: usize,
 }
 
 #[cfg(test)]
@@ -227,6 +353,43 @@ mod test {
         assert_eq!(files, vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
     }
 
+    #[test]
+    fn warmup_leaves_no_files_behind() {
+        let backup = init_backup_folders();
+        let storage = Storage::new(backup.path(), 10 * 1036, 10).unwrap();
+
+        storage.warmup().unwrap();
+        assert_eq!(fs::read_dir(backup.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn compaction_removes_only_empty_files() {
+        let backup = init_backup_folders();
+        let mut storage = Storage::new(backup.path(), 1036, 10).unwrap();
+
+        // A file with data, flushed before an empty one left behind by an interrupted flush
+        let mut publish = Publish::new("hello", QoS::AtLeastOnce, vec![1; 1024]);
+        publish.pkid = 1;
+        publish.write(storage.writer()).unwrap();
+        storage.flush_on_overflow().unwrap();
+        File::create(backup.path().join("backup@1")).unwrap();
+        File::create(backup.path().join("warmup")).unwrap();
+
+        let mut storage = Storage::new(backup.path(), 1036, 10).unwrap();
+        // Write buffer grown beyond its usual capacity, as it was read from
+        storage.writer().reserve(10 * 1036);
+        let compaction = storage.compact().unwrap();
+        assert_eq!(compaction.files, 2);
+        assert!(compaction.memory >= 8 * 1036);
+        assert_eq!(get_file_ids(&backup.path()).unwrap(), vec![0]);
+        assert_eq!(storage.segment_count(), 1);
+
+        // Data on disk is still read back
+        assert!(!storage.reload_on_eof().unwrap());
+        assert_eq!(storage.reader().len(), 1036);
+        assert_eq!(storage.compact().unwrap(), Compaction::default());
+    }
+
     #[test]
     fn old_file_is_deleted_after_limit() {
         let backup = init_backup_folders();
@@ -256,6 +419,57 @@ mod test {
         assert_eq!(files, vec![2, 3, 4, 5, 6, 7, 8, 9, 10, 11]);
     }
 
+    #[test]
+    fn old_files_are_deleted_after_disk_limit() {
+        let backup = init_backup_folders();
+        let mut storage = Storage::new(backup.path(), 10 * 1036, 10).unwrap();
+        storage.set_max_disk_size(3 * 10 * 1036);
+
+        // 5 files created. 3 on disk
+        for _ in 0..50 {
+            let mut publish = Publish::new("hello", QoS::AtLeastOnce, vec![1; 1024]);
+            publish.pkid = 1;
+            publish.write(storage.writer()).unwrap();
+            storage.flush_on_overflow().unwrap();
+        }
+
+        let files = get_file_ids(&backup.path()).unwrap();
+        assert_eq!(files, vec![2, 3, 4]);
+        assert_eq!(storage.segment_count(), 3);
+        assert_eq!(storage.disk_size(), 3 * 10 * 1036);
+
+        // Disk size is restored from files on disk
+        let storage = Storage::new(backup.path(), 10 * 1036, 10).unwrap();
+        assert_eq!(storage.disk_size(), 3 * 10 * 1036);
+    }
+
+    #[test]
+    fn storage_is_full_before_old_files_are_deleted() {
+        let backup = init_backup_folders();
+        let mut storage = Storage::new(backup.path(), 10 * 1036, 2).unwrap();
+
+        // 2 files on disk and an overflowing in memory buffer
+        for _ in 0..30 {
+            let mut publish = Publish::new("hello", QoS::AtLeastOnce, vec![1; 1024]);
+            publish.pkid = 1;
+            publish.write(storage.writer()).unwrap();
+            if storage.is_full() {
+                break;
+            }
+            storage.flush_on_overflow().unwrap();
+        }
+
+        assert!(storage.is_full());
+        assert_eq!(storage.writer().len(), 10 * 1036);
+        assert_eq!(get_file_ids(&backup.path()).unwrap(), vec![0, 1]);
+
+        // Reading a file from disk makes space for the write buffer
+        storage.reload_on_eof().unwrap();
+        assert!(!storage.is_full());
+        assert_eq!(storage.flush_on_overflow().unwrap(), 0);
+        assert_eq!(get_file_ids(&backup.path()).unwrap(), vec![1, 2]);
+    }
+
     #[test]
     fn reload_loads_correct_file_into_memory() {
         let backup = init_backup_folders();
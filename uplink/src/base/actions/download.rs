@@ -0,0 +1,315 @@
+//! Contains a built-in handler for [`Action`]s with `name: "download"`, which download a file from the url in the
+//! action's [`payload`] onto the path specified along with it, so that a separate tool isn't needed to do so.
+//! The handler is opt-in, by including "download" in `actions`, and only writes within `download_dir`,
+//! failing downloads onto an absolute path or one that contains "..".
+//!
+//! The payload of a download `Action` is deserialized into a [`DownloadFile`]. Progress of the download is updated
+//! with [`ActionResponse`]s, once every percent downloaded, if the server reports size of the file. The download is
+//! failed if no data is received for as long as the timeout configured for the action, or if the downloaded file
+//! doesn't match the SHA-256 checksum in payload. Files are downloaded into "<path>.part" alongside the destination,
+//! which is renamed over it once verified, so that a failed download removes only the partially downloaded file and
+//! leaves any file already at the destination as is.
+//!
+//! Each download runs in a spawned task, so that other actions aren't blocked while a file is being downloaded.
+//! A download in progress can be cancelled, which removes the partially downloaded file.
+//!
+//! [`payload`]: Action#structfield.payload
+
+use futures_util::StreamExt;
+use log::{error, info};
+use reqwest::Response;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::fs::{self, File};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::oneshot;
+use tokio::{select, task, time};
+
+use std::collections::HashMap;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::ota::http_client;
+use super::{action_timeout, Action, ActionResponse};
+use crate::base::{Config, Stream};
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Serde error: {0}")]
+    Serde(#[from] serde_json::Error),
+    #[error("Error from reqwest: {0}")]
+    Reqwest(#[from] reqwest::Error),
+    #[error("File io Error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Download failed, content length zero")]
+    EmptyFile,
+    #[error("Download timed out, no data received for {0}s")]
+    Timeout(u64),
+    #[error("Checksum mismatch, expected {expected}, downloaded {actual}")]
+    Checksum { expected: String, actual: String },
+    #[error("Download cancelled")]
+    Cancelled,
+    #[error("Invalid download path {0}, expected a relative path without \"..\"")]
+    InvalidPath(String),
+}
+
+/// Expected JSON format of data contained in the [`payload`] of a download [`Action`]
+///
+/// [`payload`]: Action#structfield.payload
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct DownloadFile {
+    /// URL to download the file from
+    pub url: String,
+    /// Path, relative to `download_dir`, where download will be stored
+    pub path: String,
+    /// Hex encoded SHA-256 checksum of the file, verified once downloaded
+    #[serde(default)]
+    pub checksum: Option<String>,
+}
+
+/// Handles download actions, reporting their progress onto `status_bucket`
+pub struct Downloader {
+    config: Arc<Config>,
+    status_bucket: Stream<ActionResponse>,
+    // handles to cancel downloads in progress, keyed by action id
+    cancels: Arc<Mutex<HashMap<String, oneshot::Sender<()>>>>,
+}
+
+impl Downloader {
+    pub fn new(config: Arc<Config>, status_bucket: Stream<ActionResponse>) -> Downloader {
+        Downloader { config, status_bucket, cancels: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    /// Replaces config with one reloaded while running, which applies to downloads started after
+    pub fn set_config(&mut self, config: Arc<Config>) {
+        self.config = config;
+    }
+
+    /// Downloads the file requested in action, within a spawned task
+    pub fn execute(&self, action: Action) {
+        let config = self.config.clone();
+        let status_bucket = self.status_bucket.clone();
+        let cancels = self.cancels.clone();
+        let (cancel_tx, cancel_rx) = oneshot::channel();
+        cancels.lock().unwrap().insert(action.action_id.clone(), cancel_tx);
+
+        task::spawn(async move {
+            let mut download = Download { id: action.action_id, sequence: 0, status_bucket };
+            let file = serde_json::from_str::<DownloadFile>(&action.payload)
+                .map_err(Error::from)
+                .and_then(|file| Ok((download_path(&config.download_dir, &file.path)?, file)));
+            let result = match file {
+                Ok((path, file)) => {
+                    let checksum = file.checksum.as_deref();
+                    let result = select! {
+                        result = download.run(&config, &file.url, &path, checksum) => result,
+                        Ok(_) = cancel_rx => Err(Error::Cancelled),
+                    };
+                    if result.is_err() {
+                        remove_partial(&partial_path(&path)).await;
+                    }
+                    result
+                }
+                Err(e) => Err(e),
+            };
+            cancels.lock().unwrap().remove(&download.id);
+
+            let status = match result {
+                Ok(_) => ActionResponse::success(&download.id),
+                Err(Error::Cancelled) => ActionResponse::progress(&download.id, "Cancelled", 100),
+                Err(e) => {
+                    error!("Download failed. Action ID = {}, Error = {:?}", download.id, e);
+                    ActionResponse::failure(&download.id, e.to_string())
+                }
+            };
+            let status = status.set_sequence(download.sequence());
+            download.send_status(status).await;
+        });
+    }
+
+    /// Cancels a download in progress, returns false if there is no such download
+    pub fn cancel(&self, id: &str) -> bool {
+        match self.cancels.lock().unwrap().remove(id) {
+            Some(cancel) => cancel.send(()).is_ok(),
+            None => false,
+        }
+    }
+}
+
+// State of a single download in progress
+struct Download {
+    id: String,
+    sequence: u32,
+    status_bucket: Stream<ActionResponse>,
+}
+
+impl Download {
+    async fn run(
+        &mut self,
+        config: &Config,
+        url: &str,
+        path: &Path,
+        checksum: Option<&str>,
+    ) -> Result<(), Error> {
+        let status =
+            ActionResponse::progress(&self.id, "Downloading", 0).set_sequence(self.sequence());
+        self.send_status(status).await;
+
+        let timeout = action_timeout(config, "download");
+
+        let client = http_client(config)?;
+        let resp = match time::timeout(timeout, client.get(url).send()).await {
+            Ok(resp) => resp?.error_for_status()?,
+            Err(_) => return Err(Error::Timeout(timeout.as_secs())),
+        };
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).await?;
+        }
+
+        info!("Downloading from {} into {}", url, path.display());
+        self.download(resp, path, checksum, timeout).await
+    }
+
+    // Streams response into file, while verifying its checksum
+    async fn download(
+        &mut self,
+        resp: Response,
+        path: &Path,
+        checksum: Option<&str>,
+        timeout: Duration,
+    ) -> Result<(), Error> {
+        let content_length = match resp.content_length() {
+            Some(0) => return Err(Error::EmptyFile),
+            l => l,
+        };
+
+        let partial = partial_path(path);
+        let mut out = File::create(&partial).await?;
+        let mut hasher = Sha256::new();
+        let mut downloaded = 0;
+        let mut reported = 0;
+        let mut stream = resp.bytes_stream();
+
+        loop {
+            let chunk = match time::timeout(timeout, stream.next()).await {
+                Ok(Some(chunk)) => chunk?,
+                Ok(None) => break,
+                Err(_) => return Err(Error::Timeout(timeout.as_secs())),
+            };
+            out.write_all(&chunk).await?;
+            hasher.update(&chunk);
+            downloaded += chunk.len() as u64;
+
+            // Progress of 100 is reserved for completion, after the checksum is verified
+            if let Some(content_length) = content_length {
+                let percentage = (100 * downloaded / content_length).min(99) as u8;
+                if percentage > reported {
+                    reported = percentage;
+                    let status = ActionResponse::progress(&self.id, "Downloading", percentage)
+                        .set_sequence(self.sequence());
+                    self.send_status(status).await;
+                }
+            }
+        }
+        out.flush().await?;
+
+        if let Some(expected) = checksum {
+            let actual = hex(&hasher.finalize());
+            if !actual.eq_ignore_ascii_case(expected) {
+                return Err(Error::Checksum { expected: expected.to_owned(), actual });
+            }
+        }
+        drop(out);
+        fs::rename(&partial, path).await?;
+
+        info!("Downloaded {} bytes into {}", downloaded, path.display());
+        Ok(())
+    }
+
+    async fn send_status(&mut self, status: ActionResponse) {
+        if let Err(e) = self.status_bucket.fill(status).await {
+            error!("Failed to send download status. Error = {:?}", e);
+        }
+    }
+
+    fn sequence(&mut self) -> u32 {
+        self.sequence += 1;
+        self.sequence
+    }
+}
+
+// Resolves path of a download within `download_dir`, rejecting paths that could escape it
+fn download_path(download_dir: &str, path: &str) -> Result<PathBuf, Error> {
+    let components = Path::new(path).components();
+    let escapes =
+        components.clone().any(|c| !matches!(c, Component::Normal(_) | Component::CurDir));
+    let relative: PathBuf = components.filter(|c| matches!(c, Component::Normal(_))).collect();
+    if path.trim().is_empty() || escapes || relative.file_name().is_none() {
+        return Err(Error::InvalidPath(path.to_owned()));
+    }
+
+    Ok(Path::new(download_dir).join(relative))
+}
+
+// File into which a download is written, till it's verified and renamed onto its path
+fn partial_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".part");
+    path.with_file_name(name)
+}
+
+// Removes file of a failed download, if it was created
+async fn remove_partial(path: &Path) {
+    match fs::remove_file(path).await {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+            error!("Failed to remove partial download {}. Error = {:?}", path.display(), e)
+        }
+        _ => {}
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn payload_with_optional_checksum() {
+        let payload = r#"{"url": "https://example.com/fw.bin", "path": "/tmp/fw.bin"}"#;
+        let file: DownloadFile = serde_json::from_str(payload).unwrap();
+        assert_eq!(file.checksum, None);
+
+        let payload =
+            r#"{"url": "https://example.com/fw.bin", "path": "/tmp/fw.bin", "checksum": "ab"}"#;
+        let file: DownloadFile = serde_json::from_str(payload).unwrap();
+        assert_eq!(file.checksum, Some("ab".to_owned()));
+    }
+
+    #[test]
+    fn downloads_are_confined_to_download_dir() {
+        let path = download_path("/var/tmp/downloads", "fw/fw.bin").unwrap();
+        assert_eq!(path, Path::new("/var/tmp/downloads/fw/fw.bin"));
+
+        let partial = partial_path(&path);
+        assert_eq!(partial, Path::new("/var/tmp/downloads/fw/fw.bin.part"));
+
+        for path in ["/etc/passwd", "../fw.bin", "fw/../../fw.bin", "", "."] {
+            assert!(matches!(
+                download_path("/var/tmp/downloads", path),
+                Err(Error::InvalidPath(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn checksum_is_hex_encoded_sha256() {
+        let checksum = hex(&Sha256::digest(b"abc"));
+        assert_eq!(checksum, "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    }
+}
@@ -1,19 +1,27 @@
 use super::{Config, Package};
 use flume::{Receiver, Sender, TrySendError};
-use log::{debug, error};
+use log::{debug, error, info};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
-use tokio::time::Duration;
+use tokio::sync::{oneshot, watch};
+use tokio::{select, task};
+use tokio::time::{self, Duration};
 
+use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
 
+mod download;
 pub mod ota;
 mod process;
+mod signature;
 pub mod tunshell;
 pub mod logcat;
+#[cfg(feature = "webhooks")]
+mod webhook;
 
-use crate::base::{Buffer, Point, Stream};
+use crate::base::serializer::Metrics;
+use crate::base::{serialize_timestamp, ActionHandler, Buffer, Point, Stream};
 use crate::actions::logcat::{LogcatConfig, LogcatInstance, LogLevel};
 use crate::Payload;
 
@@ -23,14 +31,22 @@ pub enum Error {
     Serde(#[from] serde_json::Error),
     #[error("Process error {0}")]
     Process(#[from] process::Error),
+    #[error("Rejected action: {0}")]
+    Signature(#[from] signature::Error),
     #[error("Error sending keys to tunshell thread {0}")]
     TunshellSend(#[from] flume::SendError<Action>),
     #[error("Error forwarding Action {0}")]
     TrySend(#[from] flume::TrySendError<Action>),
     #[error("Invalid action")]
     InvalidActionKind(String),
+    #[error("No handler for actions of kind {0}")]
+    UnroutedKind(String),
     #[error("Another OTA downloading")]
     Downloading,
+    #[error("Metrics already requested")]
+    MetricsRequested,
+    #[error("Action {0} not found, it might have completed already")]
+    CancelNotFound(String),
 }
 
 /// On the Bytebeam platform, an Action is how beamd and through it,
@@ -49,6 +65,9 @@ pub struct Action {
     pub name: String,
     // action payload. json. can be args/payload. depends on the invoked command
     pub payload: String,
+    // base64 encoded signature of the action, verified when action_signing is configured
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -57,6 +76,7 @@ pub struct ActionResponse {
     // sequence number
     pub sequence: u32,
     // timestamp
+    #[serde(serialize_with = "serialize_timestamp")]
     pub timestamp: u64,
     // running, failed
     pub state: String,
@@ -64,6 +84,9 @@ pub struct ActionResponse {
     pub progress: u8,
     // list of error
     pub errors: Vec<String>,
+    // result of the action, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<serde_json::Value>,
 }
 
 impl ActionResponse {
@@ -80,6 +103,7 @@ impl ActionResponse {
             state: state.to_owned(),
             progress,
             errors,
+            result: None,
         }
     }
 
@@ -100,6 +124,11 @@ impl ActionResponse {
         ActionResponse::new(id, "Failed", 100, vec![]).add_error(error)
     }
 
+    pub fn set_result(mut self, result: serde_json::Value) -> ActionResponse {
+        self.result = Some(result);
+        self
+    }
+
     pub fn set_sequence(mut self, seq: u32) -> ActionResponse {
         self.sequence = seq;
         self
@@ -116,16 +145,81 @@ impl Point for ActionResponse {
     }
 }
 
+/// Timeout of an action, configured by its name in `action_timeouts`, else `action_timeout_secs`
+fn action_timeout(config: &Config, name: &str) -> Duration {
+    let timeout = config.action_timeouts.get(name).copied();
+    Duration::from_secs(timeout.unwrap_or(config.action_timeout_secs))
+}
+
+/// Routes [`ActionResponse`]s onto the stream configured for an action's name in
+/// `action_results`, falling back to the `action_status` stream for all other actions.
+#[derive(Clone)]
+pub struct ActionRoutes {
+    action_status: Stream<ActionResponse>,
+    routes: HashMap<String, Stream<ActionResponse>>,
+}
+
+impl ActionRoutes {
+    pub fn new(
+        config: &Config,
+        action_status: Stream<ActionResponse>,
+        data_tx: Sender<Box<dyn Package>>,
+    ) -> ActionRoutes {
+        let routes = config
+            .action_results
+            .iter()
+            .map(|(name, stream_config)| {
+                let stream = Stream::with_config(
+                    name,
+                    &config.project_id,
+                    &config.device_id,
+                    stream_config,
+                    data_tx.clone(),
+                );
+                (name.to_owned(), stream)
+            })
+            .collect();
+
+        ActionRoutes { action_status, routes }
+    }
+
+    /// Returns a handle to the stream onto which responses for the named action are pushed
+    pub fn status(&self, action_name: &str) -> Stream<ActionResponse> {
+        self.routes.get(action_name).unwrap_or(&self.action_status).clone()
+    }
+
+    /// Fills response onto the stream associated with the named action
+    pub async fn fill(
+        &mut self,
+        action_name: &str,
+        response: ActionResponse,
+    ) -> Result<(), crate::base::Error> {
+        let stream = match self.routes.get_mut(action_name) {
+            Some(stream) => stream,
+            None => &mut self.action_status,
+        };
+        stream.fill(response).await?;
+
+        Ok(())
+    }
+}
+
 pub struct Actions {
     config: Arc<Config>,
-    action_status: Stream<ActionResponse>,
+    action_routes: ActionRoutes,
     process: process::Process,
+    downloader: download::Downloader,
+    #[cfg(feature = "webhooks")]
+    webhooks: webhook::Webhooks,
     actions_rx: Receiver<Action>,
     tunshell_tx: Sender<Action>,
     ota_tx: Sender<Action>,
     bridge_tx: Sender<Action>,
     bridge_data_tx: Sender<Box<dyn Package>>,
+    metrics_tx: Sender<oneshot::Sender<Metrics>>,
     logcat: Option<LogcatInstance>,
+    // config reloaded while uplink is running, see `config::reload`
+    config_updates: watch::Receiver<Arc<Config>>,
 }
 
 impl Actions {
@@ -134,24 +228,52 @@ impl Actions {
         actions_rx: Receiver<Action>,
         tunshell_tx: Sender<Action>,
         ota_tx: Sender<Action>,
-        action_status: Stream<ActionResponse>,
+        action_routes: ActionRoutes,
         bridge_tx: Sender<Action>,
         bridge_data_tx: Sender<Box<dyn Package>>,
+        metrics_tx: Sender<oneshot::Sender<Metrics>>,
     ) -> Actions {
-        let process = process::Process::new(action_status.clone());
+        let process = process::Process::new(config.clone(), action_routes.clone());
+        let downloader =
+            download::Downloader::new(config.clone(), action_routes.status("download"));
+        #[cfg(feature = "webhooks")]
+        let webhooks = webhook::Webhooks::new(config.clone(), action_routes.clone());
+        let (_, config_updates) = watch::channel(config.clone());
         Actions {
             config,
-            action_status,
+            action_routes,
             process,
+            downloader,
+            #[cfg(feature = "webhooks")]
+            webhooks,
             actions_rx,
             tunshell_tx,
             ota_tx,
             bridge_tx,
             bridge_data_tx,
+            metrics_tx,
             logcat: None,
+            config_updates,
         }
     }
 
+    /// Picks up config reloaded while actions are being handled, e.g. timeouts of actions, which
+    /// apply to actions received after the reload.
+    pub fn with_config_updates(mut self, config_updates: watch::Receiver<Arc<Config>>) -> Actions {
+        self.config_updates = config_updates;
+        self
+    }
+
+    fn update_config(&mut self) {
+        let config = self.config_updates.borrow().clone();
+        self.process.set_config(config.clone());
+        self.downloader.set_config(config.clone());
+        #[cfg(feature = "webhooks")]
+        self.webhooks.set_config(config.clone());
+        self.config = config;
+        info!("Reloaded config, applies to actions received from here on");
+    }
+
     fn create_log_stream(&self) -> Stream<Payload> {
         Stream::dynamic_with_size(
             "logs",
@@ -177,11 +299,24 @@ impl Actions {
             );
         }
         loop {
-            let action = match self.actions_rx.recv_async().await {
-                Ok(v) => v,
-                Err(e) => {
-                    error!("Action stream receiver error = {:?}", e);
-                    break;
+            let action = select! {
+                action = self.actions_rx.recv_async() => match action {
+                    Ok(v) => v,
+                    Err(e) => {
+                        error!("Action stream receiver error = {:?}", e);
+                        break;
+                    }
+                },
+                // Execute actions queued while their command was busy, as its processes exit
+                Ok(name) = self.process.exited() => {
+                    if let Some((id, Err(e))) = self.process.execute_queued(&name).await {
+                        self.forward_action_error(&id, &name, e.into()).await;
+                    }
+                    continue;
+                }
+                Ok(_) = self.config_updates.changed() => {
+                    self.update_config();
+                    continue;
                 }
             };
 
@@ -189,8 +324,18 @@ impl Actions {
 
             let action_id = action.action_id.clone();
             let action_name = action.name.clone();
-            let error = match self.handle(action).await {
-                Ok(_) => continue,
+            // Actions are verified before being handled in any way, when signing is configured
+            let result = match &self.config.action_signing {
+                Some(signing) => {
+                    signature::verify(signing, &self.config.device_id, &action).map_err(Error::from)
+                }
+                None => Ok(()),
+            };
+            let error = match result {
+                Ok(_) => match self.handle(action).await {
+                    Ok(_) => continue,
+                    Err(e) => e,
+                },
                 Err(e) => e,
             };
 
@@ -227,41 +372,134 @@ impl Actions {
                 })?;
                 return Ok(());
             }
+            "publish_metrics" => {
+                self.publish_metrics(action.action_id)?;
+                return Ok(());
+            }
+            "download" if self.config.actions.contains(&action.name) => {
+                self.downloader.execute(action);
+                return Ok(());
+            }
+            "cancel_action" => {
+                self.cancel(action).await?;
+                return Ok(());
+            }
             _ => (),
         }
 
-        // Bridge actions are forwarded
-        if !self.config.actions.contains(&action.name) {
-            self.bridge_tx.try_send(action)?;
+        // Actions routed by name are dispatched to their handler
+        if let Some(handler) = self.config.action_handlers.names.get(&action.name) {
+            return self.dispatch(handler.clone(), action).await;
+        }
+
+        // Actions handled by HTTP services are posted to their webhook
+        #[cfg(feature = "webhooks")]
+        if self.config.action_webhooks.contains_key(&action.name) {
+            self.webhooks.execute(action);
             return Ok(());
         }
 
         // Regular actions are executed natively
-        match action.kind.as_ref() {
-            "process" => {
-                let command = action.name.clone();
-                let payload = action.payload.clone();
-                let id = action.action_id;
+        if self.config.actions.contains(&action.name) {
+            match action.kind.as_ref() {
+                "process" => {
+                    let command = action.name.clone();
+                    let payload = action.payload.clone();
+                    let id = action.action_id;
+
+                    self.process.execute(id.clone(), command.clone(), payload).await?;
+                }
+                v => return Err(Error::InvalidActionKind(v.to_owned())),
+            }
+
+            return Ok(());
+        }
 
-                self.process.execute(id.clone(), command.clone(), payload).await?;
+        // Other actions are routed by kind, if configured, and forwarded over the bridge otherwise
+        let kinds = &self.config.action_handlers.kinds;
+        match kinds.get(&action.kind) {
+            Some(handler) => self.dispatch(handler.clone(), action).await,
+            None if kinds.is_empty() => self.dispatch(ActionHandler::Bridge, action).await,
+            None => Err(Error::UnroutedKind(action.kind)),
+        }
+    }
+
+    /// Executes action with the handler it was routed to
+    async fn dispatch(&mut self, handler: ActionHandler, action: Action) -> Result<(), Error> {
+        match handler {
+            ActionHandler::Tool { tool } => {
+                self.process.execute(action.action_id, tool, action.payload).await?;
             }
-            v => return Err(Error::InvalidActionKind(v.to_owned())),
+            ActionHandler::Bridge => self.bridge_tx.try_send(action)?,
+            ActionHandler::Download => self.downloader.execute(action),
         }
 
         Ok(())
     }
 
+    /// Cancels an action that is in progress or queued, as identified in payload of the cancel action
+    async fn cancel(&mut self, action: Action) -> Result<(), Error> {
+        let cancel: Cancel = serde_json::from_str(&action.payload)?;
+        let id = cancel.action_id;
+        if !self.downloader.cancel(&id) && !self.process.cancel(&id).await {
+            return Err(Error::CancelNotFound(id));
+        }
+
+        let status = ActionResponse::success(&action.action_id);
+        if let Err(e) = self.action_routes.fill(&action.name, status).await {
+            error!("Failed to send status. Error = {:?}", e);
+        }
+
+        Ok(())
+    }
+
+    /// Requests serializer to publish metrics immediately, responding with the published
+    /// metrics as result of the action, without blocking other actions
+    fn publish_metrics(&mut self, id: String) -> Result<(), Error> {
+        let (tx, rx) = oneshot::channel();
+        self.metrics_tx.try_send(tx).map_err(|_| Error::MetricsRequested)?;
+
+        let mut status = self.action_routes.status("publish_metrics");
+        task::spawn(async move {
+            // Serializer doesn't respond while it is writing everything to disk
+            let response = match time::timeout(Duration::from_secs(10), rx).await {
+                Ok(Ok(metrics)) => match serde_json::to_value(metrics) {
+                    Ok(metrics) => ActionResponse::success(&id).set_result(metrics),
+                    Err(e) => ActionResponse::failure(&id, e.to_string()),
+                },
+                _ => ActionResponse::failure(&id, "Serializer didn't respond with metrics"),
+            };
+
+            if let Err(e) = status.fill(response).await {
+                error!("Failed to send status. Error = {:?}", e);
+            }
+        });
+
+        Ok(())
+    }
+
     async fn forward_action_error(&mut self, id: &str, action: &str, error: Error) {
         error!("Failed to execute. Command = {:?}, Error = {:?}", action, error);
         let status = ActionResponse::failure(id, error.to_string());
 
-        if let Err(e) = self.action_status.fill(status).await {
+        if let Err(e) = self.action_routes.fill(action, status).await {
             error!("Failed to send status. Error = {:?}", e);
         }
     }
 }
 
+/// Expected JSON format of data contained in the payload of a "cancel_action" [`Action`]
+#[derive(Debug, Deserialize)]
+struct Cancel {
+    // id of the action to be cancelled
+    action_id: String,
+}
+
 impl Package for Buffer<ActionResponse> {
+    fn stream(&self) -> Arc<String> {
+        self.stream.clone()
+    }
+
     fn topic(&self) -> Arc<String> {
         self.topic.clone()
     }
@@ -273,4 +511,76 @@ impl Package for Buffer<ActionResponse> {
     fn anomalies(&self) -> Option<(String, usize)> {
         self.anomalies()
     }
+
+    fn anomaly_counts(&self) -> Vec<(String, usize)> {
+        self.anomaly_counts()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    #[cfg(unix)]
+    async fn reloaded_action_timeouts_take_effect() {
+        let config = Arc::new(Config {
+            tools_dir: "/bin".to_owned(),
+            actions: vec!["sleep".to_owned()],
+            action_timeouts: HashMap::from([("sleep".to_owned(), 100)]),
+            ..Default::default()
+        });
+        let (data_tx, data_rx) = flume::bounded(10);
+        let action_status = Stream::new("action_status", "/action/status", 1, data_tx.clone());
+        let action_routes = ActionRoutes::new(&config, action_status, data_tx.clone());
+        let (actions_tx, actions_rx) = flume::bounded(1);
+        let (tunshell_tx, _tunshell_rx) = flume::bounded(1);
+        let (ota_tx, _ota_rx) = flume::bounded(1);
+        let (bridge_tx, _bridge_rx) = flume::bounded(1);
+        let (metrics_tx, _metrics_rx) = flume::bounded(1);
+        let (config_tx, config_updates) = watch::channel(config.clone());
+        let actions = Actions::new(
+            config.clone(),
+            actions_rx,
+            tunshell_tx,
+            ota_tx,
+            action_routes,
+            bridge_tx,
+            data_tx,
+            metrics_tx,
+        )
+        .with_config_updates(config_updates);
+
+        let timed_out = async {
+            let action_timeouts = HashMap::from([("sleep".to_owned(), 1)]);
+            config_tx.send_replace(Arc::new(Config { action_timeouts, ..(*config).clone() }));
+            // Reload is picked up before the action is received
+            time::sleep(Duration::from_millis(100)).await;
+
+            // Process sleeps for 10s, with "5" as both the id and payload of the action
+            let action = Action {
+                device_id: String::new(),
+                action_id: "5".to_owned(),
+                kind: "process".to_owned(),
+                name: "sleep".to_owned(),
+                payload: "5".to_owned(),
+                signature: None,
+            };
+            actions_tx.send_async(action).await.unwrap();
+
+            loop {
+                let status = data_rx.recv_async().await.unwrap().serialize().unwrap();
+                let status = String::from_utf8(status).unwrap();
+                if status.contains("Failed") {
+                    return status;
+                }
+            }
+        };
+
+        let status = select! {
+            _ = actions.start() => unreachable!("Actions stopped"),
+            status = time::timeout(Duration::from_secs(5), timed_out) => status.unwrap(),
+        };
+        assert!(status.contains("Action timed out after 1s"));
+    }
 }
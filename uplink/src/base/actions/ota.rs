@@ -99,22 +99,7 @@ impl OtaDownloader {
         status_bucket: Stream<ActionResponse>,
         bridge_tx: Sender<Action>,
     ) -> Result<(Sender<Action>, Self), Error> {
-        // Authenticate with TLS certs from config
-        let client_builder = ClientBuilder::new();
-        let client = match &config.authentication {
-            Some(certs) => {
-                let ca = Certificate::from_pem(certs.ca_certificate.as_bytes())?;
-                let mut buf = BytesMut::from(certs.device_private_key.as_bytes());
-                buf.extend_from_slice(certs.device_certificate.as_bytes());
-                // buf contains the private key and certificate of device
-                let device = Identity::from_pem(&buf)?;
-                client_builder.add_root_certificate(ca).identity(device)
-            }
-            // TODO: cover circumstance where uplink as simulator must use certs from env variables
-            // None if config.simulator.is_some() => {},
-            None => client_builder,
-        }
-        .build()?;
+        let client = http_client(&config)?;
 
         // Create rendezvous channel with flume
         let (ota_tx, ota_rx) = flume::bounded(0);
@@ -262,6 +247,25 @@ impl OtaDownloader {
     }
 }
 
+/// Creates a HTTP client, authenticated with TLS certs from config, if any
+pub fn http_client(config: &Config) -> Result<Client, reqwest::Error> {
+    let client_builder = ClientBuilder::new();
+    match &config.authentication {
+        Some(certs) => {
+            let ca = Certificate::from_pem(certs.ca_certificate.as_bytes())?;
+            let mut buf = BytesMut::from(certs.device_private_key.as_bytes());
+            buf.extend_from_slice(certs.device_certificate.as_bytes());
+            // buf contains the private key and certificate of device
+            let device = Identity::from_pem(&buf)?;
+            client_builder.add_root_certificate(ca).identity(device)
+        }
+        // TODO: cover circumstance where uplink as simulator must use certs from env variables
+        // None if config.simulator.is_some() => {},
+        None => client_builder,
+    }
+    .build()
+}
+
 /// Expected JSON format of data contained in the [`payload`] of an OTA [`Action`]
 ///
 /// [`payload`]: Action#structfield.payload
@@ -319,6 +323,7 @@ mod test {
             kind: "firmware_update".to_string(),
             name: "firmware_update".to_string(),
             payload: json!(ota_update).to_string(),
+            signature: None,
         };
 
         std::thread::sleep(Duration::from_millis(10));
@@ -370,6 +375,7 @@ mod test {
             kind: "firmware_update".to_string(),
             name: "firmware_update".to_string(),
             payload: json!(ota_update).to_string(),
+            signature: None,
         };
 
         std::thread::sleep(Duration::from_millis(10));
@@ -1,27 +1,42 @@
-use flume::SendError;
-use log::{debug, error, info};
+use flume::{Receiver, RecvError, SendError, Sender};
+use log::{debug, error, info, warn};
 use thiserror::Error;
-use tokio::io::{AsyncBufReadExt, BufReader};
-use tokio::process::{Child, Command};
+use tokio::io::{AsyncBufReadExt, BufReader, Lines};
+use tokio::process::{Child, ChildStdout, Command};
+use tokio::sync::oneshot;
 use tokio::{pin, select, task, time};
 
-use super::{ActionResponse, Package};
+use super::{action_timeout, ActionResponse, ActionRoutes, Package};
 
-use crate::base::Stream;
-use std::io;
-use std::process::Stdio;
+use crate::base::{Config, Stream};
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process::{ExitStatus, Stdio};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 /// Process abstracts functions to spawn process and handle their output
-/// It makes sure that a new process isn't executed when the previous process
-/// is in progress.
+/// It makes sure that a new process of a command isn't executed when the
+/// command already has as many processes in progress as it is allowed to,
+/// such actions are queued to be executed as processes of the command exit.
 /// It sends result and errors to the broker over collector_tx
 pub struct Process {
-    // buffer to send status messages to cloud
-    action_status: Stream<ActionResponse>,
-    // we use this flag to ignore new process spawn while previous process is in progress
-    last_process_done: Arc<Mutex<bool>>,
+    // uplink config
+    config: Arc<Config>,
+    // routes to buffers that send status messages to cloud
+    action_routes: ActionRoutes,
+    // number of processes in progress for each command, we use this to ignore
+    // new process spawns of a command that has reached its concurrency limit
+    running: Arc<Mutex<HashMap<String, usize>>>,
+    // actions waiting on a process of their command to exit, as (id, payload)
+    queue: HashMap<String, VecDeque<(String, String)>>,
+    // notifies names of commands whose process exited
+    exited_tx: Sender<String>,
+    exited_rx: Receiver<String>,
+    // handles to kill processes in progress, keyed by action id
+    cancels: Arc<Mutex<HashMap<String, oneshot::Sender<()>>>>,
 }
 
 #[derive(Error, Debug)]
@@ -34,67 +49,195 @@ pub enum Error {
     Send(#[from] SendError<Box<dyn Package>>),
     #[error("Busy with previous action")]
     Busy,
+    #[error("Busy with previous action, {0} actions already queued")]
+    QueueFull(usize),
     #[error("No stdout in spawned action")]
     NoStdout,
+    #[error("Command {0} not found in tools directory")]
+    NotFound(String),
+    #[error("Invalid command name {0}")]
+    InvalidCommand(String),
 }
 
 impl Process {
-    pub fn new(action_status: Stream<ActionResponse>) -> Process {
-        Process { last_process_done: Arc::new(Mutex::new(true)), action_status }
+    pub fn new(config: Arc<Config>, action_routes: ActionRoutes) -> Process {
+        let running = Arc::new(Mutex::new(HashMap::new()));
+        let (exited_tx, exited_rx) = flume::unbounded();
+        Process {
+            config,
+            action_routes,
+            running,
+            queue: HashMap::new(),
+            exited_tx,
+            exited_rx,
+            cancels: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Cancels an action, killing its process or removing it from queue, responding to it
+    /// as "Cancelled". Returns false if no such action is in progress or queued.
+    pub async fn cancel(&mut self, id: &str) -> bool {
+        if let Some(cancel) = self.cancels.lock().unwrap().remove(id) {
+            // Process is killed and responded to from the task capturing its stdout
+            return cancel.send(()).is_ok();
+        }
+
+        let name = self.queue.iter_mut().find_map(|(name, queue)| {
+            let position = queue.iter().position(|(queued, _)| queued == id)?;
+            queue.remove(position).map(|_| name.to_owned())
+        });
+        let name = match name {
+            Some(name) => name,
+            None => return false,
+        };
+
+        let status = ActionResponse::progress(id, "Cancelled", 100);
+        if let Err(e) = self.action_routes.fill(&name, status).await {
+            error!("Failed to send cancelled status. Error = {:?}", e);
+        }
+
+        true
     }
 
-    /// Run a process of specified command
+    /// Replaces config with one reloaded while running, which applies to actions executed after
+    pub fn set_config(&mut self, config: Arc<Config>) {
+        self.config = config;
+    }
+
+    /// Waits for a process to exit, returning the name of its command
+    pub async fn exited(&self) -> Result<String, RecvError> {
+        self.exited_rx.recv_async().await
+    }
+
+    /// Run a process of specified command. Payloads larger than the configured threshold are
+    /// spooled into a temporary file, whose path is passed to the command in place of the payload.
+    /// Returns the path of the spooled file, if any, to be removed once the process exits.
     pub async fn run(
         &mut self,
         id: String,
-        command: String,
+        name: &str,
+        mut command: PathBuf,
         payload: String,
-    ) -> Result<Child, Error> {
-        *self.last_process_done.lock().unwrap() = false;
+    ) -> Result<(Child, Option<PathBuf>), Error> {
+        // Resolve relative commands against working directory of uplink, as it is
+        // ambiguous which directory they are resolved against, if cwd is changed
+        let process = self.config.action_processes.get(name);
+        if process.map_or(false, |p| p.cwd.is_some()) && command.is_relative() {
+            command = std::env::current_dir()?.join(command);
+        }
 
-        let mut cmd = Command::new(command);
-        cmd.arg(id).arg(payload).kill_on_drop(true).stdout(Stdio::piped());
+        let spool = match self.config.action_payload_spool_size {
+            Some(size) if payload.len() > size => Some(spool_payload(&payload)?),
+            _ => None,
+        };
+
+        let mut cmd = Command::new(&command);
+        match &spool {
+            Some(path) => cmd.arg(id).arg(path),
+            None => cmd.arg(id).arg(payload),
+        };
+        if let Some(process) = process {
+            cmd.envs(&process.env);
+            if let Some(cwd) = &process.cwd {
+                cmd.current_dir(cwd);
+            }
+        }
+        cmd.kill_on_drop(true).stdout(Stdio::piped());
 
         match cmd.spawn() {
-            Ok(child) => Ok(child),
+            Ok(child) => Ok((child, spool)),
             Err(e) => {
-                *self.last_process_done.lock().unwrap() = true;
-                Err(e.into())
+                remove_spool(spool);
+                // Missing commands are a packaging problem, report them as such to the cloud
+                match e.kind() {
+                    io::ErrorKind::NotFound => Err(Error::NotFound(command.display().to_string())),
+                    _ => Err(e.into()),
+                }
             }
         }
     }
 
-    /// Capture stdout of the running process in a spawned task
-    pub async fn spawn_and_capture_stdout(&mut self, mut child: Child) -> Result<(), Error> {
-        let stdout = child.stdout.take().ok_or(Error::NoStdout)?;
+    /// Capture stdout of the running process in a spawned task, responding with
+    /// the exit status of the process once it is done
+    pub async fn spawn_and_capture_stdout(
+        &mut self,
+        id: String,
+        name: String,
+        mut child: Child,
+        spool: Option<PathBuf>,
+        mut status_bucket: Stream<ActionResponse>,
+    ) -> Result<(), Error> {
+        let stdout = match child.stdout.take() {
+            Some(stdout) => stdout,
+            None => {
+                release(&self.running, &self.exited_tx, &name);
+                remove_spool(spool);
+                return Err(Error::NoStdout);
+            }
+        };
         let mut stdout = BufReader::new(stdout).lines();
 
-        let mut status_bucket = self.action_status.clone();
-        let last_process_done = self.last_process_done.clone();
+        let running = self.running.clone();
+        let exited_tx = self.exited_tx.clone();
+        let cancels = self.cancels.clone();
+        let (cancel_tx, mut cancel_rx) = oneshot::channel();
+        cancels.lock().unwrap().insert(id.clone(), cancel_tx);
+        let timeout = action_timeout(&self.config, &name);
+        let timeout_secs = timeout.as_secs();
 
         task::spawn(async move {
-            let timeout = time::sleep(Duration::from_secs(10));
+            let timeout = time::sleep(timeout);
             pin!(timeout);
 
             loop {
                 select! {
-                     Ok(Some(line)) = stdout.next_line() => {
-                        let status: ActionResponse = match serde_json::from_str(&line) {
-                            Ok(status) => status,
-                            Err(e) => ActionResponse::failure("dummy", e.to_string()),
-                        };
+                     Ok(Some(line)) = stdout.next_line() => forward_status(&id, line, &mut status_bucket).await,
+                     status = child.wait() => {
+                        info!("Action done!! Status = {:?}", status);
+                        // Forward status lines printed just before the process exited, without
+                        // waiting on stdout held open by processes it might have left behind
+                        let drain = drain_stdout(&id, &mut stdout, &mut status_bucket);
+                        let _ = time::timeout(Duration::from_secs(1), drain).await;
+
+                        let response = exit_response(&id, status);
+                        if let Err(e) = status_bucket.fill(response).await {
+                            error!("Failed to send child process exit status. Error = {:?}", e);
+                        }
+                        break
+                     }
+                     Ok(_) = &mut cancel_rx => {
+                        info!("Cancelling action. Action ID = {}", id);
+                        if let Err(e) = child.kill().await {
+                            error!("Failed to kill cancelled process. Error = {:?}", e);
+                        }
+
+                        let status = ActionResponse::progress(&id, "Cancelled", 100);
+                        if let Err(e) = status_bucket.fill(status).await {
+                            error!("Failed to send cancelled status. Error = {:?}", e);
+                        }
+                        break
+                     }
+                     _ = &mut timeout => {
+                        info!("Action timed out, killing its process. Action ID = {}", id);
+                        if let Err(e) = child.kill().await {
+                            error!("Failed to kill timed out process. Error = {:?}", e);
+                        }
 
-                        debug!("Action status: {:?}", status);
+                        let status = timeout_response(&id, timeout_secs);
                         if let Err(e) = status_bucket.fill(status).await {
-                            error!("Failed to send child process status. Error = {:?}", e);
+                            error!("Failed to send timed out status. Error = {:?}", e);
                         }
+                        break
                      }
-                     status = child.wait() => info!("Action done!! Status = {:?}", status),
-                     _ = &mut timeout => break
                 }
             }
 
-            *last_process_done.lock().unwrap() = true;
+            cancels.lock().unwrap().remove(&id);
+
+            // Kill the process, if still running, before removing its spooled payload
+            drop(child);
+            remove_spool(spool);
+            release(&running, &exited_tx, &name);
         });
 
         Ok(())
@@ -106,17 +249,434 @@ impl Process {
         command: S,
         payload: S,
     ) -> Result<(), Error> {
-        let command = String::from("tools/") + &command.into();
+        let name = command.into();
+        command_path(&self.config.tools_dir, &name)?;
+        let id = id.into();
+        let payload = payload.into();
+
+        // Queue the action if command already has as many processes in progress as it is
+        // allowed to, or if other actions of the command are already waiting in queue
+        let queued = self.queue.get(&name).map_or(false, |queue| !queue.is_empty());
+        if queued || !self.acquire(&name) {
+            return self.enqueue(id, name, payload).await;
+        }
+
+        self.start(id, name, payload).await
+    }
 
-        // Check if last process is in progress
-        if !(*self.last_process_done.lock().unwrap()) {
+    /// Executes the next action queued for command, as a process of the command exited.
+    /// Returns id of the action, along with the result of executing it
+    pub async fn execute_queued(&mut self, name: &str) -> Option<(String, Result<(), Error>)> {
+        if self.queue.get(name).map_or(true, |queue| queue.is_empty()) || !self.acquire(name) {
+            return None;
+        }
+
+        let (id, payload) = self.queue.get_mut(name)?.pop_front()?;
+        let result = self.start(id.clone(), name.to_owned(), payload).await;
+        Some((id, result))
+    }
+
+    // Counts a new process of command, if it has less processes in progress than allowed
+    fn acquire(&self, name: &str) -> bool {
+        let limit = self.config.action_concurrency.get(name).copied().unwrap_or(1);
+        let mut running = self.running.lock().unwrap();
+        let count = running.entry(name.to_owned()).or_insert(0);
+        if *count >= limit {
+            return false;
+        }
+
+        *count += 1;
+        true
+    }
+
+    // Spawns process of an action and captures its stdout, command must have been acquired
+    async fn start(&mut self, id: String, name: String, payload: String) -> Result<(), Error> {
+        let spawned = match command_path(&self.config.tools_dir, &name) {
+            Ok(command) => self.run(id.clone(), &name, command, payload).await,
+            Err(e) => Err(e),
+        };
+
+        let (child, spool) = match spawned {
+            Ok(child) => child,
+            Err(e) => {
+                release(&self.running, &self.exited_tx, &name);
+                return Err(e);
+            }
+        };
+        // Confirm that the action began executing, as tools might not report progress until done
+        let mut status_bucket = self.action_routes.status(&name);
+        let status = ActionResponse::progress(&id, "Started", 0);
+        if let Err(e) = status_bucket.fill(status).await {
+            error!("Failed to send started status. Error = {:?}", e);
+        }
+        self.spawn_and_capture_stdout(id, name, child, spool, status_bucket).await?;
+
+        Ok(())
+    }
+
+    // Queues an action of a busy command, within limits of the queue
+    async fn enqueue(&mut self, id: String, name: String, payload: String) -> Result<(), Error> {
+        let max_queue_size = self.config.action_queue_size;
+        if max_queue_size == 0 {
             return Err(Error::Busy);
         }
 
-        // Spawn the action and capture its stdout
-        let child = self.run(id.into(), command, payload.into()).await?;
-        self.spawn_and_capture_stdout(child).await?;
+        let queue = self.queue.entry(name.clone()).or_default();
+        if queue.len() >= max_queue_size {
+            return Err(Error::QueueFull(queue.len()));
+        }
+
+        debug!("Queueing action {} of busy command {}, {} already queued", id, name, queue.len());
+        let status = ActionResponse::progress(&id, "Queued", 0);
+        queue.push_back((id, payload));
+
+        if let Err(e) = self.action_routes.fill(&name, status).await {
+            error!("Failed to send queued status. Error = {:?}", e);
+        }
 
         Ok(())
     }
 }
+
+// Path of the binary of a command in the tools directory, rejecting names that could
+// resolve onto a binary outside of it, including drive relative paths on windows
+fn command_path(tools_dir: &str, name: &str) -> Result<PathBuf, Error> {
+    if name.is_empty()
+        || name.contains('/')
+        || name.contains('\\')
+        || name.contains("..")
+        || (cfg!(windows) && name.contains(':'))
+    {
+        return Err(Error::InvalidCommand(name.to_owned()));
+    }
+
+    Ok(resolve_extension(PathBuf::from(tools_dir).join(name)))
+}
+
+// Binaries on windows are executables or scripts, looked up as <name>.exe, <name>.bat and
+// <name>.cmd in that order when the command isn't found as named. Commands are run as named
+// on other platforms, where binaries need to be executable instead.
+#[cfg(windows)]
+fn resolve_extension(path: PathBuf) -> PathBuf {
+    if path.is_file() {
+        return path;
+    }
+
+    ["exe", "bat", "cmd"]
+        .iter()
+        .map(|extension| path.with_extension(extension))
+        .find(|path| path.is_file())
+        .unwrap_or(path)
+}
+
+#[cfg(not(windows))]
+fn resolve_extension(path: PathBuf) -> PathBuf {
+    path
+}
+
+// Forwards a status line printed by the process of an action, either an `ActionResponse`
+// or progress of the action as `PROGRESS <percentage>`. Other lines are only logged.
+async fn forward_status(id: &str, line: String, status_bucket: &mut Stream<ActionResponse>) {
+    let status = match parse_status(id, &line) {
+        Some(status) => status,
+        None => {
+            warn!("Ignoring unrecognized output of action {}: {}", id, line);
+            return;
+        }
+    };
+
+    debug!("Action status: {:?}", status);
+    if let Err(e) = status_bucket.fill(status).await {
+        error!("Failed to send child process status. Error = {:?}", e);
+    }
+}
+
+fn parse_status(id: &str, line: &str) -> Option<ActionResponse> {
+    if let Ok(status) = serde_json::from_str(line) {
+        return Some(status);
+    }
+
+    let mut tokens = line.split_whitespace();
+    match (tokens.next(), tokens.next(), tokens.next()) {
+        (Some("PROGRESS"), Some(progress), None) => {
+            let progress = progress.trim_end_matches('%').parse().ok().filter(|p| *p <= 100)?;
+            Some(ActionResponse::progress(id, "Running", progress))
+        }
+        _ => None,
+    }
+}
+
+// Forwards status lines that are left in stdout of an exited process
+async fn drain_stdout(
+    id: &str,
+    stdout: &mut Lines<BufReader<ChildStdout>>,
+    status_bucket: &mut Stream<ActionResponse>,
+) {
+    while let Ok(Some(line)) = stdout.next_line().await {
+        forward_status(id, line, status_bucket).await;
+    }
+}
+
+// Final response of an action, based on exit status of its process
+fn exit_response(id: &str, status: Result<ExitStatus, io::Error>) -> ActionResponse {
+    match status {
+        Ok(status) if status.success() => ActionResponse::success(id),
+        Ok(status) => match status.code() {
+            Some(code) => ActionResponse::failure(id, format!("Process exited with code {}", code)),
+            None => ActionResponse::failure(id, "Process terminated by signal"),
+        },
+        Err(e) => ActionResponse::failure(id, format!("Failed to wait on process: {}", e)),
+    }
+}
+
+// Final response of an action whose process didn't exit within its timeout
+fn timeout_response(id: &str, timeout_secs: u64) -> ActionResponse {
+    ActionResponse::failure(id, format!("Action timed out after {}s", timeout_secs))
+}
+
+// Writes payload of an action into a new file in the temporary directory, readable only by
+// uplink. The file is named at random rather than after the action id sent by the cloud, and
+// is never opened if it already exists, so as to not follow links planted in a shared directory.
+fn spool_payload(payload: &str) -> Result<PathBuf, Error> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let mut attempts = 0;
+    loop {
+        let name = format!("uplink-action-{:016x}", rand::random::<u64>());
+        let path = std::env::temp_dir().join(name);
+        match options.open(&path) {
+            Ok(mut file) => {
+                if let Err(e) = file.write_all(payload.as_bytes()) {
+                    remove_spool(Some(path));
+                    return Err(e.into());
+                }
+                return Ok(path);
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists && attempts < 10 => attempts += 1,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+// Removes the file an action's payload was spooled into
+fn remove_spool(spool: Option<PathBuf>) {
+    if let Some(path) = spool {
+        if let Err(e) = fs::remove_file(&path) {
+            error!("Failed to remove spooled action payload {:?}. Error = {:?}", path, e);
+        }
+    }
+}
+
+// Marks a process of command as done, allowing another to be spawned in its place
+fn release(running: &Mutex<HashMap<String, usize>>, exited_tx: &Sender<String>, command: &str) {
+    if let Some(count) = running.lock().unwrap().get_mut(command) {
+        *count = count.saturating_sub(1);
+    }
+
+    if let Err(e) = exited_tx.send(command.to_owned()) {
+        error!("Failed to notify exit of {} process. Error = {:?}", command, e);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[cfg(unix)]
+    use std::os::unix::process::ExitStatusExt;
+
+    #[tokio::test]
+    async fn actions_of_busy_command_are_queued() {
+        let config = Arc::new(Config {
+            tools_dir: "tools/".to_owned(),
+            action_queue_size: 1,
+            ..Default::default()
+        });
+        let (data_tx, data_rx) = flume::bounded(10);
+        let action_status = Stream::new("action_status", "/action/status", 1, data_tx.clone());
+        let action_routes = ActionRoutes::new(&config, action_status, data_tx);
+        let mut process = Process::new(config, action_routes);
+
+        // Occupy the only process slot of the command
+        assert!(process.acquire("missing"));
+        process.execute("1", "missing", "{}").await.unwrap();
+        assert!(matches!(process.execute("2", "missing", "{}").await, Err(Error::QueueFull(1))));
+        assert!(data_rx.try_recv().is_ok());
+        assert!(process.execute_queued("missing").await.is_none());
+
+        release(&process.running, &process.exited_tx, "missing");
+        assert_eq!(process.exited().await.unwrap(), "missing");
+        let (id, result) = process.execute_queued("missing").await.unwrap();
+        assert_eq!(id, "1");
+        assert!(matches!(result, Err(Error::NotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn queued_actions_are_cancelled() {
+        let config = Arc::new(Config {
+            tools_dir: "tools/".to_owned(),
+            action_queue_size: 2,
+            ..Default::default()
+        });
+        let (data_tx, data_rx) = flume::bounded(10);
+        let action_status = Stream::new("action_status", "/action/status", 1, data_tx.clone());
+        let action_routes = ActionRoutes::new(&config, action_status, data_tx);
+        let mut process = Process::new(config, action_routes);
+
+        assert!(process.acquire("missing"));
+        process.execute("1", "missing", "{}").await.unwrap();
+        process.execute("2", "missing", "{}").await.unwrap();
+
+        assert!(process.cancel("1").await);
+        assert!(!process.cancel("1").await);
+        assert!(!process.cancel("3").await);
+        // Queued responses of both actions, followed by cancelled response
+        assert_eq!(data_rx.len(), 3);
+
+        release(&process.running, &process.exited_tx, "missing");
+        let (id, _) = process.execute_queued("missing").await.unwrap();
+        assert_eq!(id, "2");
+    }
+
+    #[tokio::test]
+    #[cfg(unix)]
+    async fn spawned_processes_are_reported_started() {
+        let config = Arc::new(Config { tools_dir: "/bin".to_owned(), ..Default::default() });
+        let (data_tx, data_rx) = flume::bounded(10);
+        let action_status = Stream::new("action_status", "/action/status", 1, data_tx.clone());
+        let action_routes = ActionRoutes::new(&config, action_status, data_tx);
+        let mut process = Process::new(config, action_routes);
+
+        process.execute("1", "true", "{}").await.unwrap();
+        assert_eq!(process.exited().await.unwrap(), "true");
+        let states: Vec<String> = data_rx
+            .drain()
+            .map(|status| String::from_utf8(status.serialize().unwrap()).unwrap())
+            .collect();
+        assert_eq!(states.len(), 2);
+        assert!(states[0].contains(r#""state":"Started""#));
+        assert!(states[1].contains(r#""state":"Completed""#));
+
+        // Actions that fail to spawn aren't reported started
+        assert!(matches!(process.execute("2", "missing", "{}").await, Err(Error::NotFound(_))));
+        assert!(data_rx.is_empty());
+    }
+
+    #[tokio::test]
+    #[cfg(unix)]
+    async fn timed_out_processes_are_killed_and_failed() {
+        let config = Arc::new(Config {
+            action_timeouts: HashMap::from([("sleep".to_owned(), 1)]),
+            ..Default::default()
+        });
+        let (data_tx, data_rx) = flume::bounded(10);
+        let action_status = Stream::new("action_status", "/action/status", 1, data_tx.clone());
+        let action_routes = ActionRoutes::new(&config, action_status, data_tx);
+        let mut process = Process::new(config, action_routes.clone());
+
+        assert!(process.acquire("sleep"));
+        let mut cmd = Command::new("sleep");
+        let child = cmd.arg("10").kill_on_drop(true).stdout(Stdio::piped()).spawn().unwrap();
+        let status = action_routes.status("sleep");
+        process
+            .spawn_and_capture_stdout("1".to_owned(), "sleep".to_owned(), child, None, status)
+            .await
+            .unwrap();
+        assert_eq!(process.exited().await.unwrap(), "sleep");
+
+        // Exactly one terminal status is sent for the action
+        let status = String::from_utf8(data_rx.try_recv().unwrap().serialize().unwrap()).unwrap();
+        assert!(status.contains("Action timed out after 1s"));
+        assert!(data_rx.is_empty());
+    }
+
+    #[tokio::test]
+    #[cfg(unix)]
+    async fn spooled_payloads_are_not_named_after_action_id() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let config = Arc::new(Config { action_payload_spool_size: Some(1), ..Default::default() });
+        let (data_tx, _data_rx) = flume::bounded(10);
+        let action_status = Stream::new("action_status", "/action/status", 1, data_tx.clone());
+        let action_routes = ActionRoutes::new(&config, action_status, data_tx);
+        let mut process = Process::new(config, action_routes);
+
+        let id = "../../../tmp/uplink-traversal".to_owned();
+        let (mut child, spool) =
+            process.run(id, "true", PathBuf::from("/bin/true"), "{}".to_owned()).await.unwrap();
+        child.wait().await.unwrap();
+
+        let path = spool.unwrap();
+        assert_eq!(path.parent().unwrap(), std::env::temp_dir());
+        assert!(!path.to_string_lossy().contains("traversal"));
+        assert!(!std::path::Path::new("/tmp/uplink-traversal").exists());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "{}");
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        remove_spool(Some(path));
+    }
+
+    #[test]
+    fn commands_are_confined_to_tools_dir() {
+        let path = command_path("/usr/share/uplink/tools", "tunshell").unwrap();
+        assert_eq!(path, PathBuf::from("/usr/share/uplink/tools/tunshell"));
+        assert_eq!(command_path("tools/", "tunshell").unwrap(), PathBuf::from("tools/tunshell"));
+
+        for name in ["", "../bin/sh", "/bin/sh", "sub/tool", "..", "tool\\..\\sh"] {
+            assert!(matches!(command_path("tools/", name), Err(Error::InvalidCommand(_))));
+        }
+    }
+
+    #[test]
+    fn progress_lines_are_parsed() {
+        let status = parse_status("1", "PROGRESS 42").unwrap();
+        assert_eq!(
+            (status.id.as_str(), status.state.as_str(), status.progress),
+            ("1", "Running", 42)
+        );
+        assert_eq!(parse_status("1", "  PROGRESS 100%").unwrap().progress, 100);
+
+        let line = r#"{"id": "2", "sequence": 0, "timestamp": 0, "state": "Downloading", "progress": 10, "errors": []}"#;
+        let status = parse_status("1", line).unwrap();
+        assert_eq!((status.id.as_str(), status.state.as_str()), ("2", "Downloading"));
+
+        for line in
+            ["", "Downloading update...", "PROGRESS", "PROGRESS 101", "PROGRESS -1", "PROGRESS 1 2"]
+        {
+            assert!(parse_status("1", line).is_none());
+        }
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn commands_are_resolved_with_extension_on_windows() {
+        let tools_dir = std::env::temp_dir().join("uplink_tools_windows");
+        fs::create_dir_all(&tools_dir).unwrap();
+        fs::write(tools_dir.join("update.bat"), "@echo off").unwrap();
+        fs::write(tools_dir.join("tunshell.exe"), "").unwrap();
+        let tools = tools_dir.to_str().unwrap();
+
+        assert_eq!(command_path(tools, "update").unwrap(), tools_dir.join("update.bat"));
+        assert_eq!(command_path(tools, "tunshell").unwrap(), tools_dir.join("tunshell.exe"));
+        assert_eq!(command_path(tools, "update.bat").unwrap(), tools_dir.join("update.bat"));
+        // Missing commands are reported as named
+        assert_eq!(command_path(tools, "missing").unwrap(), tools_dir.join("missing"));
+        assert!(matches!(command_path(tools, "C:tool"), Err(Error::InvalidCommand(_))));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn exit_status_is_reported() {
+        let response = exit_response("1", Ok(ExitStatus::from_raw(0)));
+        assert_eq!(response.state, "Completed");
+
+        // Exit code is held in the second byte of raw wait status
+        let response = exit_response("1", Ok(ExitStatus::from_raw(3 << 8)));
+        assert_eq!(response.state, "Failed");
+        assert_eq!(response.errors, vec!["Process exited with code 3".to_owned()]);
+
+        let response = exit_response("1", Ok(ExitStatus::from_raw(9)));
+        assert_eq!(response.errors, vec!["Process terminated by signal".to_owned()]);
+    }
+}
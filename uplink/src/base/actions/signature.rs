@@ -0,0 +1,98 @@
+//! Verifies signatures of [`Action`]s when `action_signing` is configured, so that only actions signed with the
+//! private key of the configured Ed25519 public key are handled, be it by spawning a process, a built-in handler
+//! or forwarding them over the bridge. Unsigned actions and those with an invalid signature are rejected.
+//!
+//! Actions are signed over their canonical form, the UTF-8 bytes of the id of the device they are meant for,
+//! followed by their `id`, `kind`, `name` and `payload`, in that order, joined with newlines, i.e.
+//! `"{device_id}\n{id}\n{kind}\n{name}\n{payload}"`. The device id is that of the configured device, so that
+//! an action signed for one device is rejected by every other device trusting the same key. The payload is
+//! signed as is, in the exact string that is sent in the action. Ids, kinds and names can't contain newlines,
+//! so that fields of a signed action can't be shifted onto others. The Ed25519 signature is sent base64 encoded in the
+//! `signature` field of the action.
+
+use ring::signature::{UnparsedPublicKey, ED25519};
+
+use std::io;
+
+use super::Action;
+use crate::base::ActionSigning;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("missing signature")]
+    Unsigned,
+    #[error("signature isn't base64: {0}")]
+    Base64(#[from] base64::DecodeError),
+    #[error("{0} of a signed action can't contain newlines")]
+    Newline(&'static str),
+    #[error("invalid signature")]
+    Invalid,
+    #[error("public key unavailable: {0}")]
+    PublicKey(#[from] io::Error),
+}
+
+/// Canonical form of the action for the device, over which it is signed
+pub fn signed_message(device_id: &str, action: &Action) -> Result<Vec<u8>, Error> {
+    let fields = [("id", &action.action_id), ("kind", &action.kind), ("name", &action.name)];
+    if let Some((field, _)) = fields.iter().find(|(_, value)| value.contains('\n')) {
+        return Err(Error::Newline(*field));
+    }
+
+    let Action { action_id, kind, name, payload, .. } = action;
+    let message = format!("{}\n{}\n{}\n{}\n{}", device_id, action_id, kind, name, payload);
+    Ok(message.into_bytes())
+}
+
+/// Verifies signature of the action, as signed for the device, against the configured public key
+pub fn verify(signing: &ActionSigning, device_id: &str, action: &Action) -> Result<(), Error> {
+    let signature = action.signature.as_ref().ok_or(Error::Unsigned)?;
+    let signature = base64::decode(signature.trim())?;
+    let message = signed_message(device_id, action)?;
+
+    let public_key = UnparsedPublicKey::new(&ED25519, signing.public_key()?);
+    public_key.verify(&message, &signature).map_err(|_| Error::Invalid)
+}
+
+#[cfg(test)]
+mod test {
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    use super::*;
+
+    fn action(payload: &str) -> Action {
+        Action {
+            device_id: "123".to_owned(),
+            action_id: "1".to_owned(),
+            kind: "process".to_owned(),
+            name: "update".to_owned(),
+            payload: payload.to_owned(),
+            signature: None,
+        }
+    }
+
+    #[test]
+    fn only_actions_with_valid_signatures_are_verified() {
+        let keys = Ed25519KeyPair::from_seed_unchecked(&[7; 32]).unwrap();
+        let signing = ActionSigning { public_key: base64::encode(keys.public_key()) };
+
+        let mut signed = action(r#"{"version": "1.2"}"#);
+        let message = signed_message("123", &signed).unwrap();
+        assert_eq!(message, b"123\n1\nprocess\nupdate\n{\"version\": \"1.2\"}");
+        signed.signature = Some(base64::encode(keys.sign(&message)));
+        verify(&signing, "123", &signed).unwrap();
+
+        // Signed action is rejected when replayed onto another device
+        assert!(matches!(verify(&signing, "456", &signed), Err(Error::Invalid)));
+
+        assert!(matches!(verify(&signing, "123", &action("{}")), Err(Error::Unsigned)));
+
+        let tampered = Action { payload: "{}".to_owned(), ..signed.clone() };
+        assert!(matches!(verify(&signing, "123", &tampered), Err(Error::Invalid)));
+
+        let tampered = Action { name: "tunshell".to_owned(), ..signed.clone() };
+        assert!(matches!(verify(&signing, "123", &tampered), Err(Error::Invalid)));
+
+        let tampered = Action { action_id: "1\nprocess".to_owned(), ..signed };
+        assert!(matches!(verify(&signing, "123", &tampered), Err(Error::Newline("id"))));
+    }
+}
@@ -0,0 +1,165 @@
+//! Executes [`Action`]s whose name is configured in `action_webhooks` by posting them to an HTTP service,
+//! in place of spawning a process of them, for action handlers that don't live on the device as binaries.
+//!
+//! The action is posted as JSON of the form `{"id": "...", "name": "...", "payload": "..."}`, along with the
+//! headers configured for the webhook. A successful response completes the action, with the response body
+//! forwarded as is if it is an [`ActionResponse`], else as result of the action if it is any other JSON.
+//! Other responses, as well as those not received within the timeout of the action, fail it.
+//!
+//! Each request runs in a spawned task, so that other actions aren't blocked while a service responds.
+
+use log::{debug, error};
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{Client, StatusCode};
+use serde::Serialize;
+use tokio::{task, time};
+
+use std::sync::Arc;
+
+use super::{action_timeout, Action, ActionResponse, ActionRoutes};
+use crate::base::{Config, WebhookConfig};
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Serde error: {0}")]
+    Serde(#[from] serde_json::Error),
+    #[error("Error from reqwest: {0}")]
+    Reqwest(#[from] reqwest::Error),
+}
+
+// JSON posted to the webhook of an action
+#[derive(Serialize)]
+struct Request<'a> {
+    id: &'a str,
+    name: &'a str,
+    payload: &'a str,
+}
+
+/// Posts actions to their webhooks, reporting responses onto the status stream of each action
+pub struct Webhooks {
+    config: Arc<Config>,
+    action_routes: ActionRoutes,
+    client: Client,
+}
+
+impl Webhooks {
+    pub fn new(config: Arc<Config>, action_routes: ActionRoutes) -> Webhooks {
+        Webhooks { config, action_routes, client: Client::new() }
+    }
+
+    /// Replaces config with one reloaded while running, which applies to actions posted after
+    pub fn set_config(&mut self, config: Arc<Config>) {
+        self.config = config;
+    }
+
+    /// Posts action to the webhook configured for its name, within a spawned task
+    pub fn execute(&self, action: Action) {
+        let webhook = match self.config.action_webhooks.get(&action.name) {
+            Some(webhook) => webhook.clone(),
+            None => return,
+        };
+        let client = self.client.clone();
+        let timeout = action_timeout(&self.config, &action.name);
+        let mut status_bucket = self.action_routes.status(&action.name);
+
+        task::spawn(async move {
+            let id = &action.action_id;
+            let status = match time::timeout(timeout, post(&client, &webhook, &action)).await {
+                Ok(Ok(status)) => status,
+                Ok(Err(e)) => {
+                    error!("Webhook failed. Action ID = {}, Error = {:?}", id, e);
+                    ActionResponse::failure(id, e.to_string())
+                }
+                Err(_) => ActionResponse::failure(
+                    id,
+                    format!("Webhook didn't respond within {}s", timeout.as_secs()),
+                ),
+            };
+
+            debug!("Action status: {:?}", status);
+            if let Err(e) = status_bucket.fill(status).await {
+                error!("Failed to send webhook status. Error = {:?}", e);
+            }
+        });
+    }
+}
+
+async fn post(
+    client: &Client,
+    webhook: &WebhookConfig,
+    action: &Action,
+) -> Result<ActionResponse, Error> {
+    let request = Request { id: &action.action_id, name: &action.name, payload: &action.payload };
+    let mut request = client
+        .post(&webhook.url)
+        .header(CONTENT_TYPE, "application/json")
+        .body(serde_json::to_vec(&request)?);
+    for (name, value) in &webhook.headers {
+        request = request.header(name, value);
+    }
+
+    let response = request.send().await?;
+    let status = response.status();
+    let body = response.bytes().await?;
+
+    Ok(webhook_response(&action.action_id, status, &body))
+}
+
+// Response of an action, based on status and body of the response from its webhook
+fn webhook_response(id: &str, status: StatusCode, body: &[u8]) -> ActionResponse {
+    if !status.is_success() {
+        let error = format!("Webhook responded with {}", status);
+        return match std::str::from_utf8(body).map(str::trim) {
+            Ok(body) if !body.is_empty() => ActionResponse::failure(id, error).add_error(body),
+            _ => ActionResponse::failure(id, error),
+        };
+    }
+
+    if body.iter().all(u8::is_ascii_whitespace) {
+        return ActionResponse::success(id);
+    }
+
+    if let Ok(mut response) = serde_json::from_slice::<ActionResponse>(body) {
+        // Responses are always reported against the action that was posted
+        response.id = id.to_owned();
+        return response;
+    }
+
+    match serde_json::from_slice(body) {
+        Ok(result) => ActionResponse::success(id).set_result(result),
+        Err(e) => {
+            debug!("Ignoring body of webhook response to {}, as it isn't json: {}", id, e);
+            ActionResponse::success(id)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn webhook_responses_are_mapped_to_action_responses() {
+        let response = webhook_response("1", StatusCode::NO_CONTENT, b"");
+        assert_eq!((response.state.as_str(), response.progress), ("Completed", 100));
+
+        let body = br#"{"id": "2", "sequence": 0, "timestamp": 0, "state": "Running", "progress": 40, "errors": []}"#;
+        let response = webhook_response("1", StatusCode::OK, body);
+        assert_eq!((response.id.as_str(), response.state.as_str()), ("1", "Running"));
+        assert_eq!(response.progress, 40);
+
+        let response = webhook_response("1", StatusCode::OK, br#"{"version": "1.2"}"#);
+        assert_eq!(response.state, "Completed");
+        assert_eq!(response.result, Some(serde_json::json!({"version": "1.2"})));
+
+        let response = webhook_response("1", StatusCode::OK, b"done");
+        assert_eq!((response.state.as_str(), response.result), ("Completed", None));
+
+        let response = webhook_response("1", StatusCode::SERVICE_UNAVAILABLE, b"busy\n");
+        assert_eq!(response.state, "Failed");
+        assert_eq!(
+            response.errors,
+            vec!["Webhook responded with 503 Service Unavailable".to_owned(), "busy".to_owned()]
+        );
+    }
+}
@@ -0,0 +1,167 @@
+//! Aggregation coalesces data of the streams configured in `aggregation` into a single publish onto a shared
+//! topic, trading a little latency for far fewer messages on metered links. Data of these streams is held back
+//! for up to `window_ms` after the first of it arrives, or until the next batch wouldn't fit within `max_size`
+//! bytes, before being published together.
+//!
+//! The payload of an aggregate is a map of stream name to the records of that stream, e.g.
+//! `{"can": [{...}, {...}], "gps": [{...}]}`, encoded in the configured `payload_format`, which the backend
+//! demultiplexes by the keys of the map. Records of a stream keep the order in which they were collected.
+//!
+//! Data is only aggregated in normal mode, aggregates are handed over to the serializer as any other
+//! [`Package`], so that they are written onto disk as a whole when the network is slow.
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde_json::Value;
+
+use crate::base::{Aggregation, Package, PayloadError, PayloadFormat};
+
+/// Name of the stream of aggregates, as reported in metrics
+pub const AGGREGATE_STREAM: &str = "aggregate";
+
+pub struct Aggregator {
+    config: Aggregation,
+    topic: Arc<String>,
+    // aggregate that is yet to be published, if any data arrived within the window
+    pending: Option<Aggregate>,
+}
+
+impl Aggregator {
+    pub fn new(config: Aggregation) -> Aggregator {
+        let topic = Arc::new(config.topic.clone());
+        Aggregator { config, topic, pending: None }
+    }
+
+    /// Checks if data of stream is aggregated
+    pub fn accepts(&self, stream: &str) -> bool {
+        self.config.streams.iter().any(|s| s == stream)
+    }
+
+    /// Adds data onto the pending aggregate, returning the previously pending aggregate if it
+    /// couldn't fit the data within `max_size`, which is then to be published before it
+    pub fn push(&mut self, data: Box<dyn Package>) -> Result<Option<Aggregate>, serde_json::Error> {
+        let payload = data.serialize()?;
+        let records = match serde_json::from_slice(&payload)? {
+            Value::Array(records) => records,
+            record => vec![record],
+        };
+
+        let max_size = self.config.max_size;
+        let fits = self.pending.as_ref().map_or(true, |p| p.size + payload.len() <= max_size);
+        let full = if fits { None } else { self.pending.take() };
+
+        let window = Duration::from_millis(self.config.window_ms);
+        let pending = self.pending.get_or_insert_with(|| Aggregate {
+            topic: self.topic.clone(),
+            stream: Arc::new(AGGREGATE_STREAM.to_owned()),
+            streams: BTreeMap::new(),
+            size: 0,
+            deadline: Instant::now() + window,
+        });
+        pending.streams.entry(data.stream().to_string()).or_default().extend(records);
+        pending.size += payload.len();
+
+        Ok(full)
+    }
+
+    /// Time by which the pending aggregate is to be published, if any
+    pub fn deadline(&self) -> Option<Instant> {
+        self.pending.as_ref().map(|pending| pending.deadline)
+    }
+
+    /// Takes the pending aggregate, to publish it
+    pub fn take(&mut self) -> Option<Aggregate> {
+        self.pending.take()
+    }
+}
+
+/// Records of several streams, published together onto the topic of aggregation
+#[derive(Debug)]
+pub struct Aggregate {
+    topic: Arc<String>,
+    stream: Arc<String>,
+    streams: BTreeMap<String, Vec<Value>>,
+    // size of the records when serialized as json, as collected
+    size: usize,
+    deadline: Instant,
+}
+
+impl Package for Aggregate {
+    fn stream(&self) -> Arc<String> {
+        self.stream.clone()
+    }
+
+    fn topic(&self) -> Arc<String> {
+        self.topic.clone()
+    }
+
+    fn serialize(&self) -> serde_json::Result<Vec<u8>> {
+        serde_json::to_vec(&self.streams)
+    }
+
+    fn serialize_as(&self, format: PayloadFormat) -> Result<Vec<u8>, PayloadError> {
+        format.encode(&self.streams)
+    }
+
+    // Anomalies are counted as data of each stream arrives
+    fn anomalies(&self) -> Option<(String, usize)> {
+        None
+    }
+
+    fn anomaly_counts(&self) -> Vec<(String, usize)> {
+        vec![]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use flume::bounded;
+
+    use super::*;
+    use crate::{Payload, Stream};
+
+    // Batch of a single data point, serialized as `[{"sequence":1,"timestamp":0}]`, i.e. 30 bytes
+    fn batch(name: &str, sequence: u32) -> Box<dyn Package> {
+        let (tx, rx) = bounded(1);
+        let topic = format!("/{}", name);
+        let mut stream = Stream::new(name, topic.as_str(), 1, tx);
+        let data = Payload {
+            stream: name.to_owned(),
+            sequence,
+            timestamp: 0,
+            payload: serde_json::json!({}),
+        };
+        stream.push(data).unwrap();
+        rx.recv().unwrap()
+    }
+
+    #[test]
+    fn streams_are_coalesced_within_max_size() {
+        let config = Aggregation {
+            topic: "/aggregate".to_owned(),
+            streams: vec!["can".to_owned(), "gps".to_owned()],
+            window_ms: 1000,
+            max_size: 64,
+        };
+        let mut aggregator = Aggregator::new(config);
+        assert!(aggregator.accepts("can"));
+        assert!(!aggregator.accepts("imu"));
+
+        assert!(aggregator.push(batch("can", 1)).unwrap().is_none());
+        assert!(aggregator.push(batch("gps", 1)).unwrap().is_none());
+        assert!(aggregator.deadline().is_some());
+
+        // Data that doesn't fit publishes the pending aggregate, starting another with it
+        let full = aggregator.push(batch("can", 2)).unwrap().unwrap();
+        assert_eq!(full.topic().as_str(), "/aggregate");
+        let payload: Value = serde_json::from_slice(&full.serialize().unwrap()).unwrap();
+        assert_eq!(payload["can"][0]["sequence"], 1);
+        assert_eq!(payload["gps"][0]["sequence"], 1);
+
+        let pending = aggregator.take().unwrap();
+        let payload: Value = serde_json::from_slice(&pending.serialize().unwrap()).unwrap();
+        assert_eq!(payload, serde_json::json!({"can": [{"sequence": 2, "timestamp": 0}]}));
+        assert!(aggregator.deadline().is_none());
+    }
+}
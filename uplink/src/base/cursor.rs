@@ -0,0 +1,197 @@
+//! Ack cursors track the sequence of the last data point of a stream that was delivered,
+//! persisting it onto disk so that data from before a restart is not replayed from storage if it
+//! was already delivered. Cursors are only maintained for streams configured with
+//! `ack_cursor = true` and are keyed by the topic of a stream, as that is all that is known of a
+//! publish read back from storage.
+//!
+//! Delivery is considered confirmed once a publish is handed over to the MQTT client, since the
+//! client doesn't notify uplink of acknowledgements from the broker for individual publishes.
+//! Cursors are moved in memory on every delivery and only persisted when flushed, which
+//! serializer does every second and on changing modes, so that publishing isn't held back by
+//! disk. Publishes delivered after the last flush are replayed after a crash.
+//!
+//! Cursors loaded at startup are only used to skip publishes written onto storage by an earlier
+//! run. The cursor of a topic is disarmed as soon as a publish with a higher sequence is
+//! replayed, so that data from a stream whose sequence was restarted, after the previous data
+//! was replayed, is never skipped.
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::{fs, io};
+
+use log::error;
+use serde_json::Value;
+
+use crate::base::PayloadFormat;
+
+pub struct AckCursors {
+    // file onto which cursors are persisted
+    path: PathBuf,
+    // topics of streams for which cursors are maintained
+    topics: HashSet<String>,
+    // sequence of last delivered data point, for each topic
+    cursors: HashMap<String, u32>,
+    // cursors loaded at startup, used to skip replay of already delivered publishes
+    replay: HashMap<String, u32>,
+    // whether cursors were moved since they were last persisted
+    dirty: bool,
+}
+
+impl AckCursors {
+    /// Loads cursors persisted in `path`, if any, for the given topics
+    pub fn load(path: PathBuf, topics: HashSet<String>) -> AckCursors {
+        let cursors: HashMap<String, u32> = match fs::read(&path) {
+            Ok(cursors) => serde_json::from_slice(&cursors).unwrap_or_else(|e| {
+                error!("Ignoring corrupt ack cursors at {:?}. Error = {:?}", path, e);
+                HashMap::new()
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => {
+                error!("Failed to read ack cursors at {:?}. Error = {:?}", path, e);
+                HashMap::new()
+            }
+        };
+
+        let cursors: HashMap<String, u32> =
+            cursors.into_iter().filter(|(topic, _)| topics.contains(topic)).collect();
+        let replay = cursors.clone();
+
+        AckCursors { path, topics, cursors, replay, dirty: false }
+    }
+
+    /// Checks if a publish read from storage was delivered before the restart of uplink
+    pub fn delivered(&mut self, topic: &str, payload: &[u8]) -> bool {
+        let cursor = match self.replay.get(topic) {
+            Some(cursor) => *cursor,
+            None => return false,
+        };
+
+        match last_sequence(topic, payload) {
+            Some(sequence) if sequence <= cursor => true,
+            _ => {
+                self.replay.remove(topic);
+                false
+            }
+        }
+    }
+
+    /// Sequence of the last data point in payload of a publish, if topic is tracked
+    pub fn sequence(&self, topic: &str, payload: &[u8]) -> Option<u32> {
+        if !self.topics.contains(topic) {
+            return None;
+        }
+
+        last_sequence(topic, payload)
+    }
+
+    /// Moves the cursor of topic to sequence of a delivered publish, persisted on the next flush
+    pub fn ack(&mut self, topic: &str, sequence: u32) {
+        if self.cursors.insert(topic.to_owned(), sequence) != Some(sequence) {
+            self.dirty = true;
+        }
+    }
+
+    /// Persists cursors onto disk, if any were moved since they were last persisted
+    pub fn flush(&mut self) -> Result<(), io::Error> {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        persist(&self.path, &serde_json::to_vec(&self.cursors)?)?;
+        self.dirty = false;
+
+        Ok(())
+    }
+}
+
+/// Writes contents onto a temporary file beside path and renames it over path, so that a crash
+/// never leaves a partially written file behind, for state that must survive restarts
+pub(crate) fn persist(path: &Path, contents: &[u8]) -> Result<(), io::Error> {
+    let temp = path.with_extension("tmp");
+    fs::write(&temp, contents)?;
+    fs::rename(&temp, path)
+}
+
+// Extracts the highest sequence from payload, an array of data points in the format indicated
+// by suffix of topic
+fn last_sequence(topic: &str, payload: &[u8]) -> Option<u32> {
+    let points: Vec<Value> = match PayloadFormat::of_topic(topic) {
+        PayloadFormat::Json => serde_json::from_slice(payload).ok()?,
+        PayloadFormat::Cbor => ciborium::de::from_reader(payload).ok()?,
+        PayloadFormat::MsgPack => rmp_serde::from_slice(payload).ok()?,
+    };
+    points.iter().filter_map(|p| p.get("sequence")?.as_u64()).max().map(|s| s as u32)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn topics() -> HashSet<String> {
+        HashSet::from(["/devices/1/events/can/jsonarray".to_owned()])
+    }
+
+    fn payload(sequences: &[u32]) -> Vec<u8> {
+        let points: Vec<Value> =
+            sequences.iter().map(|s| serde_json::json!({"sequence": s, "timestamp": 0})).collect();
+        serde_json::to_vec(&points).unwrap()
+    }
+
+    #[test]
+    fn acked_publishes_are_skipped_after_restart() {
+        let path = std::env::temp_dir().join("uplink_ack_cursors_skip.json");
+        let _ = fs::remove_file(&path);
+        let topic = "/devices/1/events/can/jsonarray";
+
+        let mut cursors = AckCursors::load(path.clone(), topics());
+        assert_eq!(cursors.sequence(topic, &payload(&[1, 3, 2])), Some(3));
+        cursors.ack(topic, 3);
+        cursors.ack(topic, 5);
+        // Cursors are only persisted once flushed
+        assert!(!path.exists());
+        cursors.flush().unwrap();
+        // Cursors of the current run don't skip replay
+        assert!(!cursors.delivered(topic, &payload(&[4, 5])));
+
+        let mut cursors = AckCursors::load(path.clone(), topics());
+        assert!(cursors.delivered(topic, &payload(&[1, 2, 3])));
+        assert!(cursors.delivered(topic, &payload(&[4, 5])));
+        assert!(!cursors.delivered(topic, &payload(&[5, 6])));
+        // Cursor is disarmed once replay goes past it
+        assert!(!cursors.delivered(topic, &payload(&[1])));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn unflagged_topics_are_not_tracked() {
+        let path = std::env::temp_dir().join("uplink_ack_cursors_unflagged.json");
+        let _ = fs::remove_file(&path);
+        let topic = "/devices/1/events/gps/jsonarray";
+
+        let mut cursors = AckCursors::load(path.clone(), topics());
+        assert_eq!(cursors.sequence(topic, &payload(&[1, 2, 3])), None);
+        assert!(!cursors.delivered(topic, &payload(&[1])));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn sequence_is_read_from_cbor_payloads() {
+        let points: Vec<Value> =
+            [4, 7, 5].iter().map(|s| serde_json::json!({"sequence": s, "timestamp": 0})).collect();
+        let mut payload = vec![];
+        ciborium::ser::into_writer(&points, &mut payload).unwrap();
+
+        assert_eq!(last_sequence("/devices/1/events/can/jsonarray/cbor", &payload), Some(7));
+    }
+
+    #[test]
+    fn sequence_is_read_from_msgpack_payloads() {
+        let points: Vec<Value> =
+            [4, 7, 5].iter().map(|s| serde_json::json!({"sequence": s, "timestamp": 0})).collect();
+        let payload = rmp_serde::to_vec_named(&points).unwrap();
+
+        let topic = "/devices/1/events/can/jsonarray/msgpack";
+        assert_eq!(last_sequence(topic, &payload), Some(7));
+        assert_eq!(last_sequence("/devices/1/events/can/jsonarray", &payload), None);
+    }
+}
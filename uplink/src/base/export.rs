@@ -0,0 +1,232 @@
+//! Offline export of publishes buffered on disk, for inspecting data of a device that comes back
+//! from the field with undelivered data, or for re-ingesting it manually. Publishes are read from
+//! the shared storage and dedicated storages of streams, exactly as catchup reads them, decrypted
+//! and decompressed, then written out as a json object per line, i.e. NDJSON.
+//!
+//! Reading segments of [`Storage`] deletes them, hence segments are copied into a scratch
+//! directory and read from there, leaving data on disk as is, to be sent when uplink next runs.
+//! This is a debugging tool and isn't meant to be run while uplink is writing to the same storage.
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use aes_gcm::Aes256Gcm;
+use bytes::Bytes;
+use log::{info, warn};
+use rumqttc::{read, Packet};
+use serde::Serialize;
+use serde_json::Value;
+use thiserror::Error;
+
+use crate::base::replay::unstamp;
+use crate::base::serializer::{self, cipher, decompress, decrypt};
+use crate::base::Config;
+use disk::Storage;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Io error {0}")]
+    Io(#[from] io::Error),
+    #[error("Serde error {0}")]
+    Serde(#[from] serde_json::Error),
+    #[error("Serializer error {0}")]
+    Serializer(#[from] serializer::Error),
+    #[error("Persistence isn't configured")]
+    MissingPersistence,
+}
+
+/// Publish read from disk, as exported
+#[derive(Debug, Serialize)]
+struct Record<'a> {
+    /// Name of the storage the publish was read from, "shared" or name of the stream
+    storage: &'a str,
+    topic: String,
+    qos: u8,
+    /// Id the publish was stamped with, if replay dedup was enabled when it was written
+    #[serde(skip_serializing_if = "Option::is_none")]
+    replay_id: Option<u64>,
+    /// "json" if payload is json, as is, "base64" for other payloads, e.g. cbor or compressed ones
+    encoding: &'static str,
+    payload: Value,
+}
+
+/// Counts of publishes exported from disk and of those that couldn't be read or decoded
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Exported {
+    pub publishes: usize,
+    pub skipped: usize,
+}
+
+/// Writes publishes buffered in storages on disk, as configured in `persistence`, onto out as
+/// NDJSON. Dedicated storages of streams are exported before the shared one, as in catchup.
+pub fn export<W: Write>(config: &Config, mut out: W) -> Result<Exported, Error> {
+    let persistence = config.persistence.as_ref().ok_or(Error::MissingPersistence)?;
+    let cipher = cipher(config)?;
+    let path = Path::new(&persistence.path);
+
+    let mut storages: Vec<(&str, PathBuf)> = config
+        .streams
+        .iter()
+        .filter(|(_, stream)| stream.persistence.is_some())
+        .map(|(name, _)| (name.as_str(), path.join(name)))
+        .collect();
+    storages.sort();
+    storages.push(("shared", path.to_owned()));
+
+    let scratch = std::env::temp_dir().join(format!("uplink-export-{}", std::process::id()));
+    let mut exported = Exported::default();
+    for (name, path) in storages {
+        if !path.is_dir() {
+            continue;
+        }
+
+        let copy = scratch.join(name);
+        let result = copy_segments(&path, &copy).and_then(|_| {
+            let mut storage = Storage::new(&copy, persistence.max_file_size, usize::MAX)?;
+            export_storage(name, &mut storage, config.max_packet_size, cipher.as_ref(), &mut out)
+        });
+        let _ = fs::remove_dir_all(&copy);
+
+        let counts = result?;
+        info!("Exported {} publishes from storage {:?}", counts.publishes, path);
+        exported.publishes += counts.publishes;
+        exported.skipped += counts.skipped;
+    }
+    let _ = fs::remove_dir_all(&scratch);
+    out.flush()?;
+
+    Ok(exported)
+}
+
+// Copies segments of storage, i.e. files named backup@{id}, leaving out directories of streams
+fn copy_segments(from: &Path, to: &Path) -> Result<(), Error> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file()
+            && entry.file_name().to_string_lossy().starts_with("backup@")
+        {
+            fs::copy(entry.path(), to.join(entry.file_name()))?;
+        }
+    }
+
+    Ok(())
+}
+
+// Reads all publishes in storage till it is caught up. Remainder of a segment that can't be read,
+// e.g. one that was truncated by a power loss while being written, is skipped.
+fn export_storage<W: Write>(
+    name: &str,
+    storage: &mut Storage,
+    max_packet_size: usize,
+    cipher: Option<&Aes256Gcm>,
+    out: &mut W,
+) -> Result<Exported, Error> {
+    let mut exported = Exported::default();
+    while !storage.reload_on_eof()? {
+        let publish = match read(storage.reader(), max_packet_size) {
+            Ok(Packet::Publish(publish)) => publish,
+            Ok(packet) => {
+                warn!("Skipping unexpected packet in storage {}: {:?}", name, packet);
+                exported.skipped += 1;
+                continue;
+            }
+            Err(e) => {
+                warn!(
+                    "Skipping unreadable remainder of segment in storage {}. Error = {:?}",
+                    name, e
+                );
+                storage.reader().clear();
+                exported.skipped += 1;
+                continue;
+            }
+        };
+
+        let (replay_id, payload) = unstamp(publish.payload);
+        let payload = match decrypt(cipher, payload).and_then(decompress) {
+            Ok(payload) => payload,
+            Err(e) => {
+                warn!(
+                    "Skipping publish on {} that can't be decoded. Error = {:?}",
+                    publish.topic, e
+                );
+                exported.skipped += 1;
+                continue;
+            }
+        };
+
+        let (encoding, payload) = encode(payload);
+        let record = Record {
+            storage: name,
+            topic: publish.topic,
+            qos: publish.qos as u8,
+            replay_id,
+            encoding,
+            payload,
+        };
+        serde_json::to_writer(&mut *out, &record)?;
+        out.write_all(b"\n")?;
+        exported.publishes += 1;
+    }
+
+    Ok(exported)
+}
+
+// Json payloads are exported as is, others as base64
+fn encode(payload: Bytes) -> (&'static str, Value) {
+    match serde_json::from_slice(&payload) {
+        Ok(value) => ("json", value),
+        Err(_) => ("base64", Value::String(base64::encode(&payload))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rumqttc::{Publish, QoS};
+
+    use super::*;
+    use crate::config::Persistence;
+
+    #[test]
+    fn publishes_on_disk_are_exported_without_deleting_them() {
+        let path = "/tmp/uplink_export_test";
+        let _ = fs::remove_dir_all(path);
+        fs::create_dir_all(path).unwrap();
+
+        // Every publish overflows the tiny segment, flushing it onto a file of its own
+        let mut storage = Storage::new(path, 8, 10).unwrap();
+        for (topic, payload) in [("/can", &b"[{\"sequence\":1}]"[..]), ("/imu", &[0xCB, 0x01][..])]
+        {
+            Publish::new(topic, QoS::AtLeastOnce, payload).write(storage.writer()).unwrap();
+            storage.flush_on_overflow().unwrap();
+        }
+
+        let config = Config {
+            max_packet_size: 1024,
+            persistence: Some(Persistence {
+                path: path.to_owned(),
+                max_file_size: 1024,
+                max_file_count: 10,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let mut out = vec![];
+        let exported = export(&config, &mut out).unwrap();
+        assert_eq!(exported, Exported { publishes: 2, skipped: 0 });
+
+        let lines: Vec<Value> = out
+            .split(|&b| b == b'\n')
+            .filter(|l| !l.is_empty())
+            .map(|l| serde_json::from_slice(l).unwrap())
+            .collect();
+        assert_eq!(lines[0]["topic"], "/can");
+        assert_eq!(lines[0]["encoding"], "json");
+        assert_eq!(lines[0]["payload"][0]["sequence"], 1);
+        assert_eq!(lines[1]["encoding"], "base64");
+        assert_eq!(lines[1]["payload"], "ywE=");
+
+        // Storage is left as is, to be sent when uplink next runs
+        assert_eq!(Storage::new(path, 1024, 10).unwrap().segment_count(), 2);
+    }
+}
@@ -0,0 +1,169 @@
+use std::io;
+use std::time::{Duration, Instant};
+
+use log::{debug, error, info};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::{task, time};
+
+use crate::base::serializer::{SerializerState, SharedMetrics};
+use crate::base::Health;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Io error {0}")]
+    Io(#[from] io::Error),
+}
+
+// Requests that aren't read completely within this duration are dropped
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Serves health of uplink over HTTP on `/health`, for liveness and readiness probes of process
+/// supervisors. Responds with `200 OK` while serializer is in normal or catchup mode, or hasn't
+/// been in slow eventloop or crash mode for longer than `degraded_after_secs`, and with
+/// `503 Service Unavailable` otherwise, as well as before serializer starts.
+pub struct HealthCheck {
+    config: Health,
+    metrics: SharedMetrics,
+}
+
+impl HealthCheck {
+    pub fn new(config: Health, metrics: SharedMetrics) -> HealthCheck {
+        HealthCheck { config, metrics }
+    }
+
+    pub async fn start(self) -> Result<(), Error> {
+        let listener = TcpListener::bind((self.config.host.as_str(), self.config.port)).await?;
+        info!("Serving health on {}:{}", self.config.host, self.config.port);
+
+        loop {
+            let (stream, addr) = match listener.accept().await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    error!("Failed to accept health check. Error = {:?}", e);
+                    continue;
+                }
+            };
+
+            let degraded_after = Duration::from_secs(self.config.degraded_after_secs);
+            let metrics = self.metrics.clone();
+            task::spawn(async move {
+                if let Err(e) = respond(stream, &metrics, degraded_after).await {
+                    debug!("Failed to respond to health check from {}. Error = {:?}", addr, e);
+                }
+            });
+        }
+    }
+}
+
+/// Health of uplink, as of `now`, given the current state of serializer and the time since
+/// which it has been in that state. Errors describe why uplink is unhealthy.
+pub fn health(
+    state: Option<(SerializerState, Instant)>,
+    degraded_after: Duration,
+    now: Instant,
+) -> Result<(), String> {
+    let (state, since) = match state {
+        Some(state) => state,
+        None => return Err("serializer hasn't started".to_owned()),
+    };
+
+    let elapsed = now.saturating_duration_since(since);
+    match state {
+        SerializerState::Normal | SerializerState::Catchup => Ok(()),
+        SerializerState::SlowEventloop | SerializerState::Crash if elapsed < degraded_after => {
+            Ok(())
+        }
+        SerializerState::SlowEventloop | SerializerState::Crash => {
+            Err(format!("serializer in {:?} mode for {}s", state, elapsed.as_secs()))
+        }
+    }
+}
+
+// Responds to a single request and closes the connection, only the request line is inspected
+async fn respond<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    metrics: &SharedMetrics,
+    degraded_after: Duration,
+) -> Result<(), Error> {
+    let mut request = Vec::with_capacity(1024);
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < 8192 {
+        let n = match time::timeout(REQUEST_TIMEOUT, stream.read(&mut buf)).await {
+            Ok(n) => n?,
+            Err(_) => return Err(io::Error::from(io::ErrorKind::TimedOut).into()),
+        };
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next().unwrap_or_default().split_whitespace();
+    let (status, body) = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/health")) => {
+            match health(metrics.state(), degraded_after, Instant::now()) {
+                Ok(()) => ("200 OK", "OK\n".to_owned()),
+                Err(e) => ("503 Service Unavailable", format!("Degraded: {}\n", e)),
+            }
+        }
+        _ => ("404 Not Found", String::new()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn degraded_only_when_unable_to_reach_broker_for_long() {
+        let threshold = Duration::from_secs(60);
+        let since = Instant::now();
+        let later = since + Duration::from_secs(61);
+
+        assert!(health(None, threshold, since).is_err());
+        assert!(health(Some((SerializerState::Normal, since)), threshold, later).is_ok());
+        assert!(health(Some((SerializerState::Catchup, since)), threshold, later).is_ok());
+        assert!(health(Some((SerializerState::Crash, since)), threshold, since).is_ok());
+        assert_eq!(
+            health(Some((SerializerState::Crash, since)), threshold, later),
+            Err("serializer in Crash mode for 61s".to_owned())
+        );
+        assert!(health(Some((SerializerState::SlowEventloop, since)), threshold, later).is_err());
+    }
+
+    #[tokio::test]
+    async fn health_is_served_on_health_path() {
+        let metrics = SharedMetrics::new();
+        metrics.set_state(SerializerState::Normal);
+
+        let (mut client, server) = tokio::io::duplex(4096);
+        client.write_all(b"GET /health HTTP/1.1\r\n\r\n").await.unwrap();
+        respond(server, &metrics, Duration::from_secs(60)).await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nOK\n"));
+
+        // Crash mode is degraded right away without a grace period
+        metrics.set_state(SerializerState::Crash);
+        let (mut client, server) = tokio::io::duplex(4096);
+        client.write_all(b"GET /health HTTP/1.1\r\n\r\n").await.unwrap();
+        respond(server, &metrics, Duration::ZERO).await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+    }
+}
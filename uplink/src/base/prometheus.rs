@@ -0,0 +1,212 @@
+use std::fmt::Write;
+use std::io;
+use std::time::Duration;
+
+use log::{debug, error, info};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::{task, time};
+
+use crate::base::serializer::{SerializerState, SharedMetrics};
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Io error {0}")]
+    Io(#[from] io::Error),
+}
+
+// Requests that aren't read completely within this duration are dropped
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Serves the latest serializer metrics over HTTP, in prometheus text format, on `/metrics`
+pub struct Exporter {
+    host: String,
+    port: u16,
+    metrics: SharedMetrics,
+}
+
+impl Exporter {
+    pub fn new(host: String, port: u16, metrics: SharedMetrics) -> Exporter {
+        Exporter { host, port, metrics }
+    }
+
+    pub async fn start(self) -> Result<(), Error> {
+        let listener = TcpListener::bind((self.host.as_str(), self.port)).await?;
+        info!("Serving prometheus metrics on {}:{}", self.host, self.port);
+
+        loop {
+            let (stream, addr) = match listener.accept().await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    error!("Failed to accept prometheus scrape. Error = {:?}", e);
+                    continue;
+                }
+            };
+
+            let metrics = self.metrics.clone();
+            task::spawn(async move {
+                if let Err(e) = respond(stream, &metrics).await {
+                    debug!("Failed to respond to prometheus scrape from {}. Error = {:?}", addr, e);
+                }
+            });
+        }
+    }
+}
+
+// Responds to a single request and closes the connection, only the request line is inspected
+async fn respond<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    metrics: &SharedMetrics,
+) -> Result<(), Error> {
+    let mut request = Vec::with_capacity(1024);
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < 8192 {
+        let n = match time::timeout(REQUEST_TIMEOUT, stream.read(&mut buf)).await {
+            Ok(n) => n?,
+            Err(_) => return Err(io::Error::from(io::ErrorKind::TimedOut).into()),
+        };
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next().unwrap_or_default().split_whitespace();
+    let response = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = render(metrics);
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        }
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_owned(),
+    };
+
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Renders metrics and state of serializer in prometheus text format. Every state is reported,
+/// with the current one set to 1, so that transitions show up in a time series.
+pub fn render(metrics: &SharedMetrics) -> String {
+    let (metrics, state) = metrics.snapshot();
+    let mut body = String::with_capacity(1024);
+
+    let values = [
+        ("total_sent_size", "counter", "Bytes of data sent to broker", metrics.total_sent_size()),
+        ("total_disk_size", "gauge", "Bytes of data pending on disk", metrics.total_disk_size()),
+        (
+            "lost_segments",
+            "gauge",
+            "Disk segments lost in the current metrics interval",
+            metrics.lost_segments(),
+        ),
+        (
+            "dropped_publishes",
+            "gauge",
+            "Publishes of QoS 0 streams dropped in the current metrics interval",
+            metrics.dropped_publishes(),
+        ),
+        (
+            "inflight_depth",
+            "gauge",
+            "Publishes awaiting acknowledgement from broker",
+            metrics.inflight_depth(),
+        ),
+        (
+            "publish_retries",
+            "gauge",
+            "Retries of publishes from disk in the current metrics interval",
+            metrics.publish_retries(),
+        ),
+        ("error_count", "counter", "Errors in data from collectors", metrics.error_count()),
+        (
+            "disk_error_count",
+            "gauge",
+            "Failures to write data onto disk in the current metrics interval",
+            metrics.disk_error_count(),
+        ),
+        (
+            "send_rate",
+            "gauge",
+            "Bytes per second sent to broker in the last metrics interval",
+            metrics.send_rate(),
+        ),
+    ];
+
+    for (name, kind, help, value) in values {
+        let _ = writeln!(body, "# HELP uplink_{} {}", name, help);
+        let _ = writeln!(body, "# TYPE uplink_{} {}", name, kind);
+        let _ = writeln!(body, "uplink_{} {}", name, value);
+    }
+
+    let _ = writeln!(body, "# HELP uplink_serializer_state Current mode of serializer");
+    let _ = writeln!(body, "# TYPE uplink_serializer_state gauge");
+    let states = [
+        (SerializerState::Normal, "normal"),
+        (SerializerState::SlowEventloop, "slow_eventloop"),
+        (SerializerState::Catchup, "catchup"),
+        (SerializerState::Crash, "crash"),
+    ];
+    for (s, label) in states {
+        let current = (state == Some(s)) as u8;
+        let _ = writeln!(body, "uplink_serializer_state{{state=\"{}\"}} {}", label, current);
+    }
+
+    let _ = writeln!(
+        body,
+        "# HELP uplink_serializer_time_in_state_ms Time spent in each mode of serializer in the last metrics interval"
+    );
+    let _ = writeln!(body, "# TYPE uplink_serializer_time_in_state_ms gauge");
+    for (s, label) in states {
+        let time = metrics.time_in_state_ms(s);
+        let _ =
+            writeln!(body, "uplink_serializer_time_in_state_ms{{state=\"{}\"}} {}", label, time);
+    }
+
+    body
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn metrics_and_state_are_rendered() {
+        let metrics = SharedMetrics::new();
+        let body = render(&metrics);
+
+        assert!(body.contains("# TYPE uplink_total_sent_size counter\nuplink_total_sent_size 0\n"));
+        assert!(body.contains("uplink_lost_segments 0\n"));
+        assert!(body.contains("uplink_error_count 0\n"));
+        // No state is reported as current before serializer starts
+        assert!(!body.contains("} 1\n"));
+        assert!(body.contains("uplink_serializer_state{state=\"crash\"} 0\n"));
+        assert!(body.contains("uplink_serializer_time_in_state_ms{state=\"normal\"} 0\n"));
+    }
+
+    #[tokio::test]
+    async fn only_metrics_path_is_served() {
+        let metrics = SharedMetrics::new();
+
+        let (mut client, server) = tokio::io::duplex(4096);
+        client.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+        respond(server, &metrics).await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(&render(&metrics)));
+
+        let (mut client, server) = tokio::io::duplex(4096);
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        respond(server, &metrics).await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
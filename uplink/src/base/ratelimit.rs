@@ -0,0 +1,71 @@
+use std::time::{Duration, Instant};
+
+use crate::base::RateLimit;
+
+/// Token bucket limiting bytes sent over the network to `bytes_per_sec`, allowing bursts of
+/// upto `burst_size` bytes after being idle. A publish larger than the tokens available is
+/// still sent, after a delay, leaving the bucket in debt that later publishes wait on, so that
+/// the rate is held on average irrespective of the size of publishes.
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: f64,
+    capacity: f64,
+    // bytes that can be sent right away, negative while in debt
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimit, now: Instant) -> RateLimiter {
+        let rate = config.bytes_per_sec as f64;
+        let capacity = config.burst_size.unwrap_or(config.bytes_per_sec) as f64;
+        RateLimiter { rate, capacity, tokens: capacity, refilled_at: now }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.refilled_at = now;
+    }
+
+    /// Takes tokens for a publish of size bytes, returning the delay before it is to be sent
+    pub fn charge(&mut self, size: usize, now: Instant) -> Duration {
+        self.refill(now);
+        self.tokens -= size as f64;
+        self.wait(now)
+    }
+
+    /// Time till the bucket is out of debt, i.e. till a publish that was charged can be sent
+    pub fn wait(&self, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.refilled_at).as_secs_f64();
+        let tokens = self.tokens + elapsed * self.rate;
+        match tokens < 0.0 {
+            true => Duration::from_secs_f64(-tokens / self.rate),
+            false => Duration::ZERO,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn publishes_beyond_burst_wait_to_hold_the_rate() {
+        let now = Instant::now();
+        let config = RateLimit { bytes_per_sec: 1000, burst_size: Some(500) };
+        let mut limiter = RateLimiter::new(&config, now);
+
+        // Burst is sent right away, publishes beyond it wait for the debt to be paid off
+        assert_eq!(limiter.charge(500, now), Duration::ZERO);
+        assert_eq!(limiter.charge(250, now), Duration::from_millis(250));
+        assert_eq!(limiter.charge(250, now), Duration::from_millis(500));
+        assert_eq!(limiter.wait(now + Duration::from_millis(250)), Duration::from_millis(250));
+
+        // Bucket refills upto the burst while idle
+        let later = now + Duration::from_secs(10);
+        assert_eq!(limiter.wait(later), Duration::ZERO);
+        assert_eq!(limiter.charge(500, later), Duration::ZERO);
+        assert_eq!(limiter.charge(100, later), Duration::from_millis(100));
+    }
+}
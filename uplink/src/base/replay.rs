@@ -0,0 +1,190 @@
+//! Replay ids number publishes as they are written onto disk, so that those which were delivered
+//! before uplink restarted can be told apart from the rest when storage is read back. Every payload
+//! on disk is prefixed with its id, and the id of the last publish of each topic that was replayed
+//! from disk is recorded. Ids don't depend on what a payload holds, so any stream can be
+//! deduplicated, with `dedup_replay = true` in persistence.
+//!
+//! Ids must keep increasing across restarts. Rather than recording every id issued, ids are
+//! handed out from blocks, and the end of a block is recorded before its first id is issued, so
+//! that a restarted uplink starts from a fresh block.
+//!
+//! A publish counts as replayed as soon as the MQTT client takes it. Recorded ids are written onto
+//! disk only when serializer flushes them, a crash in between replays those publishes again. Ids
+//! recorded by an earlier run are only compared against publishes that run wrote, a topic stops
+//! being checked once a publish with a higher id comes up.
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::{fs, io};
+
+use bytes::Bytes;
+use log::error;
+use serde::{Deserialize, Serialize};
+
+use crate::base::cursor::persist;
+
+// Marks payloads on disk that were stamped with an id, followed by the id as a big endian u64. The
+// marker differs from those of compression and encryption and can't start a payload of any format,
+// see the markers in serializer, so payloads written without an id are read back as is.
+pub(crate) const ID_MARKER: u8 = 0xFD;
+const ID_SIZE: usize = 8;
+// Number of ids reserved at once
+const ID_BLOCK: u64 = 1000;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct State {
+    // ids below which have been issued, possibly by an earlier run
+    reserved: u64,
+    // id of the last publish delivered from disk, for each topic
+    acked: HashMap<String, u64>,
+}
+
+pub struct ReplayIds {
+    // file onto which state is persisted
+    path: PathBuf,
+    // id with which the next publish written onto disk is stamped
+    next: u64,
+    state: State,
+    // acked ids loaded at startup, used to skip replay of already delivered publishes
+    replay: HashMap<String, u64>,
+    // whether acked ids were moved since state was last persisted
+    dirty: bool,
+}
+
+impl ReplayIds {
+    /// Loads ids persisted in `path`, if any, issuing ids from the end of the last reserved block
+    pub fn load(path: PathBuf) -> ReplayIds {
+        let state: State = match fs::read(&path) {
+            Ok(state) => serde_json::from_slice(&state).unwrap_or_else(|e| {
+                error!("Ignoring corrupt replay ids at {:?}. Error = {:?}", path, e);
+                State::default()
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => State::default(),
+            Err(e) => {
+                error!("Failed to read replay ids at {:?}. Error = {:?}", path, e);
+                State::default()
+            }
+        };
+
+        let replay = state.acked.clone();
+        ReplayIds { path, next: state.reserved, state, replay, dirty: false }
+    }
+
+    /// Stamps payload of a publish that is to be written onto disk with the next id
+    pub fn stamp(&mut self, payload: Vec<u8>) -> Vec<u8> {
+        if self.next >= self.state.reserved {
+            // Block is persisted before ids of it are issued, rare enough to not be deferred
+            self.state.reserved = self.next + ID_BLOCK;
+            let state = serde_json::to_vec(&self.state).map_err(io::Error::from);
+            match state.and_then(|state| persist(&self.path, &state)) {
+                Ok(_) => self.dirty = false,
+                Err(e) => {
+                    error!("Failed to persist replay ids at {:?}. Error = {:?}", self.path, e)
+                }
+            }
+        }
+
+        let id = self.next;
+        self.next += 1;
+
+        let mut stamped = Vec::with_capacity(1 + ID_SIZE + payload.len());
+        stamped.push(ID_MARKER);
+        stamped.extend_from_slice(&id.to_be_bytes());
+        stamped.extend_from_slice(&payload);
+        stamped
+    }
+
+    /// Checks if a publish read from storage was delivered before the restart of uplink
+    pub fn delivered(&mut self, topic: &str, id: Option<u64>) -> bool {
+        let (acked, id) = match (self.replay.get(topic), id) {
+            (Some(acked), Some(id)) => (*acked, id),
+            _ => return false,
+        };
+
+        if id <= acked {
+            return true;
+        }
+
+        self.replay.remove(topic);
+        false
+    }
+
+    /// Moves the acked id of topic to that of a publish delivered from disk, persisted on the next flush
+    pub fn ack(&mut self, topic: &str, id: u64) {
+        if self.state.acked.insert(topic.to_owned(), id) != Some(id) {
+            self.dirty = true;
+        }
+    }
+
+    /// Persists acked ids onto disk, if any were moved since state was last persisted
+    pub fn flush(&mut self) -> Result<(), io::Error> {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        persist(&self.path, &serde_json::to_vec(&self.state)?)?;
+        self.dirty = false;
+
+        Ok(())
+    }
+}
+
+/// Splits id of a publish read from disk from its payload, passing through those without an id
+pub fn unstamp(payload: Bytes) -> (Option<u64>, Bytes) {
+    if payload.first() != Some(&ID_MARKER) || payload.len() < 1 + ID_SIZE {
+        return (None, payload);
+    }
+
+    let mut id = [0; ID_SIZE];
+    id.copy_from_slice(&payload[1..1 + ID_SIZE]);
+    (Some(u64::from_be_bytes(id)), payload.slice(1 + ID_SIZE..))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TOPIC: &str = "/devices/1/events/can/jsonarray";
+
+    fn id_of(payload: Vec<u8>) -> u64 {
+        let (id, payload) = unstamp(payload.into());
+        assert_eq!(&payload[..], b"[]");
+        id.unwrap()
+    }
+
+    #[test]
+    fn delivered_publishes_are_skipped_after_restart() {
+        let path = std::env::temp_dir().join("uplink_replay_ids_skip.json");
+        let _ = fs::remove_file(&path);
+
+        let mut ids = ReplayIds::load(path.clone());
+        let stamped: Vec<u64> = (0..3).map(|_| id_of(ids.stamp(b"[]".to_vec()))).collect();
+        assert_eq!(stamped, vec![0, 1, 2]);
+        ids.ack(TOPIC, 1);
+        ids.flush().unwrap();
+        // Acks of the current run don't skip replay
+        assert!(!ids.delivered(TOPIC, Some(0)));
+
+        let mut ids = ReplayIds::load(path.clone());
+        assert!(ids.delivered(TOPIC, Some(0)));
+        assert!(ids.delivered(TOPIC, Some(1)));
+        assert!(!ids.delivered("/devices/1/events/gps/jsonarray", Some(0)));
+        assert!(!ids.delivered(TOPIC, None));
+        assert!(!ids.delivered(TOPIC, Some(2)));
+        // Id of topic is disarmed once replay goes past it
+        assert!(!ids.delivered(TOPIC, Some(1)));
+
+        // Ids issued after a restart are higher than those issued before it
+        assert_eq!(id_of(ids.stamp(b"[]".to_vec())), ID_BLOCK);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn payloads_without_id_are_read_as_is() {
+        let payload = Bytes::from_static(b"[{\"sequence\": 1}]");
+        assert_eq!(unstamp(payload.clone()), (None, payload));
+
+        let truncated = Bytes::from_static(&[ID_MARKER, 0, 1]);
+        assert_eq!(unstamp(truncated.clone()), (None, truncated));
+    }
+}
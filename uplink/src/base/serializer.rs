@@ -1,17 +1,34 @@
-use crate::base::{Buffer, Config, Package};
+use crate::base::aggregate::Aggregator;
+use crate::base::cursor::AckCursors;
+use crate::base::ratelimit::RateLimiter;
+use crate::base::replay::{unstamp, ReplayIds};
+use crate::base::{
+    deserialize_timestamp, dynamic_topic, serialize_timestamp, Buffer, Compression, Config,
+    NetworkAlgorithm, OverflowPolicy, Package, PayloadError, PayloadFormat, Priority, Warmup,
+};
 use crate::{Point, Stream};
 
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
 use bytes::Bytes;
-use disk::Storage;
-use flume::{Receiver, RecvError};
-use log::{error, info};
+use disk::{Compaction, Storage};
+use flate2::write::GzEncoder;
+use flume::{Receiver, RecvError, Sender, TrySendError};
+use log::{debug, error, info, warn};
 use rumqttc::*;
-use serde::Serialize;
-use std::io;
-use std::sync::Arc;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{fs, io};
+use sysinfo::{System, SystemExt};
 use thiserror::Error;
+use tokio::sync::{oneshot, watch, Notify};
 use tokio::{select, time};
+use tracing::Instrument;
 
 #[derive(thiserror::Error, Debug)]
 pub enum MqttError {
@@ -36,22 +53,65 @@ pub enum Error {
     Collector(#[from] RecvError),
     #[error("Serde error {0}")]
     Serde(#[from] serde_json::Error),
+    #[error("Payload error {0}")]
+    Payload(#[from] PayloadError),
     #[error("Io error {0}")]
     Io(#[from] io::Error),
     #[error("Mqtt client error {0}")]
     Client(#[from] MqttError),
     #[error("Storage is disabled/missing")]
     MissingPersistence,
+    #[error("Storage warmup failed {0}")]
+    Warmup(io::Error),
+    #[error("Lz4 decompression error {0}")]
+    Lz4(#[from] lz4_flex::block::DecompressError),
+    #[error("Unknown compression of publish on disk {0:?}")]
+    UnknownCompression(Option<u8>),
+    #[error("Encryption key error {0}")]
+    EncryptionKey(io::Error),
+    #[error("Failed to encrypt publish")]
+    Encryption,
+    #[error("Failed to decrypt publish on disk")]
+    Decryption,
+    #[error("Encrypted publish on disk, but encryption isn't configured")]
+    MissingEncryptionKey,
+    #[error("Unknown encryption of publish on disk {0:?}")]
+    UnknownEncryption(Option<u8>),
 }
 
 #[derive(Debug, PartialEq)]
+// Publishes that couldn't be sent are carried along with the name of their stream, as their topic
+// may be suffixed or templated, to be written onto the storage of that stream
 enum Status {
     Normal,
-    SlowEventloop(Publish),
+    SlowEventloop(Arc<String>, Publish),
     EventLoopReady,
-    EventLoopCrash(Publish),
+    EventLoopCrash(Arc<String>, Publish),
 }
 
+/// Mode of operation of the serializer, notified on every transition between modes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SerializerState {
+    Normal,
+    SlowEventloop,
+    Catchup,
+    Crash,
+}
+
+impl From<&Status> for SerializerState {
+    fn from(status: &Status) -> Self {
+        match status {
+            Status::Normal => SerializerState::Normal,
+            Status::SlowEventloop(..) => SerializerState::SlowEventloop,
+            Status::EventLoopReady => SerializerState::Catchup,
+            Status::EventLoopCrash(..) => SerializerState::Crash,
+        }
+    }
+}
+
+/// Sink of publishes that serializer sends onto the network, implemented by [`AsyncClient`] and
+/// by mock clients in tests, so that slow networks, send failures and recovery can be simulated
+/// deterministically without a broker
 #[async_trait::async_trait]
 pub trait MqttClient: Clone {
     async fn publish<S, V>(
@@ -163,8 +223,34 @@ pub struct Serializer<C: MqttClient> {
     collector_rx: Receiver<Box<dyn Package>>,
     client: C,
     storage: Option<Storage>,
+    // dedicated storages of streams that configure one, data of other streams is shared in `storage`
+    stream_storages: HashMap<String, Storage>,
+    compression: Compression,
+    // encrypts payloads written onto disk, if configured
+    cipher: Option<Aes256Gcm>,
+    cursors: Option<AckCursors>,
+    replay_ids: Option<ReplayIds>,
     metrics: Metrics,
     metrics_stream: Option<Stream<Metrics>>,
+    metrics_rx: Receiver<oneshot::Sender<Metrics>>,
+    // notified of every transition between modes, if set
+    state_tx: Option<Sender<SerializerState>>,
+    // snapshot of metrics and state for local scraping, if set
+    shared_metrics: Option<SharedMetrics>,
+    // pauses collection on the bridge while too much data is pending on disk, if set
+    backpressure_tx: Option<watch::Sender<bool>>,
+    // number of consecutive crashes since serializer was last in normal mode
+    crashes: u32,
+    // hello is published only once per run of uplink
+    hello_sent: bool,
+    // publishes awaiting acknowledgement from broker, if tracked
+    inflight: Option<InflightWindow>,
+    // coalesces data of streams into a single publish in normal mode, if configured
+    aggregator: Option<Aggregator>,
+    // holds bytes sent over the network to the configured rate, if set
+    rate_limiter: Option<RateLimiter>,
+    // configs reloaded while running, of which stream settings and metrics intervals are applied
+    config_updates: watch::Receiver<Arc<Config>>,
 }
 
 impl<C: MqttClient> Serializer<C> {
@@ -172,82 +258,284 @@ impl<C: MqttClient> Serializer<C> {
         config: Arc<Config>,
         collector_rx: Receiver<Box<dyn Package>>,
         metrics_stream: Option<Stream<Metrics>>,
+        metrics_rx: Receiver<oneshot::Sender<Metrics>>,
+        state_tx: Option<Sender<SerializerState>>,
+        shared_metrics: Option<SharedMetrics>,
         client: C,
     ) -> Result<Serializer<C>, Error> {
         let storage = match &config.persistence {
             Some(persistence) => {
-                let storage = Storage::new(
-                    &persistence.path,
+                let path = Path::new(&persistence.path);
+                // Operators look for data buffered on disk by its absolute path
+                info!("Persisting data at {:?}", fs::canonicalize(path).as_deref().unwrap_or(path));
+                create_storage(
+                    path,
                     persistence.max_file_size,
                     persistence.max_file_count,
-                )?;
-                Some(storage)
+                    persistence.max_disk_size,
+                    persistence.warmup,
+                )?
             }
             None => None,
         };
 
+        // Streams only get a dedicated storage alongside the shared one
+        let mut stream_storages = HashMap::new();
+        if let (Some(persistence), Some(_)) = (&config.persistence, &storage) {
+            for (name, stream) in config.streams.iter() {
+                let stream_persistence = match &stream.persistence {
+                    Some(p) => p,
+                    None => continue,
+                };
+
+                let path = Path::new(&persistence.path).join(name);
+                fs::create_dir_all(&path)?;
+                let storage = create_storage(
+                    &path,
+                    stream_persistence.max_file_size,
+                    stream_persistence.max_file_count,
+                    stream_persistence.max_disk_size,
+                    persistence.warmup,
+                )?;
+
+                // Data of stream written before it was given a dedicated storage isn't migrated
+                if let Some(storage) = storage {
+                    info!(
+                        "Dedicated storage for stream {} at {:?}, data of stream written earlier is replayed from shared storage",
+                        name, path
+                    );
+                    stream_storages.insert(name.to_owned(), storage);
+                }
+            }
+        }
+        let compression = config.persistence.as_ref().map(|p| p.compression).unwrap_or_default();
+        // Fails startup when encryption is enabled, but its key isn't available
+        let cipher = cipher(&config)?;
+
+        // Ack cursors are persisted alongside storage, for streams that are flagged
+        let cursors = match (&config.persistence, &storage) {
+            (Some(persistence), Some(_)) => {
+                let topics: HashSet<String> = config
+                    .streams
+                    .iter()
+                    .filter(|(_, stream)| stream.ack_cursor)
+                    .map(|(name, stream)| match &stream.topic {
+                        Some(topic) => topic.to_owned(),
+                        None => dynamic_topic(name, &config.project_id, &config.device_id),
+                    })
+                    .map(|topic| payload_topic(&config, &topic))
+                    .collect();
+
+                match topics.is_empty() {
+                    true => None,
+                    false => {
+                        let path = Path::new(&persistence.path).join("cursors.json");
+                        Some(AckCursors::load(path, topics))
+                    }
+                }
+            }
+            _ => None,
+        };
+
+        // Publishes on disk are stamped with ids, to skip their replay once delivered, if enabled
+        let replay_ids = match (&config.persistence, &storage) {
+            (Some(persistence), Some(_)) if persistence.dedup_replay => {
+                Some(ReplayIds::load(Path::new(&persistence.path).join("replay.json")))
+            }
+            _ => None,
+        };
+
+        let mut metrics = load_metrics(config.metrics_path.as_ref());
+        metrics.set_disk_quota(config.persistence.as_ref().and_then(|p| p.max_disk_size));
+        let policies = config
+            .streams
+            .iter()
+            .filter(|(_, stream)| stream.overflow_policy != OverflowPolicy::DropOldest)
+            .map(|(name, stream)| (name.to_owned(), stream.overflow_policy))
+            .collect();
+        metrics.set_overflow_policies(policies);
+        metrics.set_storage_usage(storage.iter().chain(stream_storages.values()));
+
+        let aggregator = config.aggregation.clone().map(Aggregator::new);
+        let rate_limiter = config.rate_limit.as_ref().map(|r| RateLimiter::new(r, Instant::now()));
+        let (_, config_updates) = watch::channel(config.clone());
+
         Ok(Serializer {
             config,
             collector_rx,
             client,
             storage,
-            metrics: Metrics::new(),
+            stream_storages,
+            compression,
+            cipher,
+            cursors,
+            replay_ids,
+            metrics,
             metrics_stream,
+            metrics_rx,
+            state_tx,
+            shared_metrics,
+            backpressure_tx: None,
+            crashes: 0,
+            hello_sent: false,
+            inflight: None,
+            aggregator,
+            rate_limiter,
+            config_updates,
         })
     }
 
-    /// Write all data received, from here-on, to disk only.
-    async fn crash(&mut self, mut publish: Publish) -> Result<Status, Error> {
-        let storage = match &mut self.storage {
-            Some(s) => s,
-            None => return Err(Error::MissingPersistence),
-        };
+    /// Applies configs reloaded while running, only settings of streams that don't affect their
+    /// storage on disk and intervals of metrics are reloaded, as per `reload()` of config
+    pub fn with_config_updates(
+        mut self,
+        config_updates: watch::Receiver<Arc<Config>>,
+    ) -> Serializer<C> {
+        self.config_updates = config_updates;
+        self
+    }
+
+    /// Notifies whether collection should be paused, as data on disk crossed the high watermark
+    /// of `backpressure`, or resumed, as data on disk was read back below its low watermark
+    pub fn with_backpressure(mut self, backpressure_tx: watch::Sender<bool>) -> Serializer<C> {
+        self.backpressure_tx = Some(backpressure_tx);
+        self
+    }
+
+    /// Holds back publishes in normal mode while `max_inflight` publishes are pending acks, as
+    /// tracked by window, instead of switching to slow mode on a busy but healthy connection
+    pub fn with_inflight(mut self, inflight: InflightWindow) -> Serializer<C> {
+        self.inflight = Some(inflight);
+        self
+    }
+
+    /// Write all data received to disk only, until it is time to retry the network. Retries back
+    /// off exponentially with consecutive crashes, as per `crash_backoff`, with data collected
+    /// in the meantime still written to disk.
+    async fn crash(&mut self, stream: Arc<String>, publish: Publish) -> Result<Status, Error> {
+        self.metrics.set_disk_mode_entered();
+        if self.storage.is_none() {
+            return Err(Error::MissingPersistence);
+        }
+
         // Write failed publish to disk first
+        // Shared storage is present, to which data of streams without a dedicated storage is written
+        let policy = stream_overflow_policy(&self.config, &stream);
+        let storage = storage_for(&mut self.storage, &mut self.stream_storages, &stream).unwrap();
+        let payload = compress(self.compression, publish.payload.to_vec());
+        let payload = encrypt(self.cipher.as_ref(), payload)?;
+        let payload = stamp(&mut self.replay_ids, payload);
+        let payload_size = payload.len();
+        let mut publish = Publish::new(publish.topic, publish.qos, payload);
         publish.pkid = 1;
 
-        if let Err(e) = publish.write(storage.writer()) {
-            error!("Failed to fill write buffer during bad network. Error = {:?}", e);
+        match publish.write(storage.writer()) {
+            Ok(_) => self.metrics.add_total_disk_size(&stream, &publish.topic, payload_size),
+            Err(e) => {
+                error!("Failed to fill write buffer during bad network. Error = {:?}", e);
+                self.metrics.add_disk_error();
+            }
         }
 
-        if let Err(e) = storage.flush_on_overflow() {
-            error!("Failed to flush write buffer to disk during bad network. Error = {:?}", e);
+        match flush_on_overflow(storage, policy) {
+            Ok(discarded) => self.metrics.add_lost_segments(&stream, discarded),
+            Err(e) => {
+                error!("Failed to flush write buffer to disk during bad network. Error = {:?}", e);
+                self.metrics.add_disk_error();
+            }
         }
 
+        let backoff = crash_backoff(self.crashes);
+        info!("Retrying network after {} crashes in {:?}", self.crashes, backoff);
+        let retry = time::sleep(backoff);
+        tokio::pin!(retry);
+
         loop {
-            // Collect next data packet to write to disk
-            let data = self.collector_rx.recv_async().await?;
-            let topic = data.topic();
-            let payload = data.serialize()?;
+            select! {
+                // Data on disk isn't read in crash mode, blocked streams wait for the network
+                data = self.collector_rx.recv_async(), if !self.blocked() => {
+                    // Collect next data packet to write to disk
+                    let data = data?;
+                    for (kind, count) in data.anomaly_counts() {
+                        self.metrics.add_errors(&data.stream(), kind, count);
+                    }
 
-            let mut publish = Publish::new(topic.as_ref(), QoS::AtLeastOnce, payload);
-            publish.pkid = 1;
+                    if stream_qos(&self.config, &data.stream()) == QoS::AtMostOnce {
+                        publish_or_drop(&self.config, &self.client, &mut self.metrics, data)?;
+                        continue;
+                    }
 
-            if let Err(e) = publish.write(storage.writer()) {
-                error!("Failed to fill write buffer during bad network. Error = {:?}", e);
-                continue;
-            }
+                    let policy = stream_overflow_policy(&self.config, &data.stream());
+                    let storage =
+                        storage_for(&mut self.storage, &mut self.stream_storages, &data.stream()).unwrap();
+                    let topic = payload_topic(&self.config, &data.topic());
+                    let qos = stream_qos(&self.config, &data.stream());
+                    let payload =
+                        compress(self.compression, data.serialize_as(self.config.payload_format)?);
+                    let payload = encrypt(self.cipher.as_ref(), payload)?;
+                    let payload = stamp(&mut self.replay_ids, payload);
+                    let payload_size = payload.len();
 
-            if let Err(e) = storage.flush_on_overflow() {
-                error!("Failed to flush write buffer to disk during bad network. Error = {:?}", e);
-                continue;
+                    let mut publish = Publish::new(topic, qos, payload);
+                    publish.pkid = 1;
+
+                    if let Err(e) = publish.write(storage.writer()) {
+                        error!("Failed to fill write buffer during bad network. Error = {:?}", e);
+                        self.metrics.add_disk_error();
+                        continue;
+                    }
+                    self.metrics.add_total_disk_size(&data.stream(), &publish.topic, payload_size);
+                    update_backpressure(&self.config, &self.metrics, &self.backpressure_tx);
+
+                    match flush_on_overflow(storage, policy) {
+                        Ok(discarded) => self.metrics.add_lost_segments(&data.stream(), discarded),
+                        Err(e) => {
+                            error!(
+                                "Failed to flush write buffer to disk during bad network. Error = {:?}",
+                                e
+                            );
+                            self.metrics.add_disk_error();
+                            continue;
+                        }
+                    }
+                }
+                Ok(_) = self.config_updates.changed() => self.update_config(),
+                // Data written to disk, including the failed publish, is sent on catching up
+                _ = &mut retry => return Ok(Status::EventLoopReady),
             }
         }
     }
 
     /// Write new data to disk until back pressure due to slow n/w is resolved
-    async fn slow(&mut self, publish: Publish) -> Result<Status, Error> {
+    async fn slow(&mut self, stream: Arc<String>, publish: Publish) -> Result<Status, Error> {
         info!("Switching to slow eventloop mode!!");
+        self.metrics.set_disk_mode_entered();
 
-        // Note: self.client.publish() is executing code before await point
-        // in publish method every time. Verify this behaviour later
+        let sequence =
+            self.cursors.as_ref().and_then(|c| c.sequence(&publish.topic, &publish.payload));
+        let topic = publish.topic.clone();
+        let qos = publish.qos;
+
+        // Publish was charged to the rate limit in normal mode, it's sent once that is paid off
+        let delay = self.rate_limiter.as_ref().map_or(Duration::ZERO, |l| l.wait(Instant::now()));
+        let published_at = Instant::now() + delay;
+        let Publish { topic: publish_topic, payload, .. } = publish;
+        let client = self.client.clone();
         let publish =
-            self.client.publish(&publish.topic, QoS::AtLeastOnce, false, &publish.payload[..]);
+            send_publish(client, publish_topic, qos, payload, delay, self.config.dry_run, None);
         tokio::pin!(publish);
 
         loop {
             select! {
-                data = self.collector_rx.recv_async() => {
-                    let storage = match &mut self.storage {
+                data = self.collector_rx.recv_async(), if !self.blocked() => {
+                    let data = data?;
+                    if stream_qos(&self.config, &data.stream()) == QoS::AtMostOnce {
+                        publish_or_drop(&self.config, &self.client, &mut self.metrics, data)?;
+                        continue;
+                    }
+
+                    let policy = stream_overflow_policy(&self.config, &data.stream());
+                    let storage = match storage_for(&mut self.storage, &mut self.stream_storages, &data.stream()) {
                         Some(s) => s,
                         None => {
                             error!("Data loss, no disk to handle network backpressure: {:?}", data);
@@ -255,41 +543,60 @@ impl<C: MqttClient> Serializer<C> {
                         }
                     };
 
-                      let data = data?;
-                      if let Some((errors, count)) = data.anomalies() {
-                        self.metrics.add_errors(errors, count);
+                      for (kind, count) in data.anomaly_counts() {
+                        self.metrics.add_errors(&data.stream(), kind, count);
                       }
 
-                      let topic = data.topic();
-                      let payload = data.serialize()?;
+                      let topic = payload_topic(&self.config, &data.topic());
+                      let qos = stream_qos(&self.config, &data.stream());
+                      let payload = compress(self.compression, data.serialize_as(self.config.payload_format)?);
+                      let payload = encrypt(self.cipher.as_ref(), payload)?;
+                      let payload = stamp(&mut self.replay_ids, payload);
                       let payload_size = payload.len();
-                      let mut publish = Publish::new(topic.as_ref(), QoS::AtLeastOnce, payload);
+                      let mut publish = Publish::new(topic, qos, payload);
                       publish.pkid = 1;
 
                       match publish.write(storage.writer()) {
-                           Ok(_) => self.metrics.add_total_disk_size(payload_size),
+                           Ok(_) => {
+                               self.metrics.add_total_disk_size(&data.stream(), &publish.topic, payload_size);
+                               self.metrics.sample_write_buffer_size(storage.writer().len());
+                               update_backpressure(&self.config, &self.metrics, &self.backpressure_tx);
+                           }
                            Err(e) => {
                                error!("Failed to fill disk buffer. Error = {:?}", e);
+                               self.metrics.add_disk_error();
                                continue
                            }
                       }
 
-                      match storage.flush_on_overflow() {
-                            Ok(deleted) => if deleted.is_some() {
-                                self.metrics.increment_lost_segments();
-                            },
+                      match flush_on_overflow(storage, policy) {
+                            Ok(discarded) => {
+                                self.metrics.add_lost_segments(&data.stream(), discarded);
+                                self.metrics.set_storage_usage(self.storage.iter().chain(self.stream_storages.values()));
+                            }
                             Err(e) => {
                                 error!("Failed to flush disk buffer. Error = {:?}", e);
+                                self.metrics.add_disk_error();
                                 continue
                             }
                       }
                 }
+                Ok(tx) = self.metrics_rx.recv_async() => {
+                    let path = self.config.metrics_path.as_ref();
+                    sample_inflight(&self.inflight, &mut self.metrics);
+                    publish_metrics(&mut self.metrics, &mut self.metrics_stream, path, tx).await;
+                }
+                Ok(_) = self.config_updates.changed() => self.update_config(),
                 o = &mut publish => match o {
-                    Ok(_) => return Ok(Status::EventLoopReady),
-                    Err(MqttError::Send(Request::Publish(publish))) =>{
-                        return Ok(Status::EventLoopCrash(publish))
-                    },
-                    Err(e) => unreachable!("Unexpected error: {}", e),
+                    Ok(_) => {
+                        self.add_inflight(qos);
+                        self.metrics.add_publish_latency(published_at.elapsed());
+                        ack(&mut self.cursors, &topic, sequence);
+                        return Ok(Status::EventLoopReady)
+                    }
+                    Err(SendFailure { publish, .. }) => {
+                        return Ok(Status::EventLoopCrash(stream, publish))
+                    }
                 }
             }
         }
@@ -300,99 +607,244 @@ impl<C: MqttClient> Serializer<C> {
     /// `publish` instead of `try publish` to ensure that transient back
     /// pressure due to a lot of data on disk doesn't switch state to
     /// `Status::SlowEventLoop`
+    ///
+    /// Data on disk is replayed in the order it was written, with publishes of all streams
+    /// interleaved as they were received. Data of [`Priority::High`] streams received in the
+    /// meantime jumps this queue and is sent before the next publish is read from disk.
+    /// Dedicated storages of streams are replayed before the storage shared by other streams.
     async fn catchup(&mut self) -> Result<Status, Error> {
-        let storage = match &mut self.storage {
-            Some(s) => s,
-            None => return Ok(Status::Normal),
-        };
+        if self.storage.is_none() {
+            return Ok(Status::Normal);
+        }
         info!("Switching to catchup mode!!");
 
         let max_packet_size = self.config.max_packet_size;
         let client = self.client.clone();
 
-        // Done reading all the pending files
-        if storage.reload_on_eof().unwrap() {
-            return Ok(Status::Normal);
-        }
+        let (stream, topic, qos, payload, id) = loop {
+            // Done reading all the pending files
+            let storage = next_storage(&mut self.storage, &mut self.stream_storages).unwrap();
+            let (dedicated, storage) = match storage {
+                Some(s) => s,
+                None => return Ok(Status::Normal),
+            };
 
-        let publish = match read(storage.reader(), max_packet_size) {
-            Ok(Packet::Publish(publish)) => publish,
-            Ok(packet) => unreachable!("Unexpected packet: {:?}", packet),
-            Err(e) => {
-                error!("Failed to read from storage. Forcing into Normal mode. Error = {:?}", e);
-                return Ok(Status::Normal);
+            let publish = match read(storage.reader(), max_packet_size) {
+                Ok(Packet::Publish(publish)) => publish,
+                Ok(packet) => unreachable!("Unexpected packet: {:?}", packet),
+                Err(e) => {
+                    error!(
+                        "Failed to read from storage. Forcing into Normal mode. Error = {:?}",
+                        e
+                    );
+                    return Ok(Status::Normal);
+                }
+            };
+
+            let (id, payload) = unstamp(publish.payload);
+            let payload = decrypt(self.cipher.as_ref(), payload).and_then(decompress);
+            let payload = match payload {
+                Ok(p) => p,
+                Err(e) => {
+                    error!("Failed to decode publish. Forcing into Normal mode. Error = {:?}", e);
+                    return Ok(Status::Normal);
+                }
+            };
+
+            if delivered(&mut self.cursors, &publish.topic, &payload)
+                || replayed(&mut self.replay_ids, &publish.topic, id)
+            {
+                continue;
             }
+
+            let stream = dedicated.unwrap_or_else(|| self.metrics.stream_of(&publish.topic));
+            break (Arc::new(stream), publish.topic, publish.qos, payload, id);
         };
 
-        let send = send_publish(client, publish.topic, publish.payload);
+        // Topic, sequence and replay id of the publish being sent, to move its ack cursor and
+        // acked replay id on delivery
+        let sequence = self.cursors.as_ref().and_then(|c| c.sequence(&topic, &payload));
+        let mut inflight = (topic.clone(), sequence, id);
+        // Stream of the publish being sent, to write it onto the storage of that stream on a crash
+        let mut sending = stream;
+
+        let (topic, payload) = match network_compress(&self.config, &topic, &payload) {
+            Some((topic, compressed)) => (topic, Bytes::from(compressed)),
+            None => (topic, payload),
+        };
+        let delay = self.throttle(payload.len());
+        let mut sent_at = Instant::now() + delay;
+        let mut sent_qos = qos;
+        let timeout = send_timeout(&self.config);
+        let send = send_publish(client, topic, qos, payload, delay, self.config.dry_run, timeout);
         tokio::pin!(send);
+        // Retries of the publish being sent, on transient failures
+        let mut retries = 0;
+        // Data of high priority streams, pending to be sent ahead of data on disk
+        let mut pending: Pending = VecDeque::new();
+        // Acks of publishes replayed are persisted in batches, rather than one by one
+        let mut ack_flush = time::interval(ACK_FLUSH_INTERVAL);
 
         loop {
             select! {
-                data = self.collector_rx.recv_async() => {
+                data = self.collector_rx.recv_async(), if !self.blocked() => {
                       let data = data?;
-                      if let Some((errors, count)) = data.anomalies() {
-                        self.metrics.add_errors(errors, count);
+                      for (kind, count) in data.anomaly_counts() {
+                        self.metrics.add_errors(&data.stream(), kind, count);
+                      }
+
+                      let stream = data.stream();
+                      let qos = stream_qos(&self.config, &stream);
+                      if qos == QoS::AtMostOnce {
+                          publish_or_drop(&self.config, &self.client, &mut self.metrics, data)?;
+                          continue
+                      }
+
+                      let policy = stream_overflow_policy(&self.config, &stream);
+                      let topic = payload_topic(&self.config, &data.topic());
+                      // Beyond queue size, priority data is written to disk and replayed in order
+                      if stream_priority(&self.config, &stream) == Priority::High
+                          && pending.len() < PRIORITY_QUEUE_SIZE
+                      {
+                          let payload = Bytes::from(data.serialize_as(self.config.payload_format)?);
+                          pending.push_back((stream, topic, qos, payload));
+                          continue
                       }
 
-                      let topic = data.topic();
-                      let payload = data.serialize()?;
+                      let storage = storage_for(&mut self.storage, &mut self.stream_storages, &stream).unwrap();
+
+                      let payload = compress(self.compression, data.serialize_as(self.config.payload_format)?);
+                      let payload = encrypt(self.cipher.as_ref(), payload)?;
+                      let payload = stamp(&mut self.replay_ids, payload);
                       let payload_size = payload.len();
-                      let mut publish = Publish::new(topic.as_ref(), QoS::AtLeastOnce, payload);
+                      let mut publish = Publish::new(topic, qos, payload);
                       publish.pkid = 1;
 
                       match publish.write(storage.writer()) {
-                           Ok(_) => self.metrics.add_total_disk_size(payload_size),
+                           Ok(_) => {
+                               self.metrics.add_total_disk_size(&data.stream(), &publish.topic, payload_size);
+                               self.metrics.sample_write_buffer_size(storage.writer().len());
+                               update_backpressure(&self.config, &self.metrics, &self.backpressure_tx);
+                           }
                            Err(e) => {
                                error!("Failed to fill disk buffer. Error = {:?}", e);
+                               self.metrics.add_disk_error();
                                continue
                            }
                       }
 
-                      match storage.flush_on_overflow() {
-                            Ok(deleted) => if deleted.is_some() {
-                                self.metrics.increment_lost_segments();
-                            },
+                      match flush_on_overflow(storage, policy) {
+                            Ok(discarded) => {
+                                self.metrics.add_lost_segments(&data.stream(), discarded);
+                                self.metrics.set_storage_usage(self.storage.iter().chain(self.stream_storages.values()));
+                            }
                             Err(e) => {
                                 error!("Failed to flush write buffer to disk during catchup. Error = {:?}", e);
+                                self.metrics.add_disk_error();
                                 continue
                             }
                       }
                 }
+                Ok(tx) = self.metrics_rx.recv_async() => {
+                    let path = self.config.metrics_path.as_ref();
+                    sample_inflight(&self.inflight, &mut self.metrics);
+                    publish_metrics(&mut self.metrics, &mut self.metrics_stream, path, tx).await;
+                }
+                Ok(_) = self.config_updates.changed() => self.update_config(),
+                _ = ack_flush.tick(), if self.cursors.is_some() || self.replay_ids.is_some() => self.flush_acks(),
                 o = &mut send => {
-                    // Send failure implies eventloop crash. Switch state to
-                    // indefinitely write to disk to not loose data
                     let client = match o {
                         Ok(c) => c,
-                        Err(MqttError::Send(Request::Publish(publish))) => return Ok(Status::EventLoopCrash(publish)),
-                        Err(e) => unreachable!("Unexpected error: {}", e),
-                    };
+                        // Eventloop is gone when the request can't be sent, while it's only
+                        // busy on transient failures, which are retried within limits
+                        Err(SendFailure { publish, transient }) => {
+                            if let Some(backoff) = retry_backoff(&self.config, retries).filter(|_| transient) {
+                                warn!("Retrying publish on {} in {:?}, retry = {}", publish.topic, backoff, retries + 1);
+                                retries += 1;
+                                self.metrics.add_publish_retries(1);
+                                let Publish { topic, qos, payload, .. } = publish;
+                                send.set(send_publish(self.client.clone(), topic, qos, payload, backoff, self.config.dry_run, timeout));
+                                continue
+                            }
 
-                    match storage.reload_on_eof() {
-                        // Done reading all pending files
-                        Ok(true) => return Ok(Status::Normal),
-                        Ok(false) => {},
-                        Err(e) => {
-                            error!("Failed to reload storage. Forcing into Normal mode. Error = {:?}", e);
-                            return Ok(Status::Normal)
+                            // Send failure implies eventloop crash. Switch state to
+                            // indefinitely write to disk to not loose data
+                            persist_pending(
+                                &self.config,
+                                &mut self.storage,
+                                &mut self.stream_storages,
+                                self.compression,
+                                self.cipher.as_ref(),
+                                &mut self.replay_ids,
+                                &mut pending,
+                                &mut self.metrics,
+                            );
+                            return Ok(Status::EventLoopCrash(sending, publish))
                         }
-                    }
+                    };
+                    retries = 0;
+                    self.add_inflight(sent_qos);
+                    self.metrics.add_publish_latency(sent_at.elapsed());
+                    ack(&mut self.cursors, &inflight.0, inflight.1);
+                    ack_replay(&mut self.replay_ids, &inflight.0, inflight.2);
+
+                    let (stream, topic, qos, payload, id) = match pending.pop_front() {
+                        Some((stream, topic, qos, payload)) => (stream, topic, qos, payload, None),
+                        None => loop {
+                            let (dedicated, storage) = match next_storage(&mut self.storage, &mut self.stream_storages) {
+                                Ok(Some(s)) => s,
+                                // Done reading all pending files
+                                Ok(None) => return Ok(Status::Normal),
+                                Err(e) => {
+                                    error!("Failed to reload storage. Forcing into Normal mode. Error = {:?}", e);
+                                    return Ok(Status::Normal)
+                                }
+                            };
+
+                            let publish = match read(storage.reader(), max_packet_size) {
+                                Ok(Packet::Publish(publish)) => publish,
+                                Ok(packet) => unreachable!("Unexpected packet: {:?}", packet),
+                                Err(e) => {
+                                    error!("Failed to read from storage. Forcing into Normal mode. Error = {:?}", e);
+                                    return Ok(Status::Normal)
+                                }
+                            };
+
+                            self.metrics.sub_total_disk_size(&publish.topic, publish.payload.len());
+                            update_backpressure(&self.config, &self.metrics, &self.backpressure_tx);
+                            let (id, payload) = unstamp(publish.payload);
+                            let payload = decrypt(self.cipher.as_ref(), payload).and_then(decompress);
+                            let payload = match payload {
+                                Ok(p) => p,
+                                Err(e) => {
+                                    error!("Failed to decode publish. Forcing into Normal mode. Error = {:?}", e);
+                                    return Ok(Status::Normal)
+                                }
+                            };
+
+                            if delivered(&mut self.cursors, &publish.topic, &payload) || replayed(&mut self.replay_ids, &publish.topic, id) {
+                                continue;
+                            }
 
-                    let publish = match read(storage.reader(), max_packet_size) {
-                        Ok(Packet::Publish(publish)) => publish,
-                        Ok(packet) => unreachable!("Unexpected packet: {:?}", packet),
-                        Err(e) => {
-                            error!("Failed to read from storage. Forcing into Normal mode. Error = {:?}", e);
-                            return Ok(Status::Normal)
-                        }
+                            let stream = dedicated.unwrap_or_else(|| self.metrics.stream_of(&publish.topic));
+                            break (Arc::new(stream), publish.topic, publish.qos, payload, id);
+                        },
                     };
 
+                    let sequence = self.cursors.as_ref().and_then(|c| c.sequence(&topic, &payload));
+                    inflight = (topic.clone(), sequence, id);
+                    self.metrics.add_total_sent_size(&stream, payload.len());
+                    sending = stream;
 
-                    let payload = publish.payload;
-                    let payload_size = payload.len();
-                    self.metrics.sub_total_disk_size(payload_size);
-                    self.metrics.add_total_sent_size(payload_size);
-                    send.set(send_publish(client, publish.topic, payload));
+                    let (topic, payload) = match network_compress(&self.config, &topic, &payload) {
+                        Some((topic, compressed)) => (topic, Bytes::from(compressed)),
+                        None => (topic, payload),
+                    };
+                    self.metrics.add_total_compressed_size(payload.len());
+                    let delay = self.throttle(payload.len());
+                    sent_at = Instant::now() + delay;
+                    sent_qos = qos;
+                    send.set(send_publish(client, topic, qos, payload, delay, self.config.dry_run, timeout));
                 }
             }
         }
@@ -400,34 +852,93 @@ impl<C: MqttClient> Serializer<C> {
 
     async fn normal(&mut self) -> Result<Status, Error> {
         info!("Switching to normal mode!!");
-        let mut interval = time::interval(time::Duration::from_secs(10));
+        // Data isn't written to disk in normal mode, so collection is never held back
+        if let Some(tx) = self.backpressure_tx.as_ref().filter(|tx| *tx.borrow()) {
+            info!("Resuming collection on bridge");
+            let _ = tx.send(false);
+        }
+        let mut interval =
+            time::interval(time::Duration::from_secs(self.config.metrics_interval_secs));
+        // Peaks are sampled at a finer cadence than metrics are published, to capture bursts
+        let mut sample_interval_ms = self.config.metrics_sample_interval_ms;
+        let mut sample_interval =
+            time::interval(time::Duration::from_millis(sample_interval_ms.unwrap_or(1000)));
+        // Cursors of publishes sent are persisted in batches, to not hit disk on every publish
+        let mut ack_flush = time::interval(ACK_FLUSH_INTERVAL);
 
         loop {
+            // Pending aggregate is published once its window elapses
+            let aggregate_deadline = self.aggregator.as_ref().and_then(Aggregator::deadline);
+            let aggregate_timeout = time::sleep_until(time::Instant::from_std(
+                aggregate_deadline.unwrap_or_else(Instant::now),
+            ));
+
             select! {
                 data = self.collector_rx.recv_async() => {
                     let data = data?;
 
                     // Extract anomalies detected by package during collection
-                    if let Some((errors, count)) = data.anomalies() {
-                        self.metrics.add_errors(errors, count);
+                    for (kind, count) in data.anomaly_counts() {
+                        self.metrics.add_errors(&data.stream(), kind, count);
                     }
 
-                    let topic = data.topic();
-                    let payload = data.serialize()?;
-                    let payload_size = payload.len();
-                    match self.client.try_publish(topic.as_ref(), QoS::AtLeastOnce, false, payload) {
-                        Ok(_) => {
-                            self.metrics.add_total_sent_size(payload_size);
-                            continue;
-                        }
-                        Err(MqttError::TrySend(Request::Publish(publish))) => return Ok(Status::SlowEventloop(publish)),
-                        Err(e) => unreachable!("Unexpected error: {}", e),
+                    // Aggregated data is held back, unless it doesn't fit in the pending aggregate
+                    let data: Box<dyn Package> = match self.aggregator.as_mut() {
+                        Some(aggregator) if aggregator.accepts(&data.stream()) => match aggregator.push(data)? {
+                            Some(aggregate) => Box::new(aggregate),
+                            None => continue,
+                        },
+                        _ => data,
+                    };
+
+                    if let Some(status) = self.try_send(data).await? {
+                        return Ok(status);
                     }
+                }
+                _ = aggregate_timeout, if aggregate_deadline.is_some() => {
+                    let aggregate = match self.aggregator.as_mut().and_then(Aggregator::take) {
+                        Some(aggregate) => aggregate,
+                        None => continue,
+                    };
 
+                    if let Some(status) = self.try_send(Box::new(aggregate)).await? {
+                        return Ok(status);
+                    }
+                }
+                Ok(tx) = self.metrics_rx.recv_async() => {
+                    let path = self.config.metrics_path.as_ref();
+                    sample_inflight(&self.inflight, &mut self.metrics);
+                    publish_metrics(&mut self.metrics, &mut self.metrics_stream, path, tx).await;
+                }
+                _ = ack_flush.tick(), if self.cursors.is_some() || self.replay_ids.is_some() => self.flush_acks(),
+                Ok(_) = self.config_updates.changed() => {
+                    self.update_config();
+                    // Intervals are only restarted when changed, to not hold back metrics
+                    let period = time::Duration::from_secs(self.config.metrics_interval_secs);
+                    if interval.period() != period {
+                        interval = time::interval(period);
+                    }
+                    sample_interval_ms = self.config.metrics_sample_interval_ms;
+                    let period = time::Duration::from_millis(sample_interval_ms.unwrap_or(1000));
+                    if sample_interval.period() != period {
+                        sample_interval = time::interval(period);
+                    }
                 }
-                _ = interval.tick(), if self.metrics_stream.is_some() => {
+                _ = sample_interval.tick(), if sample_interval_ms.is_some() => {
+                    self.metrics.sample_pending_packages(self.collector_rx.len());
+                }
+                _ = interval.tick(), if self.metrics_stream.is_some() || self.shared_metrics.is_some() => {
+                    self.metrics.set_storage_usage(self.storage.iter().chain(self.stream_storages.values()));
+                    sample_inflight(&self.inflight, &mut self.metrics);
+                    if let Some(shared) = &self.shared_metrics {
+                        shared.set_metrics(&self.metrics);
+                    }
+                    let stream = match self.metrics_stream.as_mut() {
+                        Some(stream) => stream,
+                        None => continue,
+                    };
                     let metrics = self.metrics.next();
-                    let stream = self.metrics_stream.as_mut().unwrap();
+                    persist_metrics(self.config.metrics_path.as_ref(), &self.metrics);
                     if let Err(e) = stream.fill(metrics).await {
                         error!("Couldn't write serializer metrics to stream: {}", e)
                     }
@@ -436,12 +947,82 @@ impl<C: MqttClient> Serializer<C> {
         }
     }
 
+    // Publishes data in normal mode, returning the mode to switch to if the eventloop is busy
+    async fn try_send(&mut self, data: Box<dyn Package>) -> Result<Option<Status>, Error> {
+        let topic = payload_topic(&self.config, &data.topic());
+        let qos = stream_qos(&self.config, &data.stream());
+        let payload = data.serialize_as(self.config.payload_format)?;
+        let payload_size = payload.len();
+        let sequence = self.cursors.as_ref().and_then(|c| c.sequence(&topic, &payload));
+
+        // Acks continue to be tracked against topic of the stream, without compression suffix
+        let (publish_topic, payload) = match network_compress(&self.config, &topic, &payload) {
+            Some(compressed) => compressed,
+            None => (topic.clone(), payload),
+        };
+        let compressed_size = payload.len();
+        // Eventloop takes no more requests once the inflight window is full, wait on
+        // acks to free it up rather than switching to slow mode, unless they're late
+        if let (Some(window), false) = (&self.inflight, qos == QoS::AtMostOnce) {
+            if !window.wait(INFLIGHT_TIMEOUT).await {
+                let depth = window.depth();
+                debug!("No acks in {:?} with {} publishes inflight", INFLIGHT_TIMEOUT, depth);
+            }
+        }
+        // Pauses briefly to hold the rate limit, longer waits are spent writing new data onto
+        // disk in slow mode, while data of QoS 0 streams is dropped instead
+        if let Some(limiter) = self.rate_limiter.as_mut() {
+            let now = Instant::now();
+            if limiter.wait(now) > RATE_LIMIT_PAUSE {
+                if qos == QoS::AtMostOnce {
+                    self.metrics.add_dropped_publishes(1);
+                    return Ok(None);
+                }
+                limiter.charge(compressed_size, now);
+                let publish = Publish::new(publish_topic, qos, payload);
+                return Ok(Some(Status::SlowEventloop(data.stream(), publish)));
+            }
+
+            let delay = limiter.charge(compressed_size, now);
+            if !delay.is_zero() {
+                time::sleep(delay).await;
+            }
+        }
+        let published_at = Instant::now();
+        let result = match self.config.dry_run {
+            true => Ok(log_dry_run(&publish_topic, qos, &payload)),
+            false => self.client.try_publish(publish_topic, qos, false, payload),
+        };
+        match result {
+            Ok(_) => {
+                self.add_inflight(qos);
+                self.metrics.add_publish_latency(published_at.elapsed());
+                self.metrics.add_total_sent_size(&data.stream(), payload_size);
+                self.metrics.add_total_compressed_size(compressed_size);
+                ack(&mut self.cursors, &topic, sequence);
+                Ok(None)
+            }
+            // Data of QoS 0 streams is dropped rather than written to disk
+            Err(MqttError::TrySend(Request::Publish(publish)))
+                if publish.qos == QoS::AtMostOnce =>
+            {
+                self.metrics.add_dropped_publishes(1);
+                Ok(None)
+            }
+            Err(MqttError::TrySend(Request::Publish(publish))) => {
+                Ok(Some(Status::SlowEventloop(data.stream(), publish)))
+            }
+            Err(e) => unreachable!("Unexpected error: {}", e),
+        }
+    }
+
     /// The Serializer writes data directly to network in [normal mode] by [`try_publish()`]in on the MQTT client. In case
     /// of the network being slow, this fails and we are forced into [slow mode], where in new data is written into ['Storage']
     /// while consequently we await on a [`publish()`]. If the [`publish()`] succeeds, we move into [catchup mode] or otherwise,
     /// if it fails we move to [crash mode]. In [catchup mode], we continuously write to ['Storage'] while also pushing data
     /// onto network by [`publish()`]. If a [`publish()`] succeds, we load the next [`Publish`] packet from [`storage`], whereas
-    /// if it fails, we transition into [crash mode] where we merely write all data received, directly into disk.
+    /// if it fails, we transition into [crash mode] where we merely write all data received, directly into disk. The network
+    /// is retried from [crash mode] by moving back into [catchup mode], after a backoff that grows with consecutive crashes.
     ///
     /// [`try_publish()`]: AsyncClient::try_publish
     /// [`publish()`]: AsyncClient::publish
@@ -450,138 +1031,1306 @@ impl<C: MqttClient> Serializer<C> {
     /// [slow mode]: Serializer::slow
     /// [crash mode]: Serializer::crash
     pub async fn start(mut self) -> Result<(), Error> {
+        // Logs of serializer carry the mode it was in, when logged as json
+        let span = tracing::info_span!("serializer", state = tracing::field::Empty);
+        let result = self.run().instrument(span).await;
+
+        // Serializer only stops once all collectors are dropped, persist metrics before exiting
+        self.flush_acks();
+        persist_metrics(self.config.metrics_path.as_ref(), &self.metrics);
+        result
+    }
+
+    async fn run(&mut self) -> Result<(), Error> {
         let mut status = Status::EventLoopReady;
+        let mut state = None;
 
         loop {
+            let next_state = SerializerState::from(&status);
+            if state != Some(next_state) {
+                self.metrics.enter_state(next_state, Instant::now());
+                self.notify_state(next_state);
+                state = Some(next_state);
+                if let SerializerState::Normal | SerializerState::Catchup = next_state {
+                    self.say_hello();
+                }
+            }
+
             let next_status = match status {
-                Status::Normal => self.normal().await?,
-                Status::SlowEventloop(publish) => self.slow(publish).await?,
-                Status::EventLoopReady => self.catchup().await?,
-                Status::EventLoopCrash(publish) => self.crash(publish).await?,
+                Status::Normal => {
+                    self.crashes = 0;
+                    let status = self.normal().await?;
+                    self.snapshot_metrics();
+                    status
+                }
+                Status::SlowEventloop(stream, publish) => self.slow(stream, publish).await?,
+                Status::EventLoopReady => {
+                    let status = self.catchup().await?;
+                    if status == Status::Normal {
+                        compact_storage(&mut self.storage, &mut self.stream_storages);
+                    }
+                    status
+                }
+                Status::EventLoopCrash(stream, publish) => {
+                    self.crashes = self.crashes.saturating_add(1);
+                    self.crash(stream, publish).await?
+                }
             };
+            self.flush_acks();
 
             status = next_status;
         }
     }
-}
 
-async fn send_publish<C: MqttClient>(
-    client: C,
-    topic: String,
-    payload: Bytes,
-) -> Result<C, MqttError> {
-    client.publish_bytes(topic, QoS::AtLeastOnce, false, payload).await?;
-    Ok(client)
-}
+    // Persists ack cursors and replay ids moved since they were last flushed, every
+    // ACK_FLUSH_INTERVAL and on every change of mode, rather than on every publish
+    fn flush_acks(&mut self) {
+        if let Some(cursors) = &mut self.cursors {
+            if let Err(e) = cursors.flush() {
+                error!("Failed to persist ack cursors. Error = {:?}", e);
+            }
+        }
+        if let Some(replay_ids) = &mut self.replay_ids {
+            if let Err(e) = replay_ids.flush() {
+                error!("Failed to persist replay ids. Error = {:?}", e);
+            }
+        }
+    }
 
-#[derive(Debug, Default, Serialize, Clone)]
-pub struct Metrics {
-    sequence: u32,
-    timestamp: u64,
-    total_sent_size: usize,
-    total_disk_size: usize,
-    lost_segments: usize,
-    errors: String,
-    error_count: usize,
-}
+    // Writes a snapshot of metrics onto disk on leaving normal mode, as they are otherwise only
+    // published every interval in normal mode. Metrics of the interval in which the network
+    // failed are then replayed ahead of data written after, rather than held back till recovery.
+    fn snapshot_metrics(&mut self) {
+        let qos = stream_qos(&self.config, "metrics");
+        let stream = match self.metrics_stream.as_mut() {
+            Some(stream) if qos != QoS::AtMostOnce => stream,
+            _ => return,
+        };
+        // Metrics of the interval are only taken once there's a storage to write them onto
+        if storage_for(&mut self.storage, &mut self.stream_storages, "metrics").is_none() {
+            return;
+        }
 
-impl Metrics {
-    pub fn new() -> Metrics {
-        Metrics { errors: String::with_capacity(1024), ..Default::default() }
-    }
+        sample_inflight(&self.inflight, &mut self.metrics);
+        let metrics = self.metrics.next();
+        persist_metrics(self.config.metrics_path.as_ref(), &self.metrics);
+        let data = stream.take(metrics);
+        let payload = match data.serialize_as(self.config.payload_format) {
+            Ok(payload) => payload,
+            Err(e) => {
+                error!("Failed to serialize metrics snapshot. Error = {:?}", e);
+                return;
+            }
+        };
 
-    pub fn add_total_sent_size(&mut self, size: usize) {
-        self.total_sent_size = self.total_sent_size.saturating_add(size);
+        let topic = payload_topic(&self.config, &data.topic());
+        let mut pending = Pending::from([(data.stream(), topic, qos, Bytes::from(payload))]);
+        persist_pending(
+            &self.config,
+            &mut self.storage,
+            &mut self.stream_storages,
+            self.compression,
+            self.cipher.as_ref(),
+            &mut self.replay_ids,
+            &mut pending,
+            &mut self.metrics,
+        );
     }
 
-    pub fn add_total_disk_size(&mut self, size: usize) {
-        self.total_disk_size = self.total_disk_size.saturating_add(size);
+    // Publishes of QoS 1 and 2 occupy the inflight window until acknowledged, publishes that
+    // weren't sent in dry run mode are never acknowledged
+    fn add_inflight(&self, qos: QoS) {
+        if self.config.dry_run {
+            return;
+        }
+        if let (Some(window), QoS::AtLeastOnce | QoS::ExactlyOnce) = (&self.inflight, qos) {
+            window.add();
+        }
     }
 
-    pub fn sub_total_disk_size(&mut self, size: usize) {
-        self.total_disk_size = self.total_disk_size.saturating_sub(size);
+    // Delay before sending a publish of size bytes, to hold the rate limit, if configured
+    fn throttle(&mut self, size: usize) -> Duration {
+        match self.rate_limiter.as_mut() {
+            Some(limiter) => limiter.charge(size, Instant::now()),
+            None => Duration::ZERO,
+        }
     }
 
-    pub fn increment_lost_segments(&mut self) {
-        self.lost_segments += 1;
+    // Checks if storage of any stream with the block overflow policy is full, in which case new
+    // data isn't accepted until data on disk is read to make space
+    fn blocked(&self) -> bool {
+        self.config
+            .streams
+            .iter()
+            .filter(|(_, stream)| stream.overflow_policy == OverflowPolicy::Block)
+            .filter_map(|(name, _)| self.stream_storages.get(name).or(self.storage.as_ref()))
+            .any(|storage| storage.is_full())
     }
 
-    // pub fn add_error<S: Into<String>>(&mut self, error: S) {
-    //     self.error_count += 1;
-    //     if self.errors.len() > 1024 {
-    //         return;
-    //     }
-    //
-    //     self.errors.push_str(", ");
-    //     self.errors.push_str(&error.into());
-    // }
+    // Publishes hello, if configured and not already published by this run of uplink. A hello
+    // that couldn't be handed to the eventloop is retried on the next transition into normal or
+    // catchup mode, so that switching between modes within a session doesn't publish it again.
+    fn say_hello(&mut self) {
+        let hello = match &self.config.hello {
+            Some(hello) if !self.hello_sent => hello,
+            _ => return,
+        };
 
-    pub fn add_errors<S: Into<String>>(&mut self, error: S, count: usize) {
-        self.error_count += count;
-        if self.errors.len() > 1024 {
-            return;
-        }
+        let payload = match serde_json::to_vec(&HelloMessage::new(&self.config)) {
+            Ok(payload) => payload,
+            Err(e) => {
+                error!("Failed to serialize hello. Error = {:?}", e);
+                return;
+            }
+        };
 
-        self.errors.push_str(&error.into());
-        self.errors.push_str(" | ");
+        let qos = match hello.qos {
+            0 => QoS::AtMostOnce,
+            2 => QoS::ExactlyOnce,
+            _ => QoS::AtLeastOnce,
+        };
+        let result = match self.config.dry_run {
+            true => Ok(log_dry_run(&hello.topic, qos, &payload)),
+            false => self.client.try_publish(&hello.topic, qos, false, payload),
+        };
+        match result {
+            Ok(_) => {
+                info!("Published hello onto {}", hello.topic);
+                self.hello_sent = true;
+            }
+            Err(e) => warn!("Failed to publish hello, retrying later. Error = {:?}", e),
+        }
     }
 
-    pub fn next(&mut self) -> Metrics {
-        let timestamp =
-            SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
-        self.timestamp = timestamp.as_millis() as u64;
-        self.sequence += 1;
+    // Replaces running config with the one reloaded last
+    fn update_config(&mut self) {
+        self.config = self.config_updates.borrow().clone();
+        info!("Reloaded config, applies to data serialized from here on");
+    }
 
-        let metrics = self.clone();
+    // Notifies transition into state, without blocking on a slow or absent receiver
+    fn notify_state(&self, state: SerializerState) {
+        tracing::Span::current().record("state", tracing::field::debug(state));
+        if let Some(shared) = &self.shared_metrics {
+            shared.set_metrics(&self.metrics);
+            shared.set_state(state);
+        }
 
-        self.errors.clear();
-        self.lost_segments = 0;
+        let state_tx = match &self.state_tx {
+            Some(tx) => tx,
+            None => return,
+        };
 
-        metrics
+        match state_tx.try_send(state) {
+            Ok(_) => {}
+            Err(TrySendError::Full(_)) => {
+                debug!("Serializer state receiver is lagging, dropping {:?}", state)
+            }
+            Err(TrySendError::Disconnected(_)) => debug!("Serializer state receiver dropped"),
+        }
     }
 }
 
-impl Point for Metrics {
-    fn sequence(&self) -> u32 {
-        self.sequence
+// Publishes metrics out of band, on request of the `publish_metrics` action, responding with the
+// snapshot that was published. Metrics continue to be published at the regular interval as well.
+async fn publish_metrics(
+    metrics: &mut Metrics,
+    stream: &mut Option<Stream<Metrics>>,
+    path: Option<&String>,
+    tx: oneshot::Sender<Metrics>,
+) {
+    let metrics = {
+        let snapshot = metrics.next();
+        persist_metrics(path, metrics);
+        snapshot
+    };
+    if let Some(stream) = stream {
+        if let Err(e) = stream.fill(metrics.clone()).await {
+            error!("Couldn't write serializer metrics to stream: {}", e)
+        }
+
+        if let Err(e) = stream.flush().await {
+            error!("Couldn't flush serializer metrics stream: {}", e)
+        }
     }
 
-    fn timestamp(&self) -> u64 {
-        self.timestamp
+    if tx.send(metrics).is_err() {
+        error!("Metrics requested by publish_metrics action were dropped");
     }
 }
 
-impl Package for Buffer<Metrics> {
-    fn topic(&self) -> Arc<String> {
-        self.topic.clone()
+/// Published once per run of uplink, on first getting through to the broker
+#[derive(Debug, Serialize)]
+struct HelloMessage<'a> {
+    device_id: &'a str,
+    version: &'static str,
+    commit: &'static str,
+    config_hash: &'a str,
+    // time at which the device booted, in milliseconds since epoch
+    #[serde(serialize_with = "serialize_timestamp")]
+    boot_time: u64,
+    #[serde(serialize_with = "serialize_timestamp")]
+    timestamp: u64,
+}
+
+impl<'a> HelloMessage<'a> {
+    fn new(config: &'a Config) -> HelloMessage<'a> {
+        let timestamp =
+            SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+        HelloMessage {
+            device_id: &config.device_id,
+            version: env!("CARGO_PKG_VERSION"),
+            commit: env!("VERGEN_GIT_SHA"),
+            config_hash: &config.config_hash,
+            boot_time: System::new().boot_time() * 1000,
+            timestamp: timestamp.as_millis() as u64,
+        }
     }
+}
 
-    fn serialize(&self) -> serde_json::Result<Vec<u8>> {
-        serde_json::to_vec(&self.buffer)
+// Errors were persisted as text by earlier versions of uplink, these are dropped on load
+fn deserialize_errors<'de, D>(deserializer: D) -> Result<BTreeMap<String, usize>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Errors {
+        Counts(BTreeMap<String, usize>),
+        Text(String),
     }
 
-    fn anomalies(&self) -> Option<(String, usize)> {
-        self.anomalies()
+    match Errors::deserialize(deserializer)? {
+        Errors::Counts(errors) => Ok(errors),
+        Errors::Text(_) => Ok(BTreeMap::new()),
     }
 }
 
-#[cfg(test)]
-mod test {
-    use serde_json::Value;
+// Metrics of streams are published as an array of objects, each of which carries name of its stream
+fn serialize_streams<S: Serializer>(
+    streams: &BTreeMap<String, StreamMetrics>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(streams.values())
+}
 
-    use super::*;
-    use crate::{base::Stream, config::Persistence, Payload};
-    use std::collections::HashMap;
+fn deserialize_streams<'de, D>(deserializer: D) -> Result<BTreeMap<String, StreamMetrics>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let streams = Vec::<StreamMetrics>::deserialize(deserializer)?;
+    Ok(streams.into_iter().map(|metrics| (metrics.stream.clone(), metrics)).collect())
+}
 
-    #[derive(Clone)]
-    pub struct MockClient {
-        pub net_tx: flume::Sender<Request>,
+// Loads metrics persisted by an earlier run, starting afresh if they are missing or corrupt
+fn load_metrics(path: Option<&String>) -> Metrics {
+    let path = match path {
+        Some(path) => path,
+        None => return Metrics::new(),
+    };
+
+    let metrics = match fs::read(path) {
+        Ok(metrics) => metrics,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            info!("No persisted metrics at {}, starting afresh", path);
+            return Metrics::new();
+        }
+        Err(e) => {
+            warn!("Failed to read persisted metrics at {}, starting afresh. Error = {:?}", path, e);
+            return Metrics::new();
+        }
+    };
+
+    match serde_json::from_slice::<Metrics>(&metrics) {
+        Ok(metrics) => metrics,
+        Err(e) => {
+            warn!("Corrupt persisted metrics at {}, starting afresh. Error = {:?}", path, e);
+            Metrics::new()
+        }
     }
+}
 
-    const PERSIST_FOLDER: &str = "/tmp/uplink_test";
+// Persists metrics onto disk, so that counters and sequence carry over restarts
+fn persist_metrics(path: Option<&String>, metrics: &Metrics) {
+    let path = match path {
+        Some(path) => path,
+        None => return,
+    };
+
+    // Write and rename to ensure a crash never leaves a partially written file behind
+    let temp = path.to_owned() + ".tmp";
+    let result = serde_json::to_vec(metrics)
+        .map_err(io::Error::from)
+        .and_then(|metrics| fs::write(&temp, metrics))
+        .and_then(|_| fs::rename(&temp, path));
+
+    if let Err(e) = result {
+        error!("Failed to persist metrics at {}. Error = {:?}", path, e);
+    }
+}
 
-    #[async_trait::async_trait]
-    impl MqttClient for MockClient {
+// Checks if a publish read from storage was delivered before uplink restarted
+fn delivered(cursors: &mut Option<AckCursors>, topic: &str, payload: &[u8]) -> bool {
+    match cursors {
+        Some(cursors) if cursors.delivered(topic, payload) => {
+            debug!("Skipping replay of publish on {}, delivered before restart", topic);
+            true
+        }
+        _ => false,
+    }
+}
+
+// Checks if a publish read from disk was delivered before restart, as per its replay id
+fn replayed(replay_ids: &mut Option<ReplayIds>, topic: &str, id: Option<u64>) -> bool {
+    match replay_ids {
+        Some(replay_ids) if replay_ids.delivered(topic, id) => {
+            debug!("Skipping replay of publish {:?} on {}, delivered before restart", id, topic);
+            true
+        }
+        _ => false,
+    }
+}
+
+// Topic onto which data of a stream is published, marked with the format of its payload. Data on
+// disk and ack cursors use the marked topic as well, so that the backend can decode publishes that
+// were written before the format was changed.
+fn payload_topic(config: &Config, topic: &str) -> String {
+    topic.to_owned() + config.payload_format.topic_suffix()
+}
+
+fn sample_inflight(inflight: &Option<InflightWindow>, metrics: &mut Metrics) {
+    if let Some(window) = inflight {
+        metrics.set_inflight_depth(window.depth());
+    }
+}
+
+// Data of QoS 0 streams isn't worth the cost of writing to disk, it's handed to the eventloop if
+// it has room, in any mode, and dropped otherwise
+fn publish_or_drop<C: MqttClient>(
+    config: &Config,
+    client: &C,
+    metrics: &mut Metrics,
+    data: Box<dyn Package>,
+) -> Result<(), Error> {
+    for (kind, count) in data.anomaly_counts() {
+        metrics.add_errors(&data.stream(), kind, count);
+    }
+
+    let topic = payload_topic(config, &data.topic());
+    let payload = data.serialize_as(config.payload_format)?;
+    let payload_size = payload.len();
+    let (topic, payload) = match network_compress(config, &topic, &payload) {
+        Some(compressed) => compressed,
+        None => (topic, payload),
+    };
+    let compressed_size = payload.len();
+    let result = match config.dry_run {
+        true => Ok(log_dry_run(&topic, QoS::AtMostOnce, &payload)),
+        false => client.try_publish(topic, QoS::AtMostOnce, false, payload),
+    };
+    match result {
+        Ok(_) => {
+            metrics.add_total_sent_size(&data.stream(), payload_size);
+            metrics.add_total_compressed_size(compressed_size);
+        }
+        Err(_) => metrics.add_dropped_publishes(1),
+    }
+
+    Ok(())
+}
+
+// Pauses collection on the bridge once data on disk crosses the high watermark, resuming it once
+// data on disk is read back below the low watermark
+fn update_backpressure(config: &Config, metrics: &Metrics, tx: &Option<watch::Sender<bool>>) {
+    let (backpressure, tx) = match (&config.backpressure, tx) {
+        (Some(backpressure), Some(tx)) => (backpressure, tx),
+        _ => return,
+    };
+
+    let size = metrics.total_disk_size();
+    let paused = *tx.borrow();
+    if !paused && size >= backpressure.high_watermark {
+        warn!("{} bytes of data on disk, pausing collection on bridge", size);
+        let _ = tx.send(true);
+    } else if paused && size < backpressure.low_watermark {
+        info!("{} bytes of data on disk, resuming collection on bridge", size);
+        let _ = tx.send(false);
+    }
+}
+
+// Moves the ack cursor of topic on delivery of a publish, if the topic is tracked
+fn ack(cursors: &mut Option<AckCursors>, topic: &str, sequence: Option<u32>) {
+    if let (Some(cursors), Some(sequence)) = (cursors, sequence) {
+        cursors.ack(topic, sequence);
+    }
+}
+
+// Moves the acked replay id of topic on delivery of a publish read from disk, if ids are enabled
+fn ack_replay(replay_ids: &mut Option<ReplayIds>, topic: &str, id: Option<u64>) {
+    if let (Some(replay_ids), Some(id)) = (replay_ids, id) {
+        replay_ids.ack(topic, id);
+    }
+}
+
+// Stamps payload of a publish that is to be written onto disk with a replay id, if enabled
+fn stamp(replay_ids: &mut Option<ReplayIds>, payload: Vec<u8>) -> Vec<u8> {
+    match replay_ids {
+        Some(replay_ids) => replay_ids.stamp(payload),
+        None => payload,
+    }
+}
+
+// Compressed payloads on disk are prefixed with this marker and the id of the algorithm used.
+// Payloads written onto disk are a top-level array of data points, or a map of streams for
+// aggregates, in any of the payload formats. These can't start with the markers 0xFD..=0xFF:
+// json starts with "[" or "{", as the markers never occur in UTF-8, CBOR arrays and maps start
+// with a byte in 0x80..=0xBF, and MessagePack ones with a byte in 0x80..=0x9F or 0xDC..=0xDF.
+// So payloads that were written uncompressed, e.g. before compression was enabled, are still
+// read back as is.
+const COMPRESSION_MARKER: u8 = 0xFF;
+const LZ4: u8 = 1;
+const ZSTD: u8 = 2;
+
+// Marks payloads on disk that were encrypted, followed by the version of encryption used
+const ENCRYPTION_MARKER: u8 = 0xFE;
+const AES_256_GCM: u8 = 1;
+const NONCE_SIZE: usize = 12;
+
+// Compresses payload of a publish that is to be written onto disk
+fn compress(compression: Compression, payload: Vec<u8>) -> Vec<u8> {
+    let (id, compressed) = match compression {
+        Compression::None => return payload,
+        Compression::Lz4 => (LZ4, lz4_flex::compress_prepend_size(&payload)),
+        Compression::Zstd => match zstd::stream::encode_all(&payload[..], 0) {
+            Ok(compressed) => (ZSTD, compressed),
+            Err(e) => {
+                error!("Failed to compress publish, writing uncompressed. Error = {:?}", e);
+                return payload;
+            }
+        },
+    };
+
+    let mut marked = Vec::with_capacity(compressed.len() + 2);
+    marked.push(COMPRESSION_MARKER);
+    marked.push(id);
+    marked.extend_from_slice(&compressed);
+    marked
+}
+
+// Decompresses payload of a publish that was read from disk, as marked while writing
+pub(crate) fn decompress(payload: Bytes) -> Result<Bytes, Error> {
+    if payload.first() != Some(&COMPRESSION_MARKER) {
+        return Ok(payload);
+    }
+
+    match payload.get(1) {
+        Some(&LZ4) => Ok(lz4_flex::decompress_size_prepended(&payload[2..])?.into()),
+        Some(&ZSTD) => Ok(zstd::stream::decode_all(&payload[2..])?.into()),
+        id => Err(Error::UnknownCompression(id.copied())),
+    }
+}
+
+// Encrypts payload of a publish that is to be written onto disk, prefixed with a marker, the version
+// of encryption and the random nonce it was encrypted with. Nonces are unique to every publish.
+fn encrypt(cipher: Option<&Aes256Gcm>, payload: Vec<u8>) -> Result<Vec<u8>, Error> {
+    let cipher = match cipher {
+        Some(cipher) => cipher,
+        None => return Ok(payload),
+    };
+
+    let nonce: [u8; NONCE_SIZE] = rand::random();
+    let encrypted =
+        cipher.encrypt(Nonce::from_slice(&nonce), &payload[..]).map_err(|_| Error::Encryption)?;
+
+    let mut payload = Vec::with_capacity(2 + NONCE_SIZE + encrypted.len());
+    payload.extend_from_slice(&[ENCRYPTION_MARKER, AES_256_GCM]);
+    payload.extend_from_slice(&nonce);
+    payload.extend_from_slice(&encrypted);
+    Ok(payload)
+}
+
+// Cipher with which publishes on disk are encrypted, if encryption is configured
+pub(crate) fn cipher(config: &Config) -> Result<Option<Aes256Gcm>, Error> {
+    match config.persistence.as_ref().and_then(|p| p.encryption.as_ref()) {
+        Some(encryption) => {
+            let key = encryption.key().map_err(Error::EncryptionKey)?;
+            Ok(Some(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key))))
+        }
+        None => Ok(None),
+    }
+}
+
+// Decrypts payload of a publish read from disk, passing through those written without encryption
+pub(crate) fn decrypt(cipher: Option<&Aes256Gcm>, payload: Bytes) -> Result<Bytes, Error> {
+    if payload.first() != Some(&ENCRYPTION_MARKER) {
+        return Ok(payload);
+    }
+
+    let cipher = cipher.ok_or(Error::MissingEncryptionKey)?;
+    match payload.get(1) {
+        Some(&AES_256_GCM) if payload.len() >= 2 + NONCE_SIZE => {
+            let nonce = Nonce::from_slice(&payload[2..2 + NONCE_SIZE]);
+            let decrypted =
+                cipher.decrypt(nonce, &payload[2 + NONCE_SIZE..]).map_err(|_| Error::Decryption)?;
+            Ok(decrypted.into())
+        }
+        Some(&AES_256_GCM) => Err(Error::Decryption),
+        version => Err(Error::UnknownEncryption(version.copied())),
+    }
+}
+
+// Compresses payload of a publish that is to be sent over network, suffixing its topic for the backend
+// to decode it. Returns None if compression is disabled or if the publish was already compressed, as
+// would be the case for a publish that failed to be sent and was written onto disk by crash mode.
+fn network_compress(config: &Config, topic: &str, payload: &[u8]) -> Option<(String, Vec<u8>)> {
+    let compression = config.network_compression.as_ref()?;
+    if topic.ends_with(&compression.topic_suffix) {
+        return None;
+    }
+
+    let compressed = match compression.algorithm {
+        NetworkAlgorithm::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(payload).and_then(|_| encoder.finish())
+        }
+        NetworkAlgorithm::Lz4 => {
+            let mut encoder = lz4_flex::frame::FrameEncoder::new(Vec::new());
+            encoder
+                .write_all(payload)
+                .and_then(|_| encoder.finish().map_err(|e| io::Error::new(io::ErrorKind::Other, e)))
+        }
+    };
+
+    match compressed {
+        Ok(compressed) => Some((topic.to_owned() + &compression.topic_suffix, compressed)),
+        Err(e) => {
+            error!("Failed to compress publish, sending uncompressed. Error = {:?}", e);
+            None
+        }
+    }
+}
+
+// Exponential backoff before retrying network after consecutive crashes, capped at 30s
+fn crash_backoff(crashes: u32) -> Duration {
+    let secs = 2u64.saturating_pow(crashes.saturating_sub(1));
+    Duration::from_secs(secs.min(30))
+}
+
+// QoS with which data of a stream is published, defaults to QoS 1 for unconfigured streams
+fn stream_qos(config: &Config, stream: &str) -> QoS {
+    match config.streams.get(stream).map(|stream| stream.qos) {
+        Some(0) => QoS::AtMostOnce,
+        Some(2) => QoS::ExactlyOnce,
+        _ => QoS::AtLeastOnce,
+    }
+}
+
+// Creates storage on disk, verifying it at startup as configured by warmup
+fn create_storage(
+    path: &Path,
+    max_file_size: usize,
+    max_file_count: usize,
+    max_disk_size: Option<usize>,
+    warmup: Warmup,
+) -> Result<Option<Storage>, Error> {
+    let mut storage = Storage::new(path, max_file_size, max_file_count)?;
+    if let Some(max_disk_size) = max_disk_size {
+        storage.set_max_disk_size(max_disk_size);
+    }
+
+    match warmup {
+        Warmup::Disabled => Ok(Some(storage)),
+        warmup => match storage.warmup() {
+            Ok(_) => Ok(Some(storage)),
+            Err(e) if warmup == Warmup::Fallback => {
+                error!("Storage warmup failed, continuing without persistence. Error = {:?}", e);
+                Ok(None)
+            }
+            Err(e) => Err(Error::Warmup(e)),
+        },
+    }
+}
+
+// Storage into which data of stream is written, its dedicated storage if one is configured
+fn storage_for<'a>(
+    storage: &'a mut Option<Storage>,
+    stream_storages: &'a mut HashMap<String, Storage>,
+    stream: &str,
+) -> Option<&'a mut Storage> {
+    match stream_storages.get_mut(stream) {
+        Some(storage) => Some(storage),
+        None => storage.as_mut(),
+    }
+}
+
+// Storage with data pending to be read, along with the stream it is dedicated to, dedicated
+// storages of streams are read before the shared one. Returns None once data in all of them
+// has been read.
+fn next_storage<'a>(
+    storage: &'a mut Option<Storage>,
+    stream_storages: &'a mut HashMap<String, Storage>,
+) -> io::Result<Option<(Option<String>, &'a mut Storage)>> {
+    let dedicated = stream_storages.iter_mut().map(|(stream, s)| (Some(stream.clone()), s));
+    for (stream, s) in dedicated.chain(storage.as_mut().map(|s| (None, s))) {
+        if !s.reload_on_eof()? {
+            return Ok(Some((stream, s)));
+        }
+    }
+
+    Ok(None)
+}
+
+// Reclaims space held by storages once data on disk is caught up
+fn compact_storage(storage: &mut Option<Storage>, stream_storages: &mut HashMap<String, Storage>) {
+    let mut reclaimed = Compaction::default();
+    for s in stream_storages.values_mut().chain(storage.as_mut()) {
+        match s.compact() {
+            Ok(compaction) => {
+                reclaimed.files += compaction.files;
+                reclaimed.memory += compaction.memory;
+            }
+            Err(e) => error!("Failed to compact storage. Error = {:?}", e),
+        }
+    }
+
+    if reclaimed != Compaction::default() {
+        info!(
+            "Compacted storage, removed {} files and released {} bytes of memory",
+            reclaimed.files, reclaimed.memory
+        );
+    }
+}
+
+fn stream_overflow_policy(config: &Config, stream: &str) -> OverflowPolicy {
+    config.streams.get(stream).map(|stream| stream.overflow_policy).unwrap_or_default()
+}
+
+// Flushes write buffer of storage onto disk when it overflows, handling a full storage as per
+// overflow policy of the stream written. Returns the number of segments of data discarded.
+fn flush_on_overflow(storage: &mut Storage, policy: OverflowPolicy) -> io::Result<usize> {
+    if !storage.is_full() {
+        return storage.flush_on_overflow();
+    }
+
+    match policy {
+        OverflowPolicy::DropOldest => storage.flush_on_overflow(),
+        OverflowPolicy::DropNewest => {
+            warn!("Storage full, discarding {} bytes of newest data", storage.write_buffer_size());
+            storage.writer().clear();
+            Ok(1)
+        }
+        // Data is held in memory, while new data isn't accepted till data on disk is read
+        OverflowPolicy::Block => Ok(0),
+    }
+}
+
+// Maximum number of publishes of priority streams held in memory during catchup
+const PRIORITY_QUEUE_SIZE: usize = 100;
+
+fn stream_priority(config: &Config, stream: &str) -> Priority {
+    config.streams.get(stream).map(|stream| stream.priority).unwrap_or_default()
+}
+
+// Data of priority streams pending delivery during catchup, as stream, topic, qos and payload
+type Pending = VecDeque<(Arc<String>, String, QoS, Bytes)>;
+
+// Writes data pending delivery onto disk, e.g. data of priority streams when eventloop crashes
+// during catchup, so that it isn't lost
+fn persist_pending(
+    config: &Config,
+    storage: &mut Option<Storage>,
+    stream_storages: &mut HashMap<String, Storage>,
+    compression: Compression,
+    cipher: Option<&Aes256Gcm>,
+    replay_ids: &mut Option<ReplayIds>,
+    pending: &mut Pending,
+    metrics: &mut Metrics,
+) {
+    for (stream, topic, qos, payload) in pending.drain(..) {
+        let policy = stream_overflow_policy(config, &stream);
+        let storage = match storage_for(storage, stream_storages, &stream) {
+            Some(s) => s,
+            None => {
+                error!("Data loss, no disk to write pending data of stream {}", stream);
+                continue;
+            }
+        };
+        let payload = match encrypt(cipher, compress(compression, payload.to_vec())) {
+            Ok(payload) => payload,
+            Err(e) => {
+                error!("Data loss, failed to encrypt pending data. Error = {:?}", e);
+                continue;
+            }
+        };
+        let payload = stamp(replay_ids, payload);
+        let payload_size = payload.len();
+        let mut publish = Publish::new(topic, qos, payload);
+        publish.pkid = 1;
+
+        if let Err(e) = publish.write(storage.writer()) {
+            error!("Failed to fill disk buffer. Error = {:?}", e);
+            metrics.add_disk_error();
+            continue;
+        }
+        metrics.add_total_disk_size(&stream, &publish.topic, payload_size);
+
+        match flush_on_overflow(storage, policy) {
+            Ok(discarded) => metrics.add_lost_segments(&stream, discarded),
+            Err(e) => {
+                error!("Failed to flush pending priority data to disk. Error = {:?}", e);
+                metrics.add_disk_error();
+            }
+        }
+    }
+}
+
+// Logs what would have been published, in place of publishing it, in dry run mode
+fn log_dry_run(topic: &str, qos: QoS, payload: &[u8]) {
+    info!("Dry run, skipping publish of {} bytes onto {} with {:?}", payload.len(), topic, qos);
+}
+
+/// Publish that couldn't be handed over to the eventloop, along with whether the failure was
+/// transient, i.e. the eventloop was busy rather than gone. `AsyncClient` only fails a send
+/// once the eventloop is gone, so sends that are retried are bounded by a timeout instead, a
+/// send that isn't taken by the eventloop within it being a transient failure.
+#[derive(Debug)]
+struct SendFailure {
+    publish: Publish,
+    transient: bool,
+}
+
+// Sends publish after delay, or only logs it in dry run mode. The publish is recovered from a copy kept while sending, rather than
+// from the request carried back by the error, so that it's never lost, whatever the error holds.
+// The copy is cheap, as the payload is reference counted. Cancellation safe, as nothing is sent
+// until the publish is handed over to the eventloop.
+async fn send_publish<C: MqttClient>(
+    client: C,
+    topic: String,
+    qos: QoS,
+    payload: Bytes,
+    delay: Duration,
+    dry_run: bool,
+    timeout: Option<Duration>,
+) -> Result<C, SendFailure> {
+    if !delay.is_zero() {
+        time::sleep(delay).await;
+    }
+    if dry_run {
+        log_dry_run(&topic, qos, &payload);
+        return Ok(client);
+    }
+
+    let publish = Publish::from_bytes(topic.clone(), qos, payload.clone());
+    let send = client.publish_bytes(topic, qos, false, payload);
+    let result = match timeout {
+        Some(timeout) => time::timeout(timeout, send).await,
+        None => Ok(send.await),
+    };
+    match result {
+        Ok(Ok(_)) => Ok(client),
+        Ok(Err(e)) => {
+            debug!("Failed to send publish on {}. Error = {}", publish.topic, e);
+            Err(SendFailure { publish, transient: matches!(e, MqttError::TrySend(_)) })
+        }
+        Err(_) => {
+            debug!("Eventloop busy, publish on {} not taken in {:?}", publish.topic, timeout);
+            Err(SendFailure { publish, transient: true })
+        }
+    }
+}
+
+// Bound on handing a publish read from disk over to the eventloop, only when sends are retried,
+// as the eventloop is otherwise waited on for as long as the network is slow
+fn send_timeout(config: &Config) -> Option<Duration> {
+    config.persistence.as_ref().filter(|p| p.send_retries > 0).map(|_| SEND_TIMEOUT)
+}
+
+// Delay before the next retry of a publish read from disk, None once retries are exhausted
+fn retry_backoff(config: &Config, retries: u32) -> Option<Duration> {
+    let persistence = config.persistence.as_ref()?;
+    if retries >= persistence.send_retries {
+        return None;
+    }
+
+    let backoff = persistence.retry_backoff_ms.saturating_mul(1 << retries.min(16));
+    Some(Duration::from_millis(backoff))
+}
+
+/// Latest [`Metrics`] and [`SerializerState`] of serializer, shared with local consumers such as
+/// the prometheus exporter and health check. Metrics are refreshed on every transition and every
+/// metrics interval in normal mode.
+#[derive(Debug, Clone, Default)]
+pub struct SharedMetrics {
+    inner: Arc<Mutex<(Metrics, Option<SerializerState>)>>,
+    // time at which serializer transitioned into its current state
+    since: Arc<Mutex<Option<Instant>>>,
+}
+
+impl SharedMetrics {
+    pub fn new() -> SharedMetrics {
+        SharedMetrics::default()
+    }
+
+    pub fn snapshot(&self) -> (Metrics, Option<SerializerState>) {
+        self.inner.lock().unwrap().clone()
+    }
+
+    fn set_metrics(&self, metrics: &Metrics) {
+        self.inner.lock().unwrap().0 = metrics.clone();
+    }
+
+    /// Current state of serializer, along with the time at which it transitioned into it
+    pub fn state(&self) -> Option<(SerializerState, Instant)> {
+        let state = self.inner.lock().unwrap().1;
+        state.zip(*self.since.lock().unwrap())
+    }
+
+    pub(crate) fn set_state(&self, state: SerializerState) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.1 != Some(state) {
+            *self.since.lock().unwrap() = Some(Instant::now());
+        }
+        inner.1 = Some(state);
+    }
+}
+
+/// Time for which publishes are held back in normal mode while the inflight window is full
+pub const INFLIGHT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Time within which a publish read from disk is expected to be taken by the eventloop, when
+/// sends are retried, beyond which the send is failed as transient
+const SEND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Interval at which ack cursors and replay ids of delivered publishes are persisted
+const ACK_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Longest pause in normal mode to hold the rate limit, before switching to slow mode
+pub const RATE_LIMIT_PAUSE: Duration = Duration::from_secs(1);
+
+/// Window of QoS 1 and 2 publishes handed to the eventloop that are yet to be acknowledged by
+/// the broker, i.e. PUBACK for QoS 1 and PUBCOMP for QoS 2. Serializer holds back publishes in
+/// normal mode while the window is full, for upto [`INFLIGHT_TIMEOUT`], as the eventloop stops
+/// taking requests once it has `max_inflight` publishes pending acknowledgement.
+#[derive(Debug, Clone)]
+pub struct InflightWindow {
+    inner: Arc<(AtomicUsize, Notify)>,
+    max: usize,
+}
+
+impl InflightWindow {
+    pub fn new(max: usize) -> InflightWindow {
+        InflightWindow { inner: Arc::new((AtomicUsize::new(0), Notify::new())), max }
+    }
+
+    /// Number of publishes awaiting acknowledgement
+    pub fn depth(&self) -> usize {
+        self.inner.0.load(Ordering::Relaxed)
+    }
+
+    /// Frees up space in the window, on acknowledgement of a publish by the broker
+    pub fn ack(&self) {
+        let _ = self.inner.0.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |depth| {
+            Some(depth.saturating_sub(1))
+        });
+        self.inner.1.notify_one();
+    }
+
+    /// Empties the window on reconnection, publishes that are retransmitted on reconnection
+    /// aren't counted again to not hold back the serializer on acks that might never arrive
+    pub fn reset(&self) {
+        self.inner.0.store(0, Ordering::Relaxed);
+        self.inner.1.notify_one();
+    }
+
+    fn add(&self) {
+        self.inner.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Waits for space in the window, returns false if no acknowledgement arrived in time
+    async fn wait(&self, timeout: Duration) -> bool {
+        let wait = async {
+            while self.depth() >= self.max {
+                self.inner.1.notified().await;
+            }
+        };
+
+        time::timeout(timeout, wait).await.is_ok()
+    }
+}
+
+// Kinds of errors counted individually in metrics of an interval
+const MAX_ERROR_KINDS: usize = 20;
+// Failures to write onto disk in an interval beyond which they are also reported as errors, as
+// "disk", as storage is then likely full, read-only or failing
+const DISK_ERROR_THRESHOLD: usize = 5;
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct Metrics {
+    sequence: u32,
+    #[serde(serialize_with = "serialize_timestamp", deserialize_with = "deserialize_timestamp")]
+    timestamp: u64,
+    total_sent_size: usize,
+    total_compressed_size: usize,
+    total_disk_size: usize,
+    lost_segments: usize,
+    // publishes of QoS 0 streams dropped as the eventloop was busy, in the current interval
+    dropped_publishes: usize,
+    // publishes awaiting acknowledgement from broker, as of the end of the interval
+    inflight_depth: usize,
+    // retries of publishes read from disk on transient failures, in the current interval
+    publish_retries: usize,
+    disk_usage: usize,
+    disk_segment_count: usize,
+    current_write_buffer_bytes: usize,
+    disk_quota: Option<usize>,
+    overflow_policies: HashMap<String, OverflowPolicy>,
+    // Count of errors of each kind in the interval, for at most MAX_ERROR_KINDS kinds, errors of
+    // kinds beyond are counted as "others". error_count is the total of all errors.
+    #[serde(deserialize_with = "deserialize_errors")]
+    errors: BTreeMap<String, usize>,
+    error_count: usize,
+    // failures to write data onto disk or flush it, in the current interval
+    disk_error_count: usize,
+    peak_pending_packages: usize,
+    peak_write_buffer_size: usize,
+    disk_mode_entered: bool,
+    // Time taken to hand a publish over to the mqtt eventloop, in the current interval. Acks are
+    // handled by the eventloop, so this is the local enqueue time irrespective of qos and doesn't
+    // include the round trip to broker, that is reflected only once the eventloop backs up.
+    avg_publish_latency_ms: f64,
+    max_publish_latency_ms: f64,
+    #[serde(skip)]
+    publish_latency_count: u64,
+    // Bytes per second sent over the network, after compression, in the last interval
+    send_rate: usize,
+    #[serde(skip)]
+    interval_compressed_size: usize,
+    #[serde(skip)]
+    interval_started: Option<Instant>,
+    // Time spent in each mode of serializer, in the current interval
+    time_in_normal_ms: u64,
+    time_in_slow_eventloop_ms: u64,
+    time_in_catchup_ms: u64,
+    time_in_crash_ms: u64,
+    // Mode serializer is currently in, along with the time since which it hasn't been charged
+    #[serde(skip)]
+    state: Option<(SerializerState, Instant)>,
+    // Metrics of each stream, for the interval except for disk_size. Streams with nothing on disk
+    // are dropped once their metrics of an interval are taken.
+    #[serde(serialize_with = "serialize_streams", deserialize_with = "deserialize_streams")]
+    streams: BTreeMap<String, StreamMetrics>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct StreamMetrics {
+    stream: String,
+    // topic on which data of the stream is written to disk, to attribute data read back from disk
+    topic: String,
+    // bytes of data sent in the interval, before network compression
+    sent_size: usize,
+    // bytes of data pending on disk
+    disk_size: usize,
+    // segments deleted in the interval, to make space for data of the stream
+    lost_segments: usize,
+    // errors in data of the stream in the interval
+    error_count: usize,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    fn stream_mut(&mut self, stream: &str) -> &mut StreamMetrics {
+        self.streams
+            .entry(stream.to_owned())
+            .or_insert_with(|| StreamMetrics { stream: stream.to_owned(), ..Default::default() })
+    }
+
+    /// Stream of data written to disk on topic, the topic itself if no such stream is known
+    pub fn stream_of(&self, topic: &str) -> String {
+        match self.streams.values().find(|metrics| metrics.topic == topic) {
+            Some(metrics) => metrics.stream.clone(),
+            None => topic.to_owned(),
+        }
+    }
+
+    pub fn add_total_sent_size(&mut self, stream: &str, size: usize) {
+        self.total_sent_size = self.total_sent_size.saturating_add(size);
+        let stream = self.stream_mut(stream);
+        stream.sent_size = stream.sent_size.saturating_add(size);
+    }
+
+    // Size of data sent after compression, same as total_sent_size when network compression is disabled
+    pub fn add_total_compressed_size(&mut self, size: usize) {
+        self.total_compressed_size = self.total_compressed_size.saturating_add(size);
+        self.interval_compressed_size = self.interval_compressed_size.saturating_add(size);
+        self.interval_started.get_or_insert_with(Instant::now);
+    }
+
+    pub fn add_total_disk_size(&mut self, stream: &str, topic: &str, size: usize) {
+        self.total_disk_size = self.total_disk_size.saturating_add(size);
+        let stream = self.stream_mut(stream);
+        stream.topic = topic.to_owned();
+        stream.disk_size = stream.disk_size.saturating_add(size);
+    }
+
+    // Data read back from disk is only known by its topic
+    pub fn sub_total_disk_size(&mut self, topic: &str, size: usize) {
+        self.total_disk_size = self.total_disk_size.saturating_sub(size);
+        let stream = self.stream_of(topic);
+        if let Some(stream) = self.streams.get_mut(&stream) {
+            stream.disk_size = stream.disk_size.saturating_sub(size);
+        }
+    }
+
+    pub fn add_lost_segments(&mut self, stream: &str, count: usize) {
+        self.lost_segments += count;
+        if count > 0 {
+            self.stream_mut(stream).lost_segments += count;
+        }
+    }
+
+    pub fn add_disk_error(&mut self) {
+        self.disk_error_count += 1;
+    }
+
+    pub fn add_dropped_publishes(&mut self, count: usize) {
+        self.dropped_publishes += count;
+    }
+
+    pub fn add_publish_retries(&mut self, count: usize) {
+        self.publish_retries += count;
+    }
+
+    pub fn set_inflight_depth(&mut self, depth: usize) {
+        self.inflight_depth = depth;
+    }
+
+    pub fn set_storage_usage<'a>(&mut self, storages: impl Iterator<Item = &'a Storage>) {
+        self.disk_usage = 0;
+        self.disk_segment_count = 0;
+        self.current_write_buffer_bytes = 0;
+        for storage in storages {
+            self.disk_usage += storage.disk_size();
+            self.disk_segment_count += storage.segment_count();
+            self.current_write_buffer_bytes += storage.write_buffer_size();
+        }
+    }
+
+    pub fn set_disk_quota(&mut self, quota: Option<usize>) {
+        self.disk_quota = quota;
+    }
+
+    // Streams that don't drop oldest data when storage overflows
+    pub fn set_overflow_policies(&mut self, policies: HashMap<String, OverflowPolicy>) {
+        self.overflow_policies = policies;
+    }
+
+    pub fn sample_pending_packages(&mut self, pending: usize) {
+        self.peak_pending_packages = self.peak_pending_packages.max(pending);
+    }
+
+    pub fn sample_write_buffer_size(&mut self, size: usize) {
+        self.peak_write_buffer_size = self.peak_write_buffer_size.max(size);
+    }
+
+    pub fn add_publish_latency(&mut self, latency: Duration) {
+        let latency = latency.as_micros() as f64 / 1000.0;
+        let count = self.publish_latency_count as f64;
+        self.avg_publish_latency_ms =
+            (self.avg_publish_latency_ms * count + latency) / (count + 1.0);
+        self.max_publish_latency_ms = self.max_publish_latency_ms.max(latency);
+        self.publish_latency_count += 1;
+    }
+
+    pub fn set_disk_mode_entered(&mut self) {
+        self.disk_mode_entered = true;
+    }
+
+    /// Charges time since the last transition to the state serializer is leaving, and starts
+    /// counting time in the state it is entering.
+    pub fn enter_state(&mut self, state: SerializerState, now: Instant) {
+        self.charge_state(now);
+        self.state = Some((state, now));
+    }
+
+    // Adds time elapsed in the current state to its counter
+    fn charge_state(&mut self, now: Instant) {
+        let (state, since) = match &mut self.state {
+            Some(state) => state,
+            None => return,
+        };
+
+        let elapsed = now.saturating_duration_since(*since).as_millis() as u64;
+        *since = now;
+        let time = match state {
+            SerializerState::Normal => &mut self.time_in_normal_ms,
+            SerializerState::SlowEventloop => &mut self.time_in_slow_eventloop_ms,
+            SerializerState::Catchup => &mut self.time_in_catchup_ms,
+            SerializerState::Crash => &mut self.time_in_crash_ms,
+        };
+        *time = time.saturating_add(elapsed);
+    }
+
+    pub fn add_errors<S: Into<String>>(&mut self, stream: &str, kind: S, count: usize) {
+        self.error_count += count;
+        self.stream_mut(stream).error_count += count;
+        let mut kind = kind.into();
+        if !self.errors.contains_key(&kind) && self.errors.len() >= MAX_ERROR_KINDS {
+            kind = "others".to_owned();
+        }
+
+        *self.errors.entry(kind).or_default() += count;
+    }
+
+    pub fn total_sent_size(&self) -> usize {
+        self.total_sent_size
+    }
+
+    pub fn total_disk_size(&self) -> usize {
+        self.total_disk_size
+    }
+
+    pub fn lost_segments(&self) -> usize {
+        self.lost_segments
+    }
+
+    pub fn dropped_publishes(&self) -> usize {
+        self.dropped_publishes
+    }
+
+    pub fn inflight_depth(&self) -> usize {
+        self.inflight_depth
+    }
+
+    pub fn publish_retries(&self) -> usize {
+        self.publish_retries
+    }
+
+    pub fn error_count(&self) -> usize {
+        self.error_count
+    }
+
+    pub fn disk_error_count(&self) -> usize {
+        self.disk_error_count
+    }
+
+    pub fn send_rate(&self) -> usize {
+        self.send_rate
+    }
+
+    pub fn time_in_state_ms(&self, state: SerializerState) -> u64 {
+        match state {
+            SerializerState::Normal => self.time_in_normal_ms,
+            SerializerState::SlowEventloop => self.time_in_slow_eventloop_ms,
+            SerializerState::Catchup => self.time_in_catchup_ms,
+            SerializerState::Crash => self.time_in_crash_ms,
+        }
+    }
+
+    pub fn next(&mut self) -> Metrics {
+        let timestamp =
+            SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+        self.timestamp = timestamp.as_millis() as u64;
+        self.sequence += 1;
+        // Rate is retained till the next interval, for local scraping
+        let now = Instant::now();
+        let elapsed = self.interval_started.map_or(0.0, |s| now.duration_since(s).as_secs_f64());
+        self.send_rate = match elapsed > 0.0 {
+            true => (self.interval_compressed_size as f64 / elapsed) as usize,
+            false => 0,
+        };
+        self.charge_state(now);
+
+        let mut metrics = self.clone();
+        if metrics.disk_error_count > DISK_ERROR_THRESHOLD {
+            metrics.errors.insert("disk".to_owned(), metrics.disk_error_count);
+        }
+
+        self.errors.clear();
+        self.disk_error_count = 0;
+        self.lost_segments = 0;
+        self.dropped_publishes = 0;
+        self.publish_retries = 0;
+        self.peak_pending_packages = 0;
+        self.peak_write_buffer_size = 0;
+        self.disk_mode_entered = false;
+        self.avg_publish_latency_ms = 0.0;
+        self.max_publish_latency_ms = 0.0;
+        self.publish_latency_count = 0;
+        self.interval_compressed_size = 0;
+        self.interval_started = Some(now);
+        self.time_in_normal_ms = 0;
+        self.time_in_slow_eventloop_ms = 0;
+        self.time_in_catchup_ms = 0;
+        self.time_in_crash_ms = 0;
+        self.streams.retain(|_, stream| stream.disk_size > 0);
+        for stream in self.streams.values_mut() {
+            stream.sent_size = 0;
+            stream.lost_segments = 0;
+            stream.error_count = 0;
+        }
+
+        metrics
+    }
+}
+
+impl Point for Metrics {
+    fn sequence(&self) -> u32 {
+        self.sequence
+    }
+
+    fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+}
+
+impl Package for Buffer<Metrics> {
+    fn stream(&self) -> Arc<String> {
+        self.stream.clone()
+    }
+
+    fn topic(&self) -> Arc<String> {
+        self.topic.clone()
+    }
+
+    fn serialize(&self) -> serde_json::Result<Vec<u8>> {
+        serde_json::to_vec(&self.buffer)
+    }
+
+    fn serialize_as(&self, format: PayloadFormat) -> Result<Vec<u8>, PayloadError> {
+        format.encode(&self.buffer)
+    }
+
+    fn anomalies(&self) -> Option<(String, usize)> {
+        self.anomalies()
+    }
+
+    fn anomaly_counts(&self) -> Vec<(String, usize)> {
+        self.anomaly_counts()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::Value;
+
+    use super::*;
+    use crate::{base::Stream, config::Persistence, Payload};
+    use std::collections::HashMap;
+
+    #[derive(Clone)]
+    pub struct MockClient {
+        pub net_tx: flume::Sender<Request>,
+    }
+
+    const PERSIST_FOLDER: &str = "/tmp/uplink_test";
+
+    #[async_trait::async_trait]
+    impl MqttClient for MockClient {
         async fn publish<S, V>(
             &self,
             topic: S,
@@ -636,6 +2385,93 @@ mod test {
         }
     }
 
+    // Client on which sends fail transiently, as many times as failures
+    #[derive(Clone)]
+    pub struct FlakyClient {
+        pub client: MockClient,
+        pub failures: Arc<AtomicUsize>,
+    }
+
+    #[async_trait::async_trait]
+    impl MqttClient for FlakyClient {
+        async fn publish<S, V>(
+            &self,
+            topic: S,
+            qos: QoS,
+            retain: bool,
+            payload: V,
+        ) -> Result<(), MqttError>
+        where
+            S: Into<String> + Send,
+            V: Into<Vec<u8>> + Send,
+        {
+            self.client.publish(topic, qos, retain, payload).await
+        }
+
+        fn try_publish<S, V>(
+            &self,
+            topic: S,
+            qos: QoS,
+            retain: bool,
+            payload: V,
+        ) -> Result<(), MqttError>
+        where
+            S: Into<String>,
+            V: Into<Vec<u8>>,
+        {
+            self.client.try_publish(topic, qos, retain, payload)
+        }
+
+        async fn publish_bytes<S>(
+            &self,
+            topic: S,
+            qos: QoS,
+            retain: bool,
+            payload: Bytes,
+        ) -> Result<(), MqttError>
+        where
+            S: Into<String> + Send,
+        {
+            let fail = |failures: usize| failures.checked_sub(1);
+            if self.failures.fetch_update(Ordering::SeqCst, Ordering::SeqCst, fail).is_ok() {
+                let publish = Publish::from_bytes(topic, qos, payload);
+                return Err(MqttError::TrySend(Request::Publish(publish)));
+            }
+
+            self.client.publish_bytes(topic, qos, retain, payload).await
+        }
+    }
+
+    // Client on which sends fail with an error that doesn't carry back the publish being sent
+    #[derive(Clone)]
+    pub struct LossyClient;
+
+    #[async_trait::async_trait]
+    impl MqttClient for LossyClient {
+        async fn publish<S, V>(&self, _: S, _: QoS, _: bool, _: V) -> Result<(), MqttError>
+        where
+            S: Into<String> + Send,
+            V: Into<Vec<u8>> + Send,
+        {
+            Err(MqttError::Send(Request::PingReq))
+        }
+
+        fn try_publish<S, V>(&self, _: S, _: QoS, _: bool, _: V) -> Result<(), MqttError>
+        where
+            S: Into<String>,
+            V: Into<Vec<u8>>,
+        {
+            Err(MqttError::TrySend(Request::PingReq))
+        }
+
+        async fn publish_bytes<S>(&self, _: S, _: QoS, _: bool, _: Bytes) -> Result<(), MqttError>
+        where
+            S: Into<String> + Send,
+        {
+            Err(MqttError::Send(Request::PingReq))
+        }
+    }
+
     fn write_to_storage(storage: &mut Storage, publish: &Publish) {
         if let Err(e) = publish.write(storage.writer()) {
             panic!("Failed to fill write buffer. Error = {:?}", e);
@@ -666,6 +2502,7 @@ mod test {
             device_id: "123".to_owned(),
             streams: HashMap::new(),
             max_packet_size: 1024 * 1024,
+            metrics_interval_secs: 10,
             ..Default::default()
         }
     }
@@ -677,6 +2514,7 @@ mod test {
             path: path.clone(),
             max_file_size: 10 * 1024 * 1024,
             max_file_count: 3,
+            ..Default::default()
         });
 
         config
@@ -687,9 +2525,14 @@ mod test {
     ) -> (Serializer<MockClient>, flume::Sender<Box<dyn Package>>, Receiver<Request>) {
         let (data_tx, data_rx) = flume::bounded(1);
         let (net_tx, net_rx) = flume::bounded(1);
+        let (_, metrics_rx) = flume::bounded(1);
         let client = MockClient { net_tx };
 
-        (Serializer::new(config, data_rx, None, client).unwrap(), data_tx, net_rx)
+        (
+            Serializer::new(config, data_rx, None, metrics_rx, None, None, client).unwrap(),
+            data_tx,
+            net_rx,
+        )
     }
 
     #[derive(Error, Debug)]
@@ -709,23 +2552,185 @@ mod test {
             MockCollector { stream: Stream::new("hello", "hello/world", 1, data_tx) }
         }
 
-        fn send(&mut self, i: u32) -> Result<(), Error> {
-            let payload = Payload {
-                stream: "hello".to_owned(),
-                sequence: i,
-                timestamp: 0,
-                payload: serde_json::from_str("{\"msg\": \"Hello, World!\"}")?,
-            };
-            self.stream.push(payload)?;
+        fn send(&mut self, i: u32) -> Result<(), Error> {
+            let payload = Payload {
+                stream: "hello".to_owned(),
+                sequence: i,
+                timestamp: 0,
+                payload: serde_json::from_str("{\"msg\": \"Hello, World!\"}")?,
+            };
+            self.stream.push(payload)?;
+
+            Ok(())
+        }
+    }
+
+    #[test]
+    // Force runs serializer in normal mode, without persistence
+    fn normal_to_slow() {
+        let config = default_config();
+        let (mut serializer, data_tx, net_rx) = defaults(Arc::new(config));
+
+        // Slow Network, takes packets only once in 10s
+        std::thread::spawn(move || loop {
+            std::thread::sleep(time::Duration::from_secs(10));
+            net_rx.recv().unwrap();
+        });
+
+        let mut collector = MockCollector::new(data_tx);
+        std::thread::spawn(move || {
+            for i in 1..3 {
+                collector.send(i).unwrap();
+            }
+        });
+
+        match tokio::runtime::Runtime::new().unwrap().block_on(serializer.normal()).unwrap() {
+            Status::SlowEventloop(_, Publish { qos: QoS::AtLeastOnce, topic, payload, .. }) => {
+                assert_eq!(topic, "hello/world");
+                let recvd: Value = serde_json::from_slice(&payload).unwrap();
+                let obj = &recvd.as_array().unwrap()[0];
+                assert_eq!(obj.get("msg"), Some(&Value::from("Hello, World!")));
+            }
+            s => panic!("Unexpected status: {:?}", s),
+        }
+    }
+
+    #[test]
+    // Force runs serializer in normal mode, with QoS configured for the stream
+    fn normal_to_slow_with_stream_qos() {
+        let mut config = default_config();
+        let stream = crate::base::StreamConfig { qos: 2, ..Default::default() };
+        config.streams.insert("hello".to_owned(), stream);
+        let (mut serializer, data_tx, net_rx) = defaults(Arc::new(config));
+
+        // Slow Network, takes packets only once in 10s
+        std::thread::spawn(move || loop {
+            std::thread::sleep(time::Duration::from_secs(10));
+            net_rx.recv().unwrap();
+        });
+
+        let mut collector = MockCollector::new(data_tx);
+        std::thread::spawn(move || {
+            for i in 1..3 {
+                collector.send(i).unwrap();
+            }
+        });
+
+        match tokio::runtime::Runtime::new().unwrap().block_on(serializer.normal()).unwrap() {
+            Status::SlowEventloop(_, Publish { qos, topic, .. }) => {
+                assert_eq!(topic, "hello/world");
+                assert_eq!(qos, QoS::ExactlyOnce);
+            }
+            s => panic!("Unexpected status: {:?}", s),
+        }
+    }
+
+    #[test]
+    // Force runs serializer in normal mode, holding back publishes while inflight window is full
+    fn normal_waits_on_full_inflight_window() {
+        let (serializer, data_tx, net_rx) = defaults(Arc::new(default_config()));
+        let window = InflightWindow::new(1);
+        let mut serializer = serializer.with_inflight(window.clone());
+
+        let mut collector = MockCollector::new(data_tx);
+        std::thread::spawn(move || {
+            for i in 1..3 {
+                collector.send(i).unwrap();
+            }
+            std::thread::sleep(time::Duration::from_secs(10));
+        });
+
+        // Broker acks the first publish after a while
+        let broker = window.clone();
+        let broker = std::thread::spawn(move || {
+            net_rx.recv().unwrap();
+            std::thread::sleep(time::Duration::from_millis(300));
+            let held_back = net_rx.is_empty();
+            broker.ack();
+            (held_back, net_rx.recv_timeout(time::Duration::from_millis(500)).is_ok())
+        });
+
+        // Serializer doesn't switch to slow mode while waiting on acks
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let normal = time::timeout(time::Duration::from_secs(1), serializer.normal());
+        assert!(rt.block_on(normal).is_err());
+
+        assert_eq!(broker.join().unwrap(), (true, true));
+        assert_eq!(window.depth(), 1);
+    }
+
+    #[test]
+    // Force runs serializer in normal mode, with a QoS 0 stream that doesn't go to disk
+    fn normal_drops_qos0_data_on_slow_network() {
+        let mut config = default_config();
+        let stream = crate::base::StreamConfig { qos: 0, ..Default::default() };
+        config.streams.insert("hello".to_owned(), stream);
+        let (mut serializer, data_tx, net_rx) = defaults(Arc::new(config));
+
+        let mut collector = MockCollector::new(data_tx);
+        std::thread::spawn(move || {
+            for i in 1..4 {
+                collector.send(i).unwrap();
+            }
+            std::thread::sleep(time::Duration::from_secs(10));
+        });
+
+        // Network never takes packets, beyond the one that fits in the eventloop
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let normal = time::timeout(time::Duration::from_secs(1), serializer.normal());
+        assert!(rt.block_on(normal).is_err());
+
+        assert_eq!(net_rx.len(), 1);
+        assert_eq!(serializer.metrics.dropped_publishes, 2);
+        assert_eq!(serializer.metrics.next().dropped_publishes, 2);
+        assert_eq!(serializer.metrics.dropped_publishes, 0);
+    }
+
+    #[test]
+    // Force runs serializer in normal mode, coalescing data of a stream within the window
+    fn normal_publishes_aggregated_data_together() {
+        let mut config = default_config();
+        config.aggregation = Some(crate::base::Aggregation {
+            topic: "/aggregate".to_owned(),
+            streams: vec!["hello".to_owned()],
+            window_ms: 300,
+            max_size: 1024,
+        });
+        let (mut serializer, data_tx, net_rx) = defaults(Arc::new(config));
+
+        let mut collector = MockCollector::new(data_tx);
+        std::thread::spawn(move || {
+            for i in 1..4 {
+                collector.send(i).unwrap();
+            }
+            std::thread::sleep(time::Duration::from_secs(10));
+        });
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let normal = time::timeout(time::Duration::from_secs(1), serializer.normal());
+        assert!(rt.block_on(normal).is_err());
 
-            Ok(())
-        }
+        let publish = match net_rx.try_recv().unwrap() {
+            Request::Publish(publish) => publish,
+            r => unreachable!("Unexpected request: {:?}", r),
+        };
+        assert_eq!(publish.topic, "/aggregate");
+        let payload: Value = serde_json::from_slice(&publish.payload).unwrap();
+        let sequences: Vec<u64> = payload["hello"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|record| record["sequence"].as_u64().unwrap())
+            .collect();
+        assert_eq!(sequences, vec![1, 2, 3]);
+        assert!(net_rx.is_empty());
     }
 
     #[test]
-    // Force runs serializer in normal mode, without persistence
-    fn normal_to_slow() {
-        let config = default_config();
+    // Force runs serializer in normal mode, with payloads published as cbor
+    fn normal_to_slow_with_cbor_payload() {
+        let mut config = default_config();
+        config.payload_format = PayloadFormat::Cbor;
         let (mut serializer, data_tx, net_rx) = defaults(Arc::new(config));
 
         // Slow Network, takes packets only once in 10s
@@ -742,9 +2747,9 @@ mod test {
         });
 
         match tokio::runtime::Runtime::new().unwrap().block_on(serializer.normal()).unwrap() {
-            Status::SlowEventloop(Publish { qos: QoS::AtLeastOnce, topic, payload, .. }) => {
-                assert_eq!(topic, "hello/world");
-                let recvd: Value = serde_json::from_slice(&payload).unwrap();
+            Status::SlowEventloop(_, Publish { topic, payload, .. }) => {
+                assert_eq!(topic, "hello/world/cbor");
+                let recvd: Value = ciborium::de::from_reader(&payload[..]).unwrap();
                 let obj = &recvd.as_array().unwrap()[0];
                 assert_eq!(obj.get("msg"), Some(&Value::from("Hello, World!")));
             }
@@ -802,8 +2807,9 @@ mod test {
             QoS::AtLeastOnce,
             "[{{\"sequence\":1,\"timestamp\":0,\"msg\":\"Hello, World!\"}}]".as_bytes(),
         );
-        let status =
-            tokio::runtime::Runtime::new().unwrap().block_on(serializer.slow(publish)).unwrap();
+        let stream = Arc::new("hello".to_owned());
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let status = rt.block_on(serializer.slow(stream, publish)).unwrap();
 
         assert_eq!(status, Status::EventLoopReady);
     }
@@ -830,8 +2836,10 @@ mod test {
             "[{\"sequence\":1,\"timestamp\":0,\"msg\":\"Hello, World!\"}]".as_bytes(),
         );
 
-        match tokio::runtime::Runtime::new().unwrap().block_on(serializer.slow(publish)).unwrap() {
-            Status::EventLoopCrash(Publish { qos: QoS::AtLeastOnce, topic, payload, .. }) => {
+        let stream = Arc::new("hello".to_owned());
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        match rt.block_on(serializer.slow(stream, publish)).unwrap() {
+            Status::EventLoopCrash(_, Publish { qos: QoS::AtLeastOnce, topic, payload, .. }) => {
                 assert_eq!(topic, "hello/world");
                 let recvd = std::str::from_utf8(&payload).unwrap();
                 assert_eq!(recvd, "[{\"sequence\":1,\"timestamp\":0,\"msg\":\"Hello, World!\"}]");
@@ -840,6 +2848,580 @@ mod test {
         }
     }
 
+    #[test]
+    // Force runs serializer in crash mode, verifying that the publish which crashed the network is on disk
+    fn crash_writes_failed_publish() {
+        let config = Arc::new(config_with_persistence(format!("{}/crash", PERSIST_FOLDER)));
+
+        let (mut serializer, data_tx, _) = defaults(config);
+
+        let mut collector = MockCollector::new(data_tx);
+        std::thread::spawn(move || {
+            for i in 2..4 {
+                collector.send(i).unwrap();
+            }
+        });
+
+        let publish = Publish::new(
+            "hello/world",
+            QoS::AtLeastOnce,
+            "[{\"sequence\":1,\"timestamp\":0,\"msg\":\"Hello, World!\"}]".as_bytes(),
+        );
+
+        // Crash mode never returns, let it handle a couple of iterations before stopping it
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let crash = serializer.crash(Arc::new("hello".to_owned()), publish);
+        let crash = time::timeout(time::Duration::from_secs(1), crash);
+        assert!(rt.block_on(crash).is_err());
+
+        let max_packet_size = serializer.config.max_packet_size;
+        let mut storage = serializer.storage.take().unwrap();
+        let stored = read_from_storage(&mut storage, max_packet_size);
+        assert_eq!(stored.topic, "hello/world");
+        let recvd = std::str::from_utf8(&stored.payload).unwrap();
+        assert_eq!(recvd, "[{\"sequence\":1,\"timestamp\":0,\"msg\":\"Hello, World!\"}]");
+
+        // Data received after the crash follows the failed publish
+        let stored = read_from_storage(&mut storage, max_packet_size);
+        let recvd: Value = serde_json::from_slice(&stored.payload).unwrap();
+        assert_eq!(recvd[0].get("sequence"), Some(&Value::from(2)));
+    }
+
+    #[test]
+    fn metrics_carry_over_restarts() {
+        let path = format!("{}/metrics.json", PERSIST_FOLDER);
+        std::fs::create_dir_all(PERSIST_FOLDER).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        let mut metrics = load_metrics(Some(&path));
+        metrics.add_total_sent_size("can", 100);
+        metrics.next();
+        persist_metrics(Some(&path), &metrics);
+
+        let mut metrics = load_metrics(Some(&path));
+        let next = metrics.next();
+        assert_eq!(next.sequence, 2);
+        assert_eq!(next.total_sent_size, 100);
+
+        // Metrics persisted with timestamps as RFC 3339 strings are carried over just the same
+        persist_metrics(Some(&path), &metrics);
+        let mut persisted: Value = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        persisted["timestamp"] = Value::from("2023-01-01T00:00:00.500Z");
+        std::fs::write(&path, persisted.to_string()).unwrap();
+        let metrics = load_metrics(Some(&path));
+        assert_eq!(metrics.timestamp, 1672531200500);
+        assert_eq!(metrics.sequence, 2);
+        assert_eq!(metrics.total_sent_size, 100);
+
+        // Corrupt metrics are ignored
+        std::fs::write(&path, "{sequence").unwrap();
+        assert_eq!(load_metrics(Some(&path)).sequence, 0);
+    }
+
+    #[test]
+    fn metrics_are_attributed_to_streams() {
+        let mut metrics = Metrics::new();
+        metrics.add_total_disk_size("can", "/devices/123/events/can/jsonarray", 100);
+        metrics.add_total_disk_size("gps", "/devices/123/events/gps/jsonarray", 50);
+        metrics.add_lost_segments("can", 1);
+        metrics.add_total_sent_size("gps", 20);
+        // Data read back from disk is attributed by its topic
+        metrics.sub_total_disk_size("/devices/123/events/gps/jsonarray", 50);
+        let stream = metrics.stream_of("/devices/123/events/gps/jsonarray");
+        metrics.add_total_sent_size(&stream, 50);
+
+        let next = metrics.next();
+        assert_eq!((next.total_disk_size, next.total_sent_size, next.lost_segments), (100, 70, 1));
+        assert_eq!((next.streams["can"].disk_size, next.streams["can"].lost_segments), (100, 1));
+        assert_eq!((next.streams["gps"].disk_size, next.streams["gps"].sent_size), (0, 70));
+        let json = serde_json::to_value(&next).unwrap();
+        assert_eq!(json["streams"][1]["stream"], "gps");
+        assert_eq!(json["streams"][1]["sent_size"], 70);
+
+        // Counts are reset every interval, streams with nothing on disk are dropped
+        let next = metrics.next();
+        assert_eq!(next.total_sent_size, 70);
+        assert_eq!(next.streams.len(), 1);
+        assert_eq!((next.streams["can"].disk_size, next.streams["can"].lost_segments), (100, 0));
+    }
+
+    #[test]
+    fn persistent_disk_errors_are_reported() {
+        let mut metrics = Metrics::new();
+        for _ in 0..DISK_ERROR_THRESHOLD {
+            metrics.add_disk_error();
+        }
+        let next = metrics.next();
+        assert_eq!(next.disk_error_count, DISK_ERROR_THRESHOLD);
+        assert!(next.errors.is_empty());
+
+        for _ in 0..=DISK_ERROR_THRESHOLD {
+            metrics.add_disk_error();
+        }
+        let next = metrics.next();
+        assert_eq!(next.errors["disk"], DISK_ERROR_THRESHOLD + 1);
+        assert_eq!(metrics.next().disk_error_count, 0);
+    }
+
+    #[test]
+    fn send_rate_is_measured_every_interval() {
+        let mut metrics = Metrics::new();
+        metrics.interval_started = Some(Instant::now() - Duration::from_secs(2));
+        metrics.add_total_compressed_size(1000);
+        let rate = metrics.next().send_rate;
+        assert!((490..=500).contains(&rate), "rate = {}", rate);
+        assert_eq!(metrics.send_rate(), rate);
+        assert_eq!(metrics.next().send_rate, 0);
+    }
+
+    #[test]
+    fn time_in_state_is_charged_every_interval() {
+        let mut metrics = Metrics::new();
+        let start = Instant::now() - Duration::from_secs(5);
+        metrics.enter_state(SerializerState::Catchup, start);
+        metrics.enter_state(SerializerState::Normal, start + Duration::from_secs(3));
+
+        let next = metrics.next();
+        assert_eq!(next.time_in_catchup_ms, 3000);
+        assert!((2000..2100).contains(&next.time_in_normal_ms), "{}", next.time_in_normal_ms);
+        assert_eq!(next.time_in_crash_ms, 0);
+
+        // Time in current state carries on into the next interval, others are reset
+        metrics.enter_state(SerializerState::Crash, Instant::now() + Duration::from_secs(1));
+        let next = metrics.next();
+        assert_eq!(next.time_in_catchup_ms, 0);
+        assert!((1000..1100).contains(&next.time_in_normal_ms), "{}", next.time_in_normal_ms);
+        assert_eq!(next.time_in_crash_ms, 0);
+        assert_eq!(metrics.time_in_state_ms(SerializerState::Normal), 0);
+    }
+
+    #[test]
+    fn publish_latency_is_reset_every_interval() {
+        let mut metrics = Metrics::new();
+        metrics.add_publish_latency(Duration::from_millis(10));
+        metrics.add_publish_latency(Duration::from_millis(30));
+
+        let next = metrics.next();
+        assert_eq!(next.avg_publish_latency_ms, 20.0);
+        assert_eq!(next.max_publish_latency_ms, 30.0);
+
+        metrics.add_publish_latency(Duration::from_millis(5));
+        let next = metrics.next();
+        assert_eq!(next.avg_publish_latency_ms, 5.0);
+        assert_eq!(next.max_publish_latency_ms, 5.0);
+    }
+
+    #[test]
+    fn dry_run_publishes_nothing_but_counts_metrics() {
+        let mut config = default_config();
+        config.dry_run = true;
+        let (mut serializer, data_tx, net_rx) = defaults(Arc::new(config));
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+
+        // Network takes a single publish, more would switch to slow mode if they were sent
+        let mut collector = MockCollector::new(data_tx);
+        for i in 1..4 {
+            collector.send(i).unwrap();
+            let data = serializer.collector_rx.recv().unwrap();
+            assert_eq!(runtime.block_on(serializer.try_send(data)).unwrap(), None);
+        }
+        assert!(net_rx.is_empty());
+        assert!(serializer.metrics.total_sent_size > 0);
+    }
+
+    #[test]
+    fn metrics_are_written_to_disk_on_leaving_normal_mode() {
+        let path = format!("{}/metrics_snapshot", PERSIST_FOLDER);
+        let config = Arc::new(config_with_persistence(path));
+        let (data_tx, data_rx) = flume::bounded(1);
+        let (net_tx, _net_rx) = flume::bounded(1);
+        let (_, metrics_rx) = flume::bounded(1);
+        let metrics = Some(Stream::new("metrics", "/metrics", 10, data_tx));
+        let client = MockClient { net_tx };
+        let mut serializer =
+            Serializer::new(config, data_rx, metrics, metrics_rx, None, None, client).unwrap();
+
+        serializer.metrics.add_dropped_publishes(3);
+        serializer.snapshot_metrics();
+
+        // Snapshot is written as is, without waiting for the buffer of the stream to fill
+        let storage = serializer.storage.as_mut().unwrap();
+        let publish = match read(storage.writer(), 1024 * 1024).unwrap() {
+            Packet::Publish(publish) => publish,
+            p => unreachable!("Unexpected packet: {:?}", p),
+        };
+        assert_eq!(publish.topic, "/metrics");
+        let metrics: Vec<Value> = serde_json::from_slice(&publish.payload).unwrap();
+        assert_eq!(metrics[0]["dropped_publishes"], 3);
+        assert_eq!(serializer.metrics.dropped_publishes, 0);
+    }
+
+    #[test]
+    // Metrics of the interval aren't reset without a disk to write them onto, they are published
+    // with the next interval instead of being lost
+    fn metrics_are_kept_on_leaving_normal_mode_without_storage() {
+        let (data_tx, data_rx) = flume::bounded(1);
+        let (net_tx, _net_rx) = flume::bounded(1);
+        let (_, metrics_rx) = flume::bounded(1);
+        let metrics = Some(Stream::new("metrics", "/metrics", 10, data_tx));
+        let client = MockClient { net_tx };
+        let config = Arc::new(default_config());
+        let mut serializer =
+            Serializer::new(config, data_rx, metrics, metrics_rx, None, None, client).unwrap();
+
+        serializer.metrics.add_dropped_publishes(3);
+        serializer.snapshot_metrics();
+        assert_eq!(serializer.metrics.dropped_publishes, 3);
+    }
+
+    #[test]
+    fn hello_is_published_once_per_run() {
+        let mut config = default_config();
+        config.hello = Some(crate::base::Hello { topic: "/hello".to_owned(), qos: 1 });
+        config.config_hash = "abcd".to_owned();
+        let (mut serializer, _, net_rx) = defaults(Arc::new(config));
+
+        // Eventloop is busy, hello is retried on the next transition
+        serializer.client.try_publish("busy", QoS::AtLeastOnce, false, vec![]).unwrap();
+        serializer.say_hello();
+        net_rx.recv().unwrap();
+        assert!(net_rx.is_empty());
+
+        serializer.say_hello();
+        let publish = match net_rx.recv().unwrap() {
+            Request::Publish(publish) => publish,
+            r => unreachable!("Unexpected request: {:?}", r),
+        };
+        assert_eq!(publish.topic, "/hello");
+        let hello: Value = serde_json::from_slice(&publish.payload).unwrap();
+        assert_eq!(hello["device_id"], "123");
+        assert_eq!(hello["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(hello["config_hash"], "abcd");
+        assert!(hello["boot_time"].as_u64().unwrap() > 0);
+
+        // Switching between modes in the same session doesn't publish it again
+        serializer.say_hello();
+        assert!(net_rx.is_empty());
+    }
+
+    #[test]
+    fn errors_are_counted_by_kind_every_interval() {
+        let mut metrics = Metrics::new();
+        metrics.add_errors("can", "can.sequence", 2);
+        metrics.add_errors("can", "can.timestamp", 1);
+        metrics.add_errors("can", "can.sequence", 1);
+        for i in 0..MAX_ERROR_KINDS {
+            metrics.add_errors(&format!("stream{}", i), format!("stream{}.sequence", i), 1);
+        }
+
+        let next = metrics.next();
+        assert_eq!(next.errors.len(), MAX_ERROR_KINDS + 1);
+        assert_eq!(next.errors["can.sequence"], 3);
+        assert_eq!(next.errors["can.timestamp"], 1);
+        assert_eq!(next.errors["others"], 2);
+        assert_eq!(next.error_count, 4 + MAX_ERROR_KINDS);
+
+        metrics.add_errors("can", "can.sequence", 1);
+        let next = metrics.next();
+        assert_eq!(next.errors, BTreeMap::from([("can.sequence".to_owned(), 1)]));
+        assert_eq!(next.error_count, 5 + MAX_ERROR_KINDS);
+        let json = serde_json::to_value(&next).unwrap();
+        assert_eq!(json["errors"], serde_json::json!({"can.sequence": 1}));
+    }
+
+    #[test]
+    // Anomalies of data collected in crash mode are counted, as they are in other modes
+    fn crash_counts_errors_of_data_collected() {
+        let config = Arc::new(config_with_persistence(format!("{}/crash_errors", PERSIST_FOLDER)));
+        let (mut serializer, data_tx, _) = defaults(config);
+
+        let stream = Arc::new("hello".to_owned());
+        let mut data = Buffer::<Payload>::new(stream.clone(), Arc::new("hello/world".to_owned()));
+        data.add_sequence_anomaly(3, 2);
+        data_tx.send(Box::new(data)).unwrap();
+
+        let publish = Publish::new("hello/world", QoS::AtLeastOnce, "[]".as_bytes());
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let crash = time::timeout(time::Duration::from_secs(1), serializer.crash(stream, publish));
+        assert!(rt.block_on(crash).is_err());
+        assert_eq!(serializer.metrics.next().errors["hello.sequence"], 1);
+    }
+
+    #[test]
+    // Force runs serializer in crash mode, verifying that data of a stream with dedicated storage
+    // is written to it, while data of other streams is written to the shared storage
+    fn crash_writes_to_dedicated_storage_of_stream() {
+        let mut config = config_with_persistence(format!("{}/crash_dedicated", PERSIST_FOLDER));
+        let hello = crate::base::StreamConfig {
+            topic: Some("hello/world".to_owned()),
+            buf_size: 1,
+            persistence: Some(crate::base::StreamPersistence {
+                max_file_size: 1024,
+                max_file_count: 3,
+                max_disk_size: None,
+            }),
+            ..Default::default()
+        };
+        config.streams.insert("hello".to_owned(), hello);
+
+        let (mut serializer, data_tx, _) = defaults(Arc::new(config));
+        assert!(serializer.stream_storages.contains_key("hello"));
+
+        let mut collector = MockCollector::new(data_tx);
+        std::thread::spawn(move || {
+            for i in 2..4 {
+                collector.send(i).unwrap();
+            }
+        });
+
+        let publish = Publish::new("other/topic", QoS::AtLeastOnce, "[]".as_bytes());
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let crash = serializer.crash(Arc::new("other".to_owned()), publish);
+        let crash = time::timeout(time::Duration::from_secs(1), crash);
+        assert!(rt.block_on(crash).is_err());
+
+        let max_packet_size = serializer.config.max_packet_size;
+        let mut storage = serializer.storage.take().unwrap();
+        let stored = read_from_storage(&mut storage, max_packet_size);
+        assert_eq!(stored.topic, "other/topic");
+        assert!(storage.reload_on_eof().unwrap());
+
+        let mut storage = serializer.stream_storages.remove("hello").unwrap();
+        for i in 2..4 {
+            let stored = read_from_storage(&mut storage, max_packet_size);
+            assert_eq!(stored.topic, "hello/world");
+            let recvd: Value = serde_json::from_slice(&stored.payload).unwrap();
+            assert_eq!(recvd[0].get("sequence"), Some(&Value::from(i)));
+        }
+    }
+
+    #[test]
+    // Failed publish is written onto the dedicated storage of its stream, even when published on
+    // a topic other than the one configured, e.g. with the suffix of network compression
+    fn crash_writes_failed_publish_to_storage_of_its_stream() {
+        let mut config = config_with_persistence(format!("{}/crash_suffixed", PERSIST_FOLDER));
+        let hello = crate::base::StreamConfig {
+            topic: Some("hello/world".to_owned()),
+            buf_size: 1,
+            persistence: Some(crate::base::StreamPersistence {
+                max_file_size: 1024,
+                max_file_count: 3,
+                max_disk_size: None,
+            }),
+            ..Default::default()
+        };
+        config.streams.insert("hello".to_owned(), hello);
+
+        let (mut serializer, _data_tx, _) = defaults(Arc::new(config));
+        let publish = Publish::new("hello/world/lz4", QoS::AtLeastOnce, "[]".as_bytes());
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let crash = serializer.crash(Arc::new("hello".to_owned()), publish);
+        let crash = time::timeout(time::Duration::from_secs(1), crash);
+        assert!(rt.block_on(crash).is_err());
+
+        let max_packet_size = serializer.config.max_packet_size;
+        let mut storage = serializer.stream_storages.remove("hello").unwrap();
+        let stored = read_from_storage(&mut storage, max_packet_size);
+        assert_eq!(stored.topic, "hello/world/lz4");
+        let mut storage = serializer.storage.take().unwrap();
+        assert!(storage.reload_on_eof().unwrap());
+    }
+
+    #[test]
+    // Fills a storage with room for a single file, discarding data as per overflow policy
+    fn overflow_policy_decides_data_discarded() {
+        let path = format!("{}/overflow", PERSIST_FOLDER);
+        let cases = [
+            (OverflowPolicy::DropOldest, 2, b'2'),
+            (OverflowPolicy::DropNewest, 2, b'0'),
+            (OverflowPolicy::Block, 0, b'0'),
+        ];
+
+        for (policy, lost, first) in cases {
+            let _ = std::fs::remove_dir_all(&path);
+            std::fs::create_dir_all(&path).unwrap();
+            let mut storage = Storage::new(&path, 1024, 1).unwrap();
+
+            let mut discarded = 0;
+            for i in 0..3 {
+                let payload = i.to_string().repeat(1024);
+                let mut publish = Publish::new("hello/world", QoS::AtLeastOnce, payload.as_bytes());
+                publish.pkid = 1;
+                publish.write(storage.writer()).unwrap();
+                discarded += flush_on_overflow(&mut storage, policy).unwrap();
+            }
+
+            assert_eq!(discarded, lost, "{:?}", policy);
+            // Blocked data is held in memory, till data on disk is read
+            assert_eq!(storage.is_full(), policy == OverflowPolicy::Block);
+            let stored = read_from_storage(&mut storage, 2048);
+            assert_eq!(stored.payload[0], first, "{:?}", policy);
+        }
+    }
+
+    #[test]
+    // Payloads of every format, of any length, are told apart from compressed, encrypted and
+    // stamped ones by their first byte
+    fn payloads_never_start_with_a_marker() {
+        let markers = [COMPRESSION_MARKER, ENCRYPTION_MARKER, crate::base::replay::ID_MARKER];
+        let point = serde_json::json!({"sequence": 1, "timestamp": 0});
+        for len in [0, 1, 15, 16, 255, 256, 65535, 65536] {
+            let array = Value::Array(vec![point.clone(); len]);
+            let map: serde_json::Map<String, Value> =
+                (0..len).map(|i| (i.to_string(), point.clone())).collect();
+            for format in [PayloadFormat::Json, PayloadFormat::Cbor, PayloadFormat::MsgPack] {
+                for value in [&array, &Value::Object(map.clone())] {
+                    let first = format.encode(value).unwrap()[0];
+                    assert!(!markers.contains(&first), "{:?} of {} starts {}", format, len, first);
+                }
+            }
+        }
+    }
+
+    #[test]
+    // Write compressed publishes to storage and verify that they read back byte identical
+    fn compressed_storage_round_trip() {
+        let config = Arc::new(config_with_persistence(format!("{}/compression", PERSIST_FOLDER)));
+        let (mut serializer, _, _) = defaults(config);
+        let mut storage = serializer.storage.take().unwrap();
+        let max_packet_size = serializer.config.max_packet_size;
+
+        let payload = "[{\"sequence\":1,\"timestamp\":0,\"msg\":\"Hello, World!\"}]".repeat(10);
+        for compression in [Compression::None, Compression::Lz4, Compression::Zstd] {
+            let mut publish = Publish::new(
+                "hello/world",
+                QoS::AtLeastOnce,
+                compress(compression, payload.as_bytes().to_vec()),
+            );
+            publish.pkid = 1;
+            write_to_storage(&mut storage, &publish);
+
+            let stored = read_from_storage(&mut storage, max_packet_size);
+            assert_eq!(&decompress(stored.payload).unwrap()[..], payload.as_bytes());
+        }
+    }
+
+    #[test]
+    // Write encrypted publishes to storage and verify that they read back byte identical
+    fn encrypted_storage_round_trip() {
+        let config = Arc::new(config_with_persistence(format!("{}/encryption", PERSIST_FOLDER)));
+        let (mut serializer, _, _) = defaults(config);
+        let mut storage = serializer.storage.take().unwrap();
+        let max_packet_size = serializer.config.max_packet_size;
+        let cipher = Aes256Gcm::new_from_slice(&[7; 32]).unwrap();
+
+        let payload = "[{\"sequence\":1,\"timestamp\":0,\"msg\":\"Hello, World!\"}]".repeat(10);
+        for compression in [Compression::None, Compression::Zstd] {
+            let compressed = compress(compression, payload.as_bytes().to_vec());
+            let encrypted = encrypt(Some(&cipher), compressed).unwrap();
+            let mut publish = Publish::new("hello/world", QoS::AtLeastOnce, encrypted);
+            publish.pkid = 1;
+            write_to_storage(&mut storage, &publish);
+
+            let stored = read_from_storage(&mut storage, max_packet_size);
+            assert!(!stored.payload.windows(5).any(|w| w == b"Hello"));
+            let decrypted = decrypt(Some(&cipher), stored.payload.clone()).unwrap();
+            assert_eq!(&decompress(decrypted).unwrap()[..], payload.as_bytes());
+
+            // Payloads can't be read back without the right key
+            let other = Aes256Gcm::new_from_slice(&[8; 32]).unwrap();
+            let decrypted = decrypt(Some(&other), stored.payload.clone());
+            assert!(matches!(decrypted, Err(super::Error::Decryption)));
+            let decrypted = decrypt(None, stored.payload);
+            assert!(matches!(decrypted, Err(super::Error::MissingEncryptionKey)));
+        }
+
+        // Payloads written before encryption was enabled are still readable
+        let plain = Bytes::from(payload.clone());
+        assert_eq!(decrypt(Some(&cipher), plain).unwrap(), payload.as_bytes());
+    }
+
+    #[test]
+    // Collection is paused once data written to disk crosses the high watermark, resumed below low
+    fn backpressure_follows_data_on_disk() {
+        let mut config = config_with_persistence(format!("{}/backpressure", PERSIST_FOLDER));
+        config.backpressure =
+            Some(crate::base::Backpressure { high_watermark: 100, low_watermark: 50 });
+        let (backpressure_tx, backpressure_rx) = watch::channel(false);
+        let (serializer, data_tx, _) = defaults(Arc::new(config));
+        let mut serializer = serializer.with_backpressure(backpressure_tx);
+
+        let mut collector = MockCollector::new(data_tx);
+        std::thread::spawn(move || {
+            for i in 2..4 {
+                collector.send(i).unwrap();
+            }
+        });
+
+        let publish = Publish::new(
+            "hello/world",
+            QoS::AtLeastOnce,
+            "[{\"sequence\":1,\"timestamp\":0,\"msg\":\"Hello, World!\"}]".as_bytes(),
+        );
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let crash = serializer.crash(Arc::new("hello".to_owned()), publish);
+        let crash = time::timeout(time::Duration::from_secs(1), crash);
+        assert!(rt.block_on(crash).is_err());
+        assert!(serializer.metrics.total_disk_size() >= 100);
+        assert!(*backpressure_rx.borrow());
+
+        // Data read back from disk, e.g. during catchup, resumes collection below low watermark
+        let size = serializer.metrics.total_disk_size();
+        serializer.metrics.sub_total_disk_size("hello/world", size - 60);
+        update_backpressure(&serializer.config, &serializer.metrics, &serializer.backpressure_tx);
+        assert!(*backpressure_rx.borrow());
+        serializer.metrics.sub_total_disk_size("hello/world", 20);
+        update_backpressure(&serializer.config, &serializer.metrics, &serializer.backpressure_tx);
+        assert!(!*backpressure_rx.borrow());
+    }
+
+    #[test]
+    // Runs serializer on a network that always fails, retrying it from crash mode with backoff
+    fn crash_mode_retries_network_while_collecting() {
+        let path = format!("{}/crash_retries", PERSIST_FOLDER);
+        let _ = std::fs::remove_dir_all(&path);
+        let config = Arc::new(config_with_persistence(path));
+
+        let (data_tx, data_rx) = flume::bounded(1);
+        // Eventloop is gone, as nothing receives requests sent onto it
+        let (net_tx, _) = flume::bounded(1);
+        let (_, metrics_rx) = flume::bounded(1);
+        let (state_tx, state_rx) = flume::bounded(10);
+        let client = MockClient { net_tx };
+        let mut serializer =
+            Serializer::new(config, data_rx, None, metrics_rx, Some(state_tx), None, client)
+                .unwrap();
+        let mut collector = MockCollector::new(data_tx);
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.spawn(async move { serializer.run().await });
+        collector.send(1).unwrap();
+
+        let states: Vec<SerializerState> =
+            (0..4).map(|_| state_rx.recv_timeout(Duration::from_secs(1)).unwrap()).collect();
+        use SerializerState::*;
+        assert_eq!(states, vec![Catchup, Normal, SlowEventloop, Crash]);
+
+        // Collection carries on while waiting to retry the network
+        for i in 2..4 {
+            collector.send(i).unwrap();
+        }
+
+        // Network is retried after the first backoff, by catching up with data on disk
+        assert_eq!(state_rx.recv_timeout(Duration::from_secs(2)).unwrap(), Catchup);
+        assert_eq!(state_rx.recv_timeout(Duration::from_secs(1)).unwrap(), Crash);
+        // Backoff grows with consecutive crashes
+        assert!(state_rx.recv_timeout(Duration::from_millis(1500)).is_err());
+    }
+
+    #[test]
+    fn crash_backoff_grows_exponentially() {
+        let backoffs: Vec<u64> = (1..=7).map(|crashes| crash_backoff(crashes).as_secs()).collect();
+        assert_eq!(backoffs, vec![1, 2, 4, 8, 16, 30, 30]);
+        assert_eq!(crash_backoff(u32::MAX).as_secs(), 30);
+    }
+
     #[test]
     // Force runs serializer in catchup mode, with empty persistence
     fn catchup_to_normal_empty_persistence() {
@@ -904,6 +3486,176 @@ mod test {
         assert_eq!(status, Status::Normal);
     }
 
+    #[test]
+    // Force runs serializer in catchup mode, on a link that fails sends transiently
+    fn catchup_retries_transient_send_failures() {
+        let path = format!("{}/catchup_retries", PERSIST_FOLDER);
+        let _ = std::fs::remove_dir_all(&path);
+        let mut config = config_with_persistence(path);
+        let persistence = config.persistence.as_mut().unwrap();
+        persistence.send_retries = 2;
+        persistence.retry_backoff_ms = 1;
+
+        let (_data_tx, data_rx) = flume::bounded(1);
+        let (net_tx, net_rx) = flume::bounded(1);
+        let (_, metrics_rx) = flume::bounded(1);
+        let failures = Arc::new(AtomicUsize::new(2));
+        let client = FlakyClient { client: MockClient { net_tx }, failures: failures.clone() };
+        let mut serializer =
+            Serializer::new(Arc::new(config), data_rx, None, metrics_rx, None, None, client)
+                .unwrap();
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+
+        let mut storage = serializer.storage.take().unwrap();
+        let mut publish = Publish::new("hello/world", QoS::AtLeastOnce, "[]".as_bytes());
+        publish.pkid = 1;
+        write_to_storage(&mut storage, &publish);
+        serializer.storage = Some(storage);
+
+        // Publish is delivered within retries
+        assert_eq!(runtime.block_on(serializer.catchup()).unwrap(), Status::Normal);
+        assert!(net_rx.try_recv().is_ok());
+        assert_eq!(serializer.metrics.publish_retries(), 2);
+
+        // Eventloop is considered crashed once retries are exhausted
+        let mut storage = serializer.storage.take().unwrap();
+        write_to_storage(&mut storage, &publish);
+        serializer.storage = Some(storage);
+        failures.store(3, Ordering::SeqCst);
+        let status = runtime.block_on(serializer.catchup()).unwrap();
+        assert!(
+            matches!(status, Status::EventLoopCrash(_, Publish { topic, .. }) if topic == "hello/world")
+        );
+        assert!(net_rx.is_empty());
+        assert_eq!(serializer.metrics.publish_retries(), 4);
+    }
+
+    #[tokio::test]
+    // Sends on the real client are only transient while its eventloop is busy, not once it's gone
+    async fn sends_on_async_client_are_classified() {
+        let options = MqttOptions::new("test", "localhost", 1883);
+        let (client, eventloop) = AsyncClient::new(options, 1);
+        let payload = Bytes::from_static(b"[]");
+        let (qos, timeout) = (QoS::AtLeastOnce, Some(Duration::from_millis(100)));
+        let send = |client: AsyncClient| {
+            let topic = "hello/world".to_owned();
+            send_publish(client, topic, qos, payload.clone(), Duration::ZERO, false, timeout)
+        };
+
+        // Eventloop isn't polled, so its requests are backed up once the first is sent
+        assert!(send(client.clone()).await.is_ok());
+        match send(client.clone()).await {
+            Err(SendFailure { transient, .. }) => assert!(transient),
+            Ok(_) => panic!("Publish taken by a busy eventloop"),
+        }
+
+        drop(eventloop);
+        match send(client).await {
+            Err(SendFailure { transient, publish }) => {
+                assert!(!transient);
+                assert_eq!(publish.topic, "hello/world");
+            }
+            Ok(_) => panic!("Publish taken by a dropped eventloop"),
+        }
+    }
+
+    #[test]
+    // Force runs serializer in catchup mode, with data on disk partly delivered before a restart
+    fn catchup_skips_publishes_delivered_before_restart() {
+        let path = format!("{}/catchup_dedup", PERSIST_FOLDER);
+        let _ = std::fs::remove_dir_all(&path);
+        let mut config = config_with_persistence(path.clone());
+        config.persistence.as_mut().unwrap().dedup_replay = true;
+
+        // Earlier run wrote two publishes onto disk, of which the first was delivered
+        let mut ids = ReplayIds::load(Path::new(&path).join("replay.json"));
+        let payloads: Vec<Vec<u8>> = (1..3)
+            .map(|i| format!("[{{\"sequence\":{},\"timestamp\":0}}]", i).into_bytes())
+            .map(|payload| ids.stamp(payload))
+            .collect();
+        ids.ack("hello/world", 0);
+        ids.flush().unwrap();
+
+        let (mut serializer, _data_tx, net_rx) = defaults(Arc::new(config));
+        let mut storage = serializer.storage.take().unwrap();
+        for payload in payloads {
+            let mut publish = Publish::new("hello/world", QoS::AtLeastOnce, payload);
+            publish.pkid = 1;
+            write_to_storage(&mut storage, &publish);
+        }
+        serializer.storage = Some(storage);
+
+        let network = std::thread::spawn(move || match net_rx.recv().unwrap() {
+            Request::Publish(Publish { payload, .. }) => payload,
+            r => unreachable!("Unexpected request: {:?}", r),
+        });
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let status = runtime.block_on(serializer.catchup()).unwrap();
+        assert_eq!(status, Status::Normal);
+        assert_eq!(&network.join().unwrap()[..], b"[{\"sequence\":2,\"timestamp\":0}]");
+    }
+
+    #[test]
+    // Force runs serializer in catchup mode, receiving data of a high priority stream while
+    // a backlog of other data is being replayed from persistence
+    fn catchup_sends_priority_data_ahead_of_backlog() {
+        let mut config = config_with_persistence(format!("{}/catchup_priority", PERSIST_FOLDER));
+        let critical = crate::base::StreamConfig {
+            topic: Some("critical/events".to_owned()),
+            buf_size: 1,
+            priority: Priority::High,
+            ..Default::default()
+        };
+        config.streams.insert("critical".to_owned(), critical);
+
+        let (mut serializer, data_tx, net_rx) = defaults(Arc::new(config));
+        let mut storage = serializer.storage.take().unwrap();
+
+        // Force write a backlog of publishes into storage
+        for i in 1..6 {
+            let payload = format!("[{{\"sequence\":{i},\"timestamp\":0}}]");
+            let mut publish = Publish::new("hello/world", QoS::AtLeastOnce, payload.as_bytes());
+            publish.pkid = 1;
+            write_to_storage(&mut storage, &publish);
+        }
+        serializer.storage = Some(storage);
+
+        // Critical data is received while backlog is pending on network
+        let mut critical = Stream::new("critical", "critical/events", 1, data_tx);
+        std::thread::spawn(move || {
+            std::thread::sleep(time::Duration::from_millis(500));
+            let payload = Payload {
+                stream: "critical".to_owned(),
+                sequence: 1,
+                timestamp: 0,
+                payload: serde_json::from_str("{\"msg\": \"Engine failure\"}").unwrap(),
+            };
+            critical.push(payload).unwrap();
+            std::thread::sleep(time::Duration::from_secs(100));
+        });
+
+        // Slow network that only starts receiving once critical data was collected
+        let network = std::thread::spawn(move || {
+            std::thread::sleep(time::Duration::from_secs(1));
+            (0..6)
+                .map(|_| match net_rx.recv().unwrap() {
+                    Request::Publish(Publish { topic, .. }) => topic,
+                    r => unreachable!("Unexpected request: {:?}", r),
+                })
+                .collect::<Vec<String>>()
+        });
+
+        let status =
+            tokio::runtime::Runtime::new().unwrap().block_on(serializer.catchup()).unwrap();
+        assert_eq!(status, Status::Normal);
+
+        // Only the publishes already handed to network are ahead of critical data
+        let topics = network.join().unwrap();
+        assert_eq!(topics.iter().position(|t| t == "critical/events"), Some(2));
+        assert_eq!(topics.iter().filter(|t| *t == "hello/world").count(), 5);
+    }
+
     #[test]
     // Force runs serializer in catchup mode, with persistence and crashed network
     fn catchup_to_crash_with_persistence() {
@@ -936,7 +3688,7 @@ mod test {
         // Replace storage into serializer
         serializer.storage = Some(storage);
         match tokio::runtime::Runtime::new().unwrap().block_on(serializer.catchup()).unwrap() {
-            Status::EventLoopCrash(Publish { topic, payload, .. }) => {
+            Status::EventLoopCrash(_, Publish { topic, payload, .. }) => {
                 assert_eq!(topic, "hello/world");
                 let recvd = std::str::from_utf8(&payload).unwrap();
                 assert_eq!(recvd, "[{\"sequence\":1,\"timestamp\":0,\"msg\":\"Hello, World!\"}]");
@@ -944,4 +3696,47 @@ mod test {
             s => unreachable!("Unexpected status: {:?}", s),
         }
     }
+
+    #[test]
+    // Publish being sent is recovered even if the error of a failed send doesn't carry it back
+    fn failed_send_recovers_exact_publish() {
+        let path = format!("{}/lossy_send", PERSIST_FOLDER);
+        let _ = std::fs::remove_dir_all(&path);
+        let config = Arc::new(config_with_persistence(path));
+
+        let (_data_tx, data_rx) = flume::bounded(1);
+        let (_, metrics_rx) = flume::bounded(1);
+        let mut serializer =
+            Serializer::new(config, data_rx, None, metrics_rx, None, None, LossyClient).unwrap();
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+
+        let payload = "[{\"sequence\":1,\"timestamp\":0}]".as_bytes();
+        let mut storage = serializer.storage.take().unwrap();
+        let mut publish = Publish::new("hello/world", QoS::AtLeastOnce, payload);
+        publish.pkid = 1;
+        write_to_storage(&mut storage, &publish);
+        serializer.storage = Some(storage);
+
+        match runtime.block_on(serializer.catchup()).unwrap() {
+            Status::EventLoopCrash(_, Publish { topic, qos, payload: recvd, .. }) => {
+                assert_eq!(
+                    (topic.as_str(), qos, &recvd[..]),
+                    ("hello/world", QoS::AtLeastOnce, payload)
+                );
+            }
+            s => panic!("Unexpected status: {:?}", s),
+        }
+
+        let publish = Publish::new("hello/slow", QoS::ExactlyOnce, payload);
+        let stream = Arc::new("slow".to_owned());
+        match runtime.block_on(serializer.slow(stream, publish)).unwrap() {
+            Status::EventLoopCrash(_, Publish { topic, qos, payload: recvd, .. }) => {
+                assert_eq!(
+                    (topic.as_str(), qos, &recvd[..]),
+                    ("hello/slow", QoS::ExactlyOnce, payload)
+                );
+            }
+            s => panic!("Unexpected status: {:?}", s),
+        }
+    }
 }
@@ -1,4 +1,7 @@
+pub mod schema;
 pub mod simulator;
 pub mod systemstats;
 pub mod tcpjson;
+pub mod tls;
+pub mod transform;
 mod util;
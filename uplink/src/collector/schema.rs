@@ -0,0 +1,118 @@
+//! Schemas that data received on the bridge is validated against, after transforms are applied and before
+//! it is buffered into a stream, so that a misbehaving collector is caught on the device rather than by the
+//! backend. A schema is configured per stream and lists the fields required in the payload, along with their
+//! type. Fields that aren't listed are left unchecked.
+//!
+//! ```toml
+//! [streams.gps.schema]
+//! fields = { lat = "number", lon = "number", fix = "boolean" }
+//! respond = true
+//! ```
+//!
+//! `sequence` and `timestamp` are required of every record regardless of schema, records without them
+//! aren't parsed by the bridge at all.
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Payload is not a JSON object")]
+    NotAnObject,
+    #[error("Field {0} is missing")]
+    Missing(String),
+    #[error("Field {0} is not of type {1:?}")]
+    WrongType(String, FieldType),
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldType {
+    String,
+    /// Any number, integers included
+    Number,
+    Integer,
+    Boolean,
+    Object,
+    Array,
+}
+
+impl FieldType {
+    fn matches(self, value: &Value) -> bool {
+        match self {
+            FieldType::String => value.is_string(),
+            FieldType::Number => value.is_number(),
+            FieldType::Integer => value.is_i64() || value.is_u64(),
+            FieldType::Boolean => value.is_boolean(),
+            FieldType::Object => value.is_object(),
+            FieldType::Array => value.is_array(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Schema {
+    /// Fields required in the payload, by name, along with their type
+    pub fields: BTreeMap<String, FieldType>,
+    /// Respond to the client with a failed action response when a record is rejected
+    #[serde(default)]
+    pub respond: bool,
+}
+
+/// Validates that payload has all fields of schema, with the expected types
+pub fn validate(schema: &Schema, payload: &Value) -> Result<(), Error> {
+    let fields = payload.as_object().ok_or(Error::NotAnObject)?;
+    for (name, kind) in &schema.fields {
+        match fields.get(name) {
+            Some(value) if kind.matches(value) => continue,
+            Some(_) => return Err(Error::WrongType(name.to_owned(), *kind)),
+            None => return Err(Error::Missing(name.to_owned())),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn schema() -> Schema {
+        let fields = BTreeMap::from([
+            ("lat".to_owned(), FieldType::Number),
+            ("fix".to_owned(), FieldType::Boolean),
+            ("satellites".to_owned(), FieldType::Integer),
+        ]);
+        Schema { fields, respond: false }
+    }
+
+    #[test]
+    fn payloads_matching_schema_are_accepted() {
+        let payload = json!({"lat": 12.9, "fix": true, "satellites": 7, "extra": "ignored"});
+        assert!(validate(&schema(), &payload).is_ok());
+
+        // Integers are numbers too
+        let payload = json!({"lat": 12, "fix": false, "satellites": 0});
+        assert!(validate(&schema(), &payload).is_ok());
+    }
+
+    #[test]
+    fn missing_and_mistyped_fields_are_rejected() {
+        let payload = json!({"lat": 12.9, "fix": true});
+        assert!(
+            matches!(validate(&schema(), &payload), Err(Error::Missing(f)) if f == "satellites")
+        );
+
+        let payload = json!({"lat": "12.9", "fix": true, "satellites": 7});
+        assert!(matches!(
+            validate(&schema(), &payload),
+            Err(Error::WrongType(f, FieldType::Number)) if f == "lat"
+        ));
+
+        let payload = json!({"lat": 12.9, "fix": true, "satellites": 7.5});
+        assert!(matches!(validate(&schema(), &payload), Err(Error::WrongType(..))));
+        assert!(matches!(validate(&schema(), &json!([1, 2])), Err(Error::NotAnObject)));
+    }
+}
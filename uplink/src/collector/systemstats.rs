@@ -10,7 +10,7 @@ use std::{
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use crate::base::{self, Buffer, Config, Package, Point, Stream};
+use crate::base::{self, serialize_timestamp, Buffer, Config, Package, Point, Stream};
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -23,6 +23,7 @@ type Pid = u32;
 #[derive(Debug, Default, Serialize, Clone)]
 pub struct System {
     sequence: u32,
+    #[serde(serialize_with = "serialize_timestamp")]
     timestamp: u64,
     kernel_version: String,
     uptime: u64,
@@ -75,6 +76,10 @@ impl Point for System {
 }
 
 impl Package for Buffer<System> {
+    fn stream(&self) -> Arc<String> {
+        self.stream.clone()
+    }
+
     fn topic(&self) -> Arc<String> {
         self.topic.clone()
     }
@@ -86,6 +91,10 @@ impl Package for Buffer<System> {
     fn anomalies(&self) -> Option<(String, usize)> {
         self.anomalies()
     }
+
+    fn anomaly_counts(&self) -> Vec<(String, usize)> {
+        self.anomaly_counts()
+    }
 }
 
 struct SystemStats {
@@ -105,6 +114,7 @@ impl SystemStats {
 #[derive(Debug, Serialize, Clone)]
 struct Network {
     sequence: u32,
+    #[serde(serialize_with = "serialize_timestamp")]
     timestamp: u64,
     name: String,
     incoming_data_rate: f64,
@@ -146,6 +156,10 @@ impl Point for Network {
 }
 
 impl Package for Buffer<Network> {
+    fn stream(&self) -> Arc<String> {
+        self.stream.clone()
+    }
+
     fn topic(&self) -> Arc<String> {
         self.topic.clone()
     }
@@ -157,6 +171,10 @@ impl Package for Buffer<Network> {
     fn anomalies(&self) -> Option<(String, usize)> {
         self.anomalies()
     }
+
+    fn anomaly_counts(&self) -> Vec<(String, usize)> {
+        self.anomaly_counts()
+    }
 }
 
 struct NetworkStats {
@@ -184,6 +202,7 @@ impl NetworkStats {
 #[derive(Debug, Serialize, Default, Clone)]
 struct Disk {
     sequence: u32,
+    #[serde(serialize_with = "serialize_timestamp")]
     timestamp: u64,
     name: String,
     total: u64,
@@ -216,6 +235,10 @@ impl Point for Disk {
 }
 
 impl Package for Buffer<Disk> {
+    fn stream(&self) -> Arc<String> {
+        self.stream.clone()
+    }
+
     fn topic(&self) -> Arc<String> {
         self.topic.clone()
     }
@@ -227,6 +250,10 @@ impl Package for Buffer<Disk> {
     fn anomalies(&self) -> Option<(String, usize)> {
         self.anomalies()
     }
+
+    fn anomaly_counts(&self) -> Vec<(String, usize)> {
+        self.anomaly_counts()
+    }
 }
 
 struct DiskStats {
@@ -251,6 +278,7 @@ impl DiskStats {
 #[derive(Debug, Default, Serialize, Clone)]
 struct Processor {
     sequence: u32,
+    #[serde(serialize_with = "serialize_timestamp")]
     timestamp: u64,
     name: String,
     frequency: u64,
@@ -281,6 +309,10 @@ impl Point for Processor {
 }
 
 impl Package for Buffer<Processor> {
+    fn stream(&self) -> Arc<String> {
+        self.stream.clone()
+    }
+
     fn topic(&self) -> Arc<String> {
         self.topic.clone()
     }
@@ -292,6 +324,10 @@ impl Package for Buffer<Processor> {
     fn anomalies(&self) -> Option<(String, usize)> {
         self.anomalies()
     }
+
+    fn anomaly_counts(&self) -> Vec<(String, usize)> {
+        self.anomaly_counts()
+    }
 }
 
 struct ProcessorStats {
@@ -315,6 +351,7 @@ impl ProcessorStats {
 #[derive(Debug, Default, Serialize, Clone)]
 struct Process {
     sequence: u32,
+    #[serde(serialize_with = "serialize_timestamp")]
     timestamp: u64,
     pid: Pid,
     name: String,
@@ -357,6 +394,10 @@ impl Point for Process {
 }
 
 impl Package for Buffer<Process> {
+    fn stream(&self) -> Arc<String> {
+        self.stream.clone()
+    }
+
     fn topic(&self) -> Arc<String> {
         self.topic.clone()
     }
@@ -368,6 +409,10 @@ impl Package for Buffer<Process> {
     fn anomalies(&self) -> Option<(String, usize)> {
         self.anomalies()
     }
+
+    fn anomaly_counts(&self) -> Vec<(String, usize)> {
+        self.anomaly_counts()
+    }
 }
 
 struct ProcessStats {
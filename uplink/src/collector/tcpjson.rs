@@ -1,21 +1,42 @@
+use bytes::{Bytes, BytesMut};
 use flume::{Receiver, RecvError, Sender};
 use futures_util::SinkExt;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use thiserror::Error;
+use tokio::io::{stdin, stdout, AsyncRead, AsyncWrite, ReadBuf, Stdin, Stdout};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::time::{Duration, Sleep};
-use tokio::{select, time};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::watch;
+use tokio::time::{interval, sleep, timeout, Duration, Instant};
+use tokio::{select, task};
+use tokio_rustls::TlsAcceptor;
 use tokio_stream::StreamExt;
-use tokio_util::codec::{Framed, LinesCodec, LinesCodecError};
+use tokio_util::codec::{
+    Decoder, Encoder, Framed, LengthDelimitedCodec, LinesCodec, LinesCodecError,
+};
+use tracing::Instrument;
 
-use std::{collections::HashMap, io, sync::Arc};
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
 use std::pin::Pin;
+use std::string::FromUtf8Error;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use super::util::DelayMap;
+use super::{schema, tls, transform};
 use crate::base::actions::{Action, ActionResponse, Error as ActionsError};
-use crate::base::{Buffer, Config, Package, Point, Stream, StreamStatus};
+use crate::base::{
+    serialize_timestamp, BridgeFraming, BridgeInput, Buffer, Config, InflightPolicy, Package,
+    PayloadError, PayloadFormat, Point, Stream, StreamConfig, StreamStatus, DEFAULT_TIMEOUT,
+};
+use crate::config::MIN_POINT_SIZE;
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -25,21 +46,287 @@ pub enum Error {
     Recv(#[from] RecvError),
     #[error("Stream done")]
     StreamDone,
-    #[error("Lines codec error {0}")]
-    Codec(#[from] LinesCodecError),
+    #[error("Codec error {0}")]
+    Codec(#[from] CodecError),
     #[error("Serde error {0}")]
     Json(#[from] serde_json::error::Error),
     #[error("Download OTA error")]
     Actions(#[from] ActionsError),
     #[error("Couldn't fill stream")]
     Stream(#[from] crate::base::Error),
+    #[error("Invalid bridge address {0}")]
+    Addr(String),
+    #[error("Couldn't bind bridge onto {0}. Error = {1}")]
+    Bind(String, io::Error),
+    #[error("Nothing received from client for {0:?}")]
+    Idle(Duration),
+    #[error("Bridge TLS error {0}")]
+    Tls(#[from] tls::Error),
+    #[error("TLS handshake failed. Error = {0}")]
+    Handshake(io::Error),
+}
+
+/// Stream onto which records that don't name a stream are dead-lettered,
+/// in case a `default_stream` isn't configured.
+pub const DEAD_LETTER_STREAM: &str = "dead_letter";
+
+// Clients are never considered idle when `bridge_idle_timeout_secs` isn't configured,
+// the idle timer is then armed with this duration only to be never polled
+const NO_IDLE_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Maximum number of streams a client can register, or have created dynamically from
+/// `default_stream_config`, beyond which data of streams not seen yet is dropped.
+const MAX_BRIDGE_STREAMS: usize = 20;
+
+// Clients that don't complete the TLS handshake within this duration are disconnected
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Clients connected to the bridge, in the order they were accepted. Data is collected from all
+/// clients, whereas actions are only forwarded to the designated client, which is the oldest of
+/// the connected clients. When the designated client disconnects, the next oldest client takes
+/// over, while actions received with no client connected are failed as "Bridge down".
+#[derive(Clone)]
+struct Clients {
+    connected: Arc<Mutex<Connected>>,
+    designated_tx: Arc<watch::Sender<Option<u64>>>,
+    designated_rx: watch::Receiver<Option<u64>>,
+}
+
+#[derive(Default)]
+struct Connected {
+    ids: Vec<u64>,
+    next_id: u64,
+}
+
+impl Clients {
+    fn new() -> Clients {
+        let (designated_tx, designated_rx) = watch::channel(None);
+        let connected = Arc::new(Mutex::new(Connected::default()));
+        Clients { connected, designated_tx: Arc::new(designated_tx), designated_rx }
+    }
+
+    /// Registers a new client, designating it to handle actions if it is the only client
+    fn connect(&self) -> u64 {
+        let mut connected = self.connected.lock().unwrap();
+        let id = connected.next_id;
+        connected.next_id += 1;
+        connected.ids.push(id);
+        if connected.ids.len() == 1 {
+            let _ = self.designated_tx.send(Some(id));
+        }
+
+        id
+    }
+
+    /// Removes a client, designating the next oldest client to handle actions
+    fn disconnect(&self, id: u64) {
+        let mut connected = self.connected.lock().unwrap();
+        connected.ids.retain(|client| *client != id);
+        let designated = connected.ids.first().copied();
+        if *self.designated_rx.borrow() != designated {
+            let _ = self.designated_tx.send(designated);
+        }
+    }
+
+    /// Number of clients connected
+    fn count(&self) -> usize {
+        self.connected.lock().unwrap().ids.len()
+    }
+
+    /// Watches the id of the client designated to handle actions
+    fn designated(&self) -> watch::Receiver<Option<u64>> {
+        self.designated_rx.clone()
+    }
+}
+
+/// Listener on which the bridge accepts clients, a unix domain socket
+/// if `bridge_socket` is configured, a tcp port otherwise. Unix domain sockets
+/// are only supported on unix.
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+    // Accepts stdio as the only client, set once it is accepted
+    Stdio(AtomicBool),
+}
+
+enum Connection {
+    Tcp(TcpStream, SocketAddr),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    Stdio(Stdio),
 }
 
+impl Listener {
+    async fn accept(&self) -> Result<Connection, io::Error> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok(Connection::Tcp(stream, addr))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                Ok(Connection::Unix(stream))
+            }
+            // Stdin can't be reopened once closed, no other client is accepted after
+            Listener::Stdio(accepted) => match accepted.swap(true, Ordering::Relaxed) {
+                false => Ok(Connection::Stdio(Stdio { stdin: stdin(), stdout: stdout() })),
+                true => std::future::pending().await,
+            },
+        }
+    }
+}
+
+// Client on stdio of uplink, which writes records onto stdin and reads actions from stdout
+struct Stdio {
+    stdin: Stdin,
+    stdout: Stdout,
+}
+
+impl AsyncRead for Stdio {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stdin).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Stdio {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stdout).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stdout).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stdout).poll_shutdown(cx)
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum CodecError {
+    #[error("Lines codec error {0}")]
+    Lines(#[from] LinesCodecError),
+    #[error("Io error {0}")]
+    Io(#[from] io::Error),
+    #[error("Frame isn't valid UTF-8 {0}")]
+    Utf8(#[from] FromUtf8Error),
+    #[error("Record longer than {0} bytes")]
+    Oversized(usize),
+}
+
+/// Codec of records exchanged with clients, as configured by `bridge_framing`. Records are
+/// decoded into, and actions are encoded from, json strings in either framing. Records longer
+/// than `max_length` fail decoding, after which the connection is expected to be closed.
+pub enum BridgeCodec {
+    Lines(LinesCodec),
+    LengthDelimited(LengthDelimitedCodec),
+}
+
+impl BridgeCodec {
+    pub fn new(framing: BridgeFraming, max_length: usize) -> BridgeCodec {
+        match framing {
+            BridgeFraming::Lines => BridgeCodec::Lines(LinesCodec::new_with_max_length(max_length)),
+            BridgeFraming::LengthDelimited => BridgeCodec::LengthDelimited(
+                LengthDelimitedCodec::builder().max_frame_length(max_length).new_codec(),
+            ),
+        }
+    }
+
+    // Lines codec fails long lines with an error of its own, while length delimited codec fails
+    // frames longer than the maximum with an `InvalidData` io error
+    fn map_err(&self, e: CodecError) -> CodecError {
+        match (self, e) {
+            (
+                BridgeCodec::Lines(codec),
+                CodecError::Lines(LinesCodecError::MaxLineLengthExceeded),
+            ) => CodecError::Oversized(codec.max_length()),
+            (BridgeCodec::LengthDelimited(codec), CodecError::Io(e))
+                if e.kind() == io::ErrorKind::InvalidData =>
+            {
+                CodecError::Oversized(codec.max_frame_length())
+            }
+            (_, e) => e,
+        }
+    }
+
+    fn decode_frame(
+        &mut self,
+        src: &mut BytesMut,
+        eof: bool,
+    ) -> Result<Option<String>, CodecError> {
+        match self {
+            BridgeCodec::Lines(codec) if eof => Ok(codec.decode_eof(src)?),
+            BridgeCodec::Lines(codec) => Ok(codec.decode(src)?),
+            BridgeCodec::LengthDelimited(codec) => {
+                let frame = if eof { codec.decode_eof(src)? } else { codec.decode(src)? };
+                match frame {
+                    Some(frame) => Ok(Some(String::from_utf8(frame.to_vec())?)),
+                    None => Ok(None),
+                }
+            }
+        }
+    }
+}
+
+impl Decoder for BridgeCodec {
+    type Item = String;
+    type Error = CodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<String>, CodecError> {
+        self.decode_frame(src, false).map_err(|e| self.map_err(e))
+    }
+
+    // Lines codec returns the last line even if it isn't terminated by a newline
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<String>, CodecError> {
+        self.decode_frame(src, true).map_err(|e| self.map_err(e))
+    }
+}
+
+impl Encoder<String> for BridgeCodec {
+    type Error = CodecError;
+
+    fn encode(&mut self, item: String, dst: &mut BytesMut) -> Result<(), CodecError> {
+        match self {
+            BridgeCodec::Lines(codec) => Ok(codec.encode(item, dst)?),
+            BridgeCodec::LengthDelimited(codec) => Ok(codec.encode(Bytes::from(item), dst)?),
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct Bridge {
     config: Arc<Config>,
     data_tx: Sender<Box<dyn Package>>,
     actions_rx: Receiver<Action>,
     action_status: Stream<ActionResponse>,
+    clients: Clients,
+    // number of records dropped as they were of an unknown stream
+    dropped: Arc<AtomicUsize>,
+    // number of records rejected as they couldn't be parsed, transformed or didn't match schema of
+    // their stream
+    rejected: Arc<AtomicUsize>,
+    // sequence and timestamp of the last record of streams with `sequence_check`, shared by all
+    // clients so that a client that reconnects with its sequence reset is caught as well
+    last_points: Arc<Mutex<HashMap<String, (u32, u64)>>>,
+    // set while too much data is pending on disk, to stop reading data from clients
+    backpressure: watch::Receiver<bool>,
+    // config reloaded while uplink is running, see `config::reload`
+    config_updates: watch::Receiver<Arc<Config>>,
+    // ingest metrics of all clients, in the current metrics interval
+    metrics: Arc<Mutex<BridgeMetrics>>,
+    metrics_stream: Option<Stream<BridgeMetrics>>,
+    // wraps connections of clients in TLS, if `bridge_tls` is configured
+    tls: Option<TlsAcceptor>,
 }
 
 impl Bridge {
@@ -49,48 +336,296 @@ impl Bridge {
         actions_rx: Receiver<Action>,
         action_status: Stream<ActionResponse>,
     ) -> Bridge {
-        Bridge { config, data_tx, actions_rx, action_status }
+        let clients = Clients::new();
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let rejected = Arc::new(AtomicUsize::new(0));
+        let last_points = Arc::new(Mutex::new(HashMap::new()));
+        // Collection is never paused, unless backpressure is watched for
+        let (_, backpressure) = watch::channel(false);
+        let (_, config_updates) = watch::channel(config.clone());
+        let metrics = Arc::new(Mutex::new(BridgeMetrics::default()));
+        let metrics_stream = config.bridge_metrics.as_ref().map(|metrics_config| {
+            Stream::with_config(
+                &"bridge_metrics".to_owned(),
+                &config.project_id,
+                &config.device_id,
+                metrics_config,
+                data_tx.clone(),
+            )
+        });
+        Bridge {
+            config,
+            data_tx,
+            actions_rx,
+            action_status,
+            clients,
+            dropped,
+            rejected,
+            last_points,
+            backpressure,
+            config_updates,
+            metrics,
+            metrics_stream,
+            tls: None,
+        }
+    }
+
+    /// Stops reading data from clients while backpressure is set, as too much data is pending on
+    /// disk. Clients handling actions continue to be read from, so that responses aren't held back.
+    pub fn with_backpressure(mut self, backpressure: watch::Receiver<bool>) -> Bridge {
+        self.backpressure = backpressure;
+        self
+    }
+
+    /// Picks up config reloaded while the bridge is running. Streams are reconfigured on all
+    /// connections, other settings apply to connections made after the reload.
+    pub fn with_config_updates(mut self, config_updates: watch::Receiver<Arc<Config>>) -> Bridge {
+        self.config_updates = config_updates;
+        self
+    }
+
+    // Handles a control message of a client, applying to streams of its connection
+    fn control(
+        &self,
+        control: Control,
+        partitions: &mut HashMap<String, Stream<Payload>>,
+    ) -> ControlResponse {
+        match control {
+            Control::RegisterStream { stream, buf_size, topic, flush_period } => {
+                let config = StreamConfig {
+                    topic,
+                    buf_size,
+                    flush_period: flush_period.unwrap_or(DEFAULT_TIMEOUT),
+                    ..Default::default()
+                };
+                let error = self.register_stream(&stream, &config, partitions).err();
+                match &error {
+                    Some(e) => error!("Failed to register stream {}. Error = {}", stream, e),
+                    None => info!("Registered stream {} with config {:?}", stream, config),
+                }
+
+                ControlResponse {
+                    control: "register_stream".to_owned(),
+                    stream,
+                    error: error.map(|e| e.to_string()),
+                }
+            }
+        }
+    }
+
+    fn register_stream(
+        &self,
+        name: &str,
+        config: &StreamConfig,
+        partitions: &mut HashMap<String, Stream<Payload>>,
+    ) -> Result<(), ControlError> {
+        if name.trim().is_empty() || name == "action_status" {
+            return Err(ControlError::InvalidName(name.to_owned()));
+        }
+
+        if partitions.contains_key(name) || self.config.streams.contains_key(name) {
+            return Err(ControlError::Duplicate(name.to_owned()));
+        }
+
+        if partitions.len() >= MAX_BRIDGE_STREAMS {
+            return Err(ControlError::TooManyStreams);
+        }
+
+        let max_buf_size = self.config.max_packet_size / MIN_POINT_SIZE;
+        if config.buf_size == 0 || config.buf_size > max_buf_size {
+            return Err(ControlError::BufSize(max_buf_size));
+        }
+
+        if let Some(topic) = &config.topic {
+            if topic.trim().is_empty() || topic.contains(['+', '#']) {
+                return Err(ControlError::Topic(topic.to_owned()));
+            }
+        }
+
+        let stream = Stream::with_config(
+            &name.to_owned(),
+            &self.config.project_id,
+            &self.config.device_id,
+            config,
+            self.data_tx.clone(),
+        );
+        partitions.insert(name.to_owned(), stream);
+
+        Ok(())
+    }
+
+    /// Timeout of an action, configured by its name in `action_timeouts`, else `action_timeout_secs`
+    fn action_timeout(&self, name: Option<&String>) -> Duration {
+        let timeout = name.and_then(|name| self.config.action_timeouts.get(name)).copied();
+        Duration::from_secs(timeout.unwrap_or(self.config.action_timeout_secs))
+    }
+
+    /// Streams that are created on demand, without being registered by the client: those
+    /// configured under `streams`, `action_status` and the dead letter stream
+    fn is_known_stream(&self, name: &str) -> bool {
+        self.config.streams.contains_key(name)
+            || name == "action_status"
+            || name == DEAD_LETTER_STREAM
+    }
+
+    /// Number of records dropped by the bridge as they were of an unknown stream while
+    /// `default_stream` isn't configured, a non-zero count points to a misconfigured client.
+    pub fn dropped_records(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Number of records rejected by the bridge as they couldn't be parsed or transformed, or
+    /// didn't match the schema of their stream, a non-zero count points to a misbehaving client.
+    pub fn rejected_records(&self) -> usize {
+        self.rejected.load(Ordering::Relaxed)
     }
 
     pub async fn start(&mut self) -> Result<(), Error> {
+        let stdin = self.config.bridge_input == BridgeInput::Stdin;
+        if let Some(config) = self.config.bridge_tls.as_ref().filter(|_| !stdin) {
+            self.tls = Some(tls::acceptor(config)?);
+            info!("Bridge accepts clients over TLS, authenticated by their certificates");
+        }
+
+        let listener = match &self.config.bridge_socket {
+            _ if stdin => Listener::Stdio(AtomicBool::new(false)),
+            #[cfg(unix)]
+            Some(path) => {
+                // Remove socket left behind by an earlier run, as binding onto it fails otherwise
+                let _ = std::fs::remove_file(path);
+                let listener =
+                    UnixListener::bind(path).map_err(|e| Error::Bind(path.clone(), e))?;
+                Listener::Unix(listener)
+            }
+            // Rejected when validating config, on platforms without unix domain sockets
+            #[cfg(not(unix))]
+            Some(path) => {
+                let e = io::Error::new(io::ErrorKind::Unsupported, "unix domain sockets");
+                return Err(Error::Bind(path.clone(), e));
+            }
+            None => {
+                let addr = format!("{}:{}", self.config.bridge_host, self.config.bridge_port);
+                let addr: SocketAddr = addr.parse().map_err(|_| Error::Addr(addr))?;
+                let listener =
+                    TcpListener::bind(addr).await.map_err(|e| Error::Bind(addr.to_string(), e))?;
+                Listener::Tcp(listener)
+            }
+        };
+        let mut designated = self.clients.designated();
+        let mut metrics_interval = interval(Duration::from_secs(self.config.metrics_interval_secs));
+
         loop {
-            let addr = format!("0.0.0.0:{}", self.config.bridge_port);
-            let listener = TcpListener::bind(&addr).await?;
-
-            let (stream, addr) = loop {
-                select! {
-                    v = listener.accept() =>  {
-                        match v {
-                            Ok(s) => break s,
-                            Err(e) => {
-                                error!("Tcp connection accept error = {:?}", e);
-                                continue;
-                            }
+            let no_clients = designated.borrow().is_none();
+
+            select! {
+                v = listener.accept() =>  {
+                    let connection = match v {
+                        Ok(c) => c,
+                        Err(e) => {
+                            error!("Bridge connection accept error = {:?}", e);
+                            continue;
                         }
-                    }
-                    action = self.actions_rx.recv_async() => {
-                        let action = action?;
-                        error!("Bridge down!! Action ID = {}", action.action_id);
-                        let status = ActionResponse::failure(&action.action_id, "Bridge down");
-                        if let Err(e) = self.action_status.fill(status).await {
-                            error!("Failed to send busy status. Error = {:?}", e);
+                    };
+
+                    let id = self.clients.connect();
+                    match connection {
+                        Connection::Tcp(stream, addr) => {
+                            info!("Accepted new connection from {:?}, client = {}", addr, id);
+                            self.spawn_client(id, stream);
+                        }
+                        #[cfg(unix)]
+                        Connection::Unix(stream) => {
+                            let path = &self.config.bridge_socket;
+                            info!("Accepted new connection on {:?}, client = {}", path, id);
+                            self.spawn_client(id, stream);
+                        }
+                        Connection::Stdio(stream) => {
+                            info!("Collecting from stdin, client = {}", id);
+                            self.spawn_client(id, stream);
                         }
                     }
                 }
-            };
+                action = self.actions_rx.recv_async(), if no_clients => {
+                    let action = action?;
+                    error!("Bridge down!! Action ID = {}", action.action_id);
+                    let status = ActionResponse::failure(&action.action_id, "Bridge down");
+                    if let Err(e) = self.action_status.fill(status).await {
+                        error!("Failed to send busy status. Error = {:?}", e);
+                    }
+                }
+                Ok(_) = designated.changed() => {}
+                Ok(_) = self.config_updates.changed() => {
+                    self.config = self.config_updates.borrow().clone();
+                    info!("Reloaded config, applies to new connections");
+                    let period = Duration::from_secs(self.config.metrics_interval_secs);
+                    if metrics_interval.period() != period {
+                        metrics_interval = interval(period);
+                    }
+                }
+                _ = metrics_interval.tick(), if self.metrics_stream.is_some() => {
+                    let metrics = self.metrics.lock().unwrap().next(self.clients.count());
+                    let stream = self.metrics_stream.as_mut().unwrap();
+                    if let Err(e) = stream.fill(metrics).await {
+                        error!("Failed to publish bridge metrics. Error = {:?}", e);
+                    }
+                }
+            }
+        }
+    }
+
+    // Collect from each client in its own task, so that a failing client doesn't affect others.
+    // The TLS handshake is done within the task as well, to not hold up accepting other clients.
+    fn spawn_client<S>(&self, id: u64, stream: S)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let codec =
+            BridgeCodec::new(self.config.bridge_framing, self.config.bridge_max_record_size);
+        let mut bridge = self.clone();
+        // Logs of a client carry its id, when logged as json
+        let span = tracing::info_span!("bridge", client = id);
+        task::spawn(
+            async move {
+                let result = match bridge.tls.clone() {
+                    Some(acceptor) => match handshake(&acceptor, stream).await {
+                        Ok(stream) => bridge.collect(id, Framed::new(stream, codec)).await,
+                        Err(e) => Err(e),
+                    },
+                    None => bridge.collect(id, Framed::new(stream, codec)).await,
+                };
+                if let Err(e) = result {
+                    error!("Bridge client {} failed. Error = {:?}", id, e);
+                }
+                bridge.clients.disconnect(id);
+            }
+            .instrument(span),
+        );
+    }
 
-            info!("Accepted new connection from {:?}", addr);
-            let framed = Framed::new(stream, LinesCodec::new());
-            if let Err(e) = self.collect(framed).await {
-                error!("Bridge failed. Error = {:?}", e);
+    // Fails actions forwarded to a client that is going away, as their responses would never arrive
+    async fn fail_inflight(
+        &mut self,
+        action_start: &mut HashMap<String, InflightAction>,
+        reason: &str,
+    ) {
+        for (action_id, inflight) in action_start.drain() {
+            tracing::error!(action_id = %action_id, "Failing {} in flight, {}. Action ID = {}", inflight.describe(), reason, action_id);
+            let error = format!("{}: {}", inflight.describe(), reason);
+            let status = ActionResponse::failure(&action_id, error);
+            if let Err(e) = self.action_status.fill(status).await {
+                error!("Failed to fill. Error = {:?}", e);
             }
         }
     }
 
-    pub async fn collect(
+    pub async fn collect<S>(
         &mut self,
-        mut client: Framed<TcpStream, LinesCodec>,
-    ) -> Result<(), Error> {
+        id: u64,
+        mut client: Framed<S, BridgeCodec>,
+    ) -> Result<(), Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
         let mut bridge_partitions = HashMap::new();
         for (name, config) in &self.config.streams {
             let stream = Stream::with_config(
@@ -103,111 +638,279 @@ impl Bridge {
             bridge_partitions.insert(name.to_owned(), stream);
         }
 
-        let mut end = Box::pin(time::sleep(Duration::from_secs(u64::MAX)));
-        struct CurrentAction {
-            id: String,
-            timeout: Pin<Box<Sleep>>,
-        }
-        // - set to None when
-        // -- timeout ends
-        // -- A response with status "Completed" is received
-        // - set to a value when
-        // -- it is currently None and a new action is received
-        // - timeout is updated
-        // -- when a non "Completed" action is received
-        let mut current_action_: Option<CurrentAction> = None;
+        // Actions forwarded to this client, that are yet to be completed. Each action's
+        // timeout is tracked independently and is
+        // - removed when a response with state "Completed" or "Failed" is received
+        // - reset when any other response is received
+        // - failed out to cloud when it times out
+        let mut inflight_actions = DelayMap::new();
+        // Each action in flight, along with the time it was forwarded to client and its timeout
+        let mut action_start: HashMap<String, InflightAction> = HashMap::new();
+        let max_inflight_actions = self.config.max_inflight_actions;
+        let inflight_policy = self.config.inflight_actions_policy;
 
         let mut flush_handler = DelayMap::new();
+        let mut designated = self.clients.designated();
+        let mut backpressure = self.backpressure.clone();
+        let mut config_updates = self.config_updates.clone();
+        // Connections that go quiet, e.g. half-open after the client went away, are closed
+        let idle_timeout = self.config.bridge_idle_timeout_secs.map(Duration::from_secs);
+        let idle = sleep(idle_timeout.unwrap_or(NO_IDLE_TIMEOUT));
+        tokio::pin!(idle);
 
         loop {
+            // Only the designated client handles actions
+            let designated_client = *designated.borrow() == Some(id);
+            // Responses of actions in flight are read even when paused, along with any data
+            // sent before them, as both arrive over the same connection
+            let paused = *backpressure.borrow() && inflight_actions.is_empty();
+            // Paused clients aren't read from, so they can't be told apart from idle ones
+            if let Some(timeout) = idle_timeout.filter(|_| paused) {
+                idle.as_mut().reset(Instant::now() + timeout);
+            }
+
             select! {
-                line = client.next() => {
-                    let line = line.ok_or(Error::StreamDone)??;
+                line = client.next(), if !paused => {
+                    let line = match line {
+                        Some(Ok(line)) => line,
+                        Some(Err(e)) => {
+                            self.fail_inflight(&mut action_start, "Bridge client failed").await;
+                            return Err(e.into());
+                        }
+                        None => {
+                            self.fail_inflight(&mut action_start, "Bridge client disconnected").await;
+                            return Err(Error::StreamDone);
+                        }
+                    };
                     info!("Received line = {:?}", line);
+                    if let Some(timeout) = idle_timeout {
+                        idle.as_mut().reset(Instant::now() + timeout);
+                    }
+                    self.metrics.lock().unwrap().add_line(line.len());
 
-                    let data: Payload = match serde_json::from_str(&line) {
-                        Ok(d) => d,
-                        Err(e) => {
-                            error!("Deserialization error = {:?}", e);
-                            continue
-                        }
+                    // Frames with a json array carry a batch of records, which may be of different streams
+                    let records: Vec<Result<Payload, serde_json::Error>> = match is_batch(&line) {
+                        true => match serde_json::from_str::<Vec<Value>>(&line) {
+                            Ok(records) => records.into_iter().map(serde_json::from_value).collect(),
+                            Err(e) => vec![Err(e)],
+                        },
+                        false => match serde_json::from_str(&line) {
+                            Ok(data) => vec![Ok(data)],
+                            Err(e) => {
+                                // Lines that aren't data could be control messages, which are always responded to
+                                if let Ok(control) = serde_json::from_str::<Control>(&line) {
+                                    let response = self.control(control, &mut bridge_partitions);
+                                    client.send(serde_json::to_string(&response)?).await?;
+                                    continue
+                                }
+                                vec![Err(e)]
+                            }
+                        },
                     };
+                    // Bytes of a batch are counted evenly against each of its records
+                    let size = line.len() / records.len().max(1);
 
-                    // If incoming data is a response for an action, drop it
-                    // if timeout is already sent to cloud
-                    if data.stream == "action_status" {
-                        if current_action_.is_some() {
-                            if let Some(response_id) = data.payload.as_object()
-                                .and_then(|payload| payload.get("action_id"))
-                                .and_then(|id| id.as_str()) {
-                                let action_id = current_action_.as_ref().unwrap().id.as_str();
-                                if action_id == response_id {
-                                    if let Some("Completed") = data.payload.as_object().unwrap().get("state")
-                                        .and_then(|s| s.as_str()) {
-                                        current_action_ = None;
-                                    } else {
-                                        current_action_.as_mut().unwrap().timeout = Box::pin(time::sleep(Duration::from_secs(10)));
+                    for data in records {
+                        let mut data = match data {
+                            Ok(d) => d,
+                            Err(e) => {
+                                let rejected = self.rejected.fetch_add(1, Ordering::Relaxed) + 1;
+                                self.metrics.lock().unwrap().add_deserialization_error();
+                                error!("Deserialization error = {:?}. Rejected records = {}", e, rejected);
+                                if self.config.bridge_acks {
+                                    let ack = Ack::rejected(None, None, format!("Invalid record: {}", e));
+                                    client.send(serde_json::to_string(&ack)?).await?;
+                                }
+                                continue
+                            }
+                        };
+                        resolve_stream(&mut data, self.config.default_stream.as_ref());
+
+                        if let Some(config) = stream_config(&self.config, &data.stream) {
+                            if let Err(e) = transform::apply(&config.transforms, &mut data.payload) {
+                                let rejected = self.rejected.fetch_add(1, Ordering::Relaxed) + 1;
+                                self.metrics.lock().unwrap().add_transform_error();
+                                tracing::error!(stream = %data.stream, "Rejecting data of stream {} that failed to transform. Rejected records = {}. Error = {}", data.stream, rejected, e);
+                                if self.config.bridge_acks {
+                                    let ack = Ack::rejected(Some(data.stream.as_str()), Some(data.sequence), format!("Transform failed: {}", e));
+                                    client.send(serde_json::to_string(&ack)?).await?;
+                                }
+                                continue
+                            }
+
+                            if let Some(schema) = &config.schema {
+                                if let Err(e) = schema::validate(schema, &data.payload) {
+                                    let rejected = self.rejected.fetch_add(1, Ordering::Relaxed) + 1;
+                                    self.metrics.lock().unwrap().add_schema_error();
+                                    tracing::error!(stream = %data.stream, "Rejecting data of stream {} not matching schema. Rejected records = {}. Error = {}", data.stream, rejected, e);
+                                    // Response carries the name of the stream in place of an action id
+                                    if schema.respond {
+                                        let error = format!("Invalid data on stream {}: {}", data.stream, e);
+                                        let response = ActionResponse::failure(&data.stream, error).set_sequence(data.sequence);
+                                        client.send(serde_json::to_string(&response)?).await?;
+                                    }
+                                    if self.config.bridge_acks {
+                                        let ack = Ack::rejected(Some(data.stream.as_str()), Some(data.sequence), format!("Schema mismatch: {}", e));
+                                        client.send(serde_json::to_string(&ack)?).await?;
                                     }
-                                } else {
-                                    error!("action_id in action_status({response_id}) does not match that of active action ({action_id})");
+                                    continue
+                                }
+                            }
+                        }
+
+                        // If incoming data is a response for an action, drop it
+                        // if timeout is already sent to cloud
+                        if data.stream == "action_status" {
+                            let response_id = match data.payload.get("action_id").and_then(|id| id.as_str()) {
+                                Some(id) => id.to_owned(),
+                                None => {
+                                    error!("No valid action_id in action_status stream payload");
                                     continue;
                                 }
-                            } else {
-                                error!("No valid action_id in action_status stream payload");
+                            };
+
+                            if !inflight_actions.contains(&response_id) {
+                                tracing::error!(action_id = %response_id, "Action({response_id}) not in flight or timed out already, ignoring response: {:?}", data);
                                 continue;
                             }
-                        } else {
-                            error!("Action timed out already, ignoring response: {:?}", data);
-                            continue;
+
+                            // Oversized responses fail the action, rather than being forwarded
+                            let size = serde_json::to_vec(&data.payload).map_or(0, |p| p.len());
+                            if let Some(max) = self.config.max_action_payload_size.filter(|max| size > *max) {
+                                let rejected = self.rejected.fetch_add(1, Ordering::Relaxed) + 1;
+                                tracing::error!(action_id = %response_id, "Rejecting response of {} bytes to action({response_id}), larger than {} bytes. Rejected records = {}", size, max, rejected);
+                                inflight_actions.remove(&response_id);
+                                action_start.remove(&response_id);
+                                let error = format!("Response of {} bytes exceeds max_action_payload_size of {} bytes", size, max);
+                                if self.config.bridge_acks {
+                                    let ack = Ack::rejected(Some(data.stream.as_str()), Some(data.sequence), error.clone());
+                                    client.send(serde_json::to_string(&ack)?).await?;
+                                }
+                                if let Err(e) = self.action_status.fill(ActionResponse::failure(&response_id, error)).await {
+                                    error!("Failed to fill. Error = {:?}", e);
+                                }
+                                continue;
+                            }
+
+                            inflight_actions.remove(&response_id);
+                            match data.payload.get("state").and_then(|s| s.as_str()) {
+                                Some(state @ ("Completed" | "Failed")) => {
+                                    let elapsed = action_start.remove(&response_id).map(|inflight| inflight.start.elapsed());
+                                    debug!("Action({response_id}) {state} after {:?}, {} actions in flight", elapsed, inflight_actions.len());
+                                }
+                                _ => {
+                                    let timeout = action_start.get(&response_id).map(|inflight| inflight.timeout);
+                                    inflight_actions.insert(&response_id, timeout.unwrap_or_else(|| self.action_timeout(None)));
+                                }
+                            }
                         }
-                    }
 
-                    let stream = match bridge_partitions.get_mut(&data.stream) {
-                        Some(partition) => partition,
-                        None => {
-                            if bridge_partitions.keys().len() > 20 {
-                                error!("Failed to create {:?} stream. More than max 20 streams", data.stream);
-                                continue
+                        if !bridge_partitions.contains_key(&data.stream) && !self.is_known_stream(&data.stream) {
+                            match &self.config.default_stream {
+                                Some(default_stream) => {
+                                    tracing::warn!(stream = %data.stream, "Unknown stream, routing {:?} onto {:?}", data.stream, default_stream);
+                                    data.stream = default_stream.to_owned();
+                                }
+                                None if self.config.default_stream_config.is_some() && bridge_partitions.len() < MAX_BRIDGE_STREAMS => {}
+                                None => {
+                                    let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                                    self.metrics.lock().unwrap().add_dropped();
+                                    tracing::error!(stream = %data.stream, "Unknown stream, dropping data of {:?}. Dropped records = {}", data.stream, dropped);
+                                    if self.config.bridge_acks {
+                                        let ack = Ack::rejected(Some(data.stream.as_str()), Some(data.sequence), "Unknown stream".to_owned());
+                                        client.send(serde_json::to_string(&ack)?).await?;
+                                    }
+                                    continue
+                                }
                             }
+                        }
+                        if !bridge_partitions.contains_key(&data.stream) {
+                            let stream = match stream_config(&self.config, &data.stream) {
+                                Some(config) => {
+                                    let topic = config.topic.as_ref().map(|t| t.replace("{stream}", &data.stream));
+                                    let config = StreamConfig { topic, ..config.clone() };
+                                    Stream::with_config(&data.stream, &self.config.project_id, &self.config.device_id, &config, self.data_tx.clone())
+                                }
+                                None => Stream::dynamic(&data.stream, &self.config.project_id, &self.config.device_id, self.data_tx.clone()),
+                            };
+                            bridge_partitions.insert(data.stream.clone(), stream);
+                        }
+                        let stream = bridge_partitions.get_mut(&data.stream).unwrap();
 
-                            let stream = Stream::dynamic(&data.stream, &self.config.project_id, &self.config.device_id, self.data_tx.clone());
-                            bridge_partitions.entry(data.stream.clone()).or_insert(stream)
+                        if stream_config(&self.config, &data.stream).map_or(false, |c| c.sequence_check) {
+                            let point = (data.sequence, data.timestamp);
+                            let last = self.last_points.lock().unwrap().insert(data.stream.clone(), point);
+                            if let Some(last) = last {
+                                stream.check_order(last, data.sequence, data.timestamp);
+                            }
                         }
-                    };
 
-                    let max_stream_size = stream.max_buffer_size;
-                    let state = match stream.fill(data).await {
-                        Ok(s) => s,
-                        Err(e) => {
-                            error!("Failed to send data. Error = {:?}", e.to_string());
-                            continue
+                        let max_stream_size = stream.max_buffer_size;
+                        let name = data.stream.clone();
+                        let sequence = data.sequence;
+                        let state = match stream.fill(data).await {
+                            Ok(s) => s,
+                            Err(e) => {
+                                error!("Failed to send data. Error = {:?}", e.to_string());
+                                if self.config.bridge_acks {
+                                    let ack = Ack::rejected(Some(name.as_str()), Some(sequence), e.to_string());
+                                    client.send(serde_json::to_string(&ack)?).await?;
+                                }
+                                continue
+                            }
+                        };
+                        if self.config.bridge_acks {
+                            client.send(serde_json::to_string(&Ack::accepted(&name, sequence))?).await?;
                         }
-                    };
+                        self.metrics.lock().unwrap().add_ingested(name, size);
 
-                    // Remove timeout from flush_handler for selected stream if stream state is flushed,
-                    // do nothing if stream state is partial. Insert a new timeout if initial fill.
-                    // Warn in case stream flushed stream was not in the queue.
-                    if max_stream_size > 1 {
-                        match state {
-                            StreamStatus::Flushed(name) => flush_handler.remove(name),
-                            StreamStatus::Init(name, flush_period) => flush_handler.insert(name, flush_period),
-                            StreamStatus::Partial(l) => {
-                                debug!("Stream contains {} elements", l);
+                        // Remove timeout from flush_handler for selected stream if stream state is flushed,
+                        // do nothing if stream state is partial. Insert a new timeout if initial fill.
+                        // Warn in case stream flushed stream was not in the queue.
+                        if max_stream_size > 1 {
+                            match state {
+                                StreamStatus::Flushed(name) => flush_handler.remove(name),
+                                StreamStatus::Init(name, flush_period) => flush_handler.insert(name, flush_period),
+                                StreamStatus::Partial(l) => {
+                                    debug!("Stream contains {} elements", l);
+                                }
                             }
                         }
                     }
                 }
 
-                action = self.actions_rx.recv_async(), if current_action_.is_none() => {
+                // With the queue policy, actions are left in the channel till an inflight action completes
+                action = self.actions_rx.recv_async(), if designated_client && (inflight_policy == InflightPolicy::Reject || inflight_actions.len() < max_inflight_actions) => {
                     let action = action?;
-                    info!("Received action: {:?}", action);
+                    tracing::info!(action_id = %action.action_id, "Received action: {:?}", action);
+
+                    if inflight_actions.len() >= max_inflight_actions {
+                        tracing::error!(action_id = %action.action_id, "Rejecting action, {} actions already in flight. Action ID = {}", inflight_actions.len(), action.action_id);
+                        let error = format!("Too many actions in flight, limit: {}", max_inflight_actions);
+                        let status = ActionResponse::failure(&action.action_id, error);
+                        if let Err(e) = self.action_status.fill(status).await {
+                            error!("Failed to fill. Error = {:?}", e);
+                        }
+                        continue
+                    }
 
                     match serde_json::to_string(&action) {
+                        // Oversized actions are failed, rather than written in full onto a slow client
+                        Ok(data) if self.config.max_action_payload_size.map_or(false, |max| data.len() > max) => {
+                            let max = self.config.max_action_payload_size.unwrap_or_default();
+                            tracing::error!(action_id = %action.action_id, "Rejecting action of {} bytes, larger than {} bytes. Action ID = {}", data.len(), max, action.action_id);
+                            let error = format!("Action of {} bytes exceeds max_action_payload_size of {} bytes", data.len(), max);
+                            if let Err(e) = self.action_status.fill(ActionResponse::failure(&action.action_id, error)).await {
+                                error!("Failed to fill. Error = {:?}", e);
+                            }
+                            continue
+                        }
                         Ok(data) => {
-                            current_action_ = Some(CurrentAction {
-                                id: action.action_id.clone(),
-                                timeout: Box::pin(time::sleep(Duration::from_secs(10))),
-                            });
+                            let timeout = self.action_timeout(Some(&action.name));
+                            inflight_actions.insert(&action.action_id, timeout);
+                            let inflight = InflightAction { action: action.clone(), start: Instant::now(), timeout };
+                            action_start.insert(action.action_id.clone(), inflight);
+                            debug!("{} actions in flight", inflight_actions.len());
                             client.send(data).await?;
                         },
                         Err(e) => {
@@ -217,17 +920,53 @@ impl Bridge {
                     };
                 }
 
-                _ = &mut current_action_.as_mut().map(|a| &mut a.timeout).unwrap_or(&mut end) => {
-                    let action = current_action_.take().unwrap();
-                    error!("Timeout waiting for action response. Action ID = {}", action.id);
+                Some(action_id) = inflight_actions.next(), if !inflight_actions.is_empty() => {
+                    // Send failure response to cloud, telling which action timed out
+                    let error = match action_start.remove(&action_id) {
+                        Some(inflight) => {
+                            tracing::error!(action_id = %action_id, "Timeout waiting for response of {}. Action ID = {}, in flight for {:?}, payload = {}", inflight.describe(), action_id, inflight.start.elapsed(), inflight.action.payload);
+                            format!("{} timed out after {}s", inflight.describe(), inflight.timeout.as_secs())
+                        }
+                        None => {
+                            tracing::error!(action_id = %action_id, "Timeout waiting for action response. Action ID = {}", action_id);
+                            format!("Action timed out after {}s", self.action_timeout(None).as_secs())
+                        }
+                    };
 
-                    // Send failure response to cloud
-                    let status = ActionResponse::failure(&action.id, "Action timed out");
+                    let status = ActionResponse::failure(&action_id, error);
                     if let Err(e) = self.action_status.fill(status).await {
                         error!("Failed to fill. Error = {:?}", e);
                     }
                 }
 
+                Ok(_) = designated.changed() => {}
+                Ok(_) = backpressure.changed() => {}
+
+                _ = &mut idle, if idle_timeout.is_some() && !paused => {
+                    let timeout = idle_timeout.unwrap();
+                    error!("Nothing received from client {} for {:?}, closing connection", id, timeout);
+                    self.fail_inflight(&mut action_start, "Bridge client unresponsive").await;
+                    return Err(Error::Idle(timeout));
+                }
+
+                Ok(_) = config_updates.changed() => {
+                    self.config = config_updates.borrow().clone();
+                    // Buffered data is flushed as configured when it was collected, before reconfiguring
+                    let config = self.config.clone();
+                    for (name, stream_config) in &config.streams {
+                        if let Some(stream) = bridge_partitions.get_mut(name) {
+                            stream.flush().await?;
+                        }
+                        if flush_handler.contains(name) {
+                            flush_handler.remove(name);
+                        }
+
+                        let stream = Stream::with_config(name, &config.project_id, &config.device_id, stream_config, self.data_tx.clone());
+                        bridge_partitions.insert(name.to_owned(), stream);
+                    }
+                    info!("Reconfigured streams of client {}", id);
+                }
+
                 // Flush stream/partitions that timeout
                 Some(stream) = flush_handler.next(), if !flush_handler.is_empty() => {
                     let stream = bridge_partitions.get_mut(&stream).unwrap();
@@ -239,11 +978,26 @@ impl Bridge {
     }
 }
 
+/// Action forwarded to a client that is yet to be completed, retained so that failures of the
+/// action can tell which action it was, e.g. "process action update_firmware timed out after 60s"
+struct InflightAction {
+    action: Action,
+    // time at which the action was forwarded to the client
+    start: Instant,
+    timeout: Duration,
+}
+
+impl InflightAction {
+    fn describe(&self) -> String {
+        format!("{} action {}", self.action.kind, self.action.name)
+    }
+}
+
 // TODO Don't do any deserialization on payload. Read it a Vec<u8> which is in turn a json
 // TODO which cloud will double deserialize (Batch 1st and messages next)
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Payload {
-    #[serde(skip_serializing)]
+    #[serde(skip_serializing, default)]
     pub stream: String,
     pub sequence: u32,
     pub timestamp: u64,
@@ -257,6 +1011,124 @@ impl Payload {
     }
 }
 
+/// Checks if a frame carries a batch of records, i.e. a json array, rather than a single record
+fn is_batch(line: &str) -> bool {
+    line.trim_start().starts_with('[')
+}
+
+/// Control messages, told apart from data by their "control" field, e.g.
+/// `{"control": "register_stream", "stream": "can", "buf_size": 100, "topic": "/can/raw"}`
+/// registers a stream on the connection of the client, which data can be sent onto right away.
+/// Topic and flush_period are optional, defaulting as they do for streams in config.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "control", rename_all = "snake_case")]
+pub enum Control {
+    RegisterStream {
+        stream: String,
+        buf_size: usize,
+        topic: Option<String>,
+        flush_period: Option<u64>,
+    },
+}
+
+/// Written back to a client for every control message, with an "error" if it failed
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ControlResponse {
+    pub control: String,
+    pub stream: String,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub error: Option<String>,
+}
+
+#[derive(Error, Debug)]
+pub enum ControlError {
+    #[error("Invalid stream name {0:?}")]
+    InvalidName(String),
+    #[error("Stream {0} already exists")]
+    Duplicate(String),
+    #[error("Can't register more than {} streams", MAX_BRIDGE_STREAMS)]
+    TooManyStreams,
+    #[error("buf_size must be in range 1-{0}, to fit max_packet_size")]
+    BufSize(usize),
+    #[error("Invalid topic {0:?}")]
+    Topic(String),
+}
+
+/// Written back to a client for every record received on the bridge, when `bridge_acks` is
+/// enabled. Acks are wrapped in an "ack" object, which actions written onto the same connection
+/// never carry, e.g. `{"ack": {"stream": "gps", "sequence": 3}}`, along with an "error" when
+/// the record wasn't accepted. Records that couldn't be parsed are acked without a stream and
+/// sequence.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Ack {
+    pub ack: AckStatus,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct AckStatus {
+    pub stream: Option<String>,
+    pub sequence: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub error: Option<String>,
+}
+
+impl Ack {
+    pub fn accepted(stream: &str, sequence: u32) -> Ack {
+        let ack =
+            AckStatus { stream: Some(stream.to_owned()), sequence: Some(sequence), error: None };
+        Ack { ack }
+    }
+
+    pub fn rejected(stream: Option<&str>, sequence: Option<u32>, error: String) -> Ack {
+        let ack = AckStatus { stream: stream.map(str::to_owned), sequence, error: Some(error) };
+        Ack { ack }
+    }
+}
+
+// Authenticates client by its certificate, failing if it doesn't complete the handshake in time
+async fn handshake<S>(
+    acceptor: &TlsAcceptor,
+    stream: S,
+) -> Result<tokio_rustls::server::TlsStream<S>, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    match timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+        Ok(Ok(stream)) => Ok(stream),
+        Ok(Err(e)) => Err(Error::Handshake(e)),
+        Err(_) => Err(Error::Handshake(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("no handshake within {:?}", TLS_HANDSHAKE_TIMEOUT),
+        ))),
+    }
+}
+
+// Config of a stream as configured under `streams`, else `default_stream_config`, which doesn't
+// apply to responses of actions as they are published as configured by `action_status`
+fn stream_config<'a>(config: &'a Config, stream: &str) -> Option<&'a StreamConfig> {
+    match config.streams.get(stream) {
+        Some(config) => Some(config),
+        None if stream == "action_status" => None,
+        None => config.default_stream_config.as_ref(),
+    }
+}
+
+// Records missing the "stream" field are assigned to the configured default stream,
+// or are dead-lettered when one isn't configured.
+fn resolve_stream(data: &mut Payload, default_stream: Option<&String>) {
+    if !data.stream.is_empty() {
+        return;
+    }
+
+    match default_stream {
+        Some(stream) => data.stream = stream.to_owned(),
+        None => {
+            warn!("Record without stream, dead-lettering onto {}: {:?}", DEAD_LETTER_STREAM, data);
+            data.stream = DEAD_LETTER_STREAM.to_owned();
+        }
+    }
+}
+
 impl Point for Payload {
     fn sequence(&self) -> u32 {
         self.sequence
@@ -268,6 +1140,116 @@ impl Point for Payload {
 }
 
 impl Package for Buffer<Payload> {
+    fn stream(&self) -> Arc<String> {
+        self.stream.clone()
+    }
+
+    fn topic(&self) -> Arc<String> {
+        self.topic.clone()
+    }
+
+    fn serialize(&self) -> serde_json::Result<Vec<u8>> {
+        serde_json::to_vec(&self.buffer)
+    }
+
+    fn serialize_as(&self, format: PayloadFormat) -> Result<Vec<u8>, PayloadError> {
+        format.encode(&self.buffer)
+    }
+
+    fn anomalies(&self) -> Option<(String, usize)> {
+        self.anomalies()
+    }
+
+    fn anomaly_counts(&self) -> Vec<(String, usize)> {
+        self.anomaly_counts()
+    }
+}
+
+/// Metrics of data received by the bridge from all clients, within an interval of
+/// `metrics_interval_secs`, published onto the `bridge_metrics` stream if configured
+#[derive(Debug, Default, Serialize, Clone)]
+pub struct BridgeMetrics {
+    sequence: u32,
+    #[serde(serialize_with = "serialize_timestamp")]
+    timestamp: u64,
+    connected_clients: usize,
+    // lines received, of which some might not make it onto a stream
+    lines_received: usize,
+    bytes_received: usize,
+    deserialization_errors: usize,
+    transform_errors: usize,
+    schema_errors: usize,
+    // records of unknown streams, dropped beyond the limit of streams per client
+    dropped_records: usize,
+    // messages and bytes of data pushed onto each stream
+    streams: HashMap<String, StreamMetrics>,
+}
+
+#[derive(Debug, Default, Serialize, Clone, PartialEq, Eq)]
+pub struct StreamMetrics {
+    messages: usize,
+    bytes: usize,
+}
+
+impl BridgeMetrics {
+    pub fn add_line(&mut self, size: usize) {
+        self.lines_received += 1;
+        self.bytes_received += size;
+    }
+
+    pub fn add_deserialization_error(&mut self) {
+        self.deserialization_errors += 1;
+    }
+
+    pub fn add_transform_error(&mut self) {
+        self.transform_errors += 1;
+    }
+
+    pub fn add_schema_error(&mut self) {
+        self.schema_errors += 1;
+    }
+
+    pub fn add_dropped(&mut self) {
+        self.dropped_records += 1;
+    }
+
+    pub fn add_ingested(&mut self, stream: String, size: usize) {
+        let metrics = self.streams.entry(stream).or_default();
+        metrics.messages += 1;
+        metrics.bytes += size;
+    }
+
+    /// Metrics of the interval that just ended, counts are reset for the next interval
+    pub fn next(&mut self, connected_clients: usize) -> BridgeMetrics {
+        let timestamp =
+            SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+        let metrics = BridgeMetrics {
+            sequence: self.sequence + 1,
+            timestamp: timestamp.as_millis() as u64,
+            connected_clients,
+            ..std::mem::take(self)
+        };
+        self.sequence = metrics.sequence;
+
+        metrics
+    }
+}
+
+impl Point for BridgeMetrics {
+    fn sequence(&self) -> u32 {
+        self.sequence
+    }
+
+    fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+}
+
+impl Package for Buffer<BridgeMetrics> {
+    fn stream(&self) -> Arc<String> {
+        self.stream.clone()
+    }
+
     fn topic(&self) -> Arc<String> {
         self.topic.clone()
     }
@@ -276,7 +1258,922 @@ impl Package for Buffer<Payload> {
         serde_json::to_vec(&self.buffer)
     }
 
+    fn serialize_as(&self, format: PayloadFormat) -> Result<Vec<u8>, PayloadError> {
+        format.encode(&self.buffer)
+    }
+
     fn anomalies(&self) -> Option<(String, usize)> {
         self.anomalies()
     }
+
+    fn anomaly_counts(&self) -> Vec<(String, usize)> {
+        self.anomaly_counts()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::base::StreamConfig;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn numeric_payloads_are_smaller_as_msgpack() {
+        let mut buffer = Buffer::new(Arc::new("imu".to_owned()), Arc::new("/imu".to_owned()));
+        for i in 0..100 {
+            let line = format!(
+                r#"{{"sequence": {}, "timestamp": {}, "ax": {}, "ay": -0.81, "az": 9.8, "temp": 31}}"#,
+                i + 1,
+                1672531200000u64 + i * 10,
+                i as f64 * 0.01
+            );
+            buffer.buffer.push(Payload::from_string(line).unwrap());
+        }
+
+        let json = buffer.serialize_as(PayloadFormat::Json).unwrap();
+        let msgpack = buffer.serialize_as(PayloadFormat::MsgPack).unwrap();
+        // Integers are encoded in as few bytes as fit them, while floats always take 9 bytes,
+        // making this payload about 14% smaller than json, at 7.2KB against 8.3KB
+        assert!(msgpack.len() < json.len() * 9 / 10, "{} >= 0.9 * {}", msgpack.len(), json.len());
+
+        let points: Vec<Value> = rmp_serde::from_slice(&msgpack).unwrap();
+        let expected: Vec<Value> = serde_json::from_slice(&json).unwrap();
+        assert_eq!(points, expected);
+    }
+
+    #[test]
+    fn payload_with_stream_is_not_rerouted() {
+        let mut data =
+            Payload::from_string(r#"{"stream": "gps", "sequence": 1, "timestamp": 0, "lat": 1.0}"#)
+                .unwrap();
+
+        resolve_stream(&mut data, Some(&"default".to_owned()));
+        assert_eq!(data.stream, "gps");
+
+        resolve_stream(&mut data, None);
+        assert_eq!(data.stream, "gps");
+    }
+
+    #[test]
+    fn payload_without_stream_is_routed_to_default() {
+        let mut data =
+            Payload::from_string(r#"{"sequence": 1, "timestamp": 0, "lat": 1.0}"#).unwrap();
+        assert!(data.stream.is_empty());
+
+        resolve_stream(&mut data, Some(&"default".to_owned()));
+        assert_eq!(data.stream, "default");
+        assert_eq!(data.payload.get("lat"), Some(&Value::from(1.0)));
+    }
+
+    #[test]
+    fn payload_without_stream_is_dead_lettered() {
+        let mut data =
+            Payload::from_string(r#"{"sequence": 1, "timestamp": 0, "lat": 1.0}"#).unwrap();
+
+        resolve_stream(&mut data, None);
+        assert_eq!(data.stream, DEAD_LETTER_STREAM);
+    }
+
+    #[tokio::test]
+    async fn partial_buffer_is_flushed_after_flush_period() {
+        let (data_tx, data_rx) = flume::bounded(10);
+        let (_actions_tx, actions_rx) = flume::bounded(1);
+        let action_status = Stream::new("action_status", "/action/status", 1, data_tx.clone());
+
+        let gps = StreamConfig {
+            topic: Some("/devices/1/events/gps/jsonarray".to_owned()),
+            buf_size: 10,
+            flush_period: 1,
+            ..Default::default()
+        };
+        let streams = HashMap::from([("gps".to_owned(), gps)]);
+        let config = Arc::new(Config { streams, ..Default::default() });
+        let mut bridge = Bridge::new(config, data_tx, actions_rx, action_status);
+
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = Framed::new(client, LinesCodec::new());
+        let line = r#"{"stream": "gps", "sequence": 1, "timestamp": 0, "lat": 1.0}"#;
+        client.send(line.to_owned()).await.unwrap();
+
+        // A single record, well short of buf_size, is delivered once flush_period elapses
+        let collect =
+            bridge.collect(0, Framed::new(server, BridgeCodec::new(BridgeFraming::Lines, 1024)));
+        let package = tokio::time::timeout(Duration::from_secs(3), async {
+            select! {
+                r = collect => panic!("Bridge stopped unexpectedly: {:?}", r),
+                package = data_rx.recv_async() => package.unwrap(),
+            }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(package.stream().as_str(), "gps");
+        let points: Vec<Value> = serde_json::from_slice(&package.serialize().unwrap()).unwrap();
+        assert_eq!(points.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn length_delimited_frames_carry_newlines_and_actions() {
+        let (data_tx, data_rx) = flume::bounded(10);
+        let (actions_tx, actions_rx) = flume::bounded(1);
+        let action_status = Stream::new("action_status", "/action/status", 1, data_tx.clone());
+
+        let logs = StreamConfig {
+            topic: Some("/devices/1/events/logs/jsonarray".to_owned()),
+            buf_size: 1,
+            ..Default::default()
+        };
+        let streams = HashMap::from([("logs".to_owned(), logs)]);
+        let config = Arc::new(Config { streams, ..Default::default() });
+        let mut bridge = Bridge::new(config, data_tx, actions_rx, action_status);
+        let id = bridge.clients.connect();
+
+        let action = Action {
+            device_id: "123".to_owned(),
+            action_id: "1".to_owned(),
+            kind: "process".to_owned(),
+            name: "test".to_owned(),
+            payload: "{}".to_owned(),
+            signature: None,
+        };
+        actions_tx.send(action).unwrap();
+
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = Framed::new(client, LengthDelimitedCodec::new());
+        // Record is pretty printed, spanning multiple lines
+        let record =
+            "{\n\"stream\": \"logs\",\n\"sequence\": 1,\n\"timestamp\": 0,\n\"msg\": \"a\\nb\"\n}";
+        client.send(Bytes::from(record)).await.unwrap();
+
+        let collect = bridge.collect(
+            id,
+            Framed::new(server, BridgeCodec::new(BridgeFraming::LengthDelimited, 1024)),
+        );
+        let (action, package) = tokio::time::timeout(Duration::from_secs(3), async {
+            select! {
+                r = collect => panic!("Bridge stopped unexpectedly: {:?}", r),
+                r = async { (client.next().await, data_rx.recv_async().await) } => r,
+            }
+        })
+        .await
+        .unwrap();
+
+        let action: Value = serde_json::from_slice(&action.unwrap().unwrap()).unwrap();
+        assert_eq!(action.get("action_id"), Some(&Value::from("1")));
+        let points: Vec<Value> =
+            serde_json::from_slice(&package.unwrap().serialize().unwrap()).unwrap();
+        assert_eq!(points[0].get("msg"), Some(&Value::from("a\nb")));
+    }
+
+    #[tokio::test]
+    async fn records_not_matching_schema_are_rejected() {
+        let (data_tx, data_rx) = flume::bounded(10);
+        let (_actions_tx, actions_rx) = flume::bounded(1);
+        let action_status = Stream::new("action_status", "/action/status", 1, data_tx.clone());
+
+        let fields = BTreeMap::from([("lat".to_owned(), schema::FieldType::Number)]);
+        let gps = StreamConfig {
+            topic: Some("/devices/1/events/gps/jsonarray".to_owned()),
+            buf_size: 1,
+            schema: Some(schema::Schema { fields, respond: true }),
+            ..Default::default()
+        };
+        let streams = HashMap::from([("gps".to_owned(), gps)]);
+        let config = Arc::new(Config { streams, ..Default::default() });
+        let mut bridge = Bridge::new(config, data_tx, actions_rx, action_status);
+
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = Framed::new(client, LinesCodec::new());
+        for line in [
+            r#"{"stream": "gps", "sequence": 1, "lat": 1.0}"#,
+            r#"{"stream": "gps", "sequence": 2, "timestamp": 0, "lat": "1.0"}"#,
+            r#"{"stream": "gps", "sequence": 3, "timestamp": 0, "lat": 1.0}"#,
+        ] {
+            client.send(line.to_owned()).await.unwrap();
+        }
+
+        let collect =
+            bridge.collect(0, Framed::new(server, BridgeCodec::new(BridgeFraming::Lines, 1024)));
+        let (response, package) = tokio::time::timeout(Duration::from_secs(3), async {
+            select! {
+                r = collect => panic!("Bridge stopped unexpectedly: {:?}", r),
+                r = async { (client.next().await, data_rx.recv_async().await) } => r,
+            }
+        })
+        .await
+        .unwrap();
+
+        // Only the record with a mistyped field is responded to, the unparsable one is just counted
+        let response: Value = serde_json::from_str(&response.unwrap().unwrap()).unwrap();
+        assert_eq!(response.get("id"), Some(&Value::from("gps")));
+        assert_eq!(response.get("sequence"), Some(&Value::from(2)));
+        assert_eq!(response.get("state"), Some(&Value::from("Failed")));
+        let points: Vec<Value> =
+            serde_json::from_slice(&package.unwrap().serialize().unwrap()).unwrap();
+        assert_eq!(points[0].get("sequence"), Some(&Value::from(3)));
+        assert_eq!(bridge.rejected_records(), 2);
+    }
+
+    #[tokio::test]
+    async fn records_failing_to_transform_are_rejected() {
+        let (data_tx, data_rx) = flume::bounded(10);
+        let (_actions_tx, actions_rx) = flume::bounded(1);
+        let action_status = Stream::new("action_status", "/action/status", 1, data_tx.clone());
+
+        let expr = transform::Expr::try_from("voltage / current".to_owned()).unwrap();
+        let motor = StreamConfig {
+            topic: Some("/devices/1/events/motor/jsonarray".to_owned()),
+            buf_size: 1,
+            transforms: vec![transform::Transform::Compute { field: "ratio".to_owned(), expr }],
+            ..Default::default()
+        };
+        let streams = HashMap::from([("motor".to_owned(), motor)]);
+        let config = Arc::new(Config { streams, ..Default::default() });
+        let mut bridge = Bridge::new(config, data_tx, actions_rx, action_status);
+
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = Framed::new(client, LinesCodec::new());
+        for line in [
+            r#"{"stream": "motor", "sequence": 1, "timestamp": 0, "voltage": 48, "current": 0}"#,
+            r#"{"stream": "motor", "sequence": 2, "timestamp": 0, "voltage": 48, "current": 2}"#,
+        ] {
+            client.send(line.to_owned()).await.unwrap();
+        }
+
+        let collect =
+            bridge.collect(0, Framed::new(server, BridgeCodec::new(BridgeFraming::Lines, 1024)));
+        let package = tokio::time::timeout(Duration::from_secs(3), async {
+            select! {
+                r = collect => panic!("Bridge stopped unexpectedly: {:?}", r),
+                r = data_rx.recv_async() => r,
+            }
+        })
+        .await
+        .unwrap();
+
+        let points: Vec<Value> =
+            serde_json::from_slice(&package.unwrap().serialize().unwrap()).unwrap();
+        assert_eq!(points[0].get("sequence"), Some(&Value::from(2)));
+        assert_eq!(points[0].get("ratio"), Some(&Value::from(24.0)));
+        assert_eq!(bridge.rejected_records(), 1);
+        assert_eq!(bridge.metrics.lock().unwrap().next(1).transform_errors, 1);
+    }
+
+    #[tokio::test]
+    async fn records_are_acked_in_order_when_enabled() {
+        let (data_tx, data_rx) = flume::bounded(10);
+        let (_actions_tx, actions_rx) = flume::bounded(1);
+        let action_status = Stream::new("action_status", "/action/status", 1, data_tx.clone());
+
+        let gps = StreamConfig {
+            topic: Some("/devices/1/events/gps/jsonarray".to_owned()),
+            buf_size: 1,
+            ..Default::default()
+        };
+        let streams = HashMap::from([("gps".to_owned(), gps)]);
+        let config = Arc::new(Config { streams, bridge_acks: true, ..Default::default() });
+        let mut bridge = Bridge::new(config, data_tx, actions_rx, action_status);
+
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = Framed::new(client, LinesCodec::new());
+        for line in [
+            r#"{"stream": "gps", "sequence": 1, "timestamp": 0, "lat": 1.0}"#,
+            r#"{"stream": "gps", "sequence": 2}"#,
+            r#"{"stream": "gps", "sequence": 3, "timestamp": 0, "lat": 1.0}"#,
+        ] {
+            client.send(line.to_owned()).await.unwrap();
+        }
+
+        let collect =
+            bridge.collect(0, Framed::new(server, BridgeCodec::new(BridgeFraming::Lines, 1024)));
+        let acks = tokio::time::timeout(Duration::from_secs(3), async {
+            select! {
+                r = collect => panic!("Bridge stopped unexpectedly: {:?}", r),
+                r = async {
+                    let mut acks = vec![];
+                    for _ in 0..3 {
+                        let ack = client.next().await.unwrap().unwrap();
+                        acks.push(serde_json::from_str::<Ack>(&ack).unwrap());
+                    }
+                    acks
+                } => r,
+            }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(acks[0], Ack::accepted("gps", 1));
+        assert_eq!(acks[1].ack.stream, None);
+        assert!(acks[1].ack.error.is_some());
+        assert_eq!(acks[2], Ack::accepted("gps", 3));
+        assert_eq!(data_rx.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn oversized_records_close_the_connection() {
+        let (data_tx, data_rx) = flume::bounded(10);
+        let (_actions_tx, actions_rx) = flume::bounded(1);
+        let action_status = Stream::new("action_status", "/action/status", 1, data_tx.clone());
+
+        let gps = StreamConfig {
+            topic: Some("/devices/1/events/gps/jsonarray".to_owned()),
+            buf_size: 1,
+            ..Default::default()
+        };
+        let streams = HashMap::from([("gps".to_owned(), gps)]);
+        let config = Arc::new(Config { streams, ..Default::default() });
+        let mut bridge = Bridge::new(config, data_tx, actions_rx, action_status);
+
+        // A malformed record doesn't desync the ones after it, an oversized one ends the connection
+        let (client, server) = tokio::io::duplex(4096);
+        let mut client = Framed::new(client, LinesCodec::new());
+        let oversized = format!(
+            r#"{{"stream": "gps", "sequence": 3, "timestamp": 0, "msg": "{}"}}"#,
+            "a".repeat(200)
+        );
+        for line in [
+            r#"{"stream": "gps", "sequence": 1, "timestamp": 0"#.to_owned(),
+            r#"{"stream": "gps", "sequence": 2, "timestamp": 0}"#.to_owned(),
+            oversized,
+            r#"{"stream": "gps", "sequence": 4, "timestamp": 0}"#.to_owned(),
+        ] {
+            client.send(line).await.unwrap();
+        }
+
+        let framed = Framed::new(server, BridgeCodec::new(BridgeFraming::Lines, 100));
+        let r =
+            tokio::time::timeout(Duration::from_secs(3), bridge.collect(0, framed)).await.unwrap();
+        assert!(matches!(r, Err(Error::Codec(CodecError::Oversized(100)))));
+
+        let points: Vec<Value> =
+            serde_json::from_slice(&data_rx.try_recv().unwrap().serialize().unwrap()).unwrap();
+        assert_eq!(points[0].get("sequence"), Some(&Value::from(2)));
+        assert!(data_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn streams_are_reconfigured_on_reload() {
+        let (data_tx, data_rx) = flume::bounded(10);
+        let (_actions_tx, actions_rx) = flume::bounded(1);
+        let action_status = Stream::new("action_status", "/action/status", 1, data_tx.clone());
+
+        let gps = |topic: &str, buf_size| StreamConfig {
+            topic: Some(topic.to_owned()),
+            buf_size,
+            ..Default::default()
+        };
+        let streams = HashMap::from([("gps".to_owned(), gps("/gps/v1", 10))]);
+        let config = Arc::new(Config { streams, ..Default::default() });
+        let (config_tx, config_updates) = watch::channel(config.clone());
+        let mut bridge = Bridge::new(config, data_tx, actions_rx, action_status)
+            .with_config_updates(config_updates);
+
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = Framed::new(client, LinesCodec::new());
+        let collect =
+            bridge.collect(0, Framed::new(server, BridgeCodec::new(BridgeFraming::Lines, 1024)));
+        let (buffered, reconfigured) = tokio::time::timeout(Duration::from_secs(3), async {
+            select! {
+                r = collect => panic!("Bridge stopped unexpectedly: {:?}", r),
+                r = async {
+                    let record = r#"{"stream": "gps", "sequence": 1, "timestamp": 0}"#;
+                    client.send(record.to_owned()).await.unwrap();
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+
+                    let streams = HashMap::from([("gps".to_owned(), gps("/gps/v2", 1))]);
+                    config_tx.send(Arc::new(Config { streams, ..Default::default() })).unwrap();
+                    let buffered = data_rx.recv_async().await.unwrap();
+
+                    let record = r#"{"stream": "gps", "sequence": 2, "timestamp": 0}"#;
+                    client.send(record.to_owned()).await.unwrap();
+                    (buffered, data_rx.recv_async().await.unwrap())
+                } => r,
+            }
+        })
+        .await
+        .unwrap();
+
+        // Data buffered before the reload is flushed onto the topic it was collected for
+        assert_eq!(buffered.topic().as_str(), "/gps/v1");
+        assert_eq!(reconfigured.topic().as_str(), "/gps/v2");
+        let points: Vec<Value> =
+            serde_json::from_slice(&reconfigured.serialize().unwrap()).unwrap();
+        assert_eq!(points[0].get("sequence"), Some(&Value::from(2)));
+    }
+
+    #[tokio::test]
+    async fn unconfigured_streams_are_created_from_default_config() {
+        let (data_tx, data_rx) = flume::bounded(10);
+        let (_actions_tx, actions_rx) = flume::bounded(1);
+        let action_status = Stream::new("action_status", "/action/status", 1, data_tx.clone());
+
+        let gps = StreamConfig {
+            topic: Some("/devices/1/events/gps/jsonarray".to_owned()),
+            buf_size: 1,
+            ..Default::default()
+        };
+        let default = StreamConfig {
+            topic: Some("/devices/1/streams/{stream}".to_owned()),
+            buf_size: 2,
+            ..Default::default()
+        };
+        let streams = HashMap::from([("gps".to_owned(), gps)]);
+        let config = Arc::new(Config {
+            streams,
+            default_stream_config: Some(default),
+            ..Default::default()
+        });
+        let mut bridge = Bridge::new(config, data_tx, actions_rx, action_status);
+
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = Framed::new(client, LinesCodec::new());
+        for line in [
+            r#"{"stream": "temperature", "sequence": 1, "timestamp": 0}"#,
+            r#"{"stream": "gps", "sequence": 1, "timestamp": 0}"#,
+            r#"{"stream": "temperature", "sequence": 2, "timestamp": 0}"#,
+        ] {
+            client.send(line.to_owned()).await.unwrap();
+        }
+
+        let collect =
+            bridge.collect(0, Framed::new(server, BridgeCodec::new(BridgeFraming::Lines, 1024)));
+        let (gps, temperature) = tokio::time::timeout(Duration::from_secs(3), async {
+            select! {
+                r = collect => panic!("Bridge stopped unexpectedly: {:?}", r),
+                r = async {
+                    (data_rx.recv_async().await.unwrap(), data_rx.recv_async().await.unwrap())
+                } => r,
+            }
+        })
+        .await
+        .unwrap();
+
+        // Configured streams take precedence over the default
+        assert_eq!(gps.topic().as_str(), "/devices/1/events/gps/jsonarray");
+        assert_eq!(temperature.topic().as_str(), "/devices/1/streams/temperature");
+        let points: Vec<Value> = serde_json::from_slice(&temperature.serialize().unwrap()).unwrap();
+        assert_eq!(points.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn streams_registered_by_clients_accept_data_right_away() {
+        let (data_tx, data_rx) = flume::bounded(10);
+        let (_actions_tx, actions_rx) = flume::bounded(1);
+        let action_status = Stream::new("action_status", "/action/status", 1, data_tx.clone());
+
+        let config = Arc::new(Config { max_packet_size: 1024, ..Default::default() });
+        let mut bridge = Bridge::new(config, data_tx, actions_rx, action_status);
+
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = Framed::new(client, LinesCodec::new());
+        for line in [
+            r#"{"control": "register_stream", "stream": "can", "buf_size": 1, "topic": "/can/raw"}"#,
+            r#"{"control": "register_stream", "stream": "can", "buf_size": 1}"#,
+            r#"{"control": "register_stream", "stream": "imu", "buf_size": 1000}"#,
+            r#"{"control": "register_stream", "stream": "imu", "buf_size": 1, "topic": "/imu/#"}"#,
+            r#"{"stream": "can", "sequence": 1, "timestamp": 0}"#,
+        ] {
+            client.send(line.to_owned()).await.unwrap();
+        }
+
+        let collect =
+            bridge.collect(0, Framed::new(server, BridgeCodec::new(BridgeFraming::Lines, 1024)));
+        let (responses, package) = tokio::time::timeout(Duration::from_secs(3), async {
+            select! {
+                r = collect => panic!("Bridge stopped unexpectedly: {:?}", r),
+                r = async {
+                    let mut responses = vec![];
+                    for _ in 0..4 {
+                        let response = client.next().await.unwrap().unwrap();
+                        responses.push(serde_json::from_str::<ControlResponse>(&response).unwrap());
+                    }
+                    (responses, data_rx.recv_async().await.unwrap())
+                } => r,
+            }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(responses[0].error, None);
+        assert!(responses[1].error.as_ref().unwrap().contains("already exists"));
+        assert!(responses[2].error.as_ref().unwrap().contains("buf_size"));
+        assert!(responses[3].error.as_ref().unwrap().contains("topic"));
+        assert_eq!(package.topic().as_str(), "/can/raw");
+        assert_eq!(bridge.rejected_records(), 0);
+    }
+
+    #[tokio::test]
+    async fn sequence_anomalies_are_reported_across_clients() {
+        let (data_tx, data_rx) = flume::bounded(10);
+        let (_actions_tx, actions_rx) = flume::bounded(1);
+        let action_status = Stream::new("action_status", "/action/status", 1, data_tx.clone());
+
+        let can = StreamConfig {
+            topic: Some("/devices/1/events/can/jsonarray".to_owned()),
+            buf_size: 2,
+            sequence_check: true,
+            ..Default::default()
+        };
+        let gps = StreamConfig {
+            topic: Some("/devices/1/events/gps/jsonarray".to_owned()),
+            buf_size: 2,
+            ..Default::default()
+        };
+        let streams = HashMap::from([("can".to_owned(), can), ("gps".to_owned(), gps)]);
+        let config = Arc::new(Config { streams, ..Default::default() });
+        let mut bridge = Bridge::new(config, data_tx, actions_rx, action_status);
+
+        // Sequence skips ahead on first client, then restarts on the next client
+        for sequences in [[1, 2, 4, 5], [1, 2, 3, 4]] {
+            let (client, server) = tokio::io::duplex(64 * 1024);
+            let mut client = Framed::new(client, LinesCodec::new());
+            for stream in ["can", "gps"] {
+                for (sequence, timestamp) in sequences.iter().zip([5, 6, 7, 8]) {
+                    let line = format!(
+                        r#"{{"stream": "{}", "sequence": {}, "timestamp": {}}}"#,
+                        stream, sequence, timestamp
+                    );
+                    client.send(line).await.unwrap();
+                }
+            }
+
+            let collect = bridge
+                .collect(0, Framed::new(server, BridgeCodec::new(BridgeFraming::Lines, 1024)));
+            let _ = tokio::time::timeout(Duration::from_millis(500), collect).await;
+        }
+
+        let (can, gps): (Vec<_>, Vec<_>) =
+            data_rx.drain().partition(|p| p.stream().as_str() == "can");
+        // Streams without sequence_check aren't checked
+        assert!(gps.iter().all(|p| p.anomalies().is_none()));
+        let anomalies: Vec<Option<(String, usize)>> = can.iter().map(|p| p.anomalies()).collect();
+        assert_eq!(
+            anomalies,
+            vec![
+                None,
+                Some(("can.sequence: 4 after 2".to_owned(), 1)),
+                Some(("can.sequence: 1 after 5; can.timestamp: 5 after 8".to_owned(), 2)),
+                None
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn clients_are_not_read_from_under_backpressure() {
+        let (data_tx, data_rx) = flume::bounded(10);
+        let (_actions_tx, actions_rx) = flume::bounded(1);
+        let action_status = Stream::new("action_status", "/action/status", 1, data_tx.clone());
+
+        let gps = StreamConfig {
+            topic: Some("/devices/1/events/gps/jsonarray".to_owned()),
+            buf_size: 1,
+            ..Default::default()
+        };
+        let streams = HashMap::from([("gps".to_owned(), gps)]);
+        let config = Arc::new(Config { streams, ..Default::default() });
+        let (backpressure_tx, backpressure_rx) = watch::channel(true);
+        let mut bridge = Bridge::new(config, data_tx, actions_rx, action_status)
+            .with_backpressure(backpressure_rx);
+
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = Framed::new(client, LinesCodec::new());
+        let line = r#"{"stream": "gps", "sequence": 1, "timestamp": 0}"#;
+        client.send(line.to_owned()).await.unwrap();
+
+        let collect =
+            bridge.collect(0, Framed::new(server, BridgeCodec::new(BridgeFraming::Lines, 1024)));
+        let resume = async {
+            // Nothing is read while paused
+            let paused = tokio::time::timeout(Duration::from_millis(500), data_rx.recv_async());
+            assert!(paused.await.is_err());
+            backpressure_tx.send(false).unwrap();
+            data_rx.recv_async().await.unwrap()
+        };
+        let package = tokio::time::timeout(Duration::from_secs(3), async {
+            select! {
+                r = collect => panic!("Bridge stopped unexpectedly: {:?}", r),
+                package = resume => package,
+            }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(package.stream().as_str(), "gps");
+    }
+
+    #[tokio::test]
+    async fn ingest_is_counted_in_bridge_metrics() {
+        let (data_tx, _data_rx) = flume::bounded(10);
+        let (_actions_tx, actions_rx) = flume::bounded(1);
+        let action_status = Stream::new("action_status", "/action/status", 1, data_tx.clone());
+        let gps = StreamConfig { topic: Some("/gps".to_owned()), ..Default::default() };
+        let streams = HashMap::from([("gps".to_owned(), gps)]);
+        let config = Arc::new(Config { streams, ..Default::default() });
+        let mut bridge = Bridge::new(config, data_tx, actions_rx, action_status);
+
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = Framed::new(client, LinesCodec::new());
+        let lines = [
+            r#"{"stream": "gps", "sequence": 1, "timestamp": 0}"#,
+            r#"{"stream": "gps", "sequence": 2, "timestamp": 0}"#,
+            r#"{"stream": "gps", "sequence": 3"#,
+        ];
+        for line in lines {
+            client.send(line.to_owned()).await.unwrap();
+        }
+
+        let collect =
+            bridge.collect(0, Framed::new(server, BridgeCodec::new(BridgeFraming::Lines, 1024)));
+        let _ = tokio::time::timeout(Duration::from_millis(500), collect).await;
+
+        let metrics = bridge.metrics.lock().unwrap().next(1);
+        assert_eq!(metrics.sequence, 1);
+        assert_eq!(metrics.lines_received, 3);
+        assert_eq!(metrics.bytes_received, lines.iter().map(|l| l.len()).sum::<usize>());
+        assert_eq!(metrics.deserialization_errors, 1);
+        let gps = StreamMetrics { messages: 2, bytes: lines[0].len() + lines[1].len() };
+        assert_eq!(metrics.streams.get("gps"), Some(&gps));
+
+        // Counts are reset for the next interval
+        let metrics = bridge.metrics.lock().unwrap().next(0);
+        assert_eq!(metrics.sequence, 2);
+        assert_eq!(metrics.lines_received, 0);
+        assert!(metrics.streams.is_empty());
+    }
+
+    #[tokio::test]
+    async fn batches_of_records_are_ingested_onto_their_streams() {
+        let (data_tx, _data_rx) = flume::bounded(10);
+        let (_actions_tx, actions_rx) = flume::bounded(1);
+        let action_status = Stream::new("action_status", "/action/status", 1, data_tx.clone());
+        let stream =
+            |topic: &str| StreamConfig { topic: Some(topic.to_owned()), ..Default::default() };
+        let streams =
+            HashMap::from([("gps".to_owned(), stream("/gps")), ("imu".to_owned(), stream("/imu"))]);
+        let config = Arc::new(Config { streams, ..Default::default() });
+        let mut bridge = Bridge::new(config, data_tx, actions_rx, action_status);
+
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = Framed::new(client, LinesCodec::new());
+        // Invalid records of a batch are rejected alone, along with frames that aren't json
+        let lines = [
+            r#" [{"stream": "gps", "sequence": 1, "timestamp": 0}, {"stream": "imu", "sequence": 1, "timestamp": 0}, {"stream": "gps", "sequence": 2}]"#,
+            r#"[{"stream": "gps", "sequence": 3, "timestamp": 0}"#,
+        ];
+        for line in lines {
+            client.send(line.to_owned()).await.unwrap();
+        }
+
+        let collect =
+            bridge.collect(0, Framed::new(server, BridgeCodec::new(BridgeFraming::Lines, 1024)));
+        let _ = tokio::time::timeout(Duration::from_millis(500), collect).await;
+
+        let metrics = bridge.metrics.lock().unwrap().next(1);
+        assert_eq!(metrics.lines_received, 2);
+        assert_eq!(metrics.deserialization_errors, 2);
+        assert_eq!(metrics.streams.get("gps").map(|s| s.messages), Some(1));
+        assert_eq!(metrics.streams.get("imu").map(|s| s.messages), Some(1));
+        assert_eq!(bridge.rejected_records(), 2);
+    }
+
+    #[tokio::test]
+    async fn idle_clients_are_closed_failing_actions_in_flight() {
+        let (data_tx, data_rx) = flume::bounded(10);
+        let (actions_tx, actions_rx) = flume::bounded(1);
+        let action_status = Stream::new("action_status", "/action/status", 1, data_tx.clone());
+        let config = Arc::new(Config {
+            bridge_idle_timeout_secs: Some(1),
+            max_inflight_actions: 1,
+            action_timeout_secs: 10,
+            ..Default::default()
+        });
+        let mut bridge = Bridge::new(config, data_tx, actions_rx, action_status);
+        let id = bridge.clients.connect();
+
+        let action = Action {
+            device_id: "123".to_owned(),
+            action_id: "1".to_owned(),
+            kind: "process".to_owned(),
+            name: "test".to_owned(),
+            payload: "{}".to_owned(),
+            signature: None,
+        };
+        actions_tx.send(action).unwrap();
+
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = Framed::new(client, LinesCodec::new());
+        let collect =
+            bridge.collect(id, Framed::new(server, BridgeCodec::new(BridgeFraming::Lines, 1024)));
+        let r = tokio::time::timeout(Duration::from_secs(5), collect).await.unwrap();
+        assert!(matches!(r, Err(Error::Idle(_))));
+
+        // Action was forwarded, but the client went quiet before responding
+        client.next().await.unwrap().unwrap();
+        let package = data_rx.recv_async().await.unwrap();
+        let responses: Vec<Value> = serde_json::from_slice(&package.serialize().unwrap()).unwrap();
+        assert_eq!(responses[0].get("id"), Some(&Value::from("1")));
+        assert_eq!(responses[0].get("state"), Some(&Value::from("Failed")));
+        assert_eq!(
+            responses[0]["errors"][0],
+            Value::from("process action test: Bridge client unresponsive")
+        );
+    }
+
+    #[tokio::test]
+    async fn oversized_actions_and_responses_fail_the_action() {
+        let (data_tx, data_rx) = flume::bounded(10);
+        let (actions_tx, actions_rx) = flume::bounded(2);
+        let action_status = Stream::new("action_status", "/action/status", 1, data_tx.clone());
+        let config = Arc::new(Config {
+            max_action_payload_size: Some(256),
+            max_inflight_actions: 2,
+            action_timeout_secs: 10,
+            ..Default::default()
+        });
+        let mut bridge = Bridge::new(config, data_tx, actions_rx, action_status);
+        let id = bridge.clients.connect();
+
+        for (action_id, payload) in [("1", "x".repeat(256)), ("2", "{}".to_owned())] {
+            let action = Action {
+                device_id: "123".to_owned(),
+                action_id: action_id.to_owned(),
+                kind: "process".to_owned(),
+                name: "test".to_owned(),
+                payload,
+                signature: None,
+            };
+            actions_tx.send(action).unwrap();
+        }
+
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = Framed::new(client, LinesCodec::new());
+        let collect =
+            bridge.collect(id, Framed::new(server, BridgeCodec::new(BridgeFraming::Lines, 1024)));
+
+        let responder = async {
+            // Only the action within the limit is forwarded, its response is over the limit
+            let action: Action =
+                serde_json::from_str(&client.next().await.unwrap().unwrap()).unwrap();
+            assert_eq!(action.action_id, "2");
+            let response = format!(
+                r#"{{"stream": "action_status", "sequence": 1, "timestamp": 0, "action_id": "2", "state": "Completed", "progress": 100, "errors": [], "result": "{}"}}"#,
+                "x".repeat(256)
+            );
+            client.send(response).await.unwrap();
+
+            let mut failures = vec![];
+            for _ in 0..2 {
+                let package = data_rx.recv_async().await.unwrap();
+                let responses: Vec<Value> =
+                    serde_json::from_slice(&package.serialize().unwrap()).unwrap();
+                failures.push(responses[0].clone());
+            }
+            failures
+        };
+
+        let failures = tokio::time::timeout(Duration::from_secs(5), async {
+            select! {
+                r = collect => panic!("Bridge stopped unexpectedly: {:?}", r),
+                failures = responder => failures,
+            }
+        })
+        .await
+        .unwrap();
+
+        for (failure, action_id) in failures.iter().zip(["1", "2"]) {
+            assert_eq!(failure["id"], action_id);
+            assert_eq!(failure["state"], "Failed");
+            assert!(failure["errors"][0].as_str().unwrap().contains("max_action_payload_size"));
+        }
+    }
+
+    // Pushes a record onto each of the given streams, collecting till the bridge goes idle
+    async fn collect_streams(bridge: &mut Bridge, streams: &[String]) {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let mut client = Framed::new(client, LinesCodec::new());
+        for stream in streams {
+            let line = format!(r#"{{"stream": "{}", "sequence": 1, "timestamp": 0}}"#, stream);
+            client.send(line).await.unwrap();
+        }
+
+        let collect =
+            bridge.collect(0, Framed::new(server, BridgeCodec::new(BridgeFraming::Lines, 1024)));
+        let _ = tokio::time::timeout(Duration::from_millis(500), collect).await;
+    }
+
+    #[tokio::test]
+    async fn unknown_streams_are_routed_to_default_or_dropped() {
+        let streams: Vec<String> = (0..MAX_BRIDGE_STREAMS + 2).map(|i| format!("s{}", i)).collect();
+        let configured = StreamConfig { topic: Some("/s0".to_owned()), ..Default::default() };
+        let configured = HashMap::from([("s0".to_owned(), configured)]);
+
+        // Only data of configured streams is forwarded without a default stream
+        let (data_tx, _data_rx) = flume::bounded(100);
+        let (_actions_tx, actions_rx) = flume::bounded(1);
+        let action_status = Stream::new("action_status", "/action/status", 1, data_tx.clone());
+        let config = Arc::new(Config { streams: configured.clone(), ..Default::default() });
+        let mut bridge = Bridge::new(config, data_tx.clone(), actions_rx.clone(), action_status);
+        collect_streams(&mut bridge, &streams).await;
+        assert_eq!(bridge.dropped_records(), MAX_BRIDGE_STREAMS + 1);
+
+        // Default stream is created on demand, though it is neither configured nor sent onto
+        let action_status = Stream::new("action_status", "/action/status", 1, data_tx.clone());
+        let default_stream = Some("default".to_owned());
+        let config = Arc::new(Config { streams: configured, default_stream, ..Default::default() });
+        let mut bridge = Bridge::new(config, data_tx, actions_rx, action_status);
+        collect_streams(&mut bridge, &streams).await;
+        assert_eq!(bridge.dropped_records(), 0);
+    }
+
+    #[tokio::test]
+    async fn responses_are_correlated_to_actions_in_flight() {
+        let (data_tx, data_rx) = flume::bounded(10);
+        let (actions_tx, actions_rx) = flume::bounded(2);
+        let action_status = Stream::new("action_status", "/action/status", 1, data_tx.clone());
+
+        let status = StreamConfig {
+            topic: Some("/action/status".to_owned()),
+            buf_size: 1,
+            ..Default::default()
+        };
+        let streams = HashMap::from([("action_status".to_owned(), status)]);
+        let config = Arc::new(Config {
+            streams,
+            max_inflight_actions: 2,
+            action_timeout_secs: 10,
+            ..Default::default()
+        });
+        let mut bridge = Bridge::new(config, data_tx, actions_rx, action_status);
+        let id = bridge.clients.connect();
+
+        for action_id in ["1", "2"] {
+            let action = Action {
+                device_id: "123".to_owned(),
+                action_id: action_id.to_owned(),
+                kind: "process".to_owned(),
+                name: "test".to_owned(),
+                payload: "{}".to_owned(),
+                signature: None,
+            };
+            actions_tx.send(action).unwrap();
+        }
+
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = Framed::new(client, LinesCodec::new());
+        let collect =
+            bridge.collect(id, Framed::new(server, BridgeCodec::new(BridgeFraming::Lines, 1024)));
+
+        let responder = async {
+            // Both actions are forwarded before either completes
+            for _ in 0..2 {
+                client.next().await.unwrap().unwrap();
+            }
+
+            for action_id in ["3", "2"] {
+                let response = format!(
+                    r#"{{"stream": "action_status", "sequence": 1, "timestamp": 0, "action_id": "{}", "state": "Completed", "progress": 100, "errors": []}}"#,
+                    action_id
+                );
+                client.send(response).await.unwrap();
+            }
+
+            data_rx.recv_async().await.unwrap()
+        };
+
+        let package = tokio::time::timeout(Duration::from_secs(5), async {
+            select! {
+                r = collect => panic!("Bridge stopped unexpectedly: {:?}", r),
+                package = responder => package,
+            }
+        })
+        .await
+        .unwrap();
+
+        // Response to the unknown action is dropped, the next one is accepted
+        let responses: Vec<Value> = serde_json::from_slice(&package.serialize().unwrap()).unwrap();
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].get("action_id"), Some(&Value::from("2")));
+    }
+
+    #[test]
+    fn oldest_client_is_designated_for_actions() {
+        let clients = Clients::new();
+        let designated = clients.designated();
+        assert_eq!(*designated.borrow(), None);
+
+        let first = clients.connect();
+        let second = clients.connect();
+        let third = clients.connect();
+        assert_eq!(*designated.borrow(), Some(first));
+
+        // Disconnect of a client other than the designated one changes nothing
+        clients.disconnect(second);
+        assert_eq!(*designated.borrow(), Some(first));
+
+        clients.disconnect(first);
+        assert_eq!(*designated.borrow(), Some(third));
+
+        clients.disconnect(third);
+        assert_eq!(*designated.borrow(), None);
+    }
 }
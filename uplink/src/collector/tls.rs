@@ -0,0 +1,101 @@
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::sync::Arc;
+
+use rustls_pemfile::Item;
+use thiserror::Error;
+use tokio_rustls::rustls::server::AllowAnyAuthenticatedClient;
+use tokio_rustls::rustls::{self, Certificate, PrivateKey, RootCertStore, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+use crate::base::BridgeTls;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Couldn't read {0}. Error = {1}")]
+    Read(String, io::Error),
+    #[error("No certificates in {0}")]
+    MissingCertificates(String),
+    #[error("No private key in {0}")]
+    MissingKey(String),
+    #[error("Invalid CA certificate in {0}. Error = {1}")]
+    InvalidCa(String, String),
+    #[error("TLS error {0}")]
+    Rustls(#[from] rustls::Error),
+}
+
+/// Acceptor of TLS connections on the bridge, which presents the configured certificate and
+/// only accepts clients that present a certificate signed by the configured CA
+pub fn acceptor(config: &BridgeTls) -> Result<TlsAcceptor, Error> {
+    let certificates = certificates(&config.certificate)?;
+    let key = private_key(&config.private_key)?;
+
+    let mut roots = RootCertStore::empty();
+    for ca in certificates(&config.ca_certificate)? {
+        roots
+            .add(&ca)
+            .map_err(|e| Error::InvalidCa(config.ca_certificate.clone(), e.to_string()))?;
+    }
+
+    let server = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots))
+        .with_single_cert(certificates, key)?;
+
+    Ok(TlsAcceptor::from(Arc::new(server)))
+}
+
+fn read_pem(path: &str) -> Result<Vec<Item>, Error> {
+    let file = File::open(path).map_err(|e| Error::Read(path.to_owned(), e))?;
+    rustls_pemfile::read_all(&mut BufReader::new(file)).map_err(|e| Error::Read(path.to_owned(), e))
+}
+
+fn certificates(path: &str) -> Result<Vec<Certificate>, Error> {
+    let certificates: Vec<Certificate> = read_pem(path)?
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::X509Certificate(certificate) => Some(Certificate(certificate)),
+            _ => None,
+        })
+        .collect();
+
+    match certificates.is_empty() {
+        true => Err(Error::MissingCertificates(path.to_owned())),
+        false => Ok(certificates),
+    }
+}
+
+// Private key in PKCS#8, PKCS#1(RSA) or SEC1(EC) encoding, the first if there are several
+fn private_key(path: &str) -> Result<PrivateKey, Error> {
+    read_pem(path)?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| Error::MissingKey(path.to_owned()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn misconfigured_files_fail_clearly() {
+        let dir = "/tmp/uplink_bridge_tls_test";
+        std::fs::create_dir_all(dir).unwrap();
+        let empty = format!("{}/empty.pem", dir);
+        std::fs::write(&empty, "not a pem file\n").unwrap();
+
+        let config = BridgeTls {
+            certificate: format!("{}/missing.pem", dir),
+            private_key: empty.clone(),
+            ca_certificate: empty.clone(),
+        };
+        assert!(
+            matches!(acceptor(&config), Err(Error::Read(path, _)) if path == config.certificate)
+        );
+        assert!(matches!(certificates(&empty), Err(Error::MissingCertificates(_))));
+        assert!(matches!(private_key(&empty), Err(Error::MissingKey(_))));
+    }
+}
@@ -0,0 +1,346 @@
+//! Declarative transforms that are applied onto the payload of data received on the bridge, before it is
+//! buffered into a stream. Transforms are configured per stream and are applied in the order of configuration.
+//!
+//! ```toml
+//! [[streams.gps.transforms]]
+//! op = "rename"
+//! from = "lat"
+//! to = "latitude"
+//!
+//! [[streams.motor.transforms]]
+//! op = "compute"
+//! field = "power"
+//! expr = "voltage * current / 1000"
+//! ```
+//!
+//! Expressions of `compute` are parsed when config is loaded and can only refer to numeric fields of the
+//! payload, numbers, the operators `+`, `-`, `*`, `/` and parentheses. No other code is ever executed.
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Unexpected character '{0}' in expression")]
+    UnexpectedChar(char),
+    #[error("Invalid number {0} in expression")]
+    InvalidNumber(String),
+    #[error("Unexpected token {0} in expression")]
+    UnexpectedToken(String),
+    #[error("Unexpected end of expression")]
+    UnexpectedEnd,
+    #[error("Field {0} is missing or not a number")]
+    NotANumber(String),
+    #[error("Field {0} would be set to {1}, which isn't a finite number")]
+    NotFinite(String, f64),
+    #[error("Payload is not a JSON object")]
+    NotAnObject,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Transform {
+    /// Renames field `from` as `to`
+    Rename { from: String, to: String },
+    /// Multiplies a numeric field by `factor`
+    Scale { field: String, factor: f64 },
+    /// Removes field from the payload
+    Drop { field: String },
+    /// Sets field to the result of evaluating `expr` over other numeric fields
+    Compute { field: String, expr: Expr },
+}
+
+/// Applies transforms in order onto payload
+pub fn apply(transforms: &[Transform], payload: &mut Value) -> Result<(), Error> {
+    if transforms.is_empty() {
+        return Ok(());
+    }
+
+    let fields = payload.as_object_mut().ok_or(Error::NotAnObject)?;
+    for transform in transforms {
+        match transform {
+            Transform::Rename { from, to } => {
+                if let Some(value) = fields.remove(from) {
+                    fields.insert(to.to_owned(), value);
+                }
+            }
+            Transform::Scale { field, factor } => {
+                let value = match fields.get(field) {
+                    Some(value) => {
+                        value.as_f64().ok_or_else(|| Error::NotANumber(field.to_owned()))?
+                    }
+                    None => continue,
+                };
+                fields.insert(field.to_owned(), finite(field, value * factor)?);
+            }
+            Transform::Drop { field } => {
+                fields.remove(field);
+            }
+            Transform::Compute { field, expr } => {
+                let value = expr.node.eval(fields)?;
+                fields.insert(field.to_owned(), finite(field, value)?);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// JSON has no representation of infinities and NaN, which would otherwise be set as null
+fn finite(field: &str, value: f64) -> Result<Value, Error> {
+    match value.is_finite() {
+        true => Ok(Value::from(value)),
+        false => Err(Error::NotFinite(field.to_owned(), value)),
+    }
+}
+
+/// An arithmetic expression over numeric fields of a payload, parsed from its source string
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Expr {
+    source: String,
+    node: Node,
+}
+
+impl TryFrom<String> for Expr {
+    type Error = Error;
+
+    fn try_from(source: String) -> Result<Self, Self::Error> {
+        let mut parser = Parser { tokens: tokenize(&source)?, position: 0 };
+        let node = parser.expr()?;
+        if let Some(token) = parser.peek() {
+            return Err(Error::UnexpectedToken(format!("{:?}", token)));
+        }
+
+        Ok(Expr { source, node })
+    }
+}
+
+impl From<Expr> for String {
+    fn from(expr: Expr) -> Self {
+        expr.source
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Number(f64),
+    Field(String),
+    Binary(Box<Node>, Op, Box<Node>),
+}
+
+impl Node {
+    fn eval(&self, fields: &Map<String, Value>) -> Result<f64, Error> {
+        let value = match self {
+            Node::Number(n) => *n,
+            Node::Field(field) => fields
+                .get(field)
+                .and_then(|v| v.as_f64())
+                .ok_or_else(|| Error::NotANumber(field.to_owned()))?,
+            Node::Binary(lhs, op, rhs) => {
+                let (lhs, rhs) = (lhs.eval(fields)?, rhs.eval(fields)?);
+                match op {
+                    Op::Add => lhs + rhs,
+                    Op::Sub => lhs - rhs,
+                    Op::Mul => lhs * rhs,
+                    Op::Div => lhs / rhs,
+                }
+            }
+        };
+
+        Ok(value)
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Token {
+    Number(f64),
+    Ident(String),
+    Op(Op),
+    LParen,
+    RParen,
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, Error> {
+    let mut tokens = vec![];
+    let mut chars = source.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        let token = match c {
+            c if c.is_whitespace() => {
+                chars.next();
+                continue;
+            }
+            '+' => Token::Op(Op::Add),
+            '-' => Token::Op(Op::Sub),
+            '*' => Token::Op(Op::Mul),
+            '/' => Token::Op(Op::Div),
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            c if c.is_ascii_digit() || c == '.' => {
+                let mut number = String::new();
+                while let Some(&c) = chars.peek().filter(|c| c.is_ascii_digit() || **c == '.') {
+                    number.push(c);
+                    chars.next();
+                }
+
+                match number.parse() {
+                    Ok(n) => tokens.push(Token::Number(n)),
+                    Err(_) => return Err(Error::InvalidNumber(number)),
+                }
+                continue;
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let mut ident = String::new();
+                while let Some(&c) = chars.peek().filter(|c| c.is_alphanumeric() || **c == '_') {
+                    ident.push(c);
+                    chars.next();
+                }
+
+                tokens.push(Token::Ident(ident));
+                continue;
+            }
+            c => return Err(Error::UnexpectedChar(c)),
+        };
+
+        chars.next();
+        tokens.push(token);
+    }
+
+    Ok(tokens)
+}
+
+// Recursive descent parser, where `*` and `/` bind tighter than `+` and `-`
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    // expr := term (('+' | '-') term)*
+    fn expr(&mut self) -> Result<Node, Error> {
+        let mut node = self.term()?;
+        while let Some(Token::Op(op @ (Op::Add | Op::Sub))) = self.peek() {
+            let op = *op;
+            self.position += 1;
+            node = Node::Binary(Box::new(node), op, Box::new(self.term()?));
+        }
+
+        Ok(node)
+    }
+
+    // term := factor (('*' | '/') factor)*
+    fn term(&mut self) -> Result<Node, Error> {
+        let mut node = self.factor()?;
+        while let Some(Token::Op(op @ (Op::Mul | Op::Div))) = self.peek() {
+            let op = *op;
+            self.position += 1;
+            node = Node::Binary(Box::new(node), op, Box::new(self.factor()?));
+        }
+
+        Ok(node)
+    }
+
+    // factor := number | field | '(' expr ')'
+    fn factor(&mut self) -> Result<Node, Error> {
+        match self.next() {
+            Some(Token::Number(n)) => Ok(Node::Number(n)),
+            Some(Token::Ident(field)) => Ok(Node::Field(field)),
+            Some(Token::LParen) => {
+                let node = self.expr()?;
+                match self.next() {
+                    Some(Token::RParen) => Ok(node),
+                    Some(token) => Err(Error::UnexpectedToken(format!("{:?}", token))),
+                    None => Err(Error::UnexpectedEnd),
+                }
+            }
+            Some(token) => Err(Error::UnexpectedToken(format!("{:?}", token))),
+            None => Err(Error::UnexpectedEnd),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn expr(source: &str) -> Expr {
+        Expr::try_from(source.to_owned()).unwrap()
+    }
+
+    #[test]
+    fn expressions_respect_precedence() {
+        let fields = json!({"a": 2, "b": 3.5});
+        let fields = fields.as_object().unwrap();
+
+        assert_eq!(expr("a + b * 2").node.eval(fields).unwrap(), 9.0);
+        assert_eq!(expr("(a + b) * 2").node.eval(fields).unwrap(), 11.0);
+        assert_eq!(expr("a - 1 - 1").node.eval(fields).unwrap(), 0.0);
+    }
+
+    #[test]
+    fn invalid_expressions_are_rejected() {
+        assert!(Expr::try_from("a +".to_owned()).is_err());
+        assert!(Expr::try_from("(a + b".to_owned()).is_err());
+        assert!(Expr::try_from("a $ b".to_owned()).is_err());
+        assert!(Expr::try_from("a b".to_owned()).is_err());
+    }
+
+    #[test]
+    fn transforms_are_applied_in_order() {
+        let transforms = vec![
+            Transform::Rename { from: "lat".to_owned(), to: "latitude".to_owned() },
+            Transform::Scale { field: "speed".to_owned(), factor: 3.6 },
+            Transform::Drop { field: "debug".to_owned() },
+            Transform::Compute { field: "power".to_owned(), expr: expr("voltage * current") },
+        ];
+        let mut payload =
+            json!({"lat": 12.9, "speed": 10, "debug": "x", "voltage": 48, "current": 2.5});
+
+        apply(&transforms, &mut payload).unwrap();
+        assert_eq!(
+            payload,
+            json!({"latitude": 12.9, "speed": 36.0, "voltage": 48, "current": 2.5, "power": 120.0})
+        );
+    }
+
+    #[test]
+    fn compute_fails_on_missing_field() {
+        let transforms =
+            vec![Transform::Compute { field: "power".to_owned(), expr: expr("voltage * current") }];
+        let mut payload = json!({"voltage": 48});
+
+        assert!(apply(&transforms, &mut payload).is_err());
+    }
+
+    #[test]
+    fn non_finite_results_are_errors() {
+        let transforms =
+            vec![Transform::Compute { field: "ratio".to_owned(), expr: expr("voltage / current") }];
+        let mut payload = json!({"voltage": 48, "current": 0});
+        assert!(matches!(apply(&transforms, &mut payload), Err(Error::NotFinite(..))));
+        assert_eq!(payload.get("ratio"), None);
+
+        let transforms = vec![Transform::Scale { field: "speed".to_owned(), factor: f64::MAX }];
+        let mut payload = json!({"speed": 10});
+        assert!(matches!(apply(&transforms, &mut payload), Err(Error::NotFinite(..))));
+    }
+}
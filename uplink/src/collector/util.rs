@@ -49,4 +49,14 @@ impl<T: Eq + Hash + Clone + Display> DelayMap<T> {
     pub fn is_empty(&self) -> bool {
         self.queue.is_empty()
     }
+
+    // Check if a timeout exists for item.
+    pub fn contains(&self, item: &T) -> bool {
+        self.map.contains_key(item)
+    }
+
+    // Number of timeouts in queue.
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
 }
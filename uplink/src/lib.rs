@@ -5,7 +5,8 @@ use std::thread;
 use anyhow::Error;
 
 use flume::{bounded, Receiver, Sender};
-use log::error;
+use log::{error, warn};
+use tokio::sync::watch;
 use tokio::task;
 
 pub mod base;
@@ -13,8 +14,10 @@ pub mod collector;
 
 pub mod config {
     use crate::base::StreamConfig;
-    pub use crate::base::{Config, Ota, Persistence, Stats};
+    pub use crate::base::{BridgeInput, Config, LogFormat, Ota, Persistence, Stats};
+    use anyhow::Context;
     use config::{Environment, File, FileFormat};
+    use sha2::{Digest, Sha256};
     use std::fs;
     use structopt::StructOpt;
 
@@ -45,18 +48,60 @@ pub mod config {
         /// list of modules to log
         #[structopt(short = "m", long = "modules")]
         pub modules: Vec<String>,
+        /// export data buffered on disk onto file as NDJSON, then exit
+        #[structopt(short = "e", long = "export", help = "Export data on disk to file")]
+        pub export: Option<String>,
     }
 
     const DEFAULT_CONFIG: &str = r#"
+    failover_brokers = []
+    bridge_host = "0.0.0.0"
     bridge_port = 5555
+    bridge_framing = "lines"
+    bridge_input = "network"
+    bridge_acks = false
+    bridge_max_record_size = 102400
+    max_inflight_actions = 1
+    inflight_actions_policy = "queue"
     run_logcat = true
     max_packet_size = 102400
     max_inflight = 100
+    keep_alive_secs = 60
+    metrics_interval_secs = 10
+    action_timeout_secs = 10
+    action_queue_size = 10
+    payload_format = "json"
+    dry_run = false
+    timestamp_format = "millis"
+    log_format = "plain"
+    prometheus_host = "127.0.0.1"
 
     # Whitelist of binaries which uplink can spawn as a process
     # This makes sure that user is protected against random actions
     # triggered from cloud.
     actions = ["tunshell"]
+    tools_dir = "tools/"
+    download_dir = "/var/tmp/uplink/downloads"
+
+    # Create empty action concurrency map
+    [action_concurrency]
+
+    # Create empty action timeouts map
+    [action_timeouts]
+
+    # Create empty action processes map
+    [action_processes]
+
+    # Create empty action webhooks map
+    [action_webhooks]
+
+    # Create empty action handlers
+    [action_handlers]
+
+    [reconnect]
+    initial_delay_ms = 1000
+    max_delay_ms = 30000
+    mode = "always"
 
     [persistence]
     path = "/tmp/uplink"
@@ -72,6 +117,9 @@ pub mod config {
     topic = "/tenants/{tenant_id}/devices/{device_id}/action/status"
     buf_size = 1
 
+    # Create empty action results map
+    [action_results]
+
     [ota]
     enabled = false
     path = "/var/tmp/ota-file"
@@ -93,48 +141,743 @@ pub mod config {
             .build()?;
 
         let mut config: Config = config.try_deserialize()?;
+        config.config_hash = format!("{:x}", Sha256::digest(uplink_config.as_bytes()));
+        expand_env_vars(&mut config)?;
+        validate(&config)?;
+
+        // Certificates read from files take precedence over those embedded in auth file
+        if let Some(files) = &config.tls_files {
+            let authentication = files.load().context("Failed to load TLS certificates")?;
+            config.authentication = Some(authentication);
+        }
 
         if config.simulator.is_some() {
             config.device_id = "+".to_string();
         }
 
-        if let Some(persistence) = &config.persistence {
-            fs::create_dir_all(&persistence.path)?;
+        if let Some(persistence) = &mut config.persistence {
+            if let Some(instance) = &persistence.instance {
+                let path = std::path::Path::new(&persistence.path).join(instance.trim());
+                persistence.path = path.to_string_lossy().into_owned();
+            }
+            create_persistence_dir(&persistence.path)?;
         }
 
-        // replace placeholders with device/tenant ID
+        // replace placeholders with device/tenant ID and name of the stream
         let tenant_id = config.project_id.trim();
         let device_id = config.device_id.trim();
-        for config in config.streams.values_mut() {
-            replace_topic_placeholders(config, tenant_id, device_id);
+        for (name, config) in config.streams.iter_mut() {
+            replace_topic_placeholders(config, Some(name), tenant_id, device_id);
         }
 
-        replace_topic_placeholders(&mut config.action_status, tenant_id, device_id);
+        // {stream} is replaced by the bridge, with the name of each stream created from it
+        if let Some(config) = &mut config.default_stream_config {
+            replace_topic_placeholders(config, None, tenant_id, device_id);
+        }
+
+        let action_status = Some("action_status");
+        replace_topic_placeholders(&mut config.action_status, action_status, tenant_id, device_id);
+        for (name, config) in config.action_results.iter_mut() {
+            replace_topic_placeholders(config, Some(name), tenant_id, device_id);
+        }
 
         if let Some(config) = &mut config.serializer_metrics {
-            replace_topic_placeholders(config, tenant_id, device_id);
+            replace_topic_placeholders(config, Some("metrics"), tenant_id, device_id);
+        }
+
+        if let Some(config) = &mut config.bridge_metrics {
+            replace_topic_placeholders(config, Some("bridge_metrics"), tenant_id, device_id);
+        }
+
+        if let Some(aggregation) = &mut config.aggregation {
+            aggregation.topic = aggregation
+                .topic
+                .replace("{tenant_id}", tenant_id)
+                .replace("{device_id}", device_id);
+        }
+
+        if let Some(hello) = &mut config.hello {
+            hello.topic =
+                hello.topic.replace("{tenant_id}", tenant_id).replace("{device_id}", device_id);
+        }
+
+        if let Some(will) = &mut config.last_will {
+            for field in [&mut will.topic, &mut will.payload] {
+                *field = field.replace("{tenant_id}", tenant_id).replace("{device_id}", device_id);
+            }
         }
 
         Ok(config)
     }
 
-    // Replace placeholders in topic strings with configured values for tenant_id and device_id
-    fn replace_topic_placeholders(config: &mut StreamConfig, tenant_id: &str, device_id: &str) {
+    // Expand references to environment variables in fields identifying the device, the broker
+    // to connect with and paths of files, so that they can be injected at deployment
+    fn expand_env_vars(config: &mut Config) -> Result<(), anyhow::Error> {
+        let mut fields = vec![
+            &mut config.project_id,
+            &mut config.device_id,
+            &mut config.broker,
+            &mut config.bridge_host,
+            &mut config.prometheus_host,
+            &mut config.tools_dir,
+            &mut config.download_dir,
+            &mut config.ota.path,
+        ];
+        fields.extend(config.failover_brokers.iter_mut().map(|b| &mut b.host));
+        fields.extend(config.client_id.as_mut());
+        fields.extend(config.bridge_socket.as_mut());
+        fields.extend(config.log_dir.as_mut());
+        fields.extend(config.metrics_path.as_mut());
+        if let Some(credentials) = &mut config.credentials {
+            fields.extend([&mut credentials.username, &mut credentials.password]);
+        }
+        if let Some(files) = &mut config.tls_files {
+            fields.extend([
+                &mut files.ca_certificate,
+                &mut files.device_certificate,
+                &mut files.device_private_key,
+            ]);
+        }
+        if let Some(tls) = &mut config.bridge_tls {
+            fields.extend([&mut tls.certificate, &mut tls.private_key, &mut tls.ca_certificate]);
+        }
+        if let Some(health) = &mut config.health {
+            fields.push(&mut health.host);
+        }
+        if let Some(persistence) = &mut config.persistence {
+            fields.push(&mut persistence.path);
+            fields.extend(persistence.instance.as_mut());
+        }
+
+        for field in fields {
+            *field = expand_env(field)?;
+        }
+
+        Ok(())
+    }
+
+    /// Replaces every `${VAR}` in value with the variable from environment of the process,
+    /// failing if it isn't set. `$${` is replaced with a literal `${`.
+    pub fn expand_env(value: &str) -> Result<String, anyhow::Error> {
+        let mut expanded = String::with_capacity(value.len());
+        let mut rest = value;
+        while let Some(start) = rest.find('$') {
+            expanded.push_str(&rest[..start]);
+            rest = &rest[start..];
+
+            if let Some(escaped) = rest.strip_prefix("$${") {
+                expanded.push_str("${");
+                rest = escaped;
+            } else if let Some(reference) = rest.strip_prefix("${") {
+                let end = reference.find('}').ok_or_else(|| {
+                    anyhow::Error::msg(format!("Unterminated variable reference in {:?}", value))
+                })?;
+                let name = &reference[..end];
+                let var = std::env::var(name).map_err(|_| {
+                    anyhow::Error::msg(format!(
+                        "Environment variable {} referenced in config isn't set",
+                        name
+                    ))
+                })?;
+                expanded.push_str(&var);
+                rest = &reference[end + 1..];
+            } else {
+                expanded.push('$');
+                rest = &rest[1..];
+            }
+        }
+        expanded.push_str(rest);
+
+        Ok(expanded)
+    }
+
+    /// Validates config as a whole, so that uplink fails to start with a descriptive error on
+    /// a misconfiguration, rather than failing on it at runtime
+    pub fn validate(config: &Config) -> Result<(), anyhow::Error> {
+        validate_broker(config)?;
+        validate_streams(config)?;
+        validate_packet_size(config)?;
+        validate_backpressure(config)?;
+        validate_bridge_input(config)?;
+        validate_bridge_socket(config)?;
+        validate_action_signing(config)?;
+        validate_topics(config)?;
+
+        Ok(())
+    }
+
+    // Ensure that broker to connect with is configured, so that uplink fails early otherwise
+    fn validate_broker(config: &Config) -> Result<(), anyhow::Error> {
+        if config.broker.trim().is_empty() {
+            return Err(anyhow::Error::msg("Broker host missing from config"));
+        }
+
+        if config.port == 0 {
+            return Err(anyhow::Error::msg("Broker port must be in range 1-65535"));
+        }
+
+        for endpoint in &config.failover_brokers {
+            if endpoint.host.trim().is_empty() || endpoint.port == 0 {
+                return Err(anyhow::Error::msg(format!("Invalid failover broker {:?}", endpoint)));
+            }
+        }
+
+        // MQTT client doesn't support keep alives shorter than 5s
+        if config.keep_alive_secs < 5 {
+            return Err(anyhow::Error::msg("keep_alive_secs must be at least 5s"));
+        }
+
+        let reconnect = &config.reconnect;
+        if reconnect.initial_delay_ms == 0 || reconnect.max_delay_ms < reconnect.initial_delay_ms {
+            return Err(anyhow::Error::msg(
+                "reconnect delays must be non-zero, with max_delay_ms at least initial_delay_ms",
+            ));
+        }
+
+        let client_id = config.client_id.as_ref().unwrap_or(&config.device_id);
+        if client_id.trim().is_empty() {
+            return Err(anyhow::Error::msg("MQTT client id missing from config"));
+        }
+
+        if config.last_will.as_ref().map_or(false, |will| will.qos > 2) {
+            return Err(anyhow::Error::msg("qos of last_will must be 0, 1 or 2"));
+        }
+
+        if config.hello.as_ref().map_or(false, |hello| hello.qos > 2) {
+            return Err(anyhow::Error::msg("qos of hello must be 0, 1 or 2"));
+        }
+
+        Ok(())
+    }
+
+    // Smallest possible size of a serialized data point, i.e. {"sequence":0,"timestamp":0}
+    pub const MIN_POINT_SIZE: usize = 32;
+
+    // Largest packet allowed by MQTT, as the remaining length of a packet is at most 4 bytes
+    const MAX_PACKET_SIZE: usize = 268435455;
+
+    // Streams of config, by name, along with streams uplink publishes onto by itself
+    fn all_streams(config: &Config) -> Vec<(&str, &StreamConfig)> {
+        let mut streams: Vec<(&str, &StreamConfig)> =
+            vec![("action_status", &config.action_status)];
+        streams.extend(config.streams.iter().map(|(name, stream)| (name.as_str(), stream)));
+        streams.extend(config.action_results.iter().map(|(name, stream)| (name.as_str(), stream)));
+        if let Some(stream) = &config.serializer_metrics {
+            streams.push(("metrics", stream));
+        }
+        if let Some(stream) = &config.bridge_metrics {
+            streams.push(("bridge_metrics", stream));
+        }
+        if let Some(stream) = &config.default_stream_config {
+            streams.push(("default_stream_config", stream));
+        }
+
+        streams
+    }
+
+    // Ensure that every stream batches data and has a topic to publish onto, if configured. Action
+    // status falls back onto its default topic when none is configured.
+    fn validate_streams(config: &Config) -> Result<(), anyhow::Error> {
+        for (name, stream) in all_streams(config) {
+            if stream.buf_size == 0 {
+                return Err(anyhow::Error::msg(format!(
+                    "buf_size of stream {} must be at least 1",
+                    name
+                )));
+            }
+
+            if stream.topic.as_ref().map_or(false, |t| t.trim().is_empty()) {
+                return Err(anyhow::Error::msg(format!(
+                    "Topic of stream {} is empty, configure a topic or leave it out for the default",
+                    name
+                )));
+            }
+
+            if stream.qos > 2 {
+                return Err(anyhow::Error::msg(format!(
+                    "qos of stream {} must be 0, 1 or 2",
+                    name
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Ensure that a batch of every stream fits into a single packet, as larger batches are
+    // rejected by the broker and can't be read back from disk either
+    fn validate_packet_size(config: &Config) -> Result<(), anyhow::Error> {
+        if !(MIN_POINT_SIZE..=MAX_PACKET_SIZE).contains(&config.max_packet_size) {
+            return Err(anyhow::Error::msg(format!(
+                "max_packet_size must be in range {}-{} bytes",
+                MIN_POINT_SIZE, MAX_PACKET_SIZE
+            )));
+        }
+
+        if let Some(aggregation) = &config.aggregation {
+            if aggregation.max_size > config.max_packet_size {
+                return Err(anyhow::Error::msg(format!(
+                    "max_size of aggregation must be at most max_packet_size of {} bytes",
+                    config.max_packet_size
+                )));
+            }
+        }
+
+        for (name, stream) in all_streams(config) {
+            let min_batch_size = stream.buf_size * MIN_POINT_SIZE;
+            if min_batch_size > config.max_packet_size {
+                return Err(anyhow::Error::msg(format!(
+                    "max_packet_size of {} can't fit {} data points of stream {}, requiring at least {} bytes",
+                    config.max_packet_size, stream.buf_size, name, min_batch_size
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Ensure that collection paused on crossing the high watermark can be resumed, as otherwise
+    // the bridge flip-flops between paused and resumed with every publish written to disk
+    fn validate_backpressure(config: &Config) -> Result<(), anyhow::Error> {
+        if let Some(backpressure) = &config.backpressure {
+            if backpressure.low_watermark >= backpressure.high_watermark {
+                return Err(anyhow::Error::msg(
+                    "backpressure low_watermark must be less than high_watermark",
+                ));
+            }
+        }
+
+        // Nothing would ever be sent at a rate of 0
+        if let Some(rate_limit) = &config.rate_limit {
+            if rate_limit.bytes_per_sec == 0 || rate_limit.burst_size == Some(0) {
+                return Err(anyhow::Error::msg("rate_limit must allow a non-zero rate and burst"));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Ensure that actions can be verified, as they would all be rejected otherwise
+    fn validate_action_signing(config: &Config) -> Result<(), anyhow::Error> {
+        if let Some(signing) = &config.action_signing {
+            signing.public_key()?;
+        }
+
+        Ok(())
+    }
+
+    // Ensure that topics only reference placeholders that are replaced, so that a typo in a
+    // template shared by a fleet doesn't publish onto a literal "{devce_id}" from every device
+    fn validate_topics(config: &Config) -> Result<(), anyhow::Error> {
+        for (name, stream) in all_streams(config) {
+            if let Some(topic) = &stream.topic {
+                validate_placeholders(topic, &["tenant_id", "device_id", "stream"])
+                    .with_context(|| format!("Invalid topic of stream {}", name))?;
+            }
+        }
+
+        let mut topics = vec![];
+        topics.extend(config.aggregation.as_ref().map(|a| ("aggregation", &a.topic)));
+        topics.extend(config.hello.as_ref().map(|h| ("hello", &h.topic)));
+        topics.extend(config.last_will.as_ref().map(|w| ("last_will", &w.topic)));
+        for (name, topic) in topics {
+            validate_placeholders(topic, &["tenant_id", "device_id"])
+                .with_context(|| format!("Invalid topic of {}", name))?;
+        }
+
+        Ok(())
+    }
+
+    fn validate_placeholders(topic: &str, allowed: &[&str]) -> Result<(), anyhow::Error> {
+        let mut rest = topic;
+        while let Some(start) = rest.find('{') {
+            let end = rest[start..].find('}').ok_or_else(|| {
+                anyhow::Error::msg(format!("Unterminated placeholder in {:?}", topic))
+            })?;
+            let placeholder = &rest[start + 1..start + end];
+            if !allowed.contains(&placeholder) {
+                return Err(anyhow::Error::msg(format!(
+                    "Unknown placeholder {{{}}} in {:?}, expected one of {:?}",
+                    placeholder, topic, allowed
+                )));
+            }
+            rest = &rest[start + end + 1..];
+        }
+
+        Ok(())
+    }
+
+    // Ensure that nothing else writes onto stdout when actions are written onto it for a client
+    // on stdin, logs written onto files in log_dir are captured by redirecting stdout
+    fn validate_bridge_input(config: &Config) -> Result<(), anyhow::Error> {
+        if config.bridge_input == BridgeInput::Stdin && config.log_dir.is_some() {
+            return Err(anyhow::Error::msg("log_dir can't be configured with bridge_input stdin"));
+        }
+
+        Ok(())
+    }
+
+    // Bridge listens on unix domain sockets only where they are supported
+    fn validate_bridge_socket(config: &Config) -> Result<(), anyhow::Error> {
+        if cfg!(not(unix)) && config.bridge_socket.is_some() {
+            return Err(anyhow::Error::msg("bridge_socket is only supported on unix"));
+        }
+
+        Ok(())
+    }
+
+    // Ensure that data can be persisted, so that uplink doesn't fail to write it only when the
+    // network goes down
+    fn create_persistence_dir(path: &str) -> Result<(), anyhow::Error> {
+        fs::create_dir_all(path)
+            .with_context(|| format!("Failed to create persistence directory {}", path))?;
+
+        let probe = std::path::Path::new(path).join(".uplink_write_check");
+        fs::write(&probe, b"")
+            .and_then(|_| fs::remove_file(&probe))
+            .with_context(|| format!("Persistence directory {} isn't writable", path))?;
+
+        Ok(())
+    }
+
+    /// Applies settings of a reloaded config that can change while uplink is running, onto the
+    /// running config, i.e. streams, default_stream, default_stream_config, action timeouts,
+    /// intervals of metrics and settings of bridge connections, which take effect on connections
+    /// made after the reload. Settings of streams that determine how their data is stored on disk
+    /// are retained. Returns the updated config along with names of other settings that changed,
+    /// which only take effect on restart.
+    pub fn reload(running: &Config, reloaded: Config) -> (Config, Vec<&'static str>) {
+        let mut restart = vec![];
+        macro_rules! compare {
+            ($($field:ident),*) => {
+                $(
+                    if format!("{:?}", running.$field) != format!("{:?}", reloaded.$field) {
+                        restart.push(stringify!($field));
+                    }
+                )*
+            };
+        }
+        compare!(
+            project_id,
+            device_id,
+            broker,
+            port,
+            failover_brokers,
+            client_id,
+            authentication,
+            tls_files,
+            bridge_host,
+            bridge_port,
+            bridge_socket,
+            bridge_framing,
+            bridge_input,
+            bridge_tls,
+            max_action_payload_size,
+            run_logcat,
+            max_packet_size,
+            max_inflight,
+            keep_alive_secs,
+            reconnect,
+            last_will,
+            hello,
+            actions,
+            tools_dir,
+            action_concurrency,
+            action_queue_size,
+            action_processes,
+            action_webhooks,
+            action_handlers,
+            action_payload_spool_size,
+            action_signing,
+            persistence,
+            backpressure,
+            network_compression,
+            rate_limit,
+            aggregation,
+            dry_run,
+            payload_format,
+            timestamp_format,
+            log_dir,
+            log_format,
+            action_status,
+            action_results,
+            serializer_metrics,
+            bridge_metrics,
+            metrics_path,
+            prometheus_host,
+            prometheus_port,
+            health,
+            ota,
+            stats,
+            simulator
+        );
+
+        // Password isn't part of debug output of credentials
+        let credentials =
+            |c: &Config| c.credentials.as_ref().map(|c| (c.username.clone(), c.password.clone()));
+        if credentials(running) != credentials(&reloaded) {
+            restart.push("credentials");
+        }
+
+        // Serializer writes data of streams onto storages, and tracks its delivery, as configured
+        // at startup. Such settings are retained from the running config, new streams are written
+        // onto the shared storage and aren't tracked.
+        let mut streams = reloaded.streams;
+        let mut retained = false;
+        for (name, stream) in streams.iter_mut() {
+            let (priority, persistence, overflow_policy, ack_cursor) =
+                match running.streams.get(name) {
+                    Some(s) => (s.priority, s.persistence.clone(), s.overflow_policy, s.ack_cursor),
+                    None => (stream.priority, None, stream.overflow_policy, false),
+                };
+            retained |= stream.priority != priority
+                || format!("{:?}", stream.persistence) != format!("{:?}", persistence)
+                || stream.overflow_policy != overflow_policy
+                || stream.ack_cursor != ack_cursor;
+            stream.priority = priority;
+            stream.persistence = persistence;
+            stream.overflow_policy = overflow_policy;
+            stream.ack_cursor = ack_cursor;
+        }
+        if retained {
+            restart.push("streams (priority, persistence, overflow_policy or ack_cursor)");
+        }
+
+        let config = Config {
+            streams,
+            default_stream: reloaded.default_stream,
+            default_stream_config: reloaded.default_stream_config,
+            action_timeout_secs: reloaded.action_timeout_secs,
+            action_timeouts: reloaded.action_timeouts,
+            bridge_idle_timeout_secs: reloaded.bridge_idle_timeout_secs,
+            bridge_acks: reloaded.bridge_acks,
+            bridge_max_record_size: reloaded.bridge_max_record_size,
+            max_inflight_actions: reloaded.max_inflight_actions,
+            inflight_actions_policy: reloaded.inflight_actions_policy,
+            metrics_interval_secs: reloaded.metrics_interval_secs,
+            metrics_sample_interval_ms: reloaded.metrics_sample_interval_ms,
+            ..running.clone()
+        };
+
+        (config, restart)
+    }
+
+    // Replace placeholders in topic strings with configured values for tenant_id and device_id,
+    // along with the name of the stream, if known
+    fn replace_topic_placeholders(
+        config: &mut StreamConfig,
+        stream: Option<&str>,
+        tenant_id: &str,
+        device_id: &str,
+    ) {
         if let Some(topic) = &config.topic {
             let topic = topic.replace("{tenant_id}", tenant_id);
-            let topic = topic.replace("{device_id}", device_id);
+            let mut topic = topic.replace("{device_id}", device_id);
+            if let Some(stream) = stream {
+                topic = topic.replace("{stream}", stream);
+            }
             config.topic = Some(topic);
         }
     }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+        use crate::base::{ActionHandler, ActionHandlers, StreamPersistence};
+
+        fn config() -> Config {
+            let gps = StreamConfig {
+                topic: Some("/tenants/demo/devices/1/events/gps/jsonarray".to_owned()),
+                buf_size: 10,
+                ..Default::default()
+            };
+            Config {
+                device_id: "1".to_owned(),
+                broker: "localhost".to_owned(),
+                port: 1883,
+                keep_alive_secs: 60,
+                max_packet_size: 1024,
+                streams: [("gps".to_owned(), gps)].into_iter().collect(),
+                action_status: StreamConfig {
+                    topic: Some("/action/status".to_owned()),
+                    buf_size: 1,
+                    ..Default::default()
+                },
+                ..Default::default()
+            }
+        }
+
+        #[test]
+        fn action_handlers_are_routed_by_name_and_kind() {
+            let handlers = r#"
+                [names]
+                update_config = { handler = "tool", tool = "apply_config" }
+
+                [kinds]
+                process = { handler = "bridge" }
+                file = { handler = "download" }
+            "#;
+            let handlers: ActionHandlers = config::Config::builder()
+                .add_source(File::from_str(handlers, FileFormat::Toml))
+                .build()
+                .unwrap()
+                .try_deserialize()
+                .unwrap();
+
+            let tool = ActionHandler::Tool { tool: "apply_config".to_owned() };
+            assert_eq!(handlers.names["update_config"], tool);
+            assert_eq!(handlers.kinds["process"], ActionHandler::Bridge);
+            assert_eq!(handlers.kinds["file"], ActionHandler::Download);
+        }
+
+        #[test]
+        fn misconfigured_streams_fail_validation() {
+            assert!(validate(&config()).is_ok());
+
+            let mut c = config();
+            c.action_status.topic = None;
+            assert!(validate(&c).is_ok());
+            c.action_status.topic = Some("".to_owned());
+            assert!(validate(&c).unwrap_err().to_string().contains("stream action_status"));
+
+            let mut c = config();
+            c.streams.get_mut("gps").unwrap().buf_size = 0;
+            assert!(validate(&c).unwrap_err().to_string().contains("buf_size of stream gps"));
+
+            let mut c = config();
+            c.streams.get_mut("gps").unwrap().topic = Some(" ".to_owned());
+            assert!(validate(&c).unwrap_err().to_string().contains("Topic of stream gps"));
+
+            let mut c = config();
+            c.streams.get_mut("gps").unwrap().qos = 3;
+            assert!(validate(&c).unwrap_err().to_string().contains("qos of stream gps"));
+
+            let mut c = config();
+            c.max_packet_size = 0;
+            assert!(validate(&c).unwrap_err().to_string().contains("max_packet_size"));
+        }
+
+        #[test]
+        fn last_will_with_invalid_qos_fails_validation() {
+            let will = |qos| crate::base::LastWill {
+                topic: "/devices/{device_id}/lwt".to_owned(),
+                payload: "offline".to_owned(),
+                qos,
+                retain: false,
+            };
+            let mut c = config();
+            c.last_will = Some(will(2));
+            validate(&c).unwrap();
+            c.last_will = Some(will(3));
+            assert!(validate(&c).unwrap_err().to_string().contains("qos of last_will"));
+        }
+
+        #[test]
+        fn hello_with_invalid_qos_fails_validation() {
+            let hello =
+                |qos| crate::base::Hello { topic: "/devices/{device_id}/hello".to_owned(), qos };
+            let mut c = config();
+            c.hello = Some(hello(2));
+            validate(&c).unwrap();
+            c.hello = Some(hello(3));
+            assert!(validate(&c).unwrap_err().to_string().contains("qos of hello"));
+        }
+
+        #[test]
+        fn topic_templates_are_expanded_for_the_device() {
+            let mut c = config();
+            let template = "/tenants/{tenant_id}/devices/{device_id}/events/{stream}/jsonarray";
+            c.streams.get_mut("gps").unwrap().topic = Some(template.to_owned());
+            validate(&c).unwrap();
+
+            let gps = c.streams.get_mut("gps").unwrap();
+            replace_topic_placeholders(gps, Some("gps"), "demo", "1");
+            assert_eq!(gps.topic.as_deref(), Some("/tenants/demo/devices/1/events/gps/jsonarray"));
+
+            // Typos in templates are rejected, rather than published onto as is
+            let mut c = config();
+            c.streams.get_mut("gps").unwrap().topic = Some("/devices/{devce_id}/gps".to_owned());
+            let e = format!("{:#}", validate(&c).unwrap_err());
+            assert!(e.contains("stream gps") && e.contains("{devce_id}"), "{}", e);
+            c.streams.get_mut("gps").unwrap().topic = Some("/devices/{device_id/gps".to_owned());
+            assert!(validate(&c).is_err());
+            c.hello = Some(crate::base::Hello { topic: "/{stream}/hello".to_owned(), qos: 1 });
+            c.streams.get_mut("gps").unwrap().topic = None;
+            assert!(validate(&c).is_err());
+        }
+
+        #[test]
+        fn stdout_is_left_to_actions_with_stdin_input() {
+            let mut c = config();
+            c.bridge_input = BridgeInput::Stdin;
+            validate(&c).unwrap();
+
+            // Logs in log_dir are written by redirecting stdout
+            c.log_dir = Some("/var/log/uplink".to_owned());
+            assert!(validate(&c).unwrap_err().to_string().contains("log_dir"));
+        }
+
+        #[test]
+        fn storage_of_streams_is_retained_on_reload() {
+            let running = config();
+            let mut reloaded = config();
+            reloaded.broker = "broker.example.com".to_owned();
+            reloaded.metrics_interval_secs = 30;
+            let gps = reloaded.streams.get_mut("gps").unwrap();
+            gps.buf_size = 20;
+            gps.ack_cursor = true;
+            let persistence =
+                StreamPersistence { max_file_size: 1024, max_file_count: 3, max_disk_size: None };
+            let can = StreamConfig { persistence: Some(persistence), ..Default::default() };
+            reloaded.streams.insert("can".to_owned(), can);
+
+            let (config, restart) = reload(&running, reloaded);
+            assert_eq!(config.streams["gps"].buf_size, 20);
+            assert!(!config.streams["gps"].ack_cursor);
+            assert!(config.streams["can"].persistence.is_none());
+            assert_eq!(config.metrics_interval_secs, 30);
+            assert_eq!(config.broker, "localhost");
+            assert_eq!(
+                restart,
+                vec!["broker", "streams (priority, persistence, overflow_policy or ack_cursor)"]
+            );
+        }
+
+        #[test]
+        fn env_vars_are_expanded_and_escaped() {
+            std::env::set_var("UPLINK_TEST_BROKER", "broker.example.com");
+            let expanded = expand_env("${UPLINK_TEST_BROKER}:$${PORT}, $5").unwrap();
+            assert_eq!(expanded, "broker.example.com:${PORT}, $5");
+
+            let e = expand_env("/certs/${UPLINK_TEST_UNSET}/ca.pem").unwrap_err();
+            assert!(e.to_string().contains("UPLINK_TEST_UNSET"));
+            assert!(expand_env("${UPLINK_TEST_BROKER").is_err());
+        }
+
+        #[test]
+        fn persistence_dir_must_be_writable() {
+            let dir = std::env::temp_dir().join("uplink_persistence_check");
+            let _ = fs::remove_dir_all(&dir);
+            create_persistence_dir(dir.join("a").to_str().unwrap()).unwrap();
+            assert!(!dir.join("a/.uplink_write_check").exists());
+
+            // Directory can't be created within a file
+            fs::write(dir.join("file"), b"").unwrap();
+            let e = create_persistence_dir(dir.join("file/a").to_str().unwrap()).unwrap_err();
+            assert!(e.to_string().contains("Failed to create persistence directory"));
+            fs::remove_dir_all(&dir).unwrap();
+        }
+    }
 }
 
 pub use base::actions;
 use base::actions::ota::OtaDownloader;
 use base::actions::tunshell::TunshellSession;
-use base::actions::Actions;
 pub use base::actions::{Action, ActionResponse};
+use base::actions::{ActionRoutes, Actions};
 use base::mqtt::Mqtt;
-use base::serializer::Serializer;
+pub use base::serializer::SerializerState;
+use base::serializer::{InflightWindow, Serializer, SharedMetrics};
 pub use base::{Config, Package, Point, Stream};
 pub use collector::simulator;
 use collector::systemstats::StatCollector;
@@ -148,6 +891,13 @@ pub struct Uplink {
     data_rx: Receiver<Box<dyn Package>>,
     data_tx: Sender<Box<dyn Package>>,
     action_status: Stream<ActionResponse>,
+    serializer_state_tx: Sender<SerializerState>,
+    serializer_state_rx: Receiver<SerializerState>,
+    backpressure_tx: Option<watch::Sender<bool>>,
+    backpressure_rx: watch::Receiver<bool>,
+    broker_tx: Option<watch::Sender<String>>,
+    broker_rx: watch::Receiver<String>,
+    config_updates: watch::Receiver<Arc<Config>>,
 }
 
 impl Uplink {
@@ -155,17 +905,56 @@ impl Uplink {
         let (action_tx, action_rx) = bounded(10);
         let (data_tx, data_rx) = bounded(10);
 
-        let action_status_topic = &config
-            .action_status
-            .topic
-            .as_ref()
-            .ok_or_else(|| Error::msg("Action status topic missing from config"))?;
-        let action_status = Stream::new("action_status", action_status_topic, 1, data_tx.clone());
+        // Action responses are always published, onto the default topic if none is configured
+        let action_status_topic = match &config.action_status.topic {
+            Some(topic) => topic.to_owned(),
+            None => {
+                let topic = format!(
+                    "/tenants/{}/devices/{}/action/status",
+                    config.project_id.trim(),
+                    config.device_id.trim()
+                );
+                warn!("Topic of [action_status] missing from config, using {}", topic);
+                topic
+            }
+        };
+        let action_status =
+            Stream::new("action_status", action_status_topic.as_str(), 1, data_tx.clone());
+
+        let (serializer_state_tx, serializer_state_rx) = bounded(10);
+        let (backpressure_tx, backpressure_rx) = watch::channel(false);
+        let (broker_tx, broker_rx) = watch::channel(String::new());
+        let (_, config_updates) = watch::channel(config.clone());
+        config.timestamp_format.set_global();
+
+        Ok(Uplink {
+            config,
+            action_rx,
+            action_tx,
+            data_rx,
+            data_tx,
+            action_status,
+            serializer_state_tx,
+            serializer_state_rx,
+            backpressure_tx: Some(backpressure_tx),
+            backpressure_rx,
+            broker_tx: Some(broker_tx),
+            broker_rx,
+            config_updates,
+        })
+    }
 
-        Ok(Uplink { config, action_rx, action_tx, data_rx, data_tx, action_status })
+    /// Applies config reloaded while uplink is running onto the serializer and handlers of
+    /// actions, as per [`config::reload`]. Must be set before spawning.
+    pub fn with_config_updates(mut self, config_updates: watch::Receiver<Arc<Config>>) -> Uplink {
+        self.config_updates = config_updates;
+        self
     }
 
     pub fn spawn(&mut self) -> Result<(), Error> {
+        let action_routes =
+            ActionRoutes::new(&self.config, self.action_status.clone(), self.data_tx.clone());
+
         // Launch a thread to handle tunshell access
         let (tunshell_keys_tx, tunshell_keys_rx) = bounded(10);
         let tunshell_config = self.config.clone();
@@ -173,14 +962,14 @@ impl Uplink {
             tunshell_config,
             false,
             tunshell_keys_rx,
-            self.action_status.clone(),
+            action_routes.status("launch_shell"),
         );
         thread::spawn(move || tunshell_session.start());
 
         // Launch a thread to handle downloads for OTA updates
         let (ota_tx, ota_downloader) = OtaDownloader::new(
             self.config.clone(),
-            self.action_status.clone(),
+            action_routes.status("update_firmware"),
             self.action_tx.clone(),
         )?;
         if self.config.ota.enabled {
@@ -195,6 +984,11 @@ impl Uplink {
 
         let (raw_action_tx, raw_action_rx) = bounded(10);
         let mut mqtt = Mqtt::new(self.config.clone(), raw_action_tx);
+        if let Some(broker_tx) = self.broker_tx.take() {
+            mqtt = mqtt.with_broker_tx(broker_tx);
+        }
+        let inflight = InflightWindow::new(self.config.max_inflight as usize);
+        mqtt = mqtt.with_inflight(inflight.clone());
 
         let metrics_stream = self.config.serializer_metrics.as_ref().map(|metrics_config| {
             Stream::with_config(
@@ -206,22 +1000,59 @@ impl Uplink {
             )
         });
 
-        let serializer = Serializer::new(
+        // Serializer only shares metrics and state when they are served locally
+        let served_locally = self.config.prometheus_port.is_some() || self.config.health.is_some();
+        let shared_metrics = served_locally.then(SharedMetrics::new);
+        if self.config.prometheus_port.is_some() && cfg!(not(feature = "prometheus")) {
+            warn!("prometheus_port is configured, but uplink was built without prometheus feature");
+        }
+        if self.config.health.is_some() && cfg!(not(feature = "health")) {
+            warn!("health is configured, but uplink was built without health feature");
+        }
+        if !self.config.action_webhooks.is_empty() && cfg!(not(feature = "webhooks")) {
+            warn!("action_webhooks are configured, but uplink was built without webhooks feature");
+        }
+
+        let (metrics_tx, metrics_rx) = bounded(1);
+        let mut serializer = Serializer::new(
             self.config.clone(),
             self.data_rx.clone(),
             metrics_stream,
+            metrics_rx,
+            Some(self.serializer_state_tx.clone()),
+            shared_metrics.clone(),
             mqtt.client(),
         )?;
+        if let Some(backpressure_tx) = self.backpressure_tx.take() {
+            serializer = serializer.with_backpressure(backpressure_tx);
+        }
+        serializer = serializer.with_inflight(inflight);
+        serializer = serializer.with_config_updates(self.config_updates.clone());
+
+        #[cfg(feature = "health")]
+        let health = self
+            .config
+            .health
+            .clone()
+            .zip(shared_metrics.clone())
+            .map(|(config, metrics)| base::health::HealthCheck::new(config, metrics));
+
+        #[cfg(feature = "prometheus")]
+        let exporter = self.config.prometheus_port.zip(shared_metrics).map(|(port, metrics)| {
+            base::prometheus::Exporter::new(self.config.prometheus_host.clone(), port, metrics)
+        });
 
         let actions = Actions::new(
             self.config.clone(),
             raw_action_rx,
             tunshell_keys_tx,
             ota_tx,
-            self.action_status.clone(),
+            action_routes,
             self.action_tx.clone(),
             self.bridge_data_tx().clone(),
-        );
+            metrics_tx,
+        )
+        .with_config_updates(self.config_updates.clone());
 
         // Launch a thread to handle incoming and outgoing MQTT packets
         let rt = tokio::runtime::Runtime::new()?;
@@ -234,11 +1065,35 @@ impl Uplink {
                     }
                 });
 
-                // Receive [Action]s
+                // Receive [Action]s, exiting for the process supervisor to restart uplink if
+                // connecting with the broker is given up on
                 task::spawn(async move {
-                    mqtt.start().await;
+                    if let Err(e) = mqtt.start().await {
+                        error!("Mqtt stopped!! Error = {:?}", e);
+                        std::process::exit(1);
+                    }
                 });
 
+                // Serve health of uplink to process supervisors
+                #[cfg(feature = "health")]
+                if let Some(health) = health {
+                    task::spawn(async move {
+                        if let Err(e) = health.start().await {
+                            error!("Health check stopped!! Error = {:?}", e);
+                        }
+                    });
+                }
+
+                // Serve serializer metrics for scraping by prometheus
+                #[cfg(feature = "prometheus")]
+                if let Some(exporter) = exporter {
+                    task::spawn(async move {
+                        if let Err(e) = exporter.start().await {
+                            error!("Prometheus exporter stopped!! Error = {:?}", e);
+                        }
+                    });
+                }
+
                 // Process and forward received [Action]s to connected applications
                 actions.start().await;
             })
@@ -258,4 +1113,22 @@ impl Uplink {
     pub fn action_status(&self) -> Stream<ActionResponse> {
         self.action_status.clone()
     }
+
+    /// Watches whether the bridge should stop reading data from applications, as data pending
+    /// on disk crossed the high watermark of `backpressure`. Never set if it isn't configured.
+    pub fn backpressure(&self) -> watch::Receiver<bool> {
+        self.backpressure_rx.clone()
+    }
+
+    /// Watches the address of the broker uplink is connected to, as "host:port", which changes
+    /// on failing over to one of `failover_brokers`. Empty till uplink first connects.
+    pub fn active_broker(&self) -> watch::Receiver<String> {
+        self.broker_rx.clone()
+    }
+
+    /// Receives the state of serializer on every transition, e.g. to indicate connectivity locally.
+    /// Transitions are dropped when the receiver lags behind by more than 10 of them.
+    pub fn serializer_state(&self) -> Receiver<SerializerState> {
+        self.serializer_state_rx.clone()
+    }
 }
@@ -40,17 +40,29 @@
 //!```
 
 use std::fs;
+use std::io::BufWriter;
 use std::sync::Arc;
 
 use anyhow::Error;
-use log::error;
+#[cfg(unix)]
+use log::warn;
+use log::{error, info};
 use simplelog::{ColorChoice, CombinedLogger, LevelFilter, LevelPadding, TermLogger, TerminalMode};
 use structopt::StructOpt;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::prelude::*;
 
-use uplink::config::{initialize, CommandLine};
+use uplink::base::export::export;
+#[cfg(unix)]
+use uplink::config::reload;
+use uplink::config::{initialize, BridgeInput, CommandLine, LogFormat};
 use uplink::{simulator, Bridge, Config, Uplink};
 
-fn initialize_logging(commandline: &CommandLine) {
+// Logs are written onto stderr when stdout carries actions for a client on stdin
+fn initialize_logging(commandline: &CommandLine, format: LogFormat, stderr: bool) {
     let level = match commandline.verbose {
         0 => LevelFilter::Warn,
         1 => LevelFilter::Info,
@@ -58,6 +70,10 @@ fn initialize_logging(commandline: &CommandLine) {
         _ => LevelFilter::Trace,
     };
 
+    if format == LogFormat::Json {
+        return initialize_json_logging(commandline, level, stderr);
+    }
+
     let mut config = simplelog::ConfigBuilder::new();
     config
         .set_location_level(LevelFilter::Off)
@@ -73,10 +89,39 @@ fn initialize_logging(commandline: &CommandLine) {
         }
     }
 
-    let loggers = TermLogger::new(level, config.build(), TerminalMode::Mixed, ColorChoice::Auto);
+    let mode = if stderr { TerminalMode::Stderr } else { TerminalMode::Mixed };
+    let loggers = TermLogger::new(level, config.build(), mode, ColorChoice::Auto);
     CombinedLogger::init(vec![loggers]).unwrap();
 }
 
+// Logs are written as a json object per line, along with fields of the spans they're written in.
+// Lines logged with the log crate are captured as events of the current span.
+fn initialize_json_logging(commandline: &CommandLine, level: LevelFilter, stderr: bool) {
+    let level = match level {
+        LevelFilter::Warn => tracing::Level::WARN,
+        LevelFilter::Info => tracing::Level::INFO,
+        LevelFilter::Debug => tracing::Level::DEBUG,
+        _ => tracing::Level::TRACE,
+    };
+
+    let modules = match commandline.modules.is_empty() {
+        true => vec!["uplink".to_owned(), "disk".to_owned()],
+        false => commandline.modules.clone(),
+    };
+    let targets =
+        modules.into_iter().fold(Targets::new(), |t, module| t.with_target(module, level));
+
+    let builder = tracing_subscriber::fmt()
+        .json()
+        .with_current_span(true)
+        .with_span_list(true)
+        .with_max_level(level);
+    match stderr {
+        true => builder.with_writer(std::io::stderr).finish().with(targets).init(),
+        false => builder.finish().with(targets).init(),
+    }
+}
+
 fn banner(commandline: &CommandLine, config: &Arc<Config>) {
     const B: &str = r#"
     ░█░▒█░▄▀▀▄░█░░░▀░░█▀▀▄░█░▄
@@ -109,20 +154,51 @@ fn banner(commandline: &CommandLine, config: &Arc<Config>) {
     println!("\n");
 }
 
+fn read_config(auth: &str, config: Option<&String>) -> Result<Config, Error> {
+    initialize(
+        fs::read_to_string(auth)?.as_str(),
+        config
+            .and_then(|path| fs::read_to_string(path).ok())
+            .unwrap_or_else(|| "".to_string())
+            .as_str(),
+    )
+}
+
+// Re-reads config files on every SIGHUP, applying changes that don't require a restart
+#[cfg(unix)]
+async fn reload_on_sighup(
+    auth: String,
+    path: Option<String>,
+    config_tx: watch::Sender<Arc<Config>>,
+) -> Result<(), Error> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    while hangup.recv().await.is_some() {
+        let reloaded = match read_config(&auth, path.as_ref()) {
+            Ok(c) => c,
+            Err(e) => {
+                error!("Failed to reload config, continuing with running config. Error = {:?}", e);
+                continue;
+            }
+        };
+
+        let (config, restart) = reload(&config_tx.borrow(), reloaded);
+        if !restart.is_empty() {
+            warn!("Changes to {} only take effect on restarting uplink", restart.join(", "));
+        }
+        info!("Reloaded config");
+        config_tx.send_replace(Arc::new(config));
+    }
+
+    Ok(())
+}
+
 #[tokio::main(worker_threads = 4)]
 async fn main() -> Result<(), Error> {
     let commandline: CommandLine = StructOpt::from_args();
 
-    initialize_logging(&commandline);
-    let config = Arc::new(initialize(
-        fs::read_to_string(&commandline.auth)?.as_str(),
-        commandline
-            .config
-            .as_ref()
-            .and_then(|path| fs::read_to_string(path).ok())
-            .unwrap_or_else(|| "".to_string())
-            .as_str(),
-    )?);
+    let config = Arc::new(read_config(&commandline.auth, commandline.config.as_ref())?);
+    let stdin = config.bridge_input == BridgeInput::Stdin;
+    initialize_logging(&commandline, config.log_format, stdin);
 
     let _log_guards = config.log_dir.as_ref().map(|log_dir| {
         std::fs::create_dir_all(log_dir).unwrap();
@@ -135,11 +211,38 @@ async fn main() -> Result<(), Error> {
         (_out_guard, _err_guard)
     });
 
-    banner(&commandline, &config);
+    // Forensics of data left on disk, without connecting to the broker
+    if let Some(path) = &commandline.export {
+        let exported = export(&config, BufWriter::new(fs::File::create(path)?))?;
+        println!(
+            "Exported {} publishes onto {}, skipped {} unreadable ones",
+            exported.publishes, path, exported.skipped
+        );
+        return Ok(());
+    }
+
+    // Banner would be read by the client on stdin as an action
+    if !stdin {
+        banner(&commandline, &config);
+    }
 
-    let mut uplink = Uplink::new(config.clone())?;
+    let (config_tx, config_updates) = watch::channel(config.clone());
+    let mut uplink = Uplink::new(config.clone())?.with_config_updates(config_updates.clone());
     uplink.spawn()?;
 
+    #[cfg(unix)]
+    {
+        let (auth, path) = (commandline.auth.clone(), commandline.config.clone());
+        tokio::spawn(async move {
+            if let Err(e) = reload_on_sighup(auth, path, config_tx).await {
+                error!("Config reload stopped!! Error = {:?}", e);
+            }
+        });
+    }
+    // Config is only reloaded on SIGHUP, which isn't available on other platforms
+    #[cfg(not(unix))]
+    drop(config_tx);
+
     if let Some(simulator_config) = &config.simulator {
         if let Err(e) =
             simulator::start(uplink.bridge_data_tx(), uplink.bridge_action_rx(), simulator_config)
@@ -153,6 +256,8 @@ async fn main() -> Result<(), Error> {
         uplink.bridge_action_rx(),
         uplink.action_status(),
     )
+    .with_backpressure(uplink.backpressure())
+    .with_config_updates(config_updates)
     .start()
     .await
     {